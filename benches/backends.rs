@@ -0,0 +1,81 @@
+//! Times every execution backend on the same ROM: `cargo bench --bench backends`, with
+//! `--features jit` to include the JIT.
+//!
+//! Without arguments this runs a small built-in workload, ARM and Thumb loops over IWRAM.
+//! `GBA_BENCH_ROM` times a real game instead and `GBA_BENCH_FRAMES` changes the run length.
+//! The machine state must come out the same on every backend, otherwise the run fails.
+
+extern crate gba_rs;
+
+use std::env;
+use std::fs;
+use std::process;
+use std::time::Instant;
+
+use gba_rs::{Backend, Gba};
+
+const DEFAULT_FRAMES: u32 = 600;
+
+#[cfg(not(feature = "jit"))]
+const BACKENDS: &[(&str, Backend)] = &[("interpreter", Backend::Interpreter), ("cached", Backend::Cached)];
+#[cfg(feature = "jit")]
+const BACKENDS: &[(&str, Backend)] = &[("interpreter", Backend::Interpreter), ("cached", Backend::Cached), ("jit", Backend::Jit)];
+
+/// An ARM loop of loads, stores and ALU work over 1 KiB of IWRAM that calls a Thumb loop
+/// doing the same with halfwords, forever.
+const WORKLOAD: [u32; 18] = [
+    0xE3A04403, 0xE3A00000, 0xE3A06B01, 0xE0900006, 0xE7840006, 0xE7941006, 0xE0212180, 0xE2566004,
+    0x1AFFFFF9, 0xE28F3009, 0xE1A0E00F, 0xE12FFF13, 0xEAFFFFF4, 0x198026FF, 0x53A10081, 0x40425BA2,
+    0xD1F83E01, 0x00004770,
+];
+
+/// Seconds taken and the final state hash.
+fn run(rom: &[u8], backend: Backend, frames: u32) -> (f64, u64) {
+    let mut gba = Gba::new();
+    if let Err(e) = gba.load_rom(rom) {
+        println!("failed to load the ROM: {}", e);
+        process::exit(2);
+    }
+    gba.reset();
+    gba.set_backend(backend);
+
+    let start = Instant::now();
+    for _ in 0..frames {
+        gba.run_frame();
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    (seconds, gba.hash_state())
+}
+
+fn main() {
+    let rom = match env::var("GBA_BENCH_ROM") {
+        Ok(path) => fs::read(&path).unwrap_or_else(|e| {
+            println!("failed to read {}: {}", path, e);
+            process::exit(2);
+        }),
+        Err(_) => WORKLOAD.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect(),
+    };
+
+    let frames = match env::var("GBA_BENCH_FRAMES") {
+        Ok(frames) => frames.parse().unwrap_or_else(|_| {
+            println!("bad GBA_BENCH_FRAMES {}", frames);
+            process::exit(2);
+        }),
+        Err(_) => DEFAULT_FRAMES,
+    };
+
+    let mut baseline = None;
+
+    for &(name, backend) in BACKENDS.iter() {
+        let (seconds, hash) = run(&rom, backend, frames);
+        let (base_seconds, base_hash) = *baseline.get_or_insert((seconds, hash));
+
+        println!("{:<12} {} frames in {:.3} s, {:.0} fps, {:.2}x", name, frames, seconds, frames as f64 / seconds, base_seconds / seconds);
+
+        if hash != base_hash {
+            println!("{} ended in a different state than {}", name, BACKENDS[0].0);
+            process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,1364 @@
+use std::collections::VecDeque;
+use std::io;
+
+use super::save_state::{StateError, StateReader, StateWriter};
+
+pub const SAMPLE_RATE: u32 = 32768;
+
+/// FIFO addresses a sound DMA has to target to refill Direct Sound A and B.
+pub const FIFO_A: u32 = 0x040000A0;
+pub const FIFO_B: u32 = 0x040000A4;
+
+const CYCLES_PER_SAMPLE: i32 = 16777216 / SAMPLE_RATE as i32;
+const FRAME_SEQUENCER_CYCLES: i32 = 16777216 / 512;
+
+const DUTY_CYCLES: [u8; 4] = [0b00000001, 0b10000001, 0b10000111, 0b01111110];
+const NOISE_DIVISORS: [i32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+const FIFO_SIZE: usize = 32;
+const FIFO_REFILL_LEVEL: usize = 16;
+
+/// Channel timers are reloaded from their period before they drop below 1, so a negative
+/// one can only come from a damaged state; it would take millions of periods to catch up.
+fn load_timer(reader: &mut StateReader) -> Result<i32, StateError> {
+    let timer = reader.read_i32()?;
+    if timer < 0 {
+        return Err(reader.corrupt());
+    }
+
+    Ok(timer)
+}
+
+struct Envelope {
+    initial_volume: u8,
+    increase: bool,
+    step: u8,
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    fn new() -> Envelope {
+        Envelope {
+            initial_volume: 0,
+            increase: false,
+            step: 0,
+            volume: 0,
+            timer: 0,
+        }
+    }
+
+    fn write(&mut self, value: u8) {
+        self.step = value & 0x07;
+        self.increase = value & 0x08 != 0;
+        self.initial_volume = value >> 4;
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.initial_volume);
+        writer.write_bool(self.increase);
+        writer.write_u8(self.step);
+        writer.write_u8(self.volume);
+        writer.write_u8(self.timer);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.initial_volume = reader.read_u8()? & 15;
+        self.increase = reader.read_bool()?;
+        self.step = reader.read_u8()? & 7;
+        self.volume = reader.read_u8()? & 15;
+        self.timer = reader.read_u8()?;
+        Ok(())
+    }
+
+    /// The DAC is off when the envelope can only ever produce silence.
+    fn dac_enabled(&self) -> bool {
+        self.initial_volume != 0 || self.increase
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial_volume;
+        self.timer = self.step;
+    }
+
+    fn clock(&mut self) {
+        if self.step == 0 {
+            return;
+        }
+
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = self.step;
+
+            if self.increase && self.volume < 15 {
+                self.volume += 1;
+            } else if !self.increase && self.volume > 0 {
+                self.volume -= 1;
+            }
+        }
+    }
+}
+
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    fn new() -> Length {
+        Length {
+            counter: 0,
+            enabled: false,
+        }
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.counter);
+        writer.write_bool(self.enabled);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.counter = reader.read_u16()?;
+        self.enabled = reader.read_bool()?;
+        Ok(())
+    }
+
+    fn trigger(&mut self, max: u16) {
+        if self.counter == 0 {
+            self.counter = max;
+        }
+    }
+
+    /// Returns false once the counter has run out and the channel has to stop.
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            self.counter != 0
+        } else {
+            true
+        }
+    }
+}
+
+struct SquareChannel {
+    enabled: bool,
+    duty: u8,
+    duty_step: u8,
+    frequency: u16,
+    timer: i32,
+    length: Length,
+    envelope: Envelope,
+
+    sweep_shift: u8,
+    sweep_decrease: bool,
+    sweep_time: u8,
+    sweep_timer: u8,
+    sweep_enabled: bool,
+    shadow_frequency: u16,
+}
+
+impl SquareChannel {
+    fn new() -> SquareChannel {
+        SquareChannel {
+            enabled: false,
+            duty: 0,
+            duty_step: 0,
+            frequency: 0,
+            timer: 0,
+            length: Length::new(),
+            envelope: Envelope::new(),
+
+            sweep_shift: 0,
+            sweep_decrease: false,
+            sweep_time: 0,
+            sweep_timer: 0,
+            sweep_enabled: false,
+            shadow_frequency: 0,
+        }
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.duty);
+        writer.write_u8(self.duty_step);
+        writer.write_u16(self.frequency);
+        writer.write_i32(self.timer);
+        self.length.save(writer);
+        self.envelope.save(writer);
+        writer.write_u8(self.sweep_shift);
+        writer.write_bool(self.sweep_decrease);
+        writer.write_u8(self.sweep_time);
+        writer.write_u8(self.sweep_timer);
+        writer.write_bool(self.sweep_enabled);
+        writer.write_u16(self.shadow_frequency);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.duty = reader.read_u8()? & 3;
+        self.duty_step = reader.read_u8()? & 7;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = load_timer(reader)?;
+        self.length.load(reader)?;
+        self.envelope.load(reader)?;
+        self.sweep_shift = reader.read_u8()? & 7;
+        self.sweep_decrease = reader.read_bool()?;
+        self.sweep_time = reader.read_u8()? & 7;
+        self.sweep_timer = reader.read_u8()? & 7;
+        self.sweep_enabled = reader.read_bool()?;
+        self.shadow_frequency = reader.read_u16()? & 0x7FF;
+        Ok(())
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 16
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.length.trigger(64);
+        self.envelope.trigger();
+
+        self.shadow_frequency = self.frequency;
+        self.sweep_timer = if self.sweep_time == 0 { 8 } else { self.sweep_time };
+        self.sweep_enabled = self.sweep_time != 0 || self.sweep_shift != 0;
+        if self.sweep_shift != 0 {
+            self.sweep_calculate();
+        }
+    }
+
+    fn sweep_calculate(&mut self) -> u16 {
+        let delta = self.shadow_frequency >> self.sweep_shift;
+        let frequency = if self.sweep_decrease {
+            self.shadow_frequency - delta
+        } else {
+            self.shadow_frequency + delta
+        };
+
+        if frequency > 2047 {
+            self.enabled = false;
+        }
+
+        frequency
+    }
+
+    fn clock_sweep(&mut self) {
+        self.sweep_timer = self.sweep_timer.saturating_sub(1);
+        if self.sweep_timer != 0 {
+            return;
+        }
+
+        self.sweep_timer = if self.sweep_time == 0 { 8 } else { self.sweep_time };
+
+        if self.sweep_enabled && self.sweep_time != 0 {
+            let frequency = self.sweep_calculate();
+
+            if frequency <= 2047 && self.sweep_shift != 0 {
+                self.shadow_frequency = frequency;
+                self.frequency = frequency;
+                self.sweep_calculate();
+            }
+        }
+    }
+
+    fn advance(&mut self, ticks: i32) {
+        self.timer -= ticks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.duty_step = (self.duty_step + 1) & 7;
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && (DUTY_CYCLES[self.duty as usize] >> self.duty_step) & 1 != 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+struct WaveChannel {
+    enabled: bool,
+    dac_enabled: bool,
+    two_banks: bool,
+    bank: u8,
+    volume: u8,
+    force_volume: bool,
+    frequency: u16,
+    timer: i32,
+    position: u8,
+    length: Length,
+    wave_ram: [u8; 32],
+}
+
+impl WaveChannel {
+    fn new() -> WaveChannel {
+        WaveChannel {
+            enabled: false,
+            dac_enabled: false,
+            two_banks: false,
+            bank: 0,
+            volume: 0,
+            force_volume: false,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            length: Length::new(),
+            wave_ram: [0; 32],
+        }
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_bool(self.dac_enabled);
+        writer.write_bool(self.two_banks);
+        writer.write_u8(self.bank);
+        writer.write_u8(self.volume);
+        writer.write_bool(self.force_volume);
+        writer.write_u16(self.frequency);
+        writer.write_i32(self.timer);
+        writer.write_u8(self.position);
+        self.length.save(writer);
+        writer.write_bytes(&self.wave_ram);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.dac_enabled = reader.read_bool()?;
+        self.two_banks = reader.read_bool()?;
+        self.bank = reader.read_u8()? & 1;
+        self.volume = reader.read_u8()? & 3;
+        self.force_volume = reader.read_bool()?;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.timer = load_timer(reader)?;
+        self.position = reader.read_u8()? & 63;
+        self.length.load(reader)?;
+        reader.read_bytes(&mut self.wave_ram)
+    }
+
+    fn period(&self) -> i32 {
+        (2048 - self.frequency as i32) * 8
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac_enabled;
+        self.timer = self.period();
+        self.position = 0;
+        self.length.trigger(256);
+    }
+
+    fn advance(&mut self, ticks: i32) {
+        self.timer -= ticks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+            self.position = (self.position + 1) % if self.two_banks { 64 } else { 32 };
+        }
+    }
+
+    /// The CPU always sees the bank that is not being played.
+    fn cpu_bank_offset(&self) -> usize {
+        (self.bank as usize ^ 1) * 16
+    }
+
+    fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+
+        let bank = (self.bank + self.position / 32) & 1;
+        let index = (self.position % 32) as usize;
+        let pair = self.wave_ram[bank as usize * 16 + index / 2];
+        let sample = if index & 1 == 0 { pair >> 4 } else { pair & 0x0F };
+
+        if self.force_volume {
+            sample * 3 / 4
+        } else {
+            match self.volume {
+                0 => 0,
+                1 => sample,
+                2 => sample >> 1,
+                _ => sample >> 2,
+            }
+        }
+    }
+}
+
+struct NoiseChannel {
+    enabled: bool,
+    divisor: u8,
+    short_width: bool,
+    shift: u8,
+    timer: i32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+
+impl NoiseChannel {
+    fn new() -> NoiseChannel {
+        NoiseChannel {
+            enabled: false,
+            divisor: 0,
+            short_width: false,
+            shift: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: Length::new(),
+            envelope: Envelope::new(),
+        }
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.enabled);
+        writer.write_u8(self.divisor);
+        writer.write_bool(self.short_width);
+        writer.write_u8(self.shift);
+        writer.write_i32(self.timer);
+        writer.write_u16(self.lfsr);
+        self.length.save(writer);
+        self.envelope.save(writer);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = reader.read_bool()?;
+        self.divisor = reader.read_u8()? & 7;
+        self.short_width = reader.read_bool()?;
+        self.shift = reader.read_u8()? & 15;
+        self.timer = load_timer(reader)?;
+        self.lfsr = reader.read_u16()?;
+        self.length.load(reader)?;
+        self.envelope.load(reader)
+    }
+
+    fn period(&self) -> i32 {
+        (NOISE_DIVISORS[self.divisor as usize] << self.shift) * 4
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.length.trigger(64);
+        self.envelope.trigger();
+    }
+
+    fn advance(&mut self, ticks: i32) {
+        // shift clocks 14 and 15 never clock the LFSR
+        if self.shift >= 14 {
+            return;
+        }
+
+        self.timer -= ticks;
+        while self.timer <= 0 {
+            self.timer += self.period();
+
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.short_width {
+                self.lfsr = (self.lfsr & !0x40) | (bit << 6);
+            }
+        }
+    }
+
+    fn output(&self) -> u8 {
+        if self.enabled && self.lfsr & 1 == 0 {
+            self.envelope.volume
+        } else {
+            0
+        }
+    }
+}
+
+/// Consumer of the mixed output. Samples arrive interleaved left/right at `SAMPLE_RATE`
+/// unless the sink is wrapped in a `Resampler`.
+pub trait AudioSink {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()>;
+
+    /// Called once after the last samples; sinks that buffer or patch headers flush here.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Converts `SAMPLE_RATE` output to another rate by linear interpolation before passing
+/// it on to `sink`.
+pub struct Resampler<S: AudioSink> {
+    sink: S,
+    rate: u32,
+    phase: u32,
+    previous: (i16, i16),
+    buffer: Vec<i16>,
+}
+
+impl<S: AudioSink> Resampler<S> {
+    pub fn new(sink: S, rate: u32) -> Resampler<S> {
+        Resampler {
+            sink,
+            rate,
+            // the first input sample only primes the interpolation
+            phase: rate,
+            previous: (0, 0),
+            buffer: vec!(),
+        }
+    }
+}
+
+impl<S: AudioSink> AudioSink for Resampler<S> {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        self.buffer.clear();
+
+        // phase counts in 1/rate steps between the previous and the current input sample
+        for frame in samples.chunks(2) {
+            let current = (frame[0], frame[1]);
+
+            while self.phase < self.rate {
+                let (phase, rate) = (self.phase as i64, self.rate as i64);
+                let lerp = |from: i16, to: i16| (from as i64 + (to as i64 - from as i64) * phase / rate) as i16;
+                self.buffer.push(lerp(self.previous.0, current.0));
+                self.buffer.push(lerp(self.previous.1, current.1));
+                self.phase += SAMPLE_RATE;
+            }
+
+            self.phase -= self.rate;
+            self.previous = current;
+        }
+
+        self.sink.write(&self.buffer)
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.sink.finish()
+    }
+}
+
+/// One of the two 8-bit PCM channels. Samples are queued by the CPU or a sound DMA and
+/// played back one per overflow of the selected timer.
+struct DirectSound {
+    fifo: VecDeque<i8>,
+    sample: i8,
+}
+
+impl DirectSound {
+    fn new() -> DirectSound {
+        DirectSound {
+            fifo: VecDeque::with_capacity(FIFO_SIZE),
+            sample: 0,
+        }
+    }
+
+    fn push(&mut self, value: u8) {
+        if self.fifo.len() < FIFO_SIZE {
+            self.fifo.push_back(value as i8);
+        }
+    }
+
+    fn save(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.fifo.len() as u8);
+        for &sample in &self.fifo {
+            writer.write_u8(sample as u8);
+        }
+        writer.write_u8(self.sample as u8);
+    }
+
+    fn load(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        let len = reader.read_u8()? as usize;
+        if len > FIFO_SIZE {
+            return Err(reader.corrupt());
+        }
+
+        self.fifo.clear();
+        for _ in 0..len {
+            self.fifo.push_back(reader.read_u8()? as i8);
+        }
+        self.sample = reader.read_u8()? as i8;
+        Ok(())
+    }
+
+    fn reset(&mut self) {
+        self.fifo.clear();
+        self.sample = 0;
+    }
+
+    /// Plays the next sample and reports whether the FIFO wants a refill.
+    fn advance(&mut self) -> bool {
+        if let Some(sample) = self.fifo.pop_front() {
+            self.sample = sample;
+        }
+
+        self.fifo.len() <= FIFO_REFILL_LEVEL
+    }
+}
+
+/// The sound hardware: the four PSG channels and the two Direct Sound FIFOs. They are
+/// clocked by CPU cycles and mixed into interleaved stereo samples at `SAMPLE_RATE`.
+pub struct Apu {
+    registers: [u8; 0x30],
+
+    square_1: SquareChannel,
+    square_2: SquareChannel,
+    wave: WaveChannel,
+    noise: NoiseChannel,
+    direct_sound: [DirectSound; 2],
+
+    sequencer_step: u8,
+    sequencer_ticks: i32,
+    sample_ticks: i32,
+
+    samples: Vec<i16>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        let mut registers = [0; 0x30];
+        // SOUNDBIAS powers up centred at 0x200
+        registers[0x29] = 0x02;
+
+        Apu {
+            registers,
+
+            square_1: SquareChannel::new(),
+            square_2: SquareChannel::new(),
+            wave: WaveChannel::new(),
+            noise: NoiseChannel::new(),
+            direct_sound: [DirectSound::new(), DirectSound::new()],
+
+            sequencer_step: 0,
+            sequencer_ticks: 0,
+            sample_ticks: 0,
+
+            samples: vec!(),
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.registers);
+        self.square_1.save(writer);
+        self.square_2.save(writer);
+        self.wave.save(writer);
+        self.noise.save(writer);
+        self.direct_sound[0].save(writer);
+        self.direct_sound[1].save(writer);
+        writer.write_u8(self.sequencer_step);
+        writer.write_i32(self.sequencer_ticks);
+        writer.write_i32(self.sample_ticks);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes(&mut self.registers)?;
+        self.square_1.load(reader)?;
+        self.square_2.load(reader)?;
+        self.wave.load(reader)?;
+        self.noise.load(reader)?;
+        self.direct_sound[0].load(reader)?;
+        self.direct_sound[1].load(reader)?;
+        self.sequencer_step = reader.read_u8()? & 7;
+        self.sequencer_ticks = reader.read_i32()?;
+        self.sample_ticks = reader.read_i32()?;
+
+        // `tick` only makes progress while both are below their period
+        if !(0..FRAME_SEQUENCER_CYCLES).contains(&self.sequencer_ticks) || !(0..CYCLES_PER_SAMPLE).contains(&self.sample_ticks) {
+            return Err(reader.corrupt());
+        }
+        self.samples.clear();
+        Ok(())
+    }
+
+    fn master_enabled(&self) -> bool {
+        self.registers[0x24] & 0x80 != 0
+    }
+
+    /// Value the CPU reads back; length and frequency fields are write only.
+    pub fn read_register(&self, address: u32) -> u16 {
+        let address = address & 0x3FE;
+
+        if address >= 0x090 {
+            let offset = self.wave.cpu_bank_offset() + (address - 0x090) as usize;
+            return self.wave.wave_ram[offset] as u16 | (self.wave.wave_ram[offset + 1] as u16) << 8;
+        }
+
+        let index = (address - 0x060) as usize;
+        let value = self.registers[index] as u16 | (self.registers[index + 1] as u16) << 8;
+
+        match address {
+            0x060 => value & 0x007F,
+            0x062 | 0x068 => value & 0xFFC0,
+            0x064 | 0x06C | 0x074 => value & 0x4000,
+            0x070 => value & 0x00E0,
+            0x072 => value & 0xE000,
+            0x078 => value & 0xFF00,
+            0x07C => value & 0x40FF,
+            0x080 => value & 0xFF77,
+            0x082 => value & 0x770F,
+            0x084 => {
+                (value & 0x0080)
+                    | self.square_1.enabled as u16
+                    | (self.square_2.enabled as u16) << 1
+                    | (self.wave.enabled as u16) << 2
+                    | (self.noise.enabled as u16) << 3
+            },
+            0x088 => value & 0xC3FE,
+            _ => 0,
+        }
+    }
+
+    pub fn write_16(&mut self, address: u32, value: u16) {
+        self.write_8(address, value as u8);
+        self.write_8(address + 1, (value >> 8) as u8);
+    }
+
+    pub fn write_8(&mut self, address: u32, value: u8) {
+        let address = address & 0x3FF;
+
+        if (0x0A0..0x0A8).contains(&address) {
+            self.direct_sound[((address - 0x0A0) >> 2) as usize].push(value);
+            return;
+        }
+
+        if (0x090..0x0A0).contains(&address) {
+            let offset = self.wave.cpu_bank_offset() + (address - 0x090) as usize;
+            self.wave.wave_ram[offset] = value;
+            return;
+        }
+
+        if !(0x060..0x090).contains(&address) {
+            return;
+        }
+
+        // with the master enable off only SOUNDCNT_H, SOUNDCNT_X and SOUNDBIAS take writes
+        if !self.master_enabled() && address < 0x082 {
+            return;
+        }
+
+        self.registers[(address - 0x060) as usize] = value;
+
+        match address {
+            0x060 => {
+                self.square_1.sweep_shift = value & 0x07;
+                self.square_1.sweep_decrease = value & 0x08 != 0;
+                self.square_1.sweep_time = (value >> 4) & 0x07;
+            },
+            0x062 => {
+                self.square_1.length.counter = 64 - (value & 0x3F) as u16;
+                self.square_1.duty = value >> 6;
+            },
+            0x063 => {
+                self.square_1.envelope.write(value);
+                if !self.square_1.envelope.dac_enabled() {
+                    self.square_1.enabled = false;
+                }
+            },
+            0x064 => self.square_1.frequency = (self.square_1.frequency & 0x700) | value as u16,
+            0x065 => {
+                self.square_1.frequency = (self.square_1.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.square_1.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.square_1.trigger();
+                }
+            },
+            0x068 => {
+                self.square_2.length.counter = 64 - (value & 0x3F) as u16;
+                self.square_2.duty = value >> 6;
+            },
+            0x069 => {
+                self.square_2.envelope.write(value);
+                if !self.square_2.envelope.dac_enabled() {
+                    self.square_2.enabled = false;
+                }
+            },
+            0x06C => self.square_2.frequency = (self.square_2.frequency & 0x700) | value as u16,
+            0x06D => {
+                self.square_2.frequency = (self.square_2.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.square_2.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.square_2.trigger();
+                }
+            },
+            0x070 => {
+                self.wave.two_banks = value & 0x20 != 0;
+                self.wave.bank = (value >> 6) & 1;
+                self.wave.dac_enabled = value & 0x80 != 0;
+                if !self.wave.dac_enabled {
+                    self.wave.enabled = false;
+                }
+            },
+            0x072 => self.wave.length.counter = 256 - value as u16,
+            0x073 => {
+                self.wave.volume = (value >> 5) & 0x03;
+                self.wave.force_volume = value & 0x80 != 0;
+            },
+            0x074 => self.wave.frequency = (self.wave.frequency & 0x700) | value as u16,
+            0x075 => {
+                self.wave.frequency = (self.wave.frequency & 0xFF) | ((value & 0x07) as u16) << 8;
+                self.wave.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.wave.trigger();
+                }
+            },
+            0x078 => self.noise.length.counter = 64 - (value & 0x3F) as u16,
+            0x079 => {
+                self.noise.envelope.write(value);
+                if !self.noise.envelope.dac_enabled() {
+                    self.noise.enabled = false;
+                }
+            },
+            0x07C => {
+                self.noise.divisor = value & 0x07;
+                self.noise.short_width = value & 0x08 != 0;
+                self.noise.shift = value >> 4;
+            },
+            0x07D => {
+                self.noise.length.enabled = value & 0x40 != 0;
+                if value & 0x80 != 0 {
+                    self.noise.trigger();
+                }
+            },
+            0x083 => {
+                if value & 0x08 != 0 {
+                    self.direct_sound[0].reset();
+                }
+                if value & 0x80 != 0 {
+                    self.direct_sound[1].reset();
+                }
+            },
+            0x084 if value & 0x80 == 0 => self.power_off(),
+            _ => (),
+        }
+    }
+
+    /// Clearing the master enable resets every PSG register; wave RAM survives.
+    fn power_off(&mut self) {
+        for register in self.registers[..0x22].iter_mut() {
+            *register = 0;
+        }
+
+        let wave_ram = self.wave.wave_ram;
+
+        self.square_1 = SquareChannel::new();
+        self.square_2 = SquareChannel::new();
+        self.wave = WaveChannel::new();
+        self.wave.wave_ram = wave_ram;
+        self.noise = NoiseChannel::new();
+    }
+
+    pub fn tick(&mut self, ticks: i32) {
+        let mut ticks = ticks;
+
+        while ticks > 0 {
+            let step = ticks.min(CYCLES_PER_SAMPLE - self.sample_ticks);
+            ticks -= step;
+
+            if self.master_enabled() {
+                self.square_1.advance(step);
+                self.square_2.advance(step);
+                self.wave.advance(step);
+                self.noise.advance(step);
+
+                self.sequencer_ticks += step;
+                while self.sequencer_ticks >= FRAME_SEQUENCER_CYCLES {
+                    self.sequencer_ticks -= FRAME_SEQUENCER_CYCLES;
+                    self.clock_sequencer();
+                }
+            }
+
+            self.sample_ticks += step;
+            if self.sample_ticks >= CYCLES_PER_SAMPLE {
+                self.sample_ticks -= CYCLES_PER_SAMPLE;
+
+                let (left, right) = self.mix();
+                self.samples.push(left);
+                self.samples.push(right);
+            }
+        }
+    }
+
+    /// Called for every overflow of timer 0 or 1. Returns the FIFO addresses that have
+    /// run low and need a sound DMA to refill them.
+    pub fn timer_overflow(&mut self, timer: usize) -> Vec<u32> {
+        let mut requests = vec!();
+
+        if !self.master_enabled() {
+            return requests;
+        }
+
+        let control = self.registers[0x23];
+
+        if (control >> 2) & 1 == timer as u8 && self.direct_sound[0].advance() {
+            requests.push(FIFO_A);
+        }
+        if (control >> 6) & 1 == timer as u8 && self.direct_sound[1].advance() {
+            requests.push(FIFO_B);
+        }
+
+        requests
+    }
+
+    fn clock_sequencer(&mut self) {
+        if self.sequencer_step & 1 == 0 {
+            self.square_1.enabled &= self.square_1.length.clock();
+            self.square_2.enabled &= self.square_2.length.clock();
+            self.wave.enabled &= self.wave.length.clock();
+            self.noise.enabled &= self.noise.length.clock();
+        }
+
+        if self.sequencer_step == 2 || self.sequencer_step == 6 {
+            self.square_1.clock_sweep();
+        }
+
+        if self.sequencer_step == 7 {
+            self.square_1.envelope.clock();
+            self.square_2.envelope.clock();
+            self.noise.envelope.clock();
+        }
+
+        self.sequencer_step = (self.sequencer_step + 1) & 7;
+    }
+
+    /// SOUNDCNT_L routes each PSG channel to the left and right outputs and scales each
+    /// side by its master volume, SOUNDCNT_H scales the PSG sum and routes Direct Sound
+    /// A and B. Both are added to SOUNDBIAS and clipped to the 10-bit DAC range.
+    fn mix(&self) -> (i16, i16) {
+        if !self.master_enabled() {
+            return (0, 0);
+        }
+
+        let outputs = [self.square_1.output(), self.square_2.output(), self.wave.output(), self.noise.output()];
+        let right_volume = (self.registers[0x20] & 0x07) as i32 + 1;
+        let left_volume = ((self.registers[0x20] >> 4) & 0x07) as i32 + 1;
+        let enables = self.registers[0x21];
+
+        let mut left = 0;
+        let mut right = 0;
+
+        for (channel, output) in outputs.iter().enumerate() {
+            if enables & (0x01 << channel) != 0 {
+                right += *output as i32;
+            }
+            if enables & (0x10 << channel) != 0 {
+                left += *output as i32;
+            }
+        }
+
+        // 0 is 25%, 1 is 50% and 2 is 100%
+        let psg_shift = 2 - (self.registers[0x22] & 0x03).min(2);
+        left = (left * left_volume) >> psg_shift;
+        right = (right * right_volume) >> psg_shift;
+
+        let control = self.registers[0x23];
+
+        for (channel, direct_sound) in self.direct_sound.iter().enumerate() {
+            let shift = channel * 4;
+            // 50% or 100% of the 8-bit sample
+            let sample = direct_sound.sample as i32 * if self.registers[0x22] & (0x04 << channel) != 0 { 4 } else { 2 };
+
+            if control & (0x01 << shift) != 0 {
+                right += sample;
+            }
+            if control & (0x02 << shift) != 0 {
+                left += sample;
+            }
+        }
+
+        let bias = (self.registers[0x28] as i32 | (self.registers[0x29] as i32) << 8) & 0x3FE;
+        let resolution_mask = !((1 << (self.registers[0x29] >> 6)) - 1);
+
+        let dac = |value: i32| (((value + bias).clamp(0, 0x3FF) & resolution_mask) - 0x200) * 64;
+
+        (dac(left) as i16, dac(right) as i16)
+    }
+
+    /// Interleaved left/right samples produced since the last `clear_samples`.
+    pub fn samples(&self) -> &[i16] {
+        &self.samples
+    }
+
+    pub fn clear_samples(&mut self) {
+        self.samples.clear();
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use super::{Apu, AudioSink, Resampler, FIFO_A, FIFO_B, FRAME_SEQUENCER_CYCLES, SAMPLE_RATE};
+
+    const CLOCK: i32 = 16777216;
+
+    impl AudioSink for Vec<i16> {
+        fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+            self.extend_from_slice(samples);
+            Ok(())
+        }
+    }
+
+    fn powered() -> Apu {
+        let mut apu = Apu::new();
+        apu.write_16(0x084, 0x0080);
+        apu
+    }
+
+    /// Cycles between the first two times `output` goes from silent to sounding.
+    fn period(apu: &mut Apu, output: fn(&Apu) -> u8) -> i32 {
+        let mut edges = vec!();
+        let mut previous = output(apu) != 0;
+        let mut cycles = 0;
+
+        while edges.len() < 2 {
+            apu.tick(16);
+            cycles += 16;
+
+            let sounding = output(apu) != 0;
+            if sounding && !previous {
+                edges.push(cycles);
+            }
+            previous = sounding;
+        }
+
+        edges[1] - edges[0]
+    }
+
+    /// Runs `steps` frame sequencer steps.
+    fn sequence(apu: &mut Apu, steps: i32) {
+        apu.tick(FRAME_SEQUENCER_CYCLES * steps);
+    }
+
+    #[test]
+    fn square_frequency() {
+        let mut apu = powered();
+        // 50% duty, full volume, 131072 / (2048 - 1792) Hz
+        apu.write_16(0x068, 0xF080);
+        apu.write_16(0x06C, 0x8000 | 1792);
+
+        assert_eq!(CLOCK / period(&mut apu, |apu| apu.square_2.output()), 512);
+    }
+
+    #[test]
+    fn wave_frequency() {
+        let mut apu = powered();
+
+        // the CPU writes the bank that is not playing: fill bank 0 with half a period of
+        // 15s and half of 0s, then play it at 65536 / (2048 - 1792) Hz
+        apu.write_8(0x070, 0x40);
+        for offset in 0..8 {
+            apu.write_16(0x090 + offset * 2, if offset < 4 { 0xFFFF } else { 0 });
+        }
+        apu.write_8(0x070, 0x80);
+        apu.write_16(0x072, 0x2000);
+        apu.write_16(0x074, 0x8000 | 1792);
+
+        assert_eq!(apu.wave.wave_ram[..8], [0xFF; 8]);
+        assert_eq!(CLOCK / period(&mut apu, |apu| apu.wave.output()), 256);
+    }
+
+    #[test]
+    fn noise_frequency() {
+        let mut apu = powered();
+        // divisor 16, shift 2: the LFSR shifts every 256 cycles
+        apu.write_16(0x078, 0xF000);
+        apu.write_16(0x07C, 0x8021);
+
+        apu.tick(255);
+        assert_eq!(apu.noise.lfsr, 0x7FFF);
+        apu.tick(1);
+        assert_eq!(apu.noise.lfsr, 0x3FFF);
+
+        // 7-bit mode repeats every 127 shifts
+        apu.write_16(0x07C, 0x8029);
+        let start = apu.noise.lfsr & 0x7F;
+        let mut outputs = vec!();
+        for _ in 0..254 {
+            apu.tick(256);
+            outputs.push(apu.noise.output());
+        }
+        assert_eq!(apu.noise.lfsr & 0x7F, start);
+        assert_eq!(outputs[..127], outputs[127..]);
+        assert!(outputs.contains(&0) && outputs.contains(&15));
+
+        // shifts 14 and 15 stop the LFSR
+        apu.write_16(0x07C, 0x80E0);
+        apu.tick(CLOCK / 64);
+        assert_eq!(apu.noise.lfsr, 0x7FFF);
+    }
+
+    #[test]
+    fn length_counters() {
+        let mut apu = powered();
+        apu.write_16(0x062, 0xF03E);
+        apu.write_16(0x064, 0xC000);
+        apu.write_16(0x068, 0xF03E);
+        apu.write_16(0x06C, 0x8000);
+        apu.write_8(0x070, 0x80);
+        apu.write_16(0x072, 0x00FE);
+        apu.write_16(0x074, 0xC000);
+        apu.write_16(0x078, 0xF03E);
+        apu.write_16(0x07C, 0xC000);
+        assert_eq!(apu.read_register(0x084), 0x8F);
+
+        // lengths of 2 run out on the second length clock, at sequencer steps 0 and 2;
+        // square 2 does not count
+        sequence(&mut apu, 1);
+        assert_eq!(apu.read_register(0x084), 0x8F);
+        sequence(&mut apu, 2);
+        assert_eq!(apu.read_register(0x084), 0x82);
+        sequence(&mut apu, 64);
+        assert_eq!(apu.read_register(0x084), 0x82);
+
+        // triggering again reloads a spent counter with the full length
+        apu.write_16(0x064, 0xC000);
+        sequence(&mut apu, 126);
+        assert_eq!(apu.read_register(0x084), 0x83);
+        sequence(&mut apu, 2);
+        assert_eq!(apu.read_register(0x084), 0x82);
+    }
+
+    #[test]
+    fn envelopes() {
+        let mut apu = powered();
+        // down from 15 every envelope clock, up from 0 every other one
+        apu.write_16(0x068, 0xF100);
+        apu.write_16(0x06C, 0x8000);
+        apu.write_16(0x078, 0x0A00);
+        apu.write_16(0x07C, 0x8000);
+        assert_eq!((apu.square_2.envelope.volume, apu.noise.envelope.volume), (15, 0));
+
+        // the envelope clocks on sequencer step 7, at 64 Hz
+        sequence(&mut apu, 7);
+        assert_eq!((apu.square_2.envelope.volume, apu.noise.envelope.volume), (15, 0));
+        sequence(&mut apu, 1);
+        assert_eq!((apu.square_2.envelope.volume, apu.noise.envelope.volume), (14, 0));
+        sequence(&mut apu, 8 * 3);
+        assert_eq!((apu.square_2.envelope.volume, apu.noise.envelope.volume), (11, 2));
+
+        // and stops at the ends
+        sequence(&mut apu, 8 * 40);
+        assert_eq!((apu.square_2.envelope.volume, apu.noise.envelope.volume), (0, 15));
+
+        // a DAC that can only play silence turns the channel off
+        apu.write_8(0x069, 0x00);
+        assert_eq!(apu.read_register(0x084) & 0x02, 0);
+    }
+
+    #[test]
+    fn sweep() {
+        let mut apu = powered();
+        // every sweep clock, down by half
+        apu.write_16(0x060, 0x0019);
+        apu.write_16(0x062, 0xF000);
+        apu.write_16(0x064, 0x8400);
+
+        // the sweep clocks on sequencer steps 2 and 6
+        sequence(&mut apu, 2);
+        assert_eq!(apu.square_1.frequency, 1024);
+        sequence(&mut apu, 1);
+        assert_eq!(apu.square_1.frequency, 512);
+        sequence(&mut apu, 4);
+        assert_eq!(apu.square_1.frequency, 256);
+        assert!(apu.square_1.enabled);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_square_1() {
+        let mut apu = powered();
+        // up by a quarter from 1280: 1600, then 2000, whose next step would pass 2047
+        apu.write_16(0x060, 0x0012);
+        apu.write_16(0x062, 0xF000);
+        apu.write_16(0x064, 0x8500);
+
+        sequence(&mut apu, 3);
+        assert_eq!(apu.square_1.frequency, 1600);
+        assert_eq!(apu.read_register(0x084) & 1, 1);
+        sequence(&mut apu, 4);
+        assert_eq!(apu.square_1.frequency, 2000);
+        assert_eq!(apu.read_register(0x084) & 1, 0);
+
+        // a trigger that would overflow on its first step stops straight away
+        apu.write_16(0x060, 0x0011);
+        apu.write_16(0x064, 0x87F0);
+        assert_eq!(apu.read_register(0x084) & 1, 0);
+    }
+
+    #[test]
+    fn master_disable_clears_the_psg() {
+        let mut apu = powered();
+        apu.write_16(0x060, 0x0077);
+        apu.write_16(0x062, 0xF0BF);
+        apu.write_16(0x064, 0xC400);
+        apu.write_8(0x070, 0x40);
+        apu.write_16(0x090, 0x1234);
+        apu.write_8(0x070, 0xE0);
+        apu.write_16(0x072, 0x2000);
+        apu.write_16(0x074, 0x8000);
+        apu.write_16(0x078, 0xF000);
+        apu.write_16(0x07C, 0x8000);
+        apu.write_16(0x080, 0xFF77);
+        apu.write_16(0x082, 0x0002);
+        assert_eq!(apu.read_register(0x084), 0x8D);
+
+        apu.write_16(0x084, 0x0000);
+        for address in (0x060..0x084).step_by(2) {
+            let expected = if address == 0x082 { 0x0002 } else { 0 };
+            assert_eq!(apu.read_register(address), expected, "register {:03x}", address);
+        }
+        assert_eq!(apu.read_register(0x084), 0);
+
+        // PSG writes are dropped while off, SOUNDCNT_H still takes them, wave RAM survives
+        apu.write_16(0x080, 0xFF77);
+        apu.write_16(0x082, 0x0001);
+        assert_eq!(apu.read_register(0x080), 0);
+        assert_eq!(apu.read_register(0x082), 0x0001);
+        assert_eq!(apu.wave.wave_ram[..2], [0x34, 0x12]);
+
+        apu.write_16(0x084, 0x0080);
+        assert_eq!(apu.read_register(0x062), 0);
+        assert_eq!(apu.read_register(0x084), 0x80);
+    }
+
+    fn fill(apu: &mut Apu, fifo: u32, bytes: &[u8]) {
+        for pair in bytes.chunks(2) {
+            apu.write_16(fifo, pair[0] as u16 | (pair[1] as u16) << 8);
+        }
+    }
+
+    #[test]
+    fn timer_overflows_play_the_fifos() {
+        let mut apu = powered();
+        // A on timer 0, B on timer 1
+        apu.write_16(0x082, 0x4000);
+        fill(&mut apu, FIFO_A, &[1, 2, 0xFF, 0x80]);
+        fill(&mut apu, FIFO_B, &[5; 20]);
+
+        assert_eq!(apu.timer_overflow(0), [FIFO_A]);
+        assert_eq!((apu.direct_sound[0].sample, apu.direct_sound[1].sample), (1, 0));
+        assert_eq!(apu.timer_overflow(0), [FIFO_A]);
+        assert_eq!(apu.timer_overflow(0), [FIFO_A]);
+        assert_eq!(apu.direct_sound[0].sample, -1);
+        assert_eq!(apu.timer_overflow(0), [FIFO_A]);
+        assert_eq!(apu.direct_sound[0].sample, -128);
+
+        // an empty FIFO keeps playing its last sample
+        assert_eq!(apu.timer_overflow(0), [FIFO_A]);
+        assert_eq!(apu.direct_sound[0].sample, -128);
+
+        // B only asks for more once it is down to 4 words
+        for _ in 0..3 {
+            assert!(apu.timer_overflow(1).is_empty());
+        }
+        assert_eq!(apu.timer_overflow(1), [FIFO_B]);
+        assert_eq!(apu.direct_sound[1].fifo.len(), 16);
+        assert_eq!(apu.direct_sound[1].sample, 5);
+
+        // both on one timer
+        apu.write_16(0x082, 0x0000);
+        assert_eq!(apu.timer_overflow(0), [FIFO_A, FIFO_B]);
+        assert!(apu.timer_overflow(1).is_empty());
+
+        // and nothing plays with the master enable off
+        apu.write_16(0x084, 0x0000);
+        assert!(apu.timer_overflow(0).is_empty());
+        assert_eq!(apu.direct_sound[1].fifo.len(), 15);
+    }
+
+    #[test]
+    fn fifo_holds_eight_words() {
+        let mut apu = powered();
+        fill(&mut apu, FIFO_A, &[7; 40]);
+        assert_eq!(apu.direct_sound[0].fifo.len(), 32);
+    }
+
+    #[test]
+    fn soundcnt_h_resets_the_fifos() {
+        let mut apu = powered();
+        apu.write_16(0x082, 0x4000);
+        fill(&mut apu, FIFO_A, &[3; 8]);
+        fill(&mut apu, FIFO_B, &[4; 8]);
+        apu.timer_overflow(0);
+        apu.timer_overflow(1);
+
+        apu.write_16(0x082, 0x4800);
+        assert_eq!((apu.direct_sound[0].fifo.len(), apu.direct_sound[0].sample), (0, 0));
+        assert_eq!((apu.direct_sound[1].fifo.len(), apu.direct_sound[1].sample), (7, 4));
+
+        // the reset bits are write only
+        assert_eq!(apu.read_register(0x082), 0x4000);
+
+        apu.write_16(0x082, 0xC000);
+        assert_eq!((apu.direct_sound[1].fifo.len(), apu.direct_sound[1].sample), (0, 0));
+    }
+
+    #[test]
+    fn mix_adds_the_bias_and_clips_to_10_bits() {
+        let mut apu = powered();
+        // A at 100% to both sides, B at 50% to the right only
+        apu.write_16(0x082, 0x1304);
+
+        apu.direct_sound[0].sample = 100;
+        apu.direct_sound[1].sample = -20;
+        // 0x200 + 400, and 0x200 + 400 - 40
+        assert_eq!(apu.mix(), ((400 * 64) as i16, (360 * 64) as i16));
+
+        // full scale either way clips at the ends of the DAC's range
+        apu.direct_sound[0].sample = 127;
+        apu.direct_sound[1].sample = 0;
+        apu.write_16(0x088, 0x0300);
+        assert_eq!(apu.mix(), ((0x1FF * 64) as i16, (0x1FF * 64) as i16));
+        apu.direct_sound[0].sample = -128;
+        apu.write_16(0x088, 0x0100);
+        assert_eq!(apu.mix(), (-0x200 * 64, -0x200 * 64));
+
+        // coarser resolutions drop the low bits: 6 bits at 0xC000
+        apu.direct_sound[0].sample = 1;
+        apu.write_16(0x088, 0xC200);
+        assert_eq!(apu.mix(), (0, 0));
+        apu.direct_sound[0].sample = 2;
+        assert_eq!(apu.mix(), (8 * 64, 8 * 64));
+
+        // PSG channels add in before the bias: square 2 at 15, times a right volume of 8
+        apu.write_16(0x088, 0x0200);
+        apu.write_16(0x080, 0x0207);
+        apu.write_16(0x082, 0x0006);
+        apu.write_16(0x068, 0xF080);
+        apu.write_16(0x06C, 0x8000);
+        assert_eq!(apu.mix(), (0, (15 * 8 * 64) as i16));
+
+        assert_eq!(powered().mix(), (0, 0));
+        assert_eq!(Apu::new().mix(), (0, 0));
+    }
+
+    /// Resamples an eighth of a second and one sample of a ramp, left up and right down
+    /// from +-1000, fed in frame-sized chunks.
+    fn resample(rate: u32) -> Vec<i16> {
+        let input: Vec<i16> = (0..(SAMPLE_RATE / 8) as i16 + 1).flat_map(|n| vec!(1000 + n, -1000 - n)).collect();
+        let mut resampler = Resampler::new(vec!(), rate);
+        for chunk in input.chunks(548 * 2) {
+            resampler.write(chunk).unwrap();
+        }
+        resampler.sink
+    }
+
+    #[test]
+    fn resampler_rates() {
+        // output sample k sits k * 32768 / rate input samples in, rounded down on the ramp
+        let output = resample(44100);
+        assert_eq!(output.len(), 2 * 5513);
+        assert_eq!(output[..4], [1000, -1000, 1000, -1000]);
+        assert_eq!(output[4..6], [1001, -1001]);
+        assert_eq!(output[output.len() - 2..], [1000 + 4095, -1000 - 4095]);
+
+        let output = resample(SAMPLE_RATE / 2);
+        assert_eq!(output.len(), 2 * 2048);
+        assert_eq!(output[..4], [1000, -1000, 1002, -1002]);
+        assert_eq!(output[output.len() - 2..], [1000 + 4094, -1000 - 4094]);
+
+        let output = resample(SAMPLE_RATE);
+        assert_eq!(output.len(), 2 * 4096);
+        assert_eq!(output[output.len() - 2..], [1000 + 4095, -1000 - 4095]);
+    }
+}
@@ -0,0 +1,197 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use super::cpu::{Profile, PROFILE_REGIONS};
+use super::gba::Gba;
+use super::mem_map::MemMap;
+use super::pacing::FRAME_RATE;
+
+/// The outcome of `run`: how fast the emulator went and where the time went.
+pub struct Report {
+    pub frames: u32,
+    pub elapsed: Duration,
+    pub profile: Profile,
+}
+
+/// Runs `frames` frames flat out with profiling on.
+pub fn run(gba: &mut Gba, frames: u32) -> Report {
+    gba.set_profiling(true);
+    let start = Instant::now();
+
+    for _ in 0..frames {
+        gba.run_frame();
+    }
+
+    let elapsed = start.elapsed();
+    let profile = gba.profile();
+    gba.set_profiling(false);
+
+    Report {
+        frames,
+        elapsed,
+        profile,
+    }
+}
+
+impl Report {
+    pub fn frames_per_second(&self) -> f64 {
+        self.frames as f64 / self.seconds()
+    }
+
+    pub fn instructions_per_second(&self) -> f64 {
+        self.profile.instructions as f64 / self.seconds()
+    }
+
+    fn seconds(&self) -> f64 {
+        self.elapsed.as_secs_f64().max(1e-9)
+    }
+
+    /// Whatever the LCD, APU and DMA did not take: the instructions and their memory accesses,
+    /// the timers and the scheduler.
+    fn cpu_time(&self) -> Duration {
+        let profile = &self.profile;
+        self.elapsed.saturating_sub(profile.lcd_time + profile.apu_time + profile.dma_time)
+    }
+
+    /// Reads and writes by region, in address order, leaving out the untouched ones. The
+    /// profile has two entries for each ROM mirror, which read better as one.
+    fn regions(&self) -> Vec<(&'static str, u64, u64)> {
+        let mut rows: Vec<(&str, u64, u64)> = vec!();
+
+        for index in 0..PROFILE_REGIONS {
+            let name = MemMap::region_name((index as u32) << 24);
+            let (reads, writes) = (self.profile.reads[index], self.profile.writes[index]);
+
+            match rows.iter_mut().find(|row| row.0 == name) {
+                Some(row) => {
+                    row.1 += reads;
+                    row.2 += writes;
+                },
+                None => rows.push((name, reads, writes)),
+            }
+        }
+
+        rows.retain(|&(_, reads, writes)| reads != 0 || writes != 0);
+        rows
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let profile = &self.profile;
+        let fps = self.frames_per_second();
+
+        writeln!(f, "{} frames in {:.3} s", self.frames, self.elapsed.as_secs_f64())?;
+        writeln!(f, "{:.1} frames/s ({:.2}x real time)", fps, fps / FRAME_RATE)?;
+        writeln!(f, "{:.0} instructions/s", self.instructions_per_second())?;
+        writeln!(f)?;
+
+        writeln!(f, "{:<10} {:>10} {:>7}  events", "subsystem", "time", "share")?;
+        let subsystems = [
+            ("cpu", self.cpu_time(), format!("{} instructions", profile.instructions)),
+            ("ppu", profile.lcd_time, format!("{} lines", profile.lcd_lines)),
+            ("apu", profile.apu_time, format!("{} samples", profile.apu_samples)),
+            ("dma", profile.dma_time, format!("{} transfers, {} units", profile.dma_transfers, profile.dma_units)),
+        ];
+        for (name, time, events) in subsystems.iter() {
+            let share = 100.0 * time.as_secs_f64() / self.seconds();
+            writeln!(f, "{:<10} {:>8.3} s {:>6.1}%  {}", name, time.as_secs_f64(), share, events)?;
+        }
+        writeln!(f)?;
+
+        writeln!(f, "{:<10} {:>12} {:>12}", "region", "reads", "writes")?;
+        for (name, reads, writes) in self.regions() {
+            writeln!(f, "{:<10} {:>12} {:>12}", name, reads, writes)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::super::cpu::Profile;
+    use super::super::gba::Gba;
+    use super::{run, Report};
+
+    fn report(elapsed_ms: u64, profile: Profile) -> Report {
+        Report {
+            frames: 120,
+            elapsed: Duration::from_millis(elapsed_ms),
+            profile,
+        }
+    }
+
+    #[test]
+    fn regions_merge_the_rom_mirrors() {
+        let mut profile = Profile::default();
+        profile.reads[3] = 5;
+        profile.writes[3] = 6;
+        profile.writes[2] = 1;
+        profile.reads[8] = 10;
+        profile.reads[9] = 20;
+        profile.writes[13] = 7;
+        // everything past SRAM shares the last counter, which goes with the other unused space
+        profile.reads[15] = 2;
+
+        assert_eq!(report(1000, profile).regions(), [
+            ("UNUSED", 2, 0),
+            ("EWRAM", 0, 1),
+            ("IWRAM", 5, 6),
+            ("ROM0", 30, 0),
+            ("ROM2", 0, 7),
+        ]);
+        assert!(report(1000, Profile::default()).regions().is_empty());
+    }
+
+    #[test]
+    fn rates_and_shares() {
+        let profile = Profile {
+            instructions: 1_000_000,
+            lcd_time: Duration::from_millis(500),
+            apu_time: Duration::from_millis(250),
+            dma_time: Duration::from_millis(50),
+            ..Profile::default()
+        };
+
+        let busy = report(2000, profile);
+        assert_eq!(busy.frames_per_second(), 60.0);
+        assert_eq!(busy.instructions_per_second(), 500_000.0);
+        assert_eq!(busy.cpu_time(), Duration::from_millis(1200));
+
+        let text = busy.to_string();
+        assert!(text.starts_with("120 frames in 2.000 s\n60.0 frames/s"), "{}", text);
+        assert!(text.contains("cpu           1.200 s   60.0%  1000000 instructions"), "{}", text);
+        assert!(text.contains("ppu           0.500 s   25.0%  0 lines"), "{}", text);
+
+        // the subsystems' clocks can run past the wall clock, but the CPU never goes negative
+        let profile = Profile {
+            lcd_time: Duration::from_secs(3),
+            ..Profile::default()
+        };
+        assert_eq!(report(2000, profile).cpu_time(), Duration::ZERO);
+    }
+
+    #[test]
+    fn run_profiles_only_the_frames_it_runs() {
+        // mov r0, #0x02000000; loop: ldr r1, [r0]; b loop
+        let rom: Vec<u8> = [0xE3A00402u32, 0xE5901000, 0xEAFFFFFD].iter().flat_map(|word| word.to_le_bytes()).collect();
+        let mut gba = Gba::new();
+        gba.load_rom(&rom).unwrap();
+        gba.reset();
+
+        let report = run(&mut gba, 2);
+        assert_eq!(report.frames, 2);
+        // only the visible lines are drawn
+        assert_eq!(report.profile.lcd_lines, 2 * 160);
+        let loads = report.profile.reads[2];
+        assert!(loads > 1000);
+        // a load and a branch each time around
+        assert!((2 * loads - 1..=2 * loads + 1).contains(&report.profile.instructions));
+        assert_eq!(report.regions().first(), Some(&("EWRAM", loads, 0)));
+
+        assert_eq!(gba.profile().reads, [0; 16]);
+    }
+}
@@ -1,42 +1,93 @@
-const R13_IRQ: usize = 18;
-const R14_IRQ: usize = 19;
-const SPSR_IRQ: usize = 20;
-const R13_USR: usize = 26;
-const R14_USR: usize = 27;
-const R13_SVC: usize = 28;
-const R14_SVC: usize = 29;
-const SPSR_SVC: usize = 30;
-const R13_ABT: usize = 31;
-const R14_ABT: usize = 32;
-const SPSR_ABT: usize = 33;
-const R13_UND: usize = 34;
-const R14_UND: usize = 35;
-const SPSR_UND: usize = 36;
-const R8_FIQ: usize = 37;
-const R9_FIQ: usize = 38;
-const R10_FIQ: usize = 39;
-const R11_FIQ: usize = 40;
-const R12_FIQ: usize = 41;
-const R13_FIQ: usize = 42;
-const R14_FIQ: usize = 43;
-const SPSR_FIQ: usize = 44;
-
+const LCD_HDRAW_TICKS: i32 = 960;
+const LCD_HBLANK_TICKS: i32 = 272;
+const LCD_LINES: u16 = 228;
+
+const GAMEPAK_RAM_WAIT_STATE: [u8; 4] = [4, 3, 2, 8];
+const GAMEPAK_WAIT_STATE: [u8; 4] = [4, 3, 2, 8];
+const GAMEPAK_WAIT_STATE_0: [u8; 2] = [2, 1];
+const GAMEPAK_WAIT_STATE_1: [u8; 2] = [4, 1];
+const GAMEPAK_WAIT_STATE_2: [u8; 2] = [8, 1];
+
+mod arm;
+mod boot;
+mod cache;
+mod debug;
+mod dma;
+#[cfg(test)]
+mod fuzz;
+mod idle;
+#[cfg(feature = "jit")]
+mod jit;
+mod profile;
+mod registers;
+mod state;
+mod thumb;
+
+pub use self::debug::{WatchHit, WatchKind, Watchpoint};
+pub use self::profile::{Profile, PROFILE_REGIONS};
+
+use self::cache::BlockCache;
+use self::idle::IdleLoop;
+use self::profile::Profiler;
+use self::registers::{Mode, Psr, Registers};
+
+use std::cell::Cell;
+use std::str::FromStr;
+
+use super::apu;
+use super::error::Error;
+use super::frame_hash::FrameHasher;
+use super::keypad;
+use super::lcd;
+use super::link::Link;
 use super::mem_map;
+use super::multiboot;
+use super::save_state;
+use super::serial;
+use super::timer;
+
+/// Executes one decoded instruction and returns the cycles it took.
+type Handler = fn(&mut Cpu, u32) -> i32;
+
+/// How instructions get executed. Every backend gives the same results, cycle for cycle.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Backend {
+    /// Fetches, decodes and executes one opcode at a time.
+    Interpreter,
+    /// Decodes runs of instructions once into blocks of handlers and replays those.
+    Cached,
+    /// The cached interpreter, with hot blocks compiled to x86-64 machine code.
+    #[cfg(feature = "jit")]
+    Jit,
+}
+
+#[cfg(feature = "jit")]
+const BACKEND_NAMES: &str = "interpreter, cached or jit";
+#[cfg(not(feature = "jit"))]
+const BACKEND_NAMES: &str = "interpreter or cached";
+
+impl FromStr for Backend {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Backend, String> {
+        match name {
+            "interpreter" => Ok(Backend::Interpreter),
+            "cached" => Ok(Backend::Cached),
+            #[cfg(feature = "jit")]
+            "jit" => Ok(Backend::Jit),
+            _ => Err(format!("unknown backend {}, expected {}", name, BACKEND_NAMES)),
+        }
+    }
+}
 
-struct Cpu {
-    regs: [Reg; 45],
+pub struct Cpu {
+    regs: Registers,
+    cpsr: Psr,
 
     cpu_bits_set: [u8; 256],
     cpu_prefetch: [u32; 2],
-    n_flag: bool,
-    c_flag: bool,
-    z_flag: bool,
-    v_flag: bool,
 
-    arm_state: bool,
-    arm_irq_enable: bool,
     arm_next_pc: u32,
-    arm_mode: i32,
 
     bus_prefetch: bool,
     bus_prefetch_enable: bool,
@@ -53,17 +104,51 @@ struct Cpu {
 
     cpu_next_event: i32,
     cpu_total_ticks: i32,
+    halt_state: bool,
+    stop_state: bool,
+
+    backend: Backend,
+    block_cache: BlockCache,
+
+    idle_skip: bool,
+    idle_loop: IdleLoop,
+    /// Counts every store the CPU makes, so the idle loop check can tell one happened.
+    bus_writes: u32,
+    /// The same for reads that change what the next one returns: UART FIFO pops.
+    bus_read_effects: Cell<u32>,
+
+    profiling: bool,
+    profile: Profiler,
+
+    lcd_ticks: i32,
+    frame_count: u32,
+    timer_ticks: i32,
+
+    dma_source: [u32; 4],
+    dma_dest: [u32; 4],
 
     mem_map: mem_map::MemMap,
+    lcd: lcd::Lcd,
+    apu: apu::Apu,
+    timers: timer::Timers,
+    keypad: keypad::Keypad,
+    serial: serial::Serial,
 
+    bios_loaded: bool,
     bios_protected: [u8; 4],
+    rom_checksum: u64,
+    /// Waiting for a multiboot image over the link, in place of the BIOS's own receiver.
+    multiboot_receiver: Option<multiboot::Receiver>,
+
+    watchpoints: Vec<Watchpoint>,
+    watch_hit: Cell<Option<WatchHit>>,
 }
 
 impl Cpu {
     pub fn new() -> Cpu {
         let mut bits = [0; 256];
 
-        for i in 0..256 {
+        for (i, bit) in bits.iter_mut().enumerate() {
             let mut count = 0;
             for j in 0..8 {
                 if i & (1 << j) != 0 {
@@ -71,24 +156,17 @@ impl Cpu {
                 }
             }
 
-            bits[i] = count;
+            *bit = count;
         }
 
         Cpu {
-            regs: [Reg::I(0); 45],
+            regs: Registers::new(),
+            cpsr: Psr::default(),
 
             cpu_bits_set: bits,
             cpu_prefetch: [0; 2],
 
-            n_flag: false,
-            c_flag: false,
-            z_flag: false,
-            v_flag: false,
-
-            arm_state: true,
-            arm_irq_enable: true,
             arm_next_pc: 0,
-            arm_mode: 0x1f,
 
             bus_prefetch: false,
             bus_prefetch_enable: false,
@@ -105,88 +183,778 @@ impl Cpu {
 
             cpu_next_event: 0,
             cpu_total_ticks: 0,
+            halt_state: false,
+            stop_state: false,
+
+            backend: Backend::Interpreter,
+            block_cache: BlockCache::new(),
+
+            idle_skip: false,
+            idle_loop: IdleLoop::new(),
+            bus_writes: 0,
+            bus_read_effects: Cell::new(0),
+
+            profiling: false,
+            profile: Profiler::new(),
+
+            lcd_ticks: 0,
+            frame_count: 0,
+            timer_ticks: 0,
+
+            dma_source: [0; 4],
+            dma_dest: [0; 4],
 
             mem_map: mem_map::MemMap::new(),
+            lcd: lcd::Lcd::new(),
+            apu: apu::Apu::new(),
+            timers: timer::Timers::new(),
+            keypad: keypad::Keypad::new(),
+            serial: serial::Serial::new(),
 
+            bios_loaded: false,
             bios_protected: [0x00, 0xF0, 0x29, 0xE1],
+            rom_checksum: 0,
+            multiboot_receiver: None,
+
+            watchpoints: vec!(),
+            watch_hit: Cell::new(None),
         }
     }
 
+    pub fn load_bios(&mut self, bios: &[u8]) -> Result<(), Error> {
+        self.mem_map.load_bios(bios)?;
+        self.bios_loaded = true;
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        self.mem_map.load_rom(rom)?;
+
+        let mut hasher = FrameHasher::new();
+        hasher.write(self.mem_map.rom());
+        self.rom_checksum = hasher.finish();
+        Ok(())
+    }
+
+    /// Loads a multiboot image, to run from EWRAM instead of a cartridge from the next reset.
+    pub fn load_multiboot(&mut self, image: &[u8]) -> Result<(), Error> {
+        self.mem_map.load_multiboot(image)?;
+
+        let mut hasher = FrameHasher::new();
+        hasher.write(self.mem_map.multiboot());
+        self.rom_checksum = hasher.finish();
+        Ok(())
+    }
+
     pub fn reset(&mut self) {
         //reset registers
-        for i in 0..45 {
-            self.regs[i] = Reg::I(0);
+        self.regs = Registers::new();
+        self.cpsr = Psr {
+            fiq_disabled: true,
+            ..Psr::default()
+        };
+
+        let multiboot = !self.mem_map.multiboot().is_empty();
+        if multiboot {
+            // the BIOS has already received the image by the time it jumps to it
+            self.mem_map.copy_multiboot();
+        }
+
+        if self.bios_loaded && !multiboot {
+            self.cpsr.mode = Mode::Supervisor;
+            self.cpsr.irq_disabled = true;
+        } else {
+            self.cpu_skip_bios(if multiboot { 0x02000000 } else { 0x08000000 });
+        }
+
+        self.g_ie = 0;
+        self.g_if = 0;
+        self.g_ime = 0;
+        self.mem_map.write_io_16(0x000, 0x0080);
+
+        self.apu = apu::Apu::new();
+        self.cpu_update_sound_registers();
+
+        self.timers = timer::Timers::new();
+        self.timer_ticks = 0;
+        self.dma_source = [0; 4];
+        self.dma_dest = [0; 4];
+
+        self.keypad.write_keycnt(0);
+        self.mem_map.write_io_16(0x132, 0);
+        self.cpu_update_keypad();
+        self.serial.reset();
+        self.cpu_update_serial_registers();
+        self.mem_map.write_io_16(0x300, 0);
+        self.halt_state = false;
+        self.stop_state = false;
+        self.idle_loop = IdleLoop::new();
+        self.block_cache = BlockCache::new();
+
+        self.lcd_ticks = LCD_HDRAW_TICKS;
+        self.cpu_next_event = self.lcd_ticks;
+        self.cpu_total_ticks = 0;
+        self.frame_count = 0;
+
+        self.arm_next_pc = self.regs.r[15];
+        self.regs.r[15] += 4;
+
+        self.arm_prefetch();
+
+        if self.multiboot_receiver.is_some() {
+            self.cpu_wait_for_multiboot();
+        }
+    }
+
+    /// No BIOS to run, so start the program at `entry` with the registers it would have left
+    /// behind.
+    fn cpu_skip_bios(&mut self, entry: u32) {
+        self.regs.r[13] = 0x03007F00;
+        self.regs.r[15] = entry;
+        self.regs.set_in(Mode::System, Mode::Irq, 13, 0x03007FA0);
+        self.regs.set_in(Mode::System, Mode::Supervisor, 13, 0x03007FE0);
+    }
+
+    /// Runs until the LCD enters the next VBlank. In Stop mode the clocks are frozen, so this
+    /// returns straight away until a keypad interrupt wakes the CPU up.
+    pub fn run_frame(&mut self) {
+        let frame = self.frame_count;
+
+        self.apu.clear_samples();
+
+        while self.frame_count == frame && !self.stop_state {
+            self.cpu_loop();
+        }
+
+        if self.stop_state {
+            self.cpu_link_stopped_frame();
+        }
+    }
+
+    /// Runs a frame one instruction at a time, calling `before` ahead of each. Slower than
+    /// `run_frame` but otherwise identical. Nothing runs while halted, so `before` isn't
+    /// called then either.
+    pub fn run_frame_stepped<F: FnMut(&Cpu)>(&mut self, mut before: F) {
+        let frame = self.frame_count;
+
+        self.apu.clear_samples();
+
+        while self.frame_count == frame && !self.stop_state {
+            if !self.halt_state {
+                before(self);
+            }
+            self.step_instruction();
+        }
+
+        if self.stop_state {
+            self.cpu_link_stopped_frame();
+        }
+    }
+
+    /// Executes a single instruction, handling any event that falls due after it. While
+    /// halted there is no instruction to run, so this skips ahead to the next event instead.
+    pub fn step_instruction(&mut self) {
+        if self.stop_state {
+            return;
+        }
+
+        if self.halt_state {
+            self.cpu_total_ticks = self.cpu_next_event;
+            self.cpu_events();
+            return;
+        }
+
+        self.cpu_step();
+
+        if self.cpu_total_ticks >= self.cpu_next_event {
+            self.cpu_events();
+        }
+    }
+
+    pub fn press_key(&mut self, key: keypad::Key) {
+        let pressed = self.keypad.pressed() | key.mask();
+        self.set_keys(pressed);
+    }
+
+    pub fn release_key(&mut self, key: keypad::Key) {
+        let pressed = self.keypad.pressed() & !key.mask();
+        self.set_keys(pressed);
+    }
+
+    /// Replaces the whole button state; bit n of `pressed` is `keypad::Key` n.
+    pub fn set_keys(&mut self, pressed: u16) {
+        self.keypad.set_pressed(pressed);
+        self.cpu_update_keypad();
+    }
+
+    pub fn keys(&self) -> u16 {
+        self.keypad.pressed()
+    }
+
+    pub fn framebuffer(&self) -> &[u16] {
+        self.lcd.framebuffer()
+    }
+
+    /// Interleaved stereo samples at `apu::SAMPLE_RATE` produced during the last frame.
+    pub fn audio_samples(&self) -> &[i16] {
+        self.apu.samples()
+    }
+
+    /// Hashes everything that makes up the machine state: registers, pipeline, interrupt
+    /// state and all writable memory. ROM and BIOS are left out since they never change.
+    pub fn hash_state(&self) -> u64 {
+        let mut hasher = FrameHasher::new();
+
+        self.regs.hash(&mut hasher);
+        hasher.write_u32(self.cpsr.bits());
+
+        hasher.write_u32(self.arm_next_pc);
+        hasher.write_u32(self.cpu_prefetch[0]);
+        hasher.write_u32(self.cpu_prefetch[1]);
+        hasher.write_u16(self.g_ie);
+        hasher.write_u16(self.g_if);
+        hasher.write_u16(self.g_ime);
+        for ch in 0..4 {
+            hasher.write_u32(self.dma_source[ch]);
+            hasher.write_u32(self.dma_dest[ch]);
         }
+        hasher.write(self.mem_map.writable_memory());
 
-        self.arm_mode = 0x1f;
+        hasher.finish()
+    }
 
-        {
-            self.regs[15] = Reg::I(0);
-            self.arm_mode = 0x13;
-            self.arm_irq_enable = false;
+    fn cpu_loop(&mut self) {
+        while self.cpu_total_ticks < self.cpu_next_event {
+            if self.halt_state {
+                // nothing runs until an interrupt, so go straight to the next event
+                self.cpu_total_ticks = self.cpu_next_event;
+                break;
+            }
+
+            match self.backend {
+                Backend::Interpreter => self.cpu_step(),
+                Backend::Cached => self.cpu_run_block(),
+                #[cfg(feature = "jit")]
+                Backend::Jit => self.cpu_run_jit(),
+            }
         }
 
-        self.arm_state = true;
-        self.c_flag = false;
-        self.v_flag = false;
-        self.n_flag = false;
-        self.z_flag = false;
+        self.cpu_events();
+    }
 
-        self.regs[16] = Reg::I(self.get_reg_i(16) | 0x40);
+    /// Interprets one instruction. Single-stepping always comes through here, whatever the
+    /// backend, since the results are the same.
+    fn cpu_step(&mut self) {
+        let pc = self.arm_next_pc;
+        let ticks = self.cpu_execute();
+        self.cpu_total_ticks += ticks;
+        self.profile.instructions += 1;
 
-        self.cpu_update_cpsr();
+        if self.idle_skip {
+            self.cpu_idle_branch(pc);
+        }
+    }
 
-        self.arm_next_pc = self.get_reg_i(15);
-        self.regs[15] = Reg::I(self.get_reg_i(15) + 4);
+    fn cpu_events(&mut self) {
+        let ticks = self.cpu_total_ticks;
+        self.cpu_total_ticks = 0;
+
+        self.lcd_ticks -= ticks;
+        if self.lcd_ticks <= 0 {
+            self.lcd_event();
+        }
+
+        let start = self.profile_start();
+        let samples = self.apu.samples().len();
+        self.apu.tick(ticks);
+        if let Some(start) = start {
+            self.profile.count_samples(self.apu.samples().len() - samples, start);
+        }
+        self.mem_map.write_io_16(0x084, self.apu.read_register(0x084));
+
+        let timer_ticks = ticks - self.timer_ticks;
+        self.timer_ticks = 0;
+        self.cpu_timers_tick(timer_ticks);
+
+        // Halt ends on any enabled interrupt, even with IME or the CPSR masking it
+        if self.halt_state && (self.g_if & self.g_ie) != 0 {
+            self.halt_state = false;
+        }
+
+        if !self.cpsr.irq_disabled && (self.g_if & self.g_ie) != 0 && (self.g_ime & 1) != 0 {
+            self.cpu_interrupt();
+        }
+
+        self.cpu_next_event = match self.timers.ticks_to_overflow() {
+            Some(overflow) => self.lcd_ticks.min(overflow),
+            None => self.lcd_ticks,
+        };
+    }
 
-        //arm_prefetch
+    /// Brings the timers up to the current tick so a register write lands at the right time.
+    fn cpu_sync_timers(&mut self) {
+        let ticks = self.cpu_total_ticks - self.timer_ticks;
+        self.timer_ticks = self.cpu_total_ticks;
+        self.cpu_timers_tick(ticks);
     }
 
-    fn cpu_update_cpsr(&mut self) {
-        let mut cpsr = self.get_reg_i(16) & 0x40;
+    fn cpu_timers_tick(&mut self, ticks: i32) {
+        let overflows = self.timers.tick(ticks);
 
-        if self.n_flag {
-            cpsr |= 0x80000000;
+        for (index, &count) in overflows.iter().enumerate() {
+            if count == 0 {
+                continue;
+            }
+
+            if self.timers.irq_enabled(index) {
+                self.cpu_raise_interrupt(0x0008 << index);
+            }
+
+            if index < 2 {
+                for _ in 0..count {
+                    for fifo in self.apu.timer_overflow(index) {
+                        self.cpu_dma_fifo(fifo);
+                    }
+                }
+            }
+        }
+
+        for index in 0..4 {
+            self.mem_map.write_io_16(0x100 + 4 * index as u32, self.timers.counter(index, 0));
+        }
+    }
+
+    /// The timer counters keep running between events, so reads of 0x100-0x10F are worked
+    /// out from the ticks executed so far instead of coming from the IO mirror.
+    fn cpu_read_timer(&self, address: u32) -> Option<u32> {
+        if address >> 24 != 0x04 || address & 0x00FFFFF0 != 0x100 {
+            return None;
         }
 
-        if self.z_flag {
-            cpsr |= 0x40000000;
+        let index = ((address >> 2) & 3) as usize;
+        let counter = self.timers.counter(index, self.cpu_total_ticks - self.timer_ticks);
+
+        Some(counter as u32 | (self.timers.control(index) as u32) << 16)
+    }
+
+    /// In UART mode a read of SIODATA8 takes a byte off the receive FIFO, which changes
+    /// SIOCNT's flags too, so reads of 0x128-0x12B bypass the IO mirror.
+    fn cpu_read_serial(&self, address: u32, size: u32) -> Option<u32> {
+        if address >> 24 != 0x04 || address & 0x00FFFFFC != 0x128 {
+            return None;
+        }
+
+        let data = if address & 2 != 0 || size == 4 {
+            let byte = self.serial.read_uart_data()?;
+            self.bus_read_effects.set(self.bus_read_effects.get().wrapping_add(1));
+            byte as u32
+        } else {
+            self.serial.read(0x12A) as u32
+        };
+
+        Some(self.serial.read(0x128) as u32 | data << 16)
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.backend
+    }
+
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.backend = backend;
+        self.block_cache = BlockCache::new();
+    }
+
+    fn cpu_execute(&mut self) -> i32 {
+        if !self.cpsr.thumb {
+            self.arm_execute()
+        } else {
+            self.thumb_execute()
         }
+    }
 
-        if self.c_flag {
-            cpsr |= 0x20000000;
+    fn lcd_event(&mut self) {
+        let mut dispstat = self.mem_map.read_io_16(0x004);
+        let mut vcount = self.mem_map.read_io_16(0x006);
+
+        if dispstat & 0x02 == 0 {
+            // end of HDraw
+            dispstat |= 0x02;
+            if (vcount as usize) < lcd::SCREEN_HEIGHT {
+                let start = self.profile_start();
+                self.lcd.render_line(&self.mem_map, vcount as usize);
+                if let Some(start) = start {
+                    self.profile.count_line(start);
+                }
+            }
+            if dispstat & 0x10 != 0 {
+                self.cpu_raise_interrupt(0x0002);
+            }
+            if (vcount as usize) < lcd::SCREEN_HEIGHT {
+                self.cpu_dma_trigger(dma::DMA_HBLANK);
+            }
+            self.lcd_ticks += LCD_HBLANK_TICKS;
+        } else {
+            // end of HBlank
+            dispstat &= !0x02;
+            vcount = (vcount + 1) % LCD_LINES;
+
+            if self.serial.end_line() {
+                self.cpu_raise_interrupt(0x0080);
+            }
+            self.cpu_update_serial_registers();
+            if self.multiboot_receiver.is_some() {
+                self.cpu_receive_multiboot();
+            }
+
+            if vcount as usize == lcd::SCREEN_HEIGHT {
+                dispstat |= 0x01;
+                if dispstat & 0x08 != 0 {
+                    self.cpu_raise_interrupt(0x0001);
+                }
+                self.cpu_dma_trigger(dma::DMA_VBLANK);
+                self.frame_count += 1;
+            } else if vcount == LCD_LINES - 1 {
+                dispstat &= !0x01;
+            }
+
+            if vcount == dispstat >> 8 {
+                dispstat |= 0x04;
+                if dispstat & 0x20 != 0 {
+                    self.cpu_raise_interrupt(0x0004);
+                }
+            } else {
+                dispstat &= !0x04;
+            }
+
+            self.lcd_ticks += LCD_HDRAW_TICKS;
         }
 
-        if self.v_flag {
-            cpsr |= 0x10000000;
+        self.mem_map.write_io_16(0x004, dispstat);
+        self.mem_map.write_io_16(0x006, vcount);
+    }
+
+    /// Refreshes KEYINPUT and raises the keypad interrupt when KEYCNT's condition becomes
+    /// true. That interrupt is also the only way out of Stop mode.
+    fn cpu_update_keypad(&mut self) {
+        self.mem_map.write_io_16(0x130, self.keypad.keyinput());
+
+        if self.keypad.irq_edge() {
+            self.cpu_raise_interrupt(0x1000);
+            self.stop_state = false;
         }
+    }
 
-        if !self.arm_state {
-            cpsr |= 0x00000020;
+    /// Enters Stop mode: the clocks halt, the LCD and sound go dark and only a keypad
+    /// interrupt brings the CPU back.
+    fn cpu_stop(&mut self) {
+        self.stop_state = true;
+        self.lcd.blank();
+        self.apu.clear_samples();
+        self.cpu_next_event = self.cpu_total_ticks;
+    }
+
+    /// Stop freezes the clocks but not the link cable: the other players still need this one
+    /// to keep up, a frame's worth of lines at a time. Their transfers can wake it up.
+    fn cpu_link_stopped_frame(&mut self) {
+        if !self.serial.linked() {
+            return;
         }
 
-        if !self.arm_irq_enable {
-            cpsr |= 0x80;
+        for _ in 0..LCD_LINES {
+            let irq = self.serial.end_line();
+            self.cpu_update_serial_registers();
+
+            if irq {
+                self.cpu_raise_interrupt(0x0080);
+                self.stop_state = false;
+                break;
+            }
         }
+    }
 
-        //cpsr |= (self.arm_mode & 0x1f);
+    /// Plugs the serial port into a link cable to other instances.
+    pub fn connect_link(&mut self, link: Box<dyn Link>) {
+        self.serial.connect(link);
+        self.cpu_update_serial_registers();
+    }
+
+    fn cpu_update_serial_registers(&mut self) {
+        for &address in &[0x120, 0x122, 0x124, 0x126, 0x128, 0x12A, 0x134] {
+            self.mem_map.write_io_16(address, self.serial.read(address));
+        }
+    }
 
-        self.regs[16] = Reg::I(cpsr);
+    fn cpu_raise_interrupt(&mut self, flag: u16) {
+        self.g_if |= flag;
+        self.mem_map.write_io_16(0x202, self.g_if);
+        self.cpu_next_event = self.cpu_total_ticks;
+    }
+
+    fn cpu_interrupt(&mut self) {
+        let pc = self.regs.r[15];
+        let return_address = if self.cpsr.thumb { pc + 2 } else { pc };
+
+        self.cpu_exception(Mode::Irq, 0x18, return_address);
+
+        self.bios_protected = [0x02, 0xC0, 0x5E, 0xE5];
+    }
+
+    fn cpu_update_register(&mut self, address: u32, value: u16) {
+        match address {
+            0x004 => {
+                let dispstat = self.mem_map.read_io_16(0x004);
+                self.mem_map.write_io_16(0x004, (dispstat & 0x0007) | (value & 0xFFF8));
+            },
+            0x006 => (),
+            0x060..=0x09E => {
+                self.apu.write_16(address, value);
+                self.cpu_update_sound_registers();
+            },
+            0x0A0..=0x0A6 => self.apu.write_16(address, value),
+            0x0B0..=0x0DE if (address - 0x0B0) % 12 == 10 => {
+                self.cpu_update_dma_control(((address - 0x0B0) / 12) as usize, value);
+            },
+            0x120..=0x12A | 0x134 => {
+                self.serial.write(address, value);
+                self.cpu_update_serial_registers();
+            },
+            0x130 => (),
+            0x132 => {
+                self.keypad.write_keycnt(value);
+                self.mem_map.write_io_16(0x132, self.keypad.keycnt());
+                self.cpu_update_keypad();
+            },
+            0x100..=0x10E => {
+                self.cpu_sync_timers();
+                self.timers.write(address, value);
+                self.mem_map.write_io_16(address & 0x10C, self.timers.counter(((address >> 2) & 3) as usize, 0));
+                self.mem_map.write_io_16((address & 0x10C) + 2, self.timers.control(((address >> 2) & 3) as usize));
+                self.cpu_next_event = self.cpu_total_ticks;
+            },
+            0x200 => {
+                self.g_ie = value & 0x3FFF;
+                self.mem_map.write_io_16(0x200, self.g_ie);
+                self.cpu_next_event = self.cpu_total_ticks;
+            },
+            0x202 => {
+                self.g_if ^= value & self.g_if;
+                self.mem_map.write_io_16(0x202, self.g_if);
+            },
+            0x204 => {
+                self.cpu_set_wait_states(value);
+                self.bus_prefetch = false;
+                self.bus_prefetch_count = 0;
+
+                self.mem_map.write_io_16(0x204, value & 0x7FFF);
+            },
+            0x208 => {
+                self.g_ime = value & 0x0001;
+                self.mem_map.write_io_16(0x208, self.g_ime);
+                self.cpu_next_event = self.cpu_total_ticks;
+            },
+            0x300 => {
+                // POSTFLG in the low byte; HALTCNT in the high one is write only
+                self.mem_map.write_io_16(0x300, value & 0x0001);
+
+                if value & 0x8000 != 0 {
+                    self.cpu_stop();
+                } else {
+                    self.halt_state = true;
+                    self.cpu_next_event = self.cpu_total_ticks;
+                }
+            },
+            _ => self.mem_map.write_io_16(address, value),
+        }
+    }
+
+    /// Refreshes the IO mirror of the sound registers and the CPU side wave RAM bank.
+    fn cpu_update_sound_registers(&mut self) {
+        for address in (0x060..0x0A0).step_by(2) {
+            self.mem_map.write_io_16(address, self.apu.read_register(address));
+        }
+    }
+
+    fn cpu_read_8(&self, address: u32) -> u32 {
+        if self.profiling {
+            self.profile.count_read(address);
+        }
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address, 1, false);
+        }
+
+        if let Some(word) = self.cpu_read_timer(address).or_else(|| self.cpu_read_serial(address, 1)) {
+            return (word >> ((address & 3) * 8)) & 0xFF;
+        }
+
+        self.mem_map.read_8(address, self.bios_protected, self.regs.r[15]) as u32
+    }
+
+    fn cpu_read_16(&self, address: u32) -> u32 {
+        if self.profiling {
+            self.profile.count_read(address);
+        }
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address & !1, 2, false);
+        }
+
+        let value = match self.cpu_read_timer(address).or_else(|| self.cpu_read_serial(address, 2)) {
+            Some(word) => (word >> ((address & 2) * 8)) & 0xFFFF,
+            None => self.mem_map.read_16(address, self.bios_protected, self.regs.r[15]) as u32,
+        };
+
+        if address & 1 != 0 {
+            value.rotate_right(8)
+        } else {
+            value
+        }
+    }
+
+    fn cpu_read_16_signed(&self, address: u32) -> u32 {
+        if address & 1 != 0 {
+            self.cpu_read_8(address) as u8 as i8 as u32
+        } else {
+            self.cpu_read_16(address) as u16 as i16 as u32
+        }
+    }
+
+    fn cpu_read_32(&self, address: u32) -> u32 {
+        if self.profiling {
+            self.profile.count_read(address);
+        }
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address & !3, 4, false);
+        }
+
+        let value = match self.cpu_read_timer(address).or_else(|| self.cpu_read_serial(address, 4)) {
+            Some(word) => word,
+            None => self.mem_map.read_32(address, self.bios_protected, self.regs.r[15]),
+        };
+
+        value.rotate_right((address & 3) * 8)
+    }
+
+    fn cpu_write_8(&mut self, address: u32, value: u8) {
+        self.bus_writes = self.bus_writes.wrapping_add(1);
+        if self.backend != Backend::Interpreter {
+            self.block_cache.invalidate(address);
+        }
+        if self.profiling {
+            self.profile.count_write(address);
+        }
+
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address, 1, true);
+        }
+
+        if address >> 24 == 0x04 {
+            if address & 0x00FFFFFF < 0x400 {
+                let address = address & 0x3FF;
+                let shift = (address & 1) * 8;
+
+                if (0x060..0x0A0).contains(&address) {
+                    self.apu.write_8(address, value);
+                    self.cpu_update_sound_registers();
+                } else if address & 0x3FE == 0x202 {
+                    // only acknowledge the bits that were actually written
+                    self.cpu_update_register(0x202, (value as u16) << shift);
+                } else if address == 0x300 {
+                    // a byte to POSTFLG alone must not halt
+                    self.mem_map.write_io_16(0x300, value as u16 & 0x0001);
+                } else {
+                    let old = self.mem_map.read_io_16(address & 0x3FE);
+                    let merged = (old & !(0xFF << shift)) | ((value as u16) << shift);
+                    self.cpu_update_register(address & 0x3FE, merged);
+                }
+            }
+        } else {
+            self.mem_map.write_8(address, value);
+        }
+    }
+
+    fn cpu_write_16(&mut self, address: u32, value: u16) {
+        self.bus_writes = self.bus_writes.wrapping_add(1);
+        if self.backend != Backend::Interpreter {
+            self.block_cache.invalidate(address);
+        }
+        if self.profiling {
+            self.profile.count_write(address);
+        }
+
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address & !1, 2, true);
+        }
+
+        if address >> 24 == 0x04 {
+            if address & 0x00FFFFFF < 0x400 {
+                self.cpu_update_register(address & 0x3FE, value);
+            }
+        } else {
+            self.mem_map.write_16(address, value);
+        }
+    }
+
+    fn cpu_write_32(&mut self, address: u32, value: u32) {
+        self.bus_writes = self.bus_writes.wrapping_add(1);
+        if self.backend != Backend::Interpreter {
+            self.block_cache.invalidate(address);
+        }
+        if self.profiling {
+            self.profile.count_write(address);
+        }
+
+        if !self.watchpoints.is_empty() {
+            self.cpu_check_watchpoints(address & !3, 4, true);
+        }
+
+        if address >> 24 == 0x04 {
+            if address & 0x00FFFFFF < 0x400 {
+                self.cpu_update_register(address & 0x3FC, value as u16);
+                self.cpu_update_register((address & 0x3FC) + 2, (value >> 16) as u16);
+            }
+        } else {
+            self.mem_map.write_32(address, value);
+        }
+    }
+
+    /// Fills the cartridge wait state tables and the prefetch enable from a WAITCNT value.
+    /// Save states rebuild them this way rather than storing them.
+    pub(super) fn cpu_set_wait_states(&mut self, value: u16) {
+        self.memory_wait[0x0E] = GAMEPAK_RAM_WAIT_STATE[(value & 3) as usize];
+        self.memory_wait_seq[0x0E] = self.memory_wait[0x0E];
+        self.memory_wait[0x08] = GAMEPAK_WAIT_STATE[((value >> 2) & 3) as usize];
+        self.memory_wait[0x09] = self.memory_wait[0x08];
+        self.memory_wait_seq[0x08] = GAMEPAK_WAIT_STATE_0[((value >> 4) & 1) as usize];
+        self.memory_wait_seq[0x09] = self.memory_wait_seq[0x08];
+        self.memory_wait[0x0A] = GAMEPAK_WAIT_STATE[((value >> 5) & 3) as usize];
+        self.memory_wait[0x0B] = self.memory_wait[0x0A];
+        self.memory_wait_seq[0x0A] = GAMEPAK_WAIT_STATE_1[((value >> 7) & 1) as usize];
+        self.memory_wait_seq[0x0B] = self.memory_wait_seq[0x0A];
+        self.memory_wait[0x0C] = GAMEPAK_WAIT_STATE[((value >> 8) & 3) as usize];
+        self.memory_wait[0x0D] = self.memory_wait[0x0C];
+        self.memory_wait_seq[0x0C] = GAMEPAK_WAIT_STATE_2[((value >> 10) & 1) as usize];
+        self.memory_wait_seq[0x0D] = self.memory_wait_seq[0x0C];
+
+        for i in 0x08..0x0F {
+            self.memory_wait_32[i] = self.memory_wait[i] + self.memory_wait_seq[i] + 1;
+            self.memory_wait_seq_32[i] = self.memory_wait_seq[i] * 2 + 1;
+        }
+
+        self.bus_prefetch_enable = value & 0x4000 != 0;
     }
 
     fn data_ticks_access_16(&mut self, address: usize) -> u8 {
         let addr = (address >> 24) & 15;
         let value = self.memory_wait[addr];
 
-        if (addr >= 0x08) || (addr < 0x02) {
+        if !(0x02..0x08).contains(&addr) {
             self.bus_prefetch_count = 0;
-            self.bus_prefetch_enable = false;
+            self.bus_prefetch = false;
         } else if self.bus_prefetch {
             let mut wait_state = value;
             if wait_state == 0 {
                 wait_state = 1;
             }
-            self.bus_prefetch_count = ((self.bus_prefetch_count + 1) << wait_state) - 1;
+            self.bus_prefetch_count = (self.bus_prefetch_count.wrapping_add(1) << wait_state).wrapping_sub(1);
         }
 
         value
@@ -196,7 +964,7 @@ impl Cpu {
         let addr = (address >> 24) & 15;
         let value = self.memory_wait_32[addr];
 
-        if (addr > 0x08) || (addr < 0x02) {
+        if !(0x02..=0x08).contains(&addr) {
             self.bus_prefetch_count = 0;
             self.bus_prefetch = false;
         } else if self.bus_prefetch {
@@ -204,7 +972,7 @@ impl Cpu {
             if wait_state == 0 {
                 wait_state = 1;
             }
-            self.bus_prefetch_count = ((self.bus_prefetch_count + 1) << wait_state) - 1;
+            self.bus_prefetch_count = (self.bus_prefetch_count.wrapping_add(1) << wait_state).wrapping_sub(1);
         }
 
         value
@@ -214,15 +982,15 @@ impl Cpu {
         let addr = (address >> 24) & 15;
         let value = self.memory_wait_seq[addr];
 
-        if addr >= 0x08 || addr < 0x02 {
+        if !(0x02..0x08).contains(&addr) {
             self.bus_prefetch_count = 0;
-            self.bus_prefetch_enable = false;
+            self.bus_prefetch = false;
         } else if self.bus_prefetch {
             let mut wait_state = value;
             if wait_state == 0 {
                 wait_state = 1;
             }
-            self.bus_prefetch_count = ((self.bus_prefetch_count + 1) << wait_state) - 1;
+            self.bus_prefetch_count = (self.bus_prefetch_count.wrapping_add(1) << wait_state).wrapping_sub(1);
         }
 
         value
@@ -232,15 +1000,15 @@ impl Cpu {
         let addr = (address >> 24) & 15;
         let value = self.memory_wait_seq_32[addr];
 
-        if addr >= 0x08 || addr < 0x02 {
+        if !(0x02..0x08).contains(&addr) {
             self.bus_prefetch_count = 0;
-            self.bus_prefetch_enable = false;
+            self.bus_prefetch = false;
         } else if self.bus_prefetch {
             let mut wait_state = value;
             if wait_state == 0 {
                 wait_state = 1;
             }
-            self.bus_prefetch_count = ((self.bus_prefetch_count + 1) << wait_state) - 1;
+            self.bus_prefetch_count = (self.bus_prefetch_count.wrapping_add(1) << wait_state).wrapping_sub(1);
         }
 
         value
@@ -249,287 +1017,291 @@ impl Cpu {
     fn code_ticks_access_16(&mut self, address: usize) -> u8 {
         let addr = (address >> 24) & 15;
 
-        if addr >= 0x08 && addr <= 0x0D {
+        if (0x08..=0x0D).contains(&addr) {
             if self.bus_prefetch_count & 0x1 != 0 {
                 if self.bus_prefetch_count & 0x2 != 0 {
                     self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 2) | (self.bus_prefetch_count & 0xFFFFFF00);
                     return 0;
                 }
                 self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 1) | (self.bus_prefetch_count & 0xFFFFFF00);
-                return self.memory_wait_seq[addr] - 1;
+                self.memory_wait_seq[addr] - 1
             } else {
                 self.bus_prefetch_count = 0;
-                return self.memory_wait[addr];
+                self.memory_wait[addr]
             }
         } else {
             self.bus_prefetch_count = 0;
-            return self.memory_wait[addr];
+            self.memory_wait[addr]
         }
     }
 
     fn code_ticks_access_32(&mut self, address: usize) -> u8 {
         let addr = (address >> 24) & 15;
 
-        if addr >= 0x08 && addr <= 0x0D {
+        if (0x08..=0x0D).contains(&addr) {
             if self.bus_prefetch_count & 0x1 != 0 {
                 if self.bus_prefetch_count & 0x2 != 0 {
                     self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 2) | (self.bus_prefetch_count & 0xFFFFFF00);
                     return 0;
                 }
                 self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 1) | (self.bus_prefetch_count & 0xFFFFFF00);
-                return self.memory_wait_seq[addr] - 1;
+                self.memory_wait_seq[addr] - 1
             } else {
                 self.bus_prefetch_count = 0;
-                return self.memory_wait_32[addr];
+                self.memory_wait_32[addr]
             }
         } else {
             self.bus_prefetch_count = 0;
-            return self.memory_wait_32[addr];
+            self.memory_wait_32[addr]
         }
     }
 
     fn code_ticks_access_seq_16(&mut self, address: usize) -> u8 {
         let addr = (address >> 24) & 15;
 
-        if addr >= 0x08 && addr <= 0x0D {
+        if (0x08..=0x0D).contains(&addr) {
             if self.bus_prefetch_count & 0x1 != 0 {
                 self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 1) | (self.bus_prefetch_count & 0xFFFFFF00);
-                return 0;
+                0
             } else if self.bus_prefetch_count > 0xFF {
                 self.bus_prefetch_count = 0;
-                return self.memory_wait[addr];
+                self.memory_wait[addr]
             } else {
-                return self.memory_wait_seq[addr];
+                self.memory_wait_seq[addr]
             }
         } else {
             self.bus_prefetch_count = 0;
-            return self.memory_wait_seq[addr];
+            self.memory_wait_seq[addr]
         }
     }
 
     fn code_ticks_access_seq_32(&mut self, address: usize) -> u8 {
         let addr = (address >> 24) & 15;
 
-        if addr >= 0x08 && addr <= 0x0D {
+        if (0x08..=0x0D).contains(&addr) {
             if self.bus_prefetch_count & 0x1 != 0 {
                 if self.bus_prefetch_count & 0x2 != 0 {
                     self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 2) | (self.bus_prefetch_count & 0xFFFFFF00);
                     return 0;
                 }
                 self.bus_prefetch_count = ((self.bus_prefetch_count & 0xFF) >> 1) | (self.bus_prefetch_count & 0xFFFFFF00);
-                return self.memory_wait_seq[addr];
+                self.memory_wait_seq[addr]
             } else if self.bus_prefetch_count > 0xFF {
                 self.bus_prefetch_count = 0;
-                return self.memory_wait_32[addr];
+                self.memory_wait_32[addr]
             } else {
-                return self.memory_wait_seq_32[addr];
+                self.memory_wait_seq_32[addr]
             }
         } else {
-            return self.memory_wait_seq_32[addr];
+            self.memory_wait_seq_32[addr]
         }
     }
 
-    fn cpu_switch_mode(&mut self, mode: i32, save_state: bool, break_loop: bool) {
-        self.cpu_update_cpsr();
+    /// Replaces the CPSR, switching register banks if the mode changes. Unmasking an IRQ
+    /// that is already pending ends the current run so it is taken right away.
+    fn cpu_write_cpsr(&mut self, psr: Psr) {
+        self.regs.switch_mode(self.cpsr.mode, psr.mode);
+        self.cpsr = psr;
 
-        match self.arm_mode {
-            0x10 | 0x1F => {
-                self.regs[R13_USR] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_USR] = Reg::I(self.get_reg_i(14));
-                self.regs[17] = Reg::I(self.get_reg_i(16));
-            },
-            0x11 => {
-                self.cpu_swap(R8_FIQ, 8);
-                self.cpu_swap(R9_FIQ, 9);
-                self.cpu_swap(R10_FIQ, 10);
-                self.cpu_swap(R11_FIQ, 11);
-                self.cpu_swap(R12_FIQ, 12);
-                self.regs[R13_FIQ] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_FIQ] = Reg::I(self.get_reg_i(14));
-                self.regs[SPSR_FIQ] = Reg::I(self.get_reg_i(17));
-            },
-            0x12 => {
-                self.regs[R13_IRQ] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_IRQ] = Reg::I(self.get_reg_i(14));
-                self.regs[SPSR_IRQ] = Reg::I(self.get_reg_i(17));
-            },
-            0x13 => {
-                self.regs[R13_SVC] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_SVC] = Reg::I(self.get_reg_i(14));
-                self.regs[SPSR_SVC] = Reg::I(self.get_reg_i(17));
-            },
-            0x17 => {
-                self.regs[R13_ABT] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_ABT] = Reg::I(self.get_reg_i(14));
-                self.regs[SPSR_ABT] = Reg::I(self.get_reg_i(17));
-            },
-            0x1B => {
-                self.regs[R13_UND] = Reg::I(self.get_reg_i(13));
-                self.regs[R14_UND] = Reg::I(self.get_reg_i(14));
-                self.regs[SPSR_UND] = Reg::I(self.get_reg_i(17));
-            },
-            _ => (),
+        if !psr.irq_disabled && (self.g_if & self.g_ie) != 0 && (self.g_ime & 1) != 0 {
+            self.cpu_next_event = self.cpu_total_ticks;
         }
+    }
 
-        let cpsr = self.get_reg_i(16);
-        let spsr = self.get_reg_i(17);
+    /// Enters `mode` through the exception vector at `vector`, saving the CPSR in the new
+    /// mode's SPSR.
+    fn cpu_exception(&mut self, mode: Mode, vector: u32, return_address: u32) {
+        let cpsr = self.cpsr;
 
-        match mode {
-            0x10 | 0x1F => {
-                self.regs[13] = Reg::I(self.get_reg_i(R13_USR));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_USR));
-                self.regs[16] = Reg::I(spsr);
-            },
-            0x11 => {
-                self.cpu_swap(8, R8_FIQ);
-                self.cpu_swap(9, R9_FIQ);
-                self.cpu_swap(10, R10_FIQ);
-                self.cpu_swap(11, R11_FIQ);
-                self.cpu_swap(12, R12_FIQ);
-                self.regs[13] = Reg::I(self.get_reg_i(R13_FIQ));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_FIQ));
-                if save_state {
-                    self.regs[17] = Reg::I(cpsr);
-                } else {
-                    self.regs[17] = Reg::I(self.get_reg_i(SPSR_FIQ));
-                }
-            },
-            0x12 => {
-                self.regs[13] = Reg::I(self.get_reg_i(R13_IRQ));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_IRQ));
-                self.regs[16] = Reg::I(spsr);
-                if save_state {
-                    self.regs[17] = Reg::I(cpsr);
-                } else {
-                    self.regs[17] = Reg::I(self.get_reg_i(SPSR_IRQ));
-                }
-            },
-            0x13 => {
-                self.regs[13] = Reg::I(self.get_reg_i(R13_SVC));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_SVC));
-                self.regs[16] = Reg::I(spsr);
-                if save_state {
-                    self.regs[17] = Reg::I(cpsr);
-                } else {
-                    self.regs[17] = Reg::I(self.get_reg_i(SPSR_SVC));
-                }
-            },
-            0x17 => {
-                self.regs[13] = Reg::I(self.get_reg_i(R13_ABT));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_ABT));
-                self.regs[16] = Reg::I(spsr);
-                if save_state {
-                    self.regs[17] = Reg::I(cpsr);
-                } else {
-                    self.regs[17] = Reg::I(self.get_reg_i(SPSR_ABT));
-                }
-            },
-            0x1B => {
-                self.regs[13] = Reg::I(self.get_reg_i(R13_UND));
-                self.regs[14] = Reg::I(self.get_reg_i(R14_UND));
-                self.regs[16] = Reg::I(spsr);
-                if save_state {
-                    self.regs[17] = Reg::I(cpsr);
-                } else {
-                    self.regs[17] = Reg::I(self.get_reg_i(SPSR_UND));
-                }
-            },
-            _ => {
-                println!("unsupported ARM mode: {}", mode);
-            },
-        }
+        self.regs.switch_mode(cpsr.mode, mode);
+        self.regs.set_spsr(mode, cpsr);
+        self.cpsr.mode = mode;
+        self.cpsr.thumb = false;
+        self.cpsr.irq_disabled = true;
 
-        self.arm_mode = mode;
+        self.regs.r[14] = return_address;
+        self.regs.r[15] = vector;
+        self.arm_next_pc = vector;
+        self.arm_prefetch();
+        self.regs.r[15] += 4;
+    }
 
-        self.cpu_update_flags(break_loop);
-        self.cpu_update_cpsr();
+    /// Handler for opcodes that raise the undefined instruction exception.
+    fn cpu_undefined_opcode(&mut self, _opcode: u32) -> i32 {
+        self.cpu_undefined_exception();
+        1
     }
 
-    fn cpu_switch_mode_s(&mut self, mode: i32, save_state: bool) {
-        self.cpu_switch_mode(mode, save_state, true);
+    fn cpu_software_interrupt_opcode(&mut self, _opcode: u32) -> i32 {
+        self.cpu_software_interrupt();
+        3
     }
 
-    fn cpu_update_flags(&mut self,  break_loop: bool) {
-        let cpsr = self.get_reg_i(16);
+    fn cpu_undefined_exception(&mut self) {
+        let pc = self.regs.r[15];
+        let return_address = pc - if self.cpsr.thumb { 2 } else { 4 };
 
-        self.n_flag = cpsr & 0x80000000 != 0;
-        self.z_flag = cpsr & 0x40000000 != 0;
-        self.c_flag = cpsr & 0x20000000 != 0;
-        self.v_flag = cpsr & 0x10000000 != 0;
+        self.cpu_exception(Mode::Undefined, 0x04, return_address);
+    }
 
-        self.arm_state = cpsr & 0x20 == 0;
+    fn cpu_software_interrupt(&mut self) {
+        let pc = self.regs.r[15];
+        let return_address = pc - if self.cpsr.thumb { 2 } else { 4 };
 
-        self.arm_irq_enable = cpsr & 0x80 == 0;
+        self.cpu_exception(Mode::Supervisor, 0x08, return_address);
+    }
 
-        if break_loop {
-            if self.arm_irq_enable && (self.g_if & self.g_ie) != 0 && (self.g_ime & 1) != 0 {
-                self.cpu_next_event = self.cpu_total_ticks;
-            }
+    /// Refills the pipeline after an instruction wrote r15.
+    fn cpu_jump(&mut self) {
+        if !self.cpsr.thumb {
+            self.arm_next_pc = self.regs.r[15] & !3;
+            self.regs.r[15] = self.arm_next_pc.wrapping_add(4);
+            self.arm_prefetch();
+        } else {
+            self.arm_next_pc = self.regs.r[15] & !1;
+            self.regs.r[15] = self.arm_next_pc.wrapping_add(2);
+            self.thumb_prefetch();
         }
     }
 
-    fn cpu_update_flags_s(&mut self) {
-        self.cpu_update_flags(true);
+    fn arm_prefetch(&mut self) {
+        self.cpu_prefetch[0] = self.mem_map.read_32(self.arm_next_pc, self.bios_protected, self.regs.r[15]);
+        self.cpu_prefetch[1] = self.mem_map.read_32(self.arm_next_pc.wrapping_add(4), self.bios_protected, self.regs.r[15]);
     }
 
-    fn cpu_swap(&mut self, a: usize, b: usize) {
-        let temp = self.get_reg_i(b);
-        self.regs[b] = Reg::I(self.get_reg_i(a));
-        self.regs[a] = Reg::I(temp);
+    fn thumb_prefetch(&mut self) {
+        self.cpu_prefetch[0] = self.mem_map.read_16(self.arm_next_pc, self.bios_protected, self.regs.r[15]) as u32;
+        self.cpu_prefetch[1] = self.mem_map.read_16(self.arm_next_pc.wrapping_add(2), self.bios_protected, self.regs.r[15]) as u32;
     }
+}
 
-    fn cpu_undefined_exception(&mut self) {
-        let pc = self.get_reg_i(15);
-
-        let saved_arm_state = self.arm_state;
-
-        self.cpu_switch_mode(0x1B, true, false);
-
-        self.regs[14] = Reg::I(pc - (
-            if saved_arm_state {
-                4
-            } else {
-                2
-            }
-        ));
-        self.regs[15] = Reg::I(0x04);
-        self.arm_state = true;
-        self.arm_irq_enable = false;
-        self.arm_next_pc = 0x04;
-        self.arm_prefetch();
-        self.regs[15] = Reg::I(self.get_reg_i(15) + 4);
+impl Default for Cpu {
+    fn default() -> Cpu {
+        Cpu::new()
     }
+}
 
-    fn arm_prefetch(&mut self) {
-        self.cpu_prefetch[0] = self.mem_map.read_32(self.arm_next_pc);
-        self.cpu_prefetch[1] = self.mem_map.read_32(self.arm_next_pc + 4);
+#[cfg(test)]
+mod tests {
+    use super::super::keypad::Key;
+    use super::super::mem_map::WRAM_SIZE;
+    use super::Cpu;
+
+    /// Enables the vblank interrupt in DISPSTAT and IE, but not IME, then halts through
+    /// HALTCNT and counts in r5 once woken.
+    const HALT: [u32; 11] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE3A00008, // mov r0, #0x08
+        0xE1C400B4, // strh r0, [r4, #4]
+        0xE3A00001, // mov r0, #1
+        0xE2841C02, // add r1, r4, #0x200
+        0xE1C100B0, // strh r0, [r1]
+        0xE2842C03, // add r2, r4, #0x300
+        0xE3A06000, // mov r6, #0
+        0xE5C26001, // strb r6, [r2, #1]
+        0xE2855001, // add r5, r5, #1
+        0xEAFFFFFE, // b .
+    ];
+
+    /// Asks for the keypad interrupt on A in KEYCNT and IE, but not IME, then stops through
+    /// HALTCNT and counts in r5 once woken.
+    const STOP: [u32; 13] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE2841C01, // add r1, r4, #0x100
+        0xE3A00901, // mov r0, #0x4000
+        0xE3800001, // orr r0, r0, #1
+        0xE1C103B2, // strh r0, [r1, #0x32]
+        0xE2842C02, // add r2, r4, #0x200
+        0xE3A00A01, // mov r0, #0x1000
+        0xE1C200B0, // strh r0, [r2]
+        0xE2842C03, // add r2, r4, #0x300
+        0xE3A00080, // mov r0, #0x80
+        0xE5C20001, // strb r0, [r2, #1]
+        0xE2855001, // add r5, r5, #1
+        0xEAFFFFFE, // b .
+    ];
+
+    /// A reset CPU about to run `program`, ARM code written to the start of IWRAM.
+    pub(super) fn program_cpu(program: &[u32]) -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+
+        for (index, opcode) in program.iter().enumerate() {
+            let offset = WRAM_SIZE + index * 4;
+            cpu.mem_map.writable_memory_mut()[offset..offset + 4].copy_from_slice(&opcode.to_le_bytes());
+        }
+        cpu.regs.r[15] = 0x03000000;
+        cpu.cpu_jump();
+        cpu
     }
 
-    fn get_reg_b(&self, reg: usize) -> (u8, u8, u8, u8) {
-        match self.regs[reg] {
-            Reg::B(val) => val,
-            _ => panic!("reg {} was not a 8 bit reg", reg),
+    #[test]
+    fn halt_waits_for_an_enabled_interrupt() {
+        let mut cpu = program_cpu(&HALT);
+
+        for _ in 0..9 {
+            cpu.step_instruction();
         }
-    }
+        assert!(cpu.halt_state);
 
-    fn get_reg_w(&self, reg: usize) -> (u16, u16) {
-        match self.regs[reg] {
-            Reg::W(val) => val,
-            _ => panic!("reg {} was not a 16 bit reg", reg),
+        // a few lines in, still well short of vblank
+        for _ in 0..4 {
+            cpu.step_instruction();
         }
+        assert!(cpu.halt_state);
+        assert_eq!(cpu.regs.r[5], 0);
+
+        let mut steps = 0;
+        let mut halted_steps = 0;
+        for _ in 0..2 {
+            cpu.run_frame_stepped(|cpu| {
+                steps += 1;
+                if cpu.halt_state {
+                    halted_steps += 1;
+                }
+            });
+        }
+
+        assert!(!cpu.halt_state);
+        assert_eq!(cpu.g_ie & cpu.g_if & 1, 1);
+        assert_eq!(cpu.regs.r[5], 1);
+        assert!(steps > 0);
+        assert_eq!(halted_steps, 0);
     }
 
-    fn get_reg_i(&self, reg: usize) -> u32 {
-        match self.regs[reg] {
-            Reg::I(val) => val,
-            _ => panic!("reg {} was not a 32 bit reg", reg),
+    #[test]
+    fn keypad_interrupt_wakes_from_stop() {
+        let mut cpu = program_cpu(&STOP);
+        cpu.run_frame();
+        assert!(cpu.stop_state);
+
+        // frames go by without running anything until A goes down
+        let ticks = cpu.cpu_total_ticks;
+        for keys in &[0, Key::B.mask(), 0] {
+            cpu.set_keys(*keys);
+            cpu.run_frame();
+            assert!(cpu.stop_state);
         }
-    }
-}
+        assert_eq!(cpu.cpu_total_ticks, ticks);
+        assert_eq!(cpu.g_if, 0);
+
+        cpu.set_keys(Key::A.mask());
+        assert!(!cpu.stop_state);
+        assert_eq!(cpu.g_if, 0x1000);
+        cpu.run_frame();
+        assert_eq!(cpu.regs.r[5], 1);
+
+        // holding A does not ask again once acknowledged, pressing it again does
+        cpu.cpu_update_register(0x202, 0x1000);
+        for _ in 0..3 {
+            cpu.set_keys(Key::A.mask());
+            cpu.run_frame();
+        }
+        assert_eq!(cpu.g_if & 0x1000, 0);
 
-#[derive(Copy, Clone)]
-pub enum Reg {
-    B((u8, u8, u8, u8)),
-    W((u16, u16)),
-    I(u32),
+        cpu.set_keys(0);
+        cpu.set_keys(Key::A.mask());
+        assert_eq!(cpu.g_if & 0x1000, 0x1000);
+        assert_eq!(cpu.mem_map.read_io_16(0x130), 0x03FE);
+    }
 }
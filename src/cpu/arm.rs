@@ -0,0 +1,619 @@
+use super::{Cpu, Handler};
+use super::registers::{Mode, Psr};
+
+impl Cpu {
+    pub(super) fn arm_execute(&mut self) -> i32 {
+        let opcode = self.arm_fetch(None);
+
+        if !self.cpsr.condition(opcode >> 28) {
+            return 1 + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32;
+        }
+
+        Cpu::arm_decode(opcode)(self, opcode)
+    }
+
+    /// Moves the pipeline on by one instruction and returns the one to execute. The opcode
+    /// two instructions ahead is read from memory unless the caller already knows it.
+    pub(super) fn arm_fetch(&mut self, prefetched: Option<u32>) -> u32 {
+        let opcode = self.cpu_prefetch[0];
+        self.cpu_prefetch[0] = self.cpu_prefetch[1];
+
+        self.bus_prefetch = self.bus_prefetch_enable && self.arm_next_pc >> 24 >= 0x08;
+
+        self.arm_next_pc = self.regs.r[15];
+        self.regs.r[15] = self.arm_next_pc + 4;
+        self.cpu_prefetch[1] = match prefetched {
+            Some(opcode) => opcode,
+            None => self.mem_map.read_32(self.arm_next_pc + 4, self.bios_protected, self.regs.r[15]),
+        };
+
+        opcode
+    }
+
+    /// The handler for an ARM opcode. The condition is checked by the caller.
+    pub(super) fn arm_decode(opcode: u32) -> Handler {
+        if opcode & 0x0FFFFFF0 == 0x012FFF10 {
+            Cpu::arm_branch_exchange
+        } else if opcode & 0x0FC000F0 == 0x00000090 {
+            Cpu::arm_multiply
+        } else if opcode & 0x0F8000F0 == 0x00800090 {
+            Cpu::arm_multiply_long
+        } else if opcode & 0x0FB00FF0 == 0x01000090 {
+            Cpu::arm_swap
+        } else if opcode & 0x0E000090 == 0x00000090 && opcode & 0x60 != 0 {
+            Cpu::arm_halfword_transfer
+        } else if opcode & 0x0FBF0FFF == 0x010F0000 {
+            Cpu::arm_mrs
+        } else if opcode & 0x0DB0F000 == 0x0120F000 {
+            Cpu::arm_msr
+        } else if opcode & 0x0C000000 == 0x00000000 {
+            Cpu::arm_data_processing
+        } else if opcode & 0x0E000010 == 0x06000010 {
+            Cpu::cpu_undefined_opcode
+        } else if opcode & 0x0C000000 == 0x04000000 {
+            Cpu::arm_single_transfer
+        } else if opcode & 0x0E000000 == 0x08000000 {
+            Cpu::arm_block_transfer
+        } else if opcode & 0x0E000000 == 0x0A000000 {
+            Cpu::arm_branch
+        } else if opcode & 0x0F000000 == 0x0F000000 {
+            Cpu::cpu_software_interrupt_opcode
+        } else {
+            // coprocessor space, there is no coprocessor on the GBA
+            Cpu::cpu_undefined_opcode
+        }
+    }
+
+    pub(super) fn alu_add(&mut self, a: u32, b: u32, carry: bool, set_flags: bool) -> u32 {
+        let result = a as u64 + b as u64 + carry as u64;
+        let value = result as u32;
+
+        if set_flags {
+            self.cpsr.set_nz(value);
+            self.cpsr.c = result > 0xFFFFFFFF;
+            self.cpsr.v = (!(a ^ b) & (a ^ value)) & 0x80000000 != 0;
+        }
+
+        value
+    }
+
+    /// a - b - !carry, with C meaning "no borrow" like the hardware.
+    pub(super) fn alu_sub(&mut self, a: u32, b: u32, carry: bool, set_flags: bool) -> u32 {
+        self.alu_add(a, !b, carry, set_flags)
+    }
+
+    /// Shifts a value the way the barrel shifter does. Immediate shift amounts of zero
+    /// encode LSR #32, ASR #32 and RRX; register amounts of zero leave carry alone.
+    pub(super) fn barrel_shift(&self, shift_type: u32, value: u32, amount: u32, immediate: bool) -> (u32, bool) {
+        let carry = self.cpsr.c;
+
+        match shift_type {
+            0 => {
+                if amount == 0 {
+                    (value, carry)
+                } else if amount < 32 {
+                    (value << amount, (value >> (32 - amount)) & 1 != 0)
+                } else if amount == 32 {
+                    (0, value & 1 != 0)
+                } else {
+                    (0, false)
+                }
+            },
+            1 => {
+                let amount = if immediate && amount == 0 { 32 } else { amount };
+
+                if amount == 0 {
+                    (value, carry)
+                } else if amount < 32 {
+                    (value >> amount, (value >> (amount - 1)) & 1 != 0)
+                } else if amount == 32 {
+                    (0, value >> 31 != 0)
+                } else {
+                    (0, false)
+                }
+            },
+            2 => {
+                let amount = if immediate && amount == 0 { 32 } else { amount };
+
+                if amount == 0 {
+                    (value, carry)
+                } else if amount < 32 {
+                    (((value as i32) >> amount) as u32, (value >> (amount - 1)) & 1 != 0)
+                } else {
+                    (((value as i32) >> 31) as u32, value >> 31 != 0)
+                }
+            },
+            _ => {
+                if immediate && amount == 0 {
+                    ((value >> 1) | ((carry as u32) << 31), value & 1 != 0)
+                } else if amount == 0 {
+                    (value, carry)
+                } else if amount & 31 == 0 {
+                    (value, value >> 31 != 0)
+                } else {
+                    let amount = amount & 31;
+                    (value.rotate_right(amount), (value >> (amount - 1)) & 1 != 0)
+                }
+            },
+        }
+    }
+
+    pub(super) fn multiply_ticks(&self, rs: u32) -> i32 {
+        if rs & 0xFFFFFF00 == 0 || rs & 0xFFFFFF00 == 0xFFFFFF00 {
+            1
+        } else if rs & 0xFFFF0000 == 0 || rs & 0xFFFF0000 == 0xFFFF0000 {
+            2
+        } else if rs & 0xFF000000 == 0 || rs & 0xFF000000 == 0xFF000000 {
+            3
+        } else {
+            4
+        }
+    }
+
+    /// Copies SPSR back into CPSR, as done by data processing with S set and by LDM with ^.
+    pub(super) fn cpu_restore_cpsr(&mut self) {
+        if let Some(spsr) = self.regs.spsr(self.cpsr.mode) {
+            self.cpu_write_cpsr(spsr);
+        }
+    }
+
+    fn arm_branch_ticks(&mut self) -> i32 {
+        let seq = self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32;
+        let non_seq = self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+
+        3 + non_seq + seq * 2
+    }
+
+    fn arm_operand(&mut self, opcode: u32) -> (u32, bool) {
+        if opcode & 0x02000000 != 0 {
+            let rotate = ((opcode >> 8) & 0xF) * 2;
+            let value = (opcode & 0xFF).rotate_right(rotate);
+
+            (value, if rotate == 0 { self.cpsr.c } else { value >> 31 != 0 })
+        } else {
+            let rm = (opcode & 0xF) as usize;
+            let shift_type = (opcode >> 5) & 3;
+
+            if opcode & 0x10 != 0 {
+                let amount = self.regs.r[((opcode >> 8) & 0xF) as usize] & 0xFF;
+                let mut value = self.regs.r[rm];
+                if rm == 15 {
+                    value += 4;
+                }
+
+                self.barrel_shift(shift_type, value, amount, false)
+            } else {
+                self.barrel_shift(shift_type, self.regs.r[rm], (opcode >> 7) & 0x1F, true)
+            }
+        }
+    }
+
+    fn arm_data_processing(&mut self, opcode: u32) -> i32 {
+        let set_flags = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let register_shift = opcode & 0x02000010 == 0x00000010;
+
+        let (operand, shifter_carry) = self.arm_operand(opcode);
+
+        let mut a = self.regs.r[rn];
+        if rn == 15 && register_shift {
+            a += 4;
+        }
+
+        let carry = self.cpsr.c;
+        let result = match (opcode >> 21) & 0xF {
+            0x0 | 0x8 => a & operand,
+            0x1 | 0x9 => a ^ operand,
+            0x2 | 0xA => self.alu_sub(a, operand, true, set_flags),
+            0x3 => self.alu_sub(operand, a, true, set_flags),
+            0x4 | 0xB => self.alu_add(a, operand, false, set_flags),
+            0x5 => self.alu_add(a, operand, carry, set_flags),
+            0x6 => self.alu_sub(a, operand, carry, set_flags),
+            0x7 => self.alu_sub(operand, a, carry, set_flags),
+            0xC => a | operand,
+            0xD => operand,
+            0xE => a & !operand,
+            _ => !operand,
+        };
+
+        let logical = matches!((opcode >> 21) & 0xF, 0x0 | 0x1 | 0x8 | 0x9 | 0xC | 0xD | 0xE | 0xF);
+
+        if set_flags && logical {
+            self.cpsr.set_nz(result);
+            self.cpsr.c = shifter_carry;
+        }
+
+        let mut clock_ticks = 1 + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32;
+        if register_shift {
+            clock_ticks += 1;
+        }
+
+        let test_only = (opcode >> 21) & 0xC == 0x8;
+        if !test_only {
+            self.regs.r[rd] = result;
+
+            if rd == 15 {
+                if set_flags {
+                    self.cpu_restore_cpsr();
+                }
+                self.cpu_jump();
+                clock_ticks = self.arm_branch_ticks();
+            }
+        }
+
+        clock_ticks
+    }
+
+    fn arm_mrs(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode >> 12) & 0xF) as usize;
+
+        // User and System have no SPSR; reading it there is unpredictable, give the CPSR
+        let value = if opcode & 0x00400000 != 0 {
+            self.regs.spsr(self.cpsr.mode).unwrap_or(self.cpsr).bits()
+        } else {
+            self.cpsr.bits()
+        };
+
+        self.regs.r[rd] = value;
+
+        1 + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32
+    }
+
+    fn arm_msr(&mut self, opcode: u32) -> i32 {
+        let value = if opcode & 0x02000000 != 0 {
+            (opcode & 0xFF).rotate_right(((opcode >> 8) & 0xF) * 2)
+        } else {
+            self.regs.r[(opcode & 0xF) as usize]
+        };
+
+        let mut mask = 0;
+        if opcode & 0x00010000 != 0 {
+            mask |= 0x000000FF;
+        }
+        if opcode & 0x00020000 != 0 {
+            mask |= 0x0000FF00;
+        }
+        if opcode & 0x00040000 != 0 {
+            mask |= 0x00FF0000;
+        }
+        if opcode & 0x00080000 != 0 {
+            mask |= 0xFF000000;
+        }
+
+        if opcode & 0x00400000 != 0 {
+            if let Some(spsr) = self.regs.spsr(self.cpsr.mode) {
+                match Psr::from_bits((spsr.bits() & !mask) | (value & mask)) {
+                    Ok(spsr) => self.regs.set_spsr(self.cpsr.mode, spsr),
+                    Err(e) => warn!("{}", e),
+                }
+            }
+        } else {
+            if !self.cpsr.mode.privileged() {
+                mask &= 0xFF000000;
+            }
+
+            let cpsr = self.cpsr.bits();
+            match Psr::from_bits((cpsr & !mask) | (value & mask) | 0x10) {
+                Ok(new_cpsr) => {
+                    // the T bit can not be changed through MSR
+                    self.cpu_write_cpsr(Psr {
+                        thumb: self.cpsr.thumb,
+                        ..new_cpsr
+                    });
+                },
+                Err(e) => warn!("{}", e),
+            }
+        }
+
+        1 + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32
+    }
+
+    fn arm_multiply(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode >> 16) & 0xF) as usize;
+        let rn = ((opcode >> 12) & 0xF) as usize;
+        let rs = self.regs.r[((opcode >> 8) & 0xF) as usize];
+        let rm = self.regs.r[(opcode & 0xF) as usize];
+
+        let mut clock_ticks = 1 + self.multiply_ticks(rs);
+        let mut result = rm.wrapping_mul(rs);
+        if opcode & 0x00200000 != 0 {
+            result = result.wrapping_add(self.regs.r[rn]);
+            clock_ticks += 1;
+        }
+
+        self.regs.r[rd] = result;
+        if opcode & 0x00100000 != 0 {
+            self.cpsr.set_nz(result);
+        }
+
+        clock_ticks + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32
+    }
+
+    fn arm_multiply_long(&mut self, opcode: u32) -> i32 {
+        let rd_hi = ((opcode >> 16) & 0xF) as usize;
+        let rd_lo = ((opcode >> 12) & 0xF) as usize;
+        let rs = self.regs.r[((opcode >> 8) & 0xF) as usize];
+        let rm = self.regs.r[(opcode & 0xF) as usize];
+
+        let mut clock_ticks = 2 + self.multiply_ticks(rs);
+        let mut result = if opcode & 0x00400000 != 0 {
+            (rm as i32 as i64).wrapping_mul(rs as i32 as i64) as u64
+        } else {
+            rm as u64 * rs as u64
+        };
+
+        if opcode & 0x00200000 != 0 {
+            let accumulate = ((self.regs.r[rd_hi] as u64) << 32) | self.regs.r[rd_lo] as u64;
+            result = result.wrapping_add(accumulate);
+            clock_ticks += 1;
+        }
+
+        self.regs.r[rd_lo] = result as u32;
+        self.regs.r[rd_hi] = (result >> 32) as u32;
+        if opcode & 0x00100000 != 0 {
+            self.cpsr.n = result & 0x8000000000000000 != 0;
+            self.cpsr.z = result == 0;
+        }
+
+        clock_ticks + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32
+    }
+
+    fn arm_swap(&mut self, opcode: u32) -> i32 {
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let rm = (opcode & 0xF) as usize;
+
+        let address = self.regs.r[rn];
+        let source = self.regs.r[rm];
+
+        let clock_ticks = if opcode & 0x00400000 != 0 {
+            let value = self.cpu_read_8(address);
+            self.cpu_write_8(address, source as u8);
+            self.regs.r[rd] = value;
+
+            4 + self.data_ticks_access_16(address as usize) as i32 * 2
+        } else {
+            let value = self.cpu_read_32(address);
+            self.cpu_write_32(address, source);
+            self.regs.r[rd] = value;
+
+            4 + self.data_ticks_access_32(address as usize) as i32 * 2
+        };
+
+        clock_ticks + self.code_ticks_access_32(self.arm_next_pc as usize) as i32
+    }
+
+    fn arm_halfword_transfer(&mut self, opcode: u32) -> i32 {
+        let pre = opcode & 0x01000000 != 0;
+        let up = opcode & 0x00800000 != 0;
+        let writeback = !pre || opcode & 0x00200000 != 0;
+        let load = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+
+        let offset = if opcode & 0x00400000 != 0 {
+            ((opcode >> 4) & 0xF0) | (opcode & 0xF)
+        } else {
+            self.regs.r[(opcode & 0xF) as usize]
+        };
+
+        let base = self.regs.r[rn];
+        let offset_address = if up {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let address = if pre { offset_address } else { base };
+
+        let mut clock_ticks = self.data_ticks_access_16(address as usize) as i32;
+
+        if load {
+            let value = match (opcode >> 5) & 3 {
+                1 => self.cpu_read_16(address),
+                2 => self.cpu_read_8(address) as u8 as i8 as u32,
+                _ => self.cpu_read_16_signed(address),
+            };
+
+            if writeback && rn != 15 {
+                self.regs.r[rn] = offset_address;
+            }
+            self.regs.r[rd] = value;
+
+            clock_ticks += 3 + self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+            if rd == 15 {
+                self.cpu_jump();
+                clock_ticks += self.arm_branch_ticks();
+            }
+        } else {
+            let mut value = self.regs.r[rd];
+            if rd == 15 {
+                value += 4;
+            }
+
+            self.cpu_write_16(address, value as u16);
+            if writeback && rn != 15 {
+                self.regs.r[rn] = offset_address;
+            }
+
+            clock_ticks += 2 + self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+        }
+
+        clock_ticks
+    }
+
+    fn arm_single_transfer(&mut self, opcode: u32) -> i32 {
+        let pre = opcode & 0x01000000 != 0;
+        let up = opcode & 0x00800000 != 0;
+        let byte = opcode & 0x00400000 != 0;
+        let writeback = !pre || opcode & 0x00200000 != 0;
+        let load = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+
+        let offset = if opcode & 0x02000000 != 0 {
+            let rm = self.regs.r[(opcode & 0xF) as usize];
+            self.barrel_shift((opcode >> 5) & 3, rm, (opcode >> 7) & 0x1F, true).0
+        } else {
+            opcode & 0xFFF
+        };
+
+        let base = self.regs.r[rn];
+        let offset_address = if up {
+            base.wrapping_add(offset)
+        } else {
+            base.wrapping_sub(offset)
+        };
+        let address = if pre { offset_address } else { base };
+
+        let mut clock_ticks = if byte {
+            self.data_ticks_access_16(address as usize) as i32
+        } else {
+            self.data_ticks_access_32(address as usize) as i32
+        };
+
+        if load {
+            let value = if byte {
+                self.cpu_read_8(address)
+            } else {
+                self.cpu_read_32(address)
+            };
+
+            if writeback && rn != 15 {
+                self.regs.r[rn] = offset_address;
+            }
+            self.regs.r[rd] = value;
+
+            clock_ticks += 3 + self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+            if rd == 15 {
+                self.cpu_jump();
+                clock_ticks += self.arm_branch_ticks();
+            }
+        } else {
+            let mut value = self.regs.r[rd];
+            if rd == 15 {
+                value += 4;
+            }
+
+            if byte {
+                self.cpu_write_8(address, value as u8);
+            } else {
+                self.cpu_write_32(address & !3, value);
+            }
+            if writeback && rn != 15 {
+                self.regs.r[rn] = offset_address;
+            }
+
+            clock_ticks += 2 + self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+        }
+
+        clock_ticks
+    }
+
+
+    fn arm_block_transfer(&mut self, opcode: u32) -> i32 {
+        let pre = opcode & 0x01000000 != 0;
+        let up = opcode & 0x00800000 != 0;
+        let s_bit = opcode & 0x00400000 != 0;
+        let writeback = opcode & 0x00200000 != 0;
+        let load = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+
+        // an empty list transfers r15 and moves the base as if all 16 registers were used
+        let (rlist, count) = match opcode & 0xFFFF {
+            0 => (0x8000, 16),
+            rlist => (rlist, self.cpu_bits_set[(rlist & 0xFF) as usize] as u32 + self.cpu_bits_set[(rlist >> 8) as usize] as u32),
+        };
+
+        let base = self.regs.r[rn];
+        let (mut address, new_base) = if up {
+            (base.wrapping_add(if pre { 4 } else { 0 }), base.wrapping_add(count * 4))
+        } else {
+            (base.wrapping_sub(count * 4).wrapping_add(if pre { 0 } else { 4 }), base.wrapping_sub(count * 4))
+        };
+
+        let user_bank = s_bit && !(load && rlist & 0x8000 != 0);
+        let mut clock_ticks = 0;
+        let mut first = true;
+
+        if load && writeback {
+            self.regs.r[rn] = new_base;
+        }
+
+        for reg in 0..16 {
+            if rlist & (1 << reg) == 0 {
+                continue;
+            }
+
+            clock_ticks += if first {
+                self.data_ticks_access_32(address as usize) as i32
+            } else {
+                self.data_ticks_access_seq_32(address as usize) as i32
+            };
+
+            // with the S bit and no r15 to load, the user bank registers are transferred
+            let mode = if user_bank { Mode::User } else { self.cpsr.mode };
+
+            if load {
+                let value = self.cpu_read_32(address & !3);
+                self.regs.set_in(self.cpsr.mode, mode, reg, value);
+            } else {
+                let value = if reg == rn && writeback && !first {
+                    new_base
+                } else if reg == 15 {
+                    self.regs.r[15] + 4
+                } else {
+                    self.regs.get_in(self.cpsr.mode, mode, reg)
+                };
+                self.cpu_write_32(address & !3, value);
+            }
+
+            address = address.wrapping_add(4);
+            first = false;
+        }
+
+        if !load && writeback {
+            self.regs.r[rn] = new_base;
+        }
+
+        clock_ticks += 2 + self.code_ticks_access_32(self.arm_next_pc as usize) as i32;
+
+        if load {
+            clock_ticks += 1;
+
+            if rlist & 0x8000 != 0 {
+                if s_bit {
+                    self.cpu_restore_cpsr();
+                }
+                self.cpu_jump();
+                clock_ticks += self.arm_branch_ticks();
+            }
+        }
+
+        clock_ticks
+    }
+
+    fn arm_branch(&mut self, opcode: u32) -> i32 {
+        let offset = (((opcode & 0x00FFFFFF) << 8) as i32 >> 6) as u32;
+        let pc = self.regs.r[15];
+
+        if opcode & 0x01000000 != 0 {
+            self.regs.r[14] = pc - 4;
+        }
+
+        self.regs.r[15] = pc.wrapping_add(offset);
+        self.cpu_jump();
+
+        self.arm_branch_ticks()
+    }
+
+    fn arm_branch_exchange(&mut self, opcode: u32) -> i32 {
+        let target = self.regs.r[(opcode & 0xF) as usize];
+
+        self.cpsr.thumb = target & 1 != 0;
+        self.regs.r[15] = target;
+        self.cpu_jump();
+
+        self.arm_branch_ticks()
+    }
+}
@@ -0,0 +1,98 @@
+use super::Cpu;
+use super::cache::BlockCache;
+use super::idle::IdleLoop;
+use super::registers::{Psr, Registers};
+use super::super::multiboot::{Received, Receiver};
+
+impl Cpu {
+    /// Starts without a program and waits for another instance to send a multiboot image
+    /// over the link, as the BIOS does when there is no cartridge. The image boots from EWRAM
+    /// as soon as it is through; a reset before then starts the wait over.
+    pub fn receive_multiboot(&mut self) {
+        self.cpu_wait_for_multiboot();
+    }
+
+    /// Sleeps with the serial port ready on the external clock, replying 0 to the first word.
+    pub(super) fn cpu_wait_for_multiboot(&mut self) {
+        self.multiboot_receiver = Some(Receiver::new());
+        self.cpu_update_register(0x134, 0x0000);
+        self.cpu_update_register(0x128, 0x1000);
+        self.cpu_multiboot_reply(0);
+        self.halt_state = true;
+    }
+
+    fn cpu_multiboot_reply(&mut self, reply: u32) {
+        self.cpu_update_register(0x120, reply as u16);
+        self.cpu_update_register(0x122, (reply >> 16) as u16);
+        self.cpu_update_register(0x128, 0x1080);
+    }
+
+    /// Called after every line's link sync. Once a transfer has cleared the busy bit, the word
+    /// it brought goes to the receiver and its answer goes out on the next one.
+    pub(super) fn cpu_receive_multiboot(&mut self) {
+        if self.mem_map.read_io_16(0x128) & 0x0080 != 0 {
+            return;
+        }
+
+        let word = self.mem_map.read_io_16(0x120) as u32 | (self.mem_map.read_io_16(0x122) as u32) << 16;
+        let received = match self.multiboot_receiver {
+            Some(ref mut receiver) => receiver.receive(word),
+            None => return,
+        };
+
+        match received {
+            Received::Reply(reply) => self.cpu_multiboot_reply(reply),
+            Received::Image(image) => self.cpu_boot_multiboot(&image),
+            Received::Failed(reason) => {
+                warn!("multiboot transfer failed, waiting for another: {}", reason);
+                self.cpu_wait_for_multiboot();
+            },
+        }
+    }
+
+    /// Jumps to a received image the way the BIOS would, without the rest of a reset: the
+    /// scanline and the serial port carry on where they are. An image that will not load
+    /// starts the wait over.
+    fn cpu_boot_multiboot(&mut self, image: &[u8]) {
+        self.multiboot_receiver = None;
+
+        if let Err(e) = self.load_multiboot(image) {
+            warn!("multiboot image not loaded, waiting for another: {}", e);
+            self.cpu_wait_for_multiboot();
+            return;
+        }
+        self.mem_map.copy_multiboot();
+        info!("multiboot: received {} bytes, booting from EWRAM", image.len());
+
+        self.regs = Registers::new();
+        self.cpsr = Psr {
+            fiq_disabled: true,
+            ..Psr::default()
+        };
+        self.cpu_skip_bios(0x02000000);
+
+        self.halt_state = false;
+        self.idle_loop = IdleLoop::new();
+        self.block_cache = BlockCache::new();
+
+        self.arm_next_pc = self.regs.r[15];
+        self.regs.r[15] += 4;
+        self.arm_prefetch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn unloadable_image_waits_for_another() {
+        let mut cpu = super::super::tests::program_cpu(&[0xEAFFFFFE]);
+        cpu.receive_multiboot();
+
+        cpu.cpu_boot_multiboot(&[]);
+        assert!(cpu.multiboot_receiver.is_some());
+        assert!(cpu.halt_state);
+        // ready for the next transfer on the external clock, with 0 to send back
+        assert_eq!(cpu.mem_map.read_io_16(0x128), 0x1080);
+        assert_eq!(cpu.mem_map.read_io_16(0x120), 0);
+    }
+}
@@ -0,0 +1,282 @@
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::rc::Rc;
+
+use super::{Cpu, Handler};
+#[cfg(feature = "jit")]
+use super::jit::NativeSlot;
+
+/// Longest run of instructions decoded into one block.
+const BLOCK_MAX_INSTRUCTIONS: u32 = 64;
+
+/// Blocks in RAM never cross one of these, so a write only has one page of blocks to drop.
+const PAGE_SHIFT: u32 = 8;
+const EWRAM_PAGES: usize = 0x40000 >> PAGE_SHIFT;
+const IWRAM_PAGES: usize = 0x8000 >> PAGE_SHIFT;
+
+/// A straight run of pre-decoded instructions starting at `start`.
+pub(super) struct Block {
+    pub(super) start: u32,
+    pub(super) thumb: bool,
+    page: Option<usize>,
+    pub(super) instructions: Vec<(u32, Handler)>,
+    /// Cleared when a write drops the block, which may happen while it runs.
+    pub(super) valid: Cell<bool>,
+    /// Times the JIT has run the block, and its machine code once it is hot.
+    #[cfg(feature = "jit")]
+    pub(super) native: NativeSlot,
+}
+
+/// Block keys are addresses, so a multiply is hash enough and far cheaper than SipHash.
+#[derive(Default)]
+struct AddressHasher(u64);
+
+impl Hasher for AddressHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 << 8 | byte as u64).wrapping_mul(0x9E3779B97F4A7C15);
+        }
+    }
+
+    fn write_u32(&mut self, value: u32) {
+        self.0 = (value as u64).wrapping_mul(0x9E3779B97F4A7C15);
+    }
+
+    fn finish(&self) -> u64 {
+        // the low bits pick the bucket, and aligned addresses leave them zero after the multiply
+        self.0 ^ self.0 >> 32
+    }
+}
+
+/// Decoded blocks of ROM, IWRAM and EWRAM code, keyed by address and ARM/Thumb state. Blocks
+/// in RAM are dropped as soon as anything writes to their page.
+pub struct BlockCache {
+    blocks: HashMap<u32, Rc<Block>, BuildHasherDefault<AddressHasher>>,
+    /// Keys of the blocks in each RAM page.
+    pages: Vec<Vec<u32>>,
+}
+
+/// Page of EWRAM or IWRAM an address falls in, mirrors included. `None` elsewhere.
+fn ram_page(address: u32) -> Option<usize> {
+    match address >> 24 {
+        0x02 => Some(((address & 0x3FFFF) >> PAGE_SHIFT) as usize),
+        0x03 => Some(EWRAM_PAGES + ((address & 0x7FFF) >> PAGE_SHIFT) as usize),
+        _ => None,
+    }
+}
+
+fn cacheable(address: u32) -> bool {
+    matches!(address >> 24, 0x02 | 0x03 | 0x08..=0x0D)
+}
+
+fn block_key(address: u32, thumb: bool) -> u32 {
+    address | thumb as u32
+}
+
+impl BlockCache {
+    pub fn new() -> BlockCache {
+        BlockCache {
+            blocks: HashMap::default(),
+            pages: vec!(vec!(); EWRAM_PAGES + IWRAM_PAGES),
+        }
+    }
+
+    /// Drops every block whose code a write to `address` may have changed.
+    pub fn invalidate(&mut self, address: u32) {
+        let page = match ram_page(address) {
+            Some(page) if !self.pages[page].is_empty() => page,
+            _ => return,
+        };
+
+        for key in self.pages[page].drain(..) {
+            if let Some(block) = self.blocks.remove(&key) {
+                block.valid.set(false);
+            }
+        }
+    }
+
+    fn get(&self, address: u32, thumb: bool) -> Option<Rc<Block>> {
+        self.blocks.get(&block_key(address, thumb)).cloned()
+    }
+
+    fn insert(&mut self, block: Block) -> Rc<Block> {
+        let key = block_key(block.start, block.thumb);
+
+        if let Some(page) = block.page {
+            self.pages[page].push(key);
+        }
+
+        let block = Rc::new(block);
+        self.blocks.insert(key, block.clone());
+        block
+    }
+}
+
+impl Default for BlockCache {
+    fn default() -> BlockCache {
+        BlockCache::new()
+    }
+}
+
+impl Cpu {
+    /// Runs the block at `arm_next_pc` until it ends, something jumps out of it or the next
+    /// event is due. Code outside ROM and RAM is interpreted one instruction at a time.
+    pub(super) fn cpu_run_block(&mut self) {
+        match self.cpu_lookup_block() {
+            Some(block) => self.cpu_run_decoded(&block),
+            None => self.cpu_step(),
+        }
+    }
+
+    /// The block at `arm_next_pc`, decoding it on first use. `None` outside ROM and RAM.
+    pub(super) fn cpu_lookup_block(&mut self) -> Option<Rc<Block>> {
+        let thumb = self.cpsr.thumb;
+        let address = self.arm_next_pc;
+
+        if !cacheable(address) {
+            return None;
+        }
+
+        match self.block_cache.get(address, thumb) {
+            Some(block) => Some(block),
+            None => {
+                let block = self.cpu_decode_block(address, thumb);
+                Some(self.block_cache.insert(block))
+            },
+        }
+    }
+
+    /// Replays the handlers of a block that starts at `arm_next_pc`.
+    pub(super) fn cpu_run_decoded(&mut self, block: &Block) {
+        let thumb = block.thumb;
+        let width = if thumb { 2 } else { 4 };
+
+        for (index, &(cached, handler)) in block.instructions.iter().enumerate() {
+            let pc = self.arm_next_pc;
+            if pc != block.start.wrapping_add(index as u32 * width) || self.cpsr.thumb != thumb || !block.valid.get() {
+                return;
+            }
+
+            // the block matches memory, so it can stand in for the pipeline's next fetch
+            let prefetched = block.instructions.get(index + 2).map(|&(opcode, _)| opcode);
+
+            let ticks = if thumb {
+                let opcode = self.thumb_fetch(prefetched);
+
+                // the pipeline may hold an opcode fetched before its memory was rewritten
+                if opcode == cached {
+                    handler(self, opcode)
+                } else {
+                    Cpu::thumb_decode(opcode)(self, opcode)
+                }
+            } else {
+                let opcode = self.arm_fetch(prefetched);
+
+                if !self.cpsr.condition(opcode >> 28) {
+                    1 + self.code_ticks_access_seq_32(self.arm_next_pc as usize) as i32
+                } else if opcode == cached {
+                    handler(self, opcode)
+                } else {
+                    Cpu::arm_decode(opcode)(self, opcode)
+                }
+            };
+
+            self.cpu_total_ticks += ticks;
+            self.profile.instructions += 1;
+
+            if self.idle_skip {
+                self.cpu_idle_branch(pc);
+            }
+
+            if self.cpu_total_ticks >= self.cpu_next_event {
+                return;
+            }
+        }
+    }
+
+    /// Decodes from `start` up to the first unconditional branch, the end of a RAM page or
+    /// `BLOCK_MAX_INSTRUCTIONS`, whichever comes first.
+    fn cpu_decode_block(&self, start: u32, thumb: bool) -> Block {
+        let page = ram_page(start);
+        let width = if thumb { 2 } else { 4 };
+        let mut instructions = vec!();
+        let mut address = start;
+
+        loop {
+            let (opcode, handler, ends_block) = if thumb {
+                let opcode = self.mem_map.read_16(address, self.bios_protected, address) as u32;
+                // b, bx, swi and the second half of bl
+                let ends_block = opcode & 0xF800 == 0xE000 || opcode & 0xFF00 == 0x4700 || opcode & 0xFF00 == 0xDF00 || opcode & 0xF800 == 0xF800;
+                (opcode, Cpu::thumb_decode(opcode), ends_block)
+            } else {
+                let opcode = self.mem_map.read_32(address, self.bios_protected, address);
+                // unconditional b, bl, bx and swi
+                let ends_block = opcode >> 28 == 0xE && (opcode & 0x0E000000 == 0x0A000000 || opcode & 0x0FFFFFF0 == 0x012FFF10 || opcode & 0x0F000000 == 0x0F000000);
+                (opcode, Cpu::arm_decode(opcode), ends_block)
+            };
+
+            instructions.push((opcode, handler));
+            address = address.wrapping_add(width);
+
+            if ends_block || instructions.len() as u32 == BLOCK_MAX_INSTRUCTIONS || !cacheable(address) || (page.is_some() && ram_page(address) != page) {
+                break;
+            }
+        }
+
+        Block {
+            start,
+            thumb,
+            page,
+            instructions,
+            valid: Cell::new(true),
+            #[cfg(feature = "jit")]
+            native: NativeSlot::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Backend;
+    use super::super::tests::program_cpu;
+
+    /// Flips the `add r6` in its own loop between adding 1 and 0x10 on every pass, writing
+    /// through the IWRAM mirror at 0x03008000. The add is the third instruction of the loop's
+    /// block, so only dropping the block gets the new one fetched.
+    const SELF_MODIFYING: [u32; 10] = [
+        0xE3A04403, // mov r4, #0x03000000
+        0xE2847902, // add r7, r4, #0x8000
+        0xE5941018, // ldr r1, [r4, #0x18]
+        0xE3A02011, // mov r2, #0x11
+        0xE2855001, // add r5, r5, #1
+        0xE2888002, // add r8, r8, #2
+        0xE2866001, // add r6, r6, #1
+        0xE0211002, // eor r1, r1, r2
+        0xE5871018, // str r1, [r7, #0x18]
+        0xEAFFFFF9, // b 0x03000010
+    ];
+
+    fn run(backend: Backend) -> (u64, u32, u32) {
+        let mut cpu = program_cpu(&SELF_MODIFYING);
+        cpu.set_backend(backend);
+
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+        (cpu.hash_state(), cpu.regs.r[5], cpu.regs.r[6])
+    }
+
+    #[test]
+    fn self_modifying_code_matches_the_interpreter() {
+        let (expected, passes, sum) = run(Backend::Interpreter);
+        let adds = |passes: u32| passes.div_ceil(2) + passes / 2 * 0x10;
+        // the frame may end between counting a pass and its add
+        assert!(passes > 1);
+        assert!(sum == adds(passes) || sum == adds(passes - 1));
+
+        assert_eq!(run(Backend::Cached), (expected, passes, sum));
+        #[cfg(feature = "jit")]
+        assert_eq!(run(Backend::Jit), (expected, passes, sum));
+    }
+}
@@ -0,0 +1,140 @@
+use super::super::error::Error;
+use super::Cpu;
+use super::registers::Psr;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    Access,
+}
+
+/// Traps data accesses that touch `address..address + length`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub address: u32,
+    pub length: u32,
+    pub kind: WatchKind,
+}
+
+/// The first watched access made by the last instruction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatchHit {
+    pub watchpoint: Watchpoint,
+    pub address: u32,
+}
+
+impl Watchpoint {
+    fn matches(&self, address: u32, size: u32, write: bool) -> bool {
+        let kind = match self.kind {
+            WatchKind::Read => !write,
+            WatchKind::Write => write,
+            WatchKind::Access => true,
+        };
+
+        kind && address < self.address.wrapping_add(self.length) && self.address < address.wrapping_add(size)
+    }
+}
+
+impl Cpu {
+    /// Address of the next instruction to execute.
+    pub fn pc(&self) -> u32 {
+        self.arm_next_pc
+    }
+
+    /// The opcode at `pc()`, as already fetched into the pipeline.
+    pub fn opcode(&self) -> u32 {
+        self.cpu_prefetch[0]
+    }
+
+    pub fn thumb_state(&self) -> bool {
+        self.cpsr.thumb
+    }
+
+    /// r0-r15 as the current mode sees them, with r15 reading as `pc()`.
+    pub fn reg(&self, index: usize) -> u32 {
+        if index == 15 {
+            self.arm_next_pc
+        } else {
+            self.regs.r[index]
+        }
+    }
+
+    /// Writing r15 jumps there and refills the pipeline.
+    pub fn set_reg(&mut self, index: usize, value: u32) {
+        self.regs.r[index] = value;
+
+        if index == 15 {
+            self.cpu_jump();
+        }
+    }
+
+    pub fn cpsr(&self) -> u32 {
+        self.cpsr.bits()
+    }
+
+    /// Replaces the CPSR, switching register banks and ARM/Thumb state as needed. A mode
+    /// that does not exist is refused and leaves the CPU untouched.
+    pub fn set_cpsr(&mut self, value: u32) -> Result<(), Error> {
+        let psr = Psr::from_bits(value)?;
+        let was_thumb = self.cpsr.thumb;
+
+        self.cpu_write_cpsr(psr);
+
+        if psr.thumb != was_thumb {
+            self.regs.r[15] = self.arm_next_pc;
+            self.cpu_jump();
+        }
+
+        Ok(())
+    }
+
+    /// Reads memory the way the CPU would, without tripping watchpoints.
+    pub fn debug_read_8(&self, address: u32) -> u8 {
+        match self.cpu_read_timer(address) {
+            Some(word) => (word >> ((address & 3) * 8)) as u8,
+            None => self.mem_map.read_8(address, self.bios_protected, self.regs.r[15]),
+        }
+    }
+
+    /// Writes memory through the bus, so IO registers react as they would to the program.
+    pub fn debug_write_8(&mut self, address: u32, value: u8) {
+        let watchpoints = ::std::mem::take(&mut self.watchpoints);
+        self.cpu_write_8(address, value);
+        self.watchpoints = watchpoints;
+
+        // the pipeline may hold the old opcodes
+        self.regs.r[15] = self.arm_next_pc;
+        self.cpu_jump();
+    }
+
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) {
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    pub fn remove_watchpoint(&mut self, watchpoint: Watchpoint) -> bool {
+        let len = self.watchpoints.len();
+        self.watchpoints.retain(|w| *w != watchpoint);
+        self.watchpoints.len() != len
+    }
+
+    /// Returns and clears the watchpoint hit by the instructions since the last call.
+    pub fn take_watch_hit(&mut self) -> Option<WatchHit> {
+        self.watch_hit.take()
+    }
+
+    pub(super) fn cpu_check_watchpoints(&self, address: u32, size: u32, write: bool) {
+        if self.watch_hit.get().is_some() {
+            return;
+        }
+
+        if let Some(watchpoint) = self.watchpoints.iter().find(|w| w.matches(address, size, write)) {
+            self.watch_hit.set(Some(WatchHit {
+                watchpoint: *watchpoint,
+                address,
+            }));
+        }
+    }
+}
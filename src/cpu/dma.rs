@@ -0,0 +1,231 @@
+use super::Cpu;
+
+pub(super) const DMA_IMMEDIATE: u16 = 0;
+pub(super) const DMA_VBLANK: u16 = 1;
+pub(super) const DMA_HBLANK: u16 = 2;
+const DMA_SPECIAL: u16 = 3;
+
+const DMA_SOURCE_MASK: [u32; 4] = [0x07FFFFFF, 0x0FFFFFFF, 0x0FFFFFFF, 0x0FFFFFFF];
+const DMA_DEST_MASK: [u32; 4] = [0x07FFFFFF, 0x07FFFFFF, 0x07FFFFFF, 0x0FFFFFFF];
+const DMA_COUNT_MASK: [u32; 4] = [0x3FFF, 0x3FFF, 0x3FFF, 0xFFFF];
+
+/// IO offset of DMAxSAD; DAD, CNT_L and CNT_H follow it.
+fn dma_base(ch: usize) -> u32 {
+    0x0B0 + 12 * ch as u32
+}
+
+impl Cpu {
+    /// Handles a write to DMAxCNT_H. Turning a channel on latches the source and destination,
+    /// and an immediate channel transfers straight away.
+    pub(super) fn cpu_update_dma_control(&mut self, ch: usize, value: u16) {
+        let base = dma_base(ch);
+        let old = self.mem_map.read_io_16(base + 10);
+        let value = value & if ch == 3 { 0xFFE0 } else { 0xF7E0 };
+
+        self.mem_map.write_io_16(base + 10, value);
+
+        if value & 0x8000 != 0 && old & 0x8000 == 0 {
+            self.dma_source[ch] = self.dma_io_32(base) & DMA_SOURCE_MASK[ch];
+            self.dma_dest[ch] = self.dma_io_32(base + 4) & DMA_DEST_MASK[ch];
+
+            if (value >> 12) & 3 == DMA_IMMEDIATE {
+                self.cpu_dma_transfer(ch);
+            }
+        }
+    }
+
+    /// Starts every enabled channel waiting on `timing` (VBlank or HBlank).
+    pub(super) fn cpu_dma_trigger(&mut self, timing: u16) {
+        for ch in 0..4 {
+            let control = self.mem_map.read_io_16(dma_base(ch) + 10);
+
+            if control & 0x8000 != 0 && (control >> 12) & 3 == timing {
+                self.cpu_dma_transfer(ch);
+            }
+        }
+    }
+
+    /// A Direct Sound FIFO ran low; DMA1 or DMA2 refills it if set up in sound mode for it.
+    pub(super) fn cpu_dma_fifo(&mut self, fifo: u32) {
+        for ch in 1..3 {
+            let control = self.mem_map.read_io_16(dma_base(ch) + 10);
+
+            if control & 0x8000 != 0 && (control >> 12) & 3 == DMA_SPECIAL && self.dma_dest[ch] == fifo {
+                self.cpu_dma_transfer(ch);
+            }
+        }
+    }
+
+    fn dma_io_32(&self, address: u32) -> u32 {
+        self.mem_map.read_io_16(address) as u32 | (self.mem_map.read_io_16(address + 2) as u32) << 16
+    }
+
+    fn cpu_dma_transfer(&mut self, ch: usize) {
+        let start = self.profile_start();
+        let base = dma_base(ch);
+        let control = self.mem_map.read_io_16(base + 10);
+        let timing = (control >> 12) & 3;
+        let sound = timing == DMA_SPECIAL && (ch == 1 || ch == 2);
+
+        let mut count = self.mem_map.read_io_16(base + 8) as u32 & DMA_COUNT_MASK[ch];
+        if count == 0 {
+            count = DMA_COUNT_MASK[ch] + 1;
+        }
+
+        let mut transfer_32 = control & 0x0400 != 0;
+        let mut dest_control = (control >> 5) & 3;
+
+        // sound DMA always moves four words into the fixed FIFO address
+        if sound {
+            count = 4;
+            transfer_32 = true;
+            dest_control = 2;
+        }
+
+        let size = if transfer_32 { 4 } else { 2 };
+        let source_step = match (control >> 7) & 3 {
+            1 => -size,
+            2 => 0,
+            _ => size,
+        };
+        let dest_step = match dest_control {
+            1 => -size,
+            2 => 0,
+            _ => size,
+        };
+
+        let mut source = self.dma_source[ch];
+        let mut dest = self.dma_dest[ch];
+        let mut ticks = 0;
+
+        for i in 0..count {
+            if self.profiling {
+                self.profile.count_read(source);
+            }
+
+            if transfer_32 {
+                let value = self.mem_map.read_32(source & !3, self.bios_protected, self.regs.r[15]);
+                self.cpu_write_32(dest & !3, value);
+
+                let access = if i == 0 {
+                    self.data_ticks_access_32(source as usize) + self.data_ticks_access_32(dest as usize)
+                } else {
+                    self.data_ticks_access_seq_32(source as usize) + self.data_ticks_access_seq_32(dest as usize)
+                };
+                ticks += access as i32 + 2;
+            } else {
+                let value = self.mem_map.read_16(source & !1, self.bios_protected, self.regs.r[15]);
+                self.cpu_write_16(dest & !1, value);
+
+                let access = if i == 0 {
+                    self.data_ticks_access_16(source as usize) + self.data_ticks_access_16(dest as usize)
+                } else {
+                    self.data_ticks_access_seq_16(source as usize) + self.data_ticks_access_seq_16(dest as usize)
+                };
+                ticks += access as i32 + 2;
+            }
+
+            source = source.wrapping_add(source_step as u32);
+            dest = dest.wrapping_add(dest_step as u32);
+        }
+
+        self.dma_source[ch] = source;
+        self.dma_dest[ch] = dest;
+
+        if control & 0x4000 != 0 {
+            self.cpu_raise_interrupt(0x0100 << ch);
+        }
+
+        if control & 0x0200 != 0 && timing != DMA_IMMEDIATE {
+            if dest_control == 3 {
+                self.dma_dest[ch] = self.dma_io_32(base + 4) & DMA_DEST_MASK[ch];
+            }
+        } else {
+            self.mem_map.write_io_16(base + 10, control & 0x7FFF);
+        }
+
+        self.cpu_total_ticks += ticks;
+
+        if let Some(start) = start {
+            self.profile.count_dma(count, start);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::apu::{FIFO_A, FIFO_B};
+    use super::super::Cpu;
+
+    /// Sound on, FIFO A played by timer 0 overflowing every tick, and DMA1 set to refill
+    /// it from EWRAM.
+    fn sound_dma() -> Cpu {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+
+        cpu.cpu_update_register(0x084, 0x0080);
+        cpu.cpu_update_register(0x082, 0x0300);
+        cpu.cpu_update_register(0x100, 0xFFFF);
+        cpu.cpu_update_register(0x102, 0x0080);
+
+        cpu.cpu_update_register(0x0BC, 0x0000);
+        cpu.cpu_update_register(0x0BE, 0x0200);
+        cpu.cpu_update_register(0x0C0, FIFO_A as u16);
+        cpu.cpu_update_register(0x0C2, (FIFO_A >> 16) as u16);
+        // a count the sound DMA ignores
+        cpu.cpu_update_register(0x0C4, 0x0001);
+        // enabled, sound timing, repeating, 16-bit, incrementing destination
+        cpu.cpu_update_register(0x0C6, 0xB200);
+        cpu
+    }
+
+    #[test]
+    fn low_fifo_requests_four_words() {
+        let mut cpu = sound_dma();
+        assert_eq!((cpu.dma_source[1], cpu.dma_dest[1]), (0x02000000, FIFO_A));
+
+        // the first overflow finds the FIFO empty and refills it with four words to the
+        // same address; the next pop leaves 15 bytes, so it refills again
+        cpu.cpu_timers_tick(1);
+        assert_eq!((cpu.dma_source[1], cpu.dma_dest[1]), (0x02000010, FIFO_A));
+        cpu.cpu_timers_tick(1);
+        assert_eq!(cpu.dma_source[1], 0x02000020);
+
+        // 31 bytes: the next 14 pops stay above four words
+        cpu.cpu_timers_tick(14);
+        assert_eq!(cpu.dma_source[1], 0x02000020);
+        cpu.cpu_timers_tick(1);
+        assert_eq!(cpu.dma_source[1], 0x02000030);
+
+        // repeating sound DMA stays enabled
+        assert_eq!(cpu.mem_map.read_io_16(0x0C6), 0xB200);
+    }
+
+    #[test]
+    fn fifo_requests_go_to_the_matching_channel() {
+        let mut cpu = sound_dma();
+
+        // DMA2 refills B only, and DMA3 has no sound mode; B plays on timer 1, which is
+        // not running
+        cpu.cpu_update_register(0x082, 0x4300);
+        cpu.cpu_update_register(0x0C8, 0x0000);
+        cpu.cpu_update_register(0x0CA, 0x0300);
+        cpu.cpu_update_register(0x0CC, FIFO_B as u16);
+        cpu.cpu_update_register(0x0CE, (FIFO_B >> 16) as u16);
+        cpu.cpu_update_register(0x0D2, 0xB600);
+        cpu.cpu_update_register(0x0D4, 0x0000);
+        cpu.cpu_update_register(0x0D6, 0x0300);
+        cpu.cpu_update_register(0x0D8, FIFO_A as u16);
+        cpu.cpu_update_register(0x0DA, (FIFO_A >> 16) as u16);
+        cpu.cpu_update_register(0x0DE, 0xB600);
+
+        cpu.cpu_timers_tick(1);
+        assert_eq!(cpu.dma_source[1], 0x02000010);
+        assert_eq!(cpu.dma_source[2], 0x03000000);
+        assert_eq!(cpu.dma_source[3], 0x03000000);
+
+        cpu.cpu_dma_fifo(FIFO_B);
+        assert_eq!(cpu.dma_source[2], 0x03000010);
+        assert_eq!(cpu.dma_dest[2], FIFO_B);
+    }
+}
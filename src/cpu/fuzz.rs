@@ -0,0 +1,1497 @@
+//! Differential testing of the interpreter. Random opcodes are run from random register
+//! states on both `Cpu` and `Model`, a deliberately plain ARMv4T interpreter written from the
+//! architecture manual, and every difference is shrunk to a small reproducer.
+//!
+//! `GBA_FUZZ_SEED` and `GBA_FUZZ_CASES` override the seed and the number of cases per
+//! instruction set, for longer runs than the default.
+
+use std::env;
+use std::fmt::Write;
+
+use super::super::disasm::{disasm_arm, disasm_thumb};
+use super::super::mem_map::WRAM_SIZE;
+use super::Cpu;
+use super::registers::{Mode, Psr, Registers};
+
+/// A window of IWRAM holding both the instruction and everything it may load or store.
+const WINDOW: u32 = 0x03000000;
+const WINDOW_SIZE: usize = 0x1000;
+const CODE: u32 = WINDOW + 0x800;
+
+const DEFAULT_SEED: u64 = 0x9E3779B97F4A7C15;
+const DEFAULT_CASES: u32 = 20000;
+
+/// Reproducers reported per run; the rest are counted.
+const MAX_REPORTS: usize = 8;
+
+const USR: u32 = 0x10;
+const FIQ: u32 = 0x11;
+const IRQ: u32 = 0x12;
+const SVC: u32 = 0x13;
+const ABT: u32 = 0x17;
+const UND: u32 = 0x1B;
+const SYS: u32 = 0x1F;
+const MODES: [u32; 7] = [USR, FIQ, IRQ, SVC, ABT, UND, SYS];
+
+/// Only the bits the CPU implements are compared: flags, I, F, T and mode.
+const PSR_MASK: u32 = 0xF00000FF;
+
+const N: u32 = 0x80000000;
+const Z: u32 = 0x40000000;
+const C: u32 = 0x20000000;
+const V: u32 = 0x10000000;
+const T: u32 = 0x20;
+const I: u32 = 0x80;
+
+/// xorshift64*, so the cases are the same on every platform.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545F4914F6CDD1D)
+    }
+
+    fn u32(&mut self) -> u32 {
+        (self.next() >> 32) as u32
+    }
+
+    fn below(&mut self, limit: u32) -> u32 {
+        self.u32() % limit
+    }
+
+    fn chance(&mut self, percent: u32) -> bool {
+        self.below(100) < percent
+    }
+}
+
+/// The encoding is unpredictable or reaches outside the window, so it is not compared.
+struct Skip;
+
+#[derive(Clone, PartialEq)]
+struct Model {
+    /// r0-r15 of user and system mode; r15 is unused, see `pc`.
+    usr: [u32; 16],
+    /// r8-r14 of FIQ mode.
+    fiq: [u32; 7],
+    /// r13-r14 of IRQ, SVC, ABT and UND mode, in that order.
+    banked: [[u32; 2]; 4],
+    /// SPSR of FIQ, IRQ, SVC, ABT and UND mode, in that order.
+    spsr: [u32; 5],
+    cpsr: u32,
+    /// Address of the instruction to execute.
+    pc: u32,
+    memory: Vec<u8>,
+}
+
+fn banked_index(mode: u32) -> Option<usize> {
+    match mode {
+        IRQ => Some(0),
+        SVC => Some(1),
+        ABT => Some(2),
+        UND => Some(3),
+        _ => None,
+    }
+}
+
+fn spsr_index(mode: u32) -> Option<usize> {
+    match mode {
+        FIQ => Some(0),
+        _ => banked_index(mode).map(|index| index + 1),
+    }
+}
+
+fn mode_name(mode: u32) -> &'static str {
+    match mode {
+        USR => "usr",
+        FIQ => "fiq",
+        IRQ => "irq",
+        SVC => "svc",
+        ABT => "abt",
+        UND => "und",
+        _ => "sys",
+    }
+}
+
+fn add_flags(a: u32, b: u32, carry_in: bool) -> (u32, bool, bool) {
+    let wide = a as u64 + b as u64 + carry_in as u64;
+    let result = wide as u32;
+    let overflow = (a ^ result) & (b ^ result) & 0x80000000 != 0;
+    (result, wide > 0xFFFFFFFF, overflow)
+}
+
+fn sub_flags(a: u32, b: u32, carry_in: bool) -> (u32, bool, bool) {
+    // a - b - !carry is a + !b + carry
+    add_flags(a, !b, carry_in)
+}
+
+fn sign_extend(value: u32, bits: u32) -> u32 {
+    ((value << (32 - bits)) as i32 >> (32 - bits)) as u32
+}
+
+impl Model {
+    fn random(rng: &mut Rng, thumb: bool) -> Model {
+        let mut usr = [0; 16];
+        for value in usr.iter_mut().take(15) {
+            *value = rng.u32();
+        }
+
+        let mut fiq = [0; 7];
+        for value in fiq.iter_mut() {
+            *value = rng.u32();
+        }
+
+        let mut banked = [[0; 2]; 4];
+        for bank in banked.iter_mut() {
+            *bank = [rng.u32(), rng.u32()];
+        }
+
+        let mut spsr = [0; 5];
+        for value in spsr.iter_mut() {
+            *value = rng.u32() & 0xF00000C0 | if rng.chance(10) { T } else { 0 } | MODES[rng.below(7) as usize];
+        }
+
+        let mode = MODES[rng.below(7) as usize];
+        let cpsr = rng.u32() & 0xF00000C0 | if thumb { T } else { 0 } | mode;
+
+        let mut memory = vec!(0; WINDOW_SIZE);
+        for byte in memory.iter_mut() {
+            *byte = rng.u32() as u8;
+        }
+
+        Model {
+            usr,
+            fiq,
+            banked,
+            spsr,
+            cpsr,
+            pc: CODE,
+            memory,
+        }
+    }
+
+    fn mode(&self) -> u32 {
+        self.cpsr & 0x1F
+    }
+
+    fn thumb(&self) -> bool {
+        self.cpsr & T != 0
+    }
+
+    fn flag(&self, flag: u32) -> bool {
+        self.cpsr & flag != 0
+    }
+
+    fn set_flag(&mut self, flag: u32, value: bool) {
+        if value {
+            self.cpsr |= flag;
+        } else {
+            self.cpsr &= !flag;
+        }
+    }
+
+    fn set_nz(&mut self, value: u32) {
+        self.set_flag(N, value & 0x80000000 != 0);
+        self.set_flag(Z, value == 0);
+    }
+
+    fn reg_in(&self, mode: u32, index: usize) -> u32 {
+        match (index, mode) {
+            (8..=14, FIQ) => self.fiq[index - 8],
+            (13 | 14, _) if banked_index(mode).is_some() => self.banked[banked_index(mode).unwrap()][index - 13],
+            _ => self.usr[index],
+        }
+    }
+
+    fn set_reg_in(&mut self, mode: u32, index: usize, value: u32) {
+        match (index, mode) {
+            (8..=14, FIQ) => self.fiq[index - 8] = value,
+            (13 | 14, _) if banked_index(mode).is_some() => self.banked[banked_index(mode).unwrap()][index - 13] = value,
+            _ => self.usr[index] = value,
+        }
+    }
+
+    /// A register as an operand. `pc` already points at the next instruction while one
+    /// executes, so r15 reads one instruction further.
+    fn reg(&self, index: usize) -> u32 {
+        if index == 15 {
+            self.pc.wrapping_add(if self.thumb() { 2 } else { 4 })
+        } else {
+            self.reg_in(self.mode(), index)
+        }
+    }
+
+    fn set_reg(&mut self, index: usize, value: u32) {
+        let mode = self.mode();
+        self.set_reg_in(mode, index, value);
+    }
+
+    fn spsr(&self) -> Result<u32, Skip> {
+        spsr_index(self.mode()).map(|index| self.spsr[index]).ok_or(Skip)
+    }
+
+    /// Replaces the whole CPSR, which may change mode; invalid modes are unpredictable.
+    fn set_cpsr(&mut self, value: u32) -> Result<(), Skip> {
+        if !MODES.contains(&(value & 0x1F)) {
+            return Err(Skip);
+        }
+
+        self.cpsr = value;
+        Ok(())
+    }
+
+    fn offset(&self, address: u32, size: usize) -> Result<usize, Skip> {
+        let offset = address.wrapping_sub(WINDOW) as usize;
+
+        if offset + size <= WINDOW_SIZE {
+            Ok(offset)
+        } else {
+            Err(Skip)
+        }
+    }
+
+    fn read_8(&self, address: u32) -> Result<u32, Skip> {
+        Ok(self.memory[self.offset(address, 1)?] as u32)
+    }
+
+    fn read_16(&self, address: u32) -> Result<u32, Skip> {
+        let offset = self.offset(address & !1, 2)?;
+        Ok(self.memory[offset] as u32 | (self.memory[offset + 1] as u32) << 8)
+    }
+
+    fn read_32(&self, address: u32) -> Result<u32, Skip> {
+        let offset = self.offset(address & !3, 4)?;
+        Ok((0..4).fold(0, |value, i| value | (self.memory[offset + i] as u32) << (i * 8)))
+    }
+
+    fn write_8(&mut self, address: u32, value: u32) -> Result<(), Skip> {
+        let offset = self.offset(address, 1)?;
+        self.memory[offset] = value as u8;
+        Ok(())
+    }
+
+    fn write_16(&mut self, address: u32, value: u32) -> Result<(), Skip> {
+        let offset = self.offset(address & !1, 2)?;
+        self.memory[offset] = value as u8;
+        self.memory[offset + 1] = (value >> 8) as u8;
+        Ok(())
+    }
+
+    fn write_32(&mut self, address: u32, value: u32) -> Result<(), Skip> {
+        let offset = self.offset(address & !3, 4)?;
+        for i in 0..4 {
+            self.memory[offset + i] = (value >> (i * 8)) as u8;
+        }
+        Ok(())
+    }
+
+    /// LDR from an unaligned address rotates the aligned word.
+    fn load_word(&self, address: u32) -> Result<u32, Skip> {
+        Ok(self.read_32(address)?.rotate_right((address & 3) * 8))
+    }
+
+    /// LDRH from an odd address rotates the halfword into the top byte.
+    fn load_halfword(&self, address: u32) -> Result<u32, Skip> {
+        Ok(self.read_16(address)?.rotate_right((address & 1) * 8))
+    }
+
+    /// LDRSH from an odd address loads the byte sign extended.
+    fn load_signed_halfword(&self, address: u32) -> Result<u32, Skip> {
+        if address & 1 != 0 {
+            Ok(sign_extend(self.read_8(address)?, 8))
+        } else {
+            Ok(sign_extend(self.read_16(address)?, 16))
+        }
+    }
+
+    fn condition(&self, cond: u32) -> bool {
+        let (n, z, c, v) = (self.flag(N), self.flag(Z), self.flag(C), self.flag(V));
+
+        match cond {
+            0x0 => z,
+            0x1 => !z,
+            0x2 => c,
+            0x3 => !c,
+            0x4 => n,
+            0x5 => !n,
+            0x6 => v,
+            0x7 => !v,
+            0x8 => c && !z,
+            0x9 => !c || z,
+            0xA => n == v,
+            0xB => n != v,
+            0xC => !z && n == v,
+            0xD => z || n != v,
+            _ => true,
+        }
+    }
+
+    /// Enters an exception: bank the CPSR, switch to ARM state in `mode` with IRQs off.
+    fn exception(&mut self, mode: u32, vector: u32, return_address: u32) {
+        let cpsr = self.cpsr;
+
+        self.cpsr = (cpsr & !0x3F) | I | mode;
+        self.spsr[spsr_index(mode).unwrap()] = cpsr;
+        self.set_reg(14, return_address);
+        self.pc = vector;
+    }
+
+    /// The barrel shifter. `immediate` selects the encodings where an amount of zero means
+    /// LSR/ASR #32 or RRX.
+    fn shift(&self, kind: u32, value: u32, amount: u32, immediate: bool) -> (u32, bool) {
+        let carry = self.flag(C);
+        let bit = |index: u32| (value >> index) & 1 != 0;
+
+        match (kind, amount) {
+            (3, 0) if immediate => ((value >> 1) | (carry as u32) << 31, bit(0)),
+            (1, 0) | (2, 0) if immediate => self.shift(kind, value, 32, false),
+            (_, 0) => (value, carry),
+            (0, 1..=31) => (value << amount, bit(32 - amount)),
+            (0, 32) => (0, bit(0)),
+            (0, _) => (0, false),
+            (1, 1..=31) => (value >> amount, bit(amount - 1)),
+            (1, 32) => (0, bit(31)),
+            (1, _) => (0, false),
+            (2, 1..=31) => (((value as i32) >> amount) as u32, bit(amount - 1)),
+            (2, _) => (((value as i32) >> 31) as u32, bit(31)),
+            (_, _) if amount & 31 == 0 => (value, bit(31)),
+            (_, _) => (value.rotate_right(amount & 31), bit((amount & 31) - 1)),
+        }
+    }
+
+    /// Executes one instruction. Returns the CPSR bits the architecture leaves unpredictable.
+    fn step(&mut self, opcode: u32) -> Result<u32, Skip> {
+        if self.thumb() {
+            self.thumb_step(opcode & 0xFFFF)
+        } else {
+            self.arm_step(opcode)
+        }
+    }
+
+    fn arm_step(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(4);
+
+        if opcode >> 28 == 0xF {
+            // the NV space is unpredictable on ARMv4
+            return Err(Skip);
+        }
+        if !self.condition(opcode >> 28) {
+            return Ok(0);
+        }
+
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let rs = ((opcode >> 8) & 0xF) as usize;
+        let rm = (opcode & 0xF) as usize;
+        let set_flags = opcode & 0x00100000 != 0;
+
+        match (opcode >> 25) & 7 {
+            0b000 if opcode & 0x0FFFFFF0 == 0x012FFF10 => {
+                let target = self.reg(rm);
+                if target & 3 == 2 {
+                    return Err(Skip);
+                }
+
+                self.set_flag(T, target & 1 != 0);
+                self.pc = target & !1;
+                Ok(0)
+            },
+            0b000 if opcode & 0x90 == 0x90 => self.arm_multiply_or_transfer(opcode),
+            0b000 | 0b001 if opcode & 0x01900000 == 0x01000000 => self.arm_psr_transfer(opcode),
+            0b000 | 0b001 => {
+                let immediate = opcode & 0x02000000 != 0;
+                let register_shift = !immediate && opcode & 0x10 != 0;
+
+                if register_shift && (rn == 15 || rd == 15 || rs == 15 || rm == 15) {
+                    return Err(Skip);
+                }
+
+                let (operand, shifter_carry) = if immediate {
+                    let rotate = ((opcode >> 8) & 0xF) * 2;
+                    let value = (opcode & 0xFF).rotate_right(rotate);
+                    (value, if rotate == 0 { self.flag(C) } else { value >> 31 != 0 })
+                } else if register_shift {
+                    self.shift((opcode >> 5) & 3, self.reg(rm), self.reg(rs) & 0xFF, false)
+                } else {
+                    self.shift((opcode >> 5) & 3, self.reg(rm), (opcode >> 7) & 0x1F, true)
+                };
+
+                let a = self.reg(rn);
+                let carry = self.flag(C);
+                let op = (opcode >> 21) & 0xF;
+
+                let (result, arithmetic) = match op {
+                    0x0 | 0x8 => (a & operand, None),
+                    0x1 | 0x9 => (a ^ operand, None),
+                    0x2 | 0xA => {
+                        let (result, c, v) = sub_flags(a, operand, true);
+                        (result, Some((c, v)))
+                    },
+                    0x3 => {
+                        let (result, c, v) = sub_flags(operand, a, true);
+                        (result, Some((c, v)))
+                    },
+                    0x4 | 0xB => {
+                        let (result, c, v) = add_flags(a, operand, false);
+                        (result, Some((c, v)))
+                    },
+                    0x5 => {
+                        let (result, c, v) = add_flags(a, operand, carry);
+                        (result, Some((c, v)))
+                    },
+                    0x6 => {
+                        let (result, c, v) = sub_flags(a, operand, carry);
+                        (result, Some((c, v)))
+                    },
+                    0x7 => {
+                        let (result, c, v) = sub_flags(operand, a, carry);
+                        (result, Some((c, v)))
+                    },
+                    0xC => (a | operand, None),
+                    0xD => (operand, None),
+                    0xE => (a & !operand, None),
+                    _ => (!operand, None),
+                };
+
+                let test_only = (0x8..=0xB).contains(&op);
+
+                if set_flags && rd == 15 {
+                    if test_only {
+                        return Err(Skip);
+                    }
+
+                    let spsr = self.spsr()?;
+                    self.set_cpsr(spsr)?;
+                    self.pc = result & if self.thumb() { !1 } else { !3 };
+                    return Ok(0);
+                }
+
+                if set_flags {
+                    self.set_nz(result);
+
+                    match arithmetic {
+                        Some((c, v)) => {
+                            self.set_flag(C, c);
+                            self.set_flag(V, v);
+                        },
+                        None => self.set_flag(C, shifter_carry),
+                    }
+                }
+
+                if !test_only {
+                    if rd == 15 {
+                        self.pc = result & !3;
+                    } else {
+                        self.set_reg(rd, result);
+                    }
+                }
+
+                Ok(0)
+            },
+            0b010 | 0b011 if opcode & 0x02000010 == 0x02000010 => {
+                self.exception(UND, 0x04, pc.wrapping_add(4));
+                Ok(0)
+            },
+            0b010 | 0b011 => self.arm_single_transfer(opcode),
+            0b100 => self.arm_block_transfer(opcode),
+            0b101 => {
+                if opcode & 0x01000000 != 0 {
+                    self.set_reg(14, pc.wrapping_add(4));
+                }
+                self.pc = pc.wrapping_add(8).wrapping_add(sign_extend(opcode & 0xFFFFFF, 24) << 2);
+                Ok(0)
+            },
+            0b111 if opcode & 0x01000000 != 0 => {
+                self.exception(SVC, 0x08, pc.wrapping_add(4));
+                Ok(0)
+            },
+            _ => {
+                // coprocessor instructions, none of which exist
+                self.exception(UND, 0x04, pc.wrapping_add(4));
+                Ok(0)
+            },
+        }
+    }
+
+    fn arm_multiply_or_transfer(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let rs = ((opcode >> 8) & 0xF) as usize;
+        let rm = (opcode & 0xF) as usize;
+        let set_flags = opcode & 0x00100000 != 0;
+
+        match (opcode >> 5) & 3 {
+            0 if opcode & 0x0FC00000 == 0 => {
+                // MUL and MLA keep the destination in the rn field
+                if rn == 15 || rd == 15 || rs == 15 || rm == 15 || rn == rm {
+                    return Err(Skip);
+                }
+
+                let mut result = self.reg(rm).wrapping_mul(self.reg(rs));
+                if opcode & 0x00200000 != 0 {
+                    result = result.wrapping_add(self.reg(rd));
+                }
+
+                self.set_reg(rn, result);
+                if set_flags {
+                    self.set_nz(result);
+                    return Ok(C);
+                }
+                Ok(0)
+            },
+            0 if opcode & 0x0F800000 == 0x00800000 => {
+                let (high, low) = (rn, rd);
+                if high == 15 || low == 15 || rs == 15 || rm == 15 || high == low || high == rm || low == rm {
+                    return Err(Skip);
+                }
+
+                let signed = opcode & 0x00400000 != 0;
+                let mut result = if signed {
+                    (self.reg(rm) as i32 as i64).wrapping_mul(self.reg(rs) as i32 as i64) as u64
+                } else {
+                    self.reg(rm) as u64 * self.reg(rs) as u64
+                };
+                if opcode & 0x00200000 != 0 {
+                    result = result.wrapping_add((self.reg(high) as u64) << 32 | self.reg(low) as u64);
+                }
+
+                self.set_reg(low, result as u32);
+                self.set_reg(high, (result >> 32) as u32);
+                if set_flags {
+                    self.set_flag(N, result >> 63 != 0);
+                    self.set_flag(Z, result == 0);
+                    return Ok(C | V);
+                }
+                Ok(0)
+            },
+            0 if opcode & 0x0FB00FF0 == 0x01000090 => {
+                if rn == 15 || rd == 15 || rm == 15 || rn == rd || rn == rm {
+                    return Err(Skip);
+                }
+
+                let address = self.reg(rn);
+                if opcode & 0x00400000 != 0 {
+                    let value = self.read_8(address)?;
+                    self.write_8(address, self.reg(rm))?;
+                    self.set_reg(rd, value);
+                } else {
+                    let value = self.load_word(address)?;
+                    self.write_32(address, self.reg(rm))?;
+                    self.set_reg(rd, value);
+                }
+                Ok(0)
+            },
+            0 => Err(Skip),
+            kind => {
+                let pre = opcode & 0x01000000 != 0;
+                let up = opcode & 0x00800000 != 0;
+                let writeback = !pre || opcode & 0x00200000 != 0;
+                let load = set_flags;
+
+                if (!pre && opcode & 0x00200000 != 0) || rd == 15 || (writeback && (rn == 15 || rn == rd)) {
+                    return Err(Skip);
+                }
+                if !load && kind != 1 {
+                    // LDRD and STRD only exist from ARMv5TE
+                    return Err(Skip);
+                }
+
+                let offset = if opcode & 0x00400000 != 0 {
+                    ((opcode >> 4) & 0xF0) | (opcode & 0xF)
+                } else if rm == 15 {
+                    return Err(Skip);
+                } else {
+                    self.reg(rm)
+                };
+
+                let base = self.reg(rn);
+                let offset_address = if up { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+                let address = if pre { offset_address } else { base };
+
+                if load {
+                    let value = match kind {
+                        1 => self.load_halfword(address)?,
+                        2 => sign_extend(self.read_8(address)?, 8),
+                        _ => self.load_signed_halfword(address)?,
+                    };
+
+                    if writeback {
+                        self.set_reg(rn, offset_address);
+                    }
+                    self.set_reg(rd, value);
+                } else {
+                    self.write_16(address, self.reg(rd))?;
+                    if writeback {
+                        self.set_reg(rn, offset_address);
+                    }
+                }
+                Ok(0)
+            },
+        }
+    }
+
+    fn arm_psr_transfer(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let spsr = opcode & 0x00400000 != 0;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let privileged = self.mode() != USR;
+
+        if opcode & 0x00200000 == 0 {
+            // MRS
+            if opcode & 0x020F0FFF != 0x000F0000 || rd == 15 {
+                return Err(Skip);
+            }
+
+            let value = if spsr { self.spsr()? } else { self.cpsr };
+            self.set_reg(rd, value);
+            return Ok(0);
+        }
+
+        if opcode & 0x0000F000 != 0x0000F000 {
+            return Err(Skip);
+        }
+
+        let value = if opcode & 0x02000000 != 0 {
+            (opcode & 0xFF).rotate_right(((opcode >> 8) & 0xF) * 2)
+        } else if opcode & 0xFF0 != 0 || opcode & 0xF == 15 {
+            return Err(Skip);
+        } else {
+            self.reg((opcode & 0xF) as usize)
+        };
+
+        let mut mask = 0;
+        for field in 0..4 {
+            if opcode & (1 << (16 + field)) != 0 {
+                mask |= 0xFF << (field * 8);
+            }
+        }
+
+        if spsr {
+            let index = spsr_index(self.mode()).ok_or(Skip)?;
+            let spsr = (self.spsr[index] & !mask) | (value & mask);
+            // the CPU only holds PSRs with a valid mode
+            if !MODES.contains(&(spsr & 0x1F)) {
+                return Err(Skip);
+            }
+            self.spsr[index] = spsr;
+        } else {
+            if !privileged {
+                mask &= 0xFF000000;
+            }
+
+            let cpsr = (self.cpsr & !mask) | (value & mask);
+            if (cpsr ^ self.cpsr) & T != 0 {
+                return Err(Skip);
+            }
+            self.set_cpsr(cpsr)?;
+        }
+        Ok(0)
+    }
+
+    fn arm_single_transfer(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let pre = opcode & 0x01000000 != 0;
+        let up = opcode & 0x00800000 != 0;
+        let byte = opcode & 0x00400000 != 0;
+        let writeback = !pre || opcode & 0x00200000 != 0;
+        let load = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rd = ((opcode >> 12) & 0xF) as usize;
+        let rm = (opcode & 0xF) as usize;
+
+        if writeback && (rn == 15 || rn == rd) {
+            return Err(Skip);
+        }
+
+        let offset = if opcode & 0x02000000 == 0 {
+            opcode & 0xFFF
+        } else if rm == 15 {
+            return Err(Skip);
+        } else {
+            self.shift((opcode >> 5) & 3, self.reg(rm), (opcode >> 7) & 0x1F, true).0
+        };
+
+        let base = self.reg(rn);
+        let offset_address = if up { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+        let address = if pre { offset_address } else { base };
+
+        if load {
+            let value = if byte { self.read_8(address)? } else { self.load_word(address)? };
+
+            if writeback {
+                self.set_reg(rn, offset_address);
+            }
+
+            if rd == 15 {
+                self.pc = value & !3;
+            } else {
+                self.set_reg(rd, value);
+            }
+        } else {
+            // a stored pc is the instruction address plus 12
+            let value = if rd == 15 { self.pc.wrapping_add(8) } else { self.reg(rd) };
+
+            if byte {
+                self.write_8(address, value)?;
+            } else {
+                self.write_32(address, value)?;
+            }
+
+            if writeback {
+                self.set_reg(rn, offset_address);
+            }
+        }
+        Ok(0)
+    }
+
+    fn arm_block_transfer(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let pre = opcode & 0x01000000 != 0;
+        let up = opcode & 0x00800000 != 0;
+        let user = opcode & 0x00400000 != 0;
+        let writeback = opcode & 0x00200000 != 0;
+        let load = opcode & 0x00100000 != 0;
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let list = opcode & 0xFFFF;
+
+        let count = list.count_ones();
+        let with_pc = list & 0x8000 != 0;
+        let lowest = list.trailing_zeros() as usize;
+
+        if rn == 15 || list == 0 {
+            return Err(Skip);
+        }
+        if user && (self.mode() == USR || self.mode() == SYS || (writeback && !(load && with_pc))) {
+            return Err(Skip);
+        }
+        if writeback && list & (1 << rn) != 0 && (load || lowest != rn) {
+            return Err(Skip);
+        }
+
+        let base = self.reg(rn);
+        let start = match (pre, up) {
+            (false, true) => base,
+            (true, true) => base.wrapping_add(4),
+            (false, false) => base.wrapping_sub(count * 4).wrapping_add(4),
+            (true, false) => base.wrapping_sub(count * 4),
+        };
+        let final_base = if up { base.wrapping_add(count * 4) } else { base.wrapping_sub(count * 4) };
+
+        // ^ without pc in the list transfers the user bank
+        let bank = if user && !(load && with_pc) { USR } else { self.mode() };
+        let mut address = start;
+
+        if load {
+            let mut values = vec!();
+            for index in (0..16).filter(|index| list & (1 << index) != 0) {
+                values.push((index, self.read_32(address)?));
+                address = address.wrapping_add(4);
+            }
+
+            if writeback {
+                self.set_reg(rn, final_base);
+            }
+
+            for (index, value) in values {
+                if index == 15 {
+                    if user {
+                        let spsr = self.spsr()?;
+                        self.set_cpsr(spsr)?;
+                    }
+                    self.pc = value & if self.thumb() { !1 } else { !3 };
+                } else {
+                    self.set_reg_in(bank, index, value);
+                }
+            }
+        } else {
+            for index in (0..16).filter(|index| list & (1 << index) != 0) {
+                let value = if index == 15 { self.pc.wrapping_add(8) } else { self.reg_in(bank, index) };
+                self.write_32(address, value)?;
+                address = address.wrapping_add(4);
+            }
+
+            if writeback {
+                self.set_reg(rn, final_base);
+            }
+        }
+        Ok(0)
+    }
+
+    fn thumb_step(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let pc = self.pc;
+        self.pc = pc.wrapping_add(2);
+
+        let rd = (opcode & 7) as usize;
+        let rs = ((opcode >> 3) & 7) as usize;
+        let rn = ((opcode >> 6) & 7) as usize;
+        let high_rd = ((opcode >> 8) & 7) as usize;
+
+        match opcode >> 11 {
+            0b00000..=0b00010 => {
+                let (result, carry) = self.shift(opcode >> 11, self.reg(rs), (opcode >> 6) & 0x1F, true);
+                self.set_reg(rd, result);
+                self.set_nz(result);
+                self.set_flag(C, carry);
+            },
+            0b00011 => {
+                let operand = if opcode & 0x0400 != 0 { (opcode >> 6) & 7 } else { self.reg(rn) };
+                let (result, c, v) = if opcode & 0x0200 != 0 {
+                    sub_flags(self.reg(rs), operand, true)
+                } else {
+                    add_flags(self.reg(rs), operand, false)
+                };
+
+                self.set_reg(rd, result);
+                self.set_nz(result);
+                self.set_flag(C, c);
+                self.set_flag(V, v);
+            },
+            0b00100..=0b00111 => {
+                let operand = opcode & 0xFF;
+                let value = self.reg(high_rd);
+
+                match (opcode >> 11) & 3 {
+                    0 => {
+                        self.set_reg(high_rd, operand);
+                        self.set_nz(operand);
+                    },
+                    1 => self.compare(value, operand),
+                    2 => {
+                        let (result, c, v) = add_flags(value, operand, false);
+                        self.set_reg(high_rd, result);
+                        self.set_arithmetic(result, c, v);
+                    },
+                    _ => {
+                        let (result, c, v) = sub_flags(value, operand, true);
+                        self.set_reg(high_rd, result);
+                        self.set_arithmetic(result, c, v);
+                    },
+                }
+            },
+            0b01000 if opcode & 0x0400 == 0 => return self.thumb_alu(opcode),
+            0b01000 => {
+                let rd = rd | ((opcode >> 4) & 8) as usize;
+                let rs = ((opcode >> 3) & 0xF) as usize;
+                let op = (opcode >> 8) & 3;
+
+                if op != 3 && opcode & 0xC0 == 0 {
+                    // both registers low is unpredictable before ARMv6
+                    return Err(Skip);
+                }
+
+                match op {
+                    0 => {
+                        let result = self.reg(rd).wrapping_add(self.reg(rs));
+                        self.write_high(rd, result);
+                    },
+                    1 => {
+                        if rd == 15 {
+                            return Err(Skip);
+                        }
+                        self.compare(self.reg(rd), self.reg(rs));
+                    },
+                    2 => {
+                        let result = self.reg(rs);
+                        self.write_high(rd, result);
+                    },
+                    _ => {
+                        let target = self.reg(rs);
+                        if opcode & 0x80 != 0 || target & 3 == 2 {
+                            return Err(Skip);
+                        }
+
+                        self.set_flag(T, target & 1 != 0);
+                        self.pc = target & !1;
+                    },
+                }
+            },
+            0b01001 => {
+                let address = (pc.wrapping_add(4) & !3).wrapping_add((opcode & 0xFF) << 2);
+                let value = self.read_32(address)?;
+                self.set_reg(high_rd, value);
+            },
+            0b01010 | 0b01011 => {
+                let address = self.reg(rs).wrapping_add(self.reg(rn));
+
+                match (opcode >> 9) & 7 {
+                    0 => self.write_32(address, self.reg(rd))?,
+                    1 => self.write_16(address, self.reg(rd))?,
+                    2 => self.write_8(address, self.reg(rd))?,
+                    3 => {
+                        let value = sign_extend(self.read_8(address)?, 8);
+                        self.set_reg(rd, value);
+                    },
+                    4 => {
+                        let value = self.load_word(address)?;
+                        self.set_reg(rd, value);
+                    },
+                    5 => {
+                        let value = self.load_halfword(address)?;
+                        self.set_reg(rd, value);
+                    },
+                    6 => {
+                        let value = self.read_8(address)?;
+                        self.set_reg(rd, value);
+                    },
+                    _ => {
+                        let value = self.load_signed_halfword(address)?;
+                        self.set_reg(rd, value);
+                    },
+                }
+            },
+            0b01100..=0b10001 => {
+                let offset = (opcode >> 6) & 0x1F;
+                let load = opcode & 0x0800 != 0;
+
+                let (address, size) = match opcode >> 12 {
+                    0b0110 => (self.reg(rs).wrapping_add(offset << 2), 4),
+                    0b0111 => (self.reg(rs).wrapping_add(offset), 1),
+                    _ => (self.reg(rs).wrapping_add(offset << 1), 2),
+                };
+
+                match (load, size) {
+                    (false, 4) => self.write_32(address, self.reg(rd))?,
+                    (false, 1) => self.write_8(address, self.reg(rd))?,
+                    (false, _) => self.write_16(address, self.reg(rd))?,
+                    (true, 4) => {
+                        let value = self.load_word(address)?;
+                        self.set_reg(rd, value);
+                    },
+                    (true, 1) => {
+                        let value = self.read_8(address)?;
+                        self.set_reg(rd, value);
+                    },
+                    (true, _) => {
+                        let value = self.load_halfword(address)?;
+                        self.set_reg(rd, value);
+                    },
+                }
+            },
+            0b10010 | 0b10011 => {
+                let address = self.reg(13).wrapping_add((opcode & 0xFF) << 2);
+
+                if opcode & 0x0800 != 0 {
+                    let value = self.load_word(address)?;
+                    self.set_reg(high_rd, value);
+                } else {
+                    self.write_32(address, self.reg(high_rd))?;
+                }
+            },
+            0b10100 => {
+                let value = (pc.wrapping_add(4) & !3).wrapping_add((opcode & 0xFF) << 2);
+                self.set_reg(high_rd, value);
+            },
+            0b10101 => {
+                let value = self.reg(13).wrapping_add((opcode & 0xFF) << 2);
+                self.set_reg(high_rd, value);
+            },
+            0b10110 | 0b10111 if opcode & 0x0600 == 0x0400 => return self.thumb_push_pop(opcode),
+            0b10110 if opcode & 0x0F00 == 0 => {
+                let offset = (opcode & 0x7F) << 2;
+                let sp = self.reg(13);
+                self.set_reg(13, if opcode & 0x80 != 0 { sp.wrapping_sub(offset) } else { sp.wrapping_add(offset) });
+            },
+            0b11000 | 0b11001 => {
+                let base = high_rd;
+                let list = opcode & 0xFF;
+                let load = opcode & 0x0800 != 0;
+
+                if list == 0 || (!load && list & (1 << base) != 0 && list.trailing_zeros() as usize != base) {
+                    return Err(Skip);
+                }
+
+                let mut address = self.reg(base);
+                let final_base = address.wrapping_add(list.count_ones() * 4);
+
+                for index in (0..8).filter(|index| list & (1 << index) != 0) {
+                    if load {
+                        let value = self.read_32(address)?;
+                        self.set_reg(index, value);
+                    } else {
+                        self.write_32(address, self.reg(index))?;
+                    }
+                    address = address.wrapping_add(4);
+                }
+
+                // a loaded base wins over the written back one
+                if !load || list & (1 << base) == 0 {
+                    self.set_reg(base, final_base);
+                }
+            },
+            0b11010 | 0b11011 => match (opcode >> 8) & 0xF {
+                0xE => self.exception(UND, 0x04, pc.wrapping_add(2)),
+                0xF => self.exception(SVC, 0x08, pc.wrapping_add(2)),
+                cond => {
+                    if self.condition(cond) {
+                        self.pc = pc.wrapping_add(4).wrapping_add(sign_extend(opcode & 0xFF, 8) << 1);
+                    }
+                },
+            },
+            0b11100 => self.pc = pc.wrapping_add(4).wrapping_add(sign_extend(opcode & 0x7FF, 11) << 1),
+            0b11110 => {
+                let value = pc.wrapping_add(4).wrapping_add(sign_extend(opcode & 0x7FF, 11) << 12);
+                self.set_reg(14, value);
+            },
+            0b11111 => {
+                let target = self.reg(14).wrapping_add((opcode & 0x7FF) << 1);
+                self.set_reg(14, pc.wrapping_add(2) | 1);
+                self.pc = target & !1;
+            },
+            _ => self.exception(UND, 0x04, pc.wrapping_add(2)),
+        }
+
+        Ok(0)
+    }
+
+    fn compare(&mut self, a: u32, b: u32) {
+        let (result, c, v) = sub_flags(a, b, true);
+        self.set_arithmetic(result, c, v);
+    }
+
+    fn set_arithmetic(&mut self, result: u32, carry: bool, overflow: bool) {
+        self.set_nz(result);
+        self.set_flag(C, carry);
+        self.set_flag(V, overflow);
+    }
+
+    /// ADD and MOV to a high register; writing r15 branches without changing state.
+    fn write_high(&mut self, rd: usize, value: u32) {
+        if rd == 15 {
+            self.pc = value & !1;
+        } else {
+            self.set_reg(rd, value);
+        }
+    }
+
+    fn thumb_alu(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let rd = (opcode & 7) as usize;
+        let rs = (opcode >> 3) & 7;
+        let a = self.reg(rd);
+        let b = self.reg(rs as usize);
+        let carry = self.flag(C);
+
+        let result = match (opcode >> 6) & 0xF {
+            0x0 => a & b,
+            0x1 => a ^ b,
+            kind @ (0x2 | 0x3 | 0x4 | 0x7) => {
+                let kind = match kind {
+                    0x2 => 0,
+                    0x3 => 1,
+                    0x4 => 2,
+                    _ => 3,
+                };
+                let (result, carry) = self.shift(kind, a, b & 0xFF, false);
+                self.set_flag(C, carry);
+                result
+            },
+            0x5 => {
+                let (result, c, v) = add_flags(a, b, carry);
+                self.set_arithmetic(result, c, v);
+                result
+            },
+            0x6 => {
+                let (result, c, v) = sub_flags(a, b, carry);
+                self.set_arithmetic(result, c, v);
+                result
+            },
+            0x8 => {
+                self.set_nz(a & b);
+                return Ok(0);
+            },
+            0x9 => {
+                let (result, c, v) = sub_flags(0, b, true);
+                self.set_arithmetic(result, c, v);
+                result
+            },
+            0xA => {
+                self.compare(a, b);
+                return Ok(0);
+            },
+            0xB => {
+                let (result, c, v) = add_flags(a, b, false);
+                self.set_arithmetic(result, c, v);
+                return Ok(0);
+            },
+            0xC => a | b,
+            0xD => {
+                let result = a.wrapping_mul(b);
+                self.set_reg(rd, result);
+                self.set_nz(result);
+                return Ok(C);
+            },
+            0xE => a & !b,
+            _ => !b,
+        };
+
+        self.set_reg(rd, result);
+        self.set_nz(result);
+        Ok(0)
+    }
+
+    fn thumb_push_pop(&mut self, opcode: u32) -> Result<u32, Skip> {
+        let list = opcode & 0xFF;
+        let extra = opcode & 0x0100 != 0;
+        let count = list.count_ones() + extra as u32;
+
+        if count == 0 {
+            return Err(Skip);
+        }
+
+        let sp = self.reg(13);
+
+        if opcode & 0x0800 != 0 {
+            let mut address = sp;
+
+            for index in (0..8).filter(|index| list & (1 << index) != 0) {
+                let value = self.read_32(address)?;
+                self.set_reg(index, value);
+                address = address.wrapping_add(4);
+            }
+            if extra {
+                self.pc = self.read_32(address)? & !1;
+            }
+
+            self.set_reg(13, sp.wrapping_add(count * 4));
+        } else {
+            let mut address = sp.wrapping_sub(count * 4);
+
+            for index in (0..8).filter(|index| list & (1 << index) != 0) {
+                self.write_32(address, self.reg(index))?;
+                address = address.wrapping_add(4);
+            }
+            if extra {
+                self.write_32(address, self.reg(14))?;
+            }
+
+            self.set_reg(13, sp.wrapping_sub(count * 4));
+        }
+
+        Ok(0)
+    }
+}
+
+/// Points the registers an opcode addresses memory through into the window, so loads and
+/// stores stay comparable. Offsets in registers are kept small for the same reason.
+fn aim(model: &mut Model, opcode: u32, rng: &mut Rng) {
+    let base = |model: &mut Model, index: usize, rng: &mut Rng| {
+        model.set_reg(index, WINDOW + 0x100 + rng.below(WINDOW_SIZE as u32 - 0x200));
+    };
+    let small = |model: &mut Model, index: usize, rng: &mut Rng| {
+        model.set_reg(index, rng.below(0x80));
+    };
+
+    if model.thumb() {
+        let rs = ((opcode >> 3) & 7) as usize;
+
+        match opcode >> 12 {
+            0b0101 => {
+                base(model, rs, rng);
+                small(model, ((opcode >> 6) & 7) as usize, rng);
+            },
+            0b0110..=0b1000 => base(model, rs, rng),
+            0b1001 | 0b1011 => base(model, 13, rng),
+            0b1100 => base(model, ((opcode >> 8) & 7) as usize, rng),
+            _ => (),
+        }
+    } else {
+        let rn = ((opcode >> 16) & 0xF) as usize;
+        let rm = (opcode & 0xF) as usize;
+
+        let memory = match (opcode >> 25) & 7 {
+            0b000 => opcode & 0x90 == 0x90 && (opcode & 0x60 != 0 || opcode & 0x0FB00FF0 == 0x01000090),
+            0b010..=0b100 => true,
+            _ => false,
+        };
+
+        if memory && rn != 15 {
+            if opcode & 0x0E000000 != 0x08000000 && rm != rn {
+                small(model, rm, rng);
+            }
+            base(model, rn, rng);
+        }
+    }
+}
+
+/// Random opcodes weighted towards the interesting classes: a third are fully random, the
+/// rest come from per-class templates.
+fn random_arm(rng: &mut Rng) -> u32 {
+    let bits = rng.u32();
+    let cond = if rng.chance(60) { 0xE0000000 } else { (rng.below(15)) << 28 };
+
+    let body = match rng.below(12) {
+        0..=3 => bits & 0x0FFFFFFF,
+        // data processing, immediate or shifted register
+        4 | 5 => bits & 0x03FFFFFF & !0x00000010 | if rng.chance(30) { 0x10 } else { 0 } & !0x80 | if bits & 0x01800000 == 0x01000000 { 0x00100000 } else { 0 },
+        6 => bits & 0x00F0FF0F | 0x00000090,
+        7 => bits & 0x01FFFF6F | 0x00000090 | [0x20, 0x40, 0x60][rng.below(3) as usize],
+        8 => bits & 0x07FFFFEF | 0x04000000,
+        9 => bits & 0x01FFFFFF | 0x08000000,
+        10 => [0x012FFF10 | bits & 0xF, 0x010F0000 | bits & 0x0040F000, 0x0120F000 | bits & 0x024F0FFF, 0x0A000000 | bits & 0x01FFFFFF][rng.below(4) as usize],
+        _ => bits & 0x0F0FFFFF | 0x0F000000,
+    };
+
+    cond | body
+}
+
+fn random_thumb(rng: &mut Rng) -> u32 {
+    let bits = rng.u32() & 0xFFFF;
+
+    match rng.below(6) {
+        0 => bits,
+        1 => bits & 0x03FF | 0x4000,
+        2 => bits & 0x03FF | 0x4400,
+        3 => bits & 0x1FFF | 0x4000 | [0x1000, 0x2000, 0x3000][rng.below(3) as usize],
+        4 => bits & 0x0DFF | 0xB400,
+        _ => bits,
+    }
+}
+
+const SPSR_MODES: [u32; 5] = [FIQ, IRQ, SVC, ABT, UND];
+
+fn cpu_mode(mode: u32) -> Mode {
+    Mode::from_bits(mode).expect("the model only uses valid modes")
+}
+
+/// Copies the model into the CPU, going through the register bank of every mode.
+fn load(cpu: &mut Cpu, model: &Model, opcode: u32) {
+    let current = cpu_mode(model.mode());
+
+    cpu.regs = Registers::new();
+    for &mode in MODES.iter() {
+        for index in 0..15 {
+            cpu.regs.set_in(current, cpu_mode(mode), index, model.reg_in(mode, index));
+        }
+    }
+    for (&mode, &value) in SPSR_MODES.iter().zip(model.spsr.iter()) {
+        cpu.regs.set_spsr(cpu_mode(mode), Psr::from_bits(value).expect("the model only uses valid modes"));
+    }
+    cpu.cpsr = Psr::from_bits(model.cpsr).expect("the model only uses valid modes");
+
+    let window = WRAM_SIZE;
+    let mut memory = model.memory.clone();
+    let code = (CODE - WINDOW) as usize;
+    memory[code..code + 4].copy_from_slice(&opcode.to_le_bytes()[..]);
+    cpu.mem_map.writable_memory_mut()[window..window + WINDOW_SIZE].copy_from_slice(&memory);
+
+    cpu.regs.r[15] = model.pc;
+    cpu.cpu_jump();
+}
+
+fn capture(cpu: &Cpu, template: &Model) -> Model {
+    let mut model = template.clone();
+    let current = cpu.cpsr.mode;
+
+    for &mode in MODES.iter() {
+        for index in 0..15 {
+            model.set_reg_in(mode, index, cpu.regs.get_in(current, cpu_mode(mode), index));
+        }
+    }
+    for (spsr, &mode) in model.spsr.iter_mut().zip(SPSR_MODES.iter()) {
+        *spsr = cpu.regs.spsr(cpu_mode(mode)).expect("every exception mode has an SPSR").bits();
+    }
+    model.cpsr = cpu.cpsr.bits();
+
+    model.pc = cpu.arm_next_pc;
+
+    let window = WRAM_SIZE;
+    model.memory = cpu.mem_map.writable_memory()[window..window + WINDOW_SIZE].to_vec();
+
+    model
+}
+
+/// Field by field differences, ignoring the CPSR bits in `ignore`. Empty when they agree.
+fn differences(expected: &Model, actual: &Model, ignore: u32) -> Vec<String> {
+    let mut found = vec!();
+
+    for (mode, name) in [(USR, "usr"), (FIQ, "fiq"), (IRQ, "irq"), (SVC, "svc"), (ABT, "abt"), (UND, "und")].iter() {
+        for index in 0..15 {
+            if *mode != USR && *mode != FIQ && index < 13 {
+                continue;
+            }
+            if *mode == FIQ && index < 8 {
+                continue;
+            }
+
+            let (e, a) = (expected.reg_in(*mode, index), actual.reg_in(*mode, index));
+            if e != a {
+                found.push(format!("r{}_{}: expected {:08x}, got {:08x}", index, name, e, a));
+            }
+        }
+    }
+
+    for (i, name) in ["fiq", "irq", "svc", "abt", "und"].iter().enumerate() {
+        if (expected.spsr[i] ^ actual.spsr[i]) & PSR_MASK != 0 {
+            found.push(format!("spsr_{}: expected {:08x}, got {:08x}", name, expected.spsr[i] & PSR_MASK, actual.spsr[i] & PSR_MASK));
+        }
+    }
+
+    if (expected.cpsr ^ actual.cpsr) & PSR_MASK & !ignore != 0 {
+        found.push(format!("cpsr: expected {:08x}, got {:08x}", expected.cpsr & PSR_MASK, actual.cpsr & PSR_MASK));
+    }
+
+    if expected.pc != actual.pc {
+        found.push(format!("pc: expected {:08x}, got {:08x}", expected.pc, actual.pc));
+    }
+
+    for (offset, (e, a)) in expected.memory.iter().zip(actual.memory.iter()).enumerate() {
+        if e != a {
+            found.push(format!("[{:08x}]: expected {:02x}, got {:02x}", WINDOW + offset as u32, e, a));
+        }
+    }
+
+    found
+}
+
+/// Runs one case on both sides. None if the model skipped it.
+fn check(cpu: &mut Cpu, model: &Model, opcode: u32) -> Option<Vec<String>> {
+    let mut expected = model.clone();
+    let code = (CODE - WINDOW) as usize;
+    expected.memory[code..code + 4].copy_from_slice(&opcode.to_le_bytes()[..]);
+    let before = expected.clone();
+
+    let ignore = match expected.step(opcode) {
+        Ok(ignore) => ignore,
+        Err(Skip) => return None,
+    };
+
+    load(cpu, &before, opcode);
+    cpu.cpu_execute();
+    let actual = capture(cpu, &before);
+
+    Some(differences(&expected, &actual, ignore))
+}
+
+/// Simplifies a failing state one field at a time, keeping each change that still fails.
+fn shrink(cpu: &mut Cpu, model: &Model, opcode: u32) -> Model {
+    let mut best = model.clone();
+
+    let fails = |cpu: &mut Cpu, candidate: &Model| check(cpu, candidate, opcode).is_some_and(|found| !found.is_empty());
+
+    let try_change = |cpu: &mut Cpu, best: &mut Model, change: &dyn Fn(&mut Model)| {
+        let mut candidate = best.clone();
+        change(&mut candidate);
+        if candidate != *best && fails(cpu, &candidate) {
+            *best = candidate;
+        }
+    };
+
+    try_change(cpu, &mut best, &|m: &mut Model| m.memory.iter_mut().for_each(|byte| *byte = 0));
+
+    for index in 0..15 {
+        try_change(cpu, &mut best, &|m: &mut Model| m.usr[index] = 0);
+    }
+    for index in 0..7 {
+        try_change(cpu, &mut best, &|m: &mut Model| m.fiq[index] = 0);
+    }
+    for bank in 0..4 {
+        for index in 0..2 {
+            try_change(cpu, &mut best, &|m: &mut Model| m.banked[bank][index] = 0);
+        }
+    }
+    for index in 0..5 {
+        try_change(cpu, &mut best, &|m: &mut Model| m.spsr[index] = SYS);
+    }
+    for &flag in &[N, Z, C, V, I, 0x40] {
+        try_change(cpu, &mut best, &|m: &mut Model| m.cpsr &= !flag);
+    }
+    for &mode in &[SYS, USR] {
+        try_change(cpu, &mut best, &|m: &mut Model| m.cpsr = (m.cpsr & !0x1F) | mode);
+    }
+
+    best
+}
+
+fn describe(model: &Model, opcode: u32) -> String {
+    let mut text = String::new();
+
+    let disassembly = if model.thumb() {
+        disasm_thumb(opcode as u16, 0, model.pc).0
+    } else {
+        disasm_arm(opcode, model.pc)
+    };
+    let _ = writeln!(text, "  {} {:08x}  {}", if model.thumb() { "thumb" } else { "arm" }, opcode, disassembly);
+    let _ = writeln!(text, "  cpsr {:08x} ({})", model.cpsr & PSR_MASK, mode_name(model.mode()));
+
+    let mut registers = vec!();
+    for index in 0..15 {
+        if model.usr[index] != 0 {
+            registers.push(format!("r{}={:08x}", index, model.usr[index]));
+        }
+    }
+    for (index, &value) in model.fiq.iter().enumerate() {
+        if value != 0 {
+            registers.push(format!("r{}_fiq={:08x}", index + 8, value));
+        }
+    }
+    for (bank, name) in ["irq", "svc", "abt", "und"].iter().enumerate() {
+        for index in 0..2 {
+            if model.banked[bank][index] != 0 {
+                registers.push(format!("r{}_{}={:08x}", index + 13, name, model.banked[bank][index]));
+            }
+        }
+    }
+    for (index, name) in ["fiq", "irq", "svc", "abt", "und"].iter().enumerate() {
+        if model.spsr[index] != SYS {
+            registers.push(format!("spsr_{}={:08x}", name, model.spsr[index]));
+        }
+    }
+    let _ = writeln!(text, "  {}", if registers.is_empty() { "all other registers zero".to_string() } else { registers.join(" ") });
+
+    if model.memory.iter().any(|&byte| byte != 0) {
+        let _ = writeln!(text, "  memory: random bytes (mismatch needs them)");
+    }
+
+    text
+}
+
+fn settings() -> (u64, u32) {
+    let seed = env::var("GBA_FUZZ_SEED").ok().and_then(|seed| seed.parse().ok()).unwrap_or(DEFAULT_SEED);
+    let cases = env::var("GBA_FUZZ_CASES").ok().and_then(|cases| cases.parse().ok()).unwrap_or(DEFAULT_CASES);
+    (seed, cases)
+}
+
+fn fuzz(thumb: bool) {
+    let (seed, cases) = settings();
+    let mut rng = Rng(seed ^ if thumb { 0x5555 } else { 0 });
+    let mut cpu = Cpu::new();
+
+    let mut reports = vec!();
+    let mut failures = 0;
+    let mut compared = 0;
+
+    for case in 0..cases {
+        let opcode = if thumb { random_thumb(&mut rng) } else { random_arm(&mut rng) };
+        let mut model = Model::random(&mut rng, thumb);
+        aim(&mut model, opcode, &mut rng);
+
+        let found = match check(&mut cpu, &model, opcode) {
+            Some(found) => found,
+            None => continue,
+        };
+        compared += 1;
+
+        if found.is_empty() {
+            continue;
+        }
+        failures += 1;
+
+        if reports.len() < MAX_REPORTS {
+            let minimal = shrink(&mut cpu, &model, opcode);
+            let found = check(&mut cpu, &minimal, opcode).unwrap_or(found);
+            reports.push(format!("case {} (seed {}):\n{}  {}\n", case, seed, describe(&minimal, opcode), found.join("\n  ")));
+        }
+    }
+
+    assert!(compared > cases / 4, "only {} of {} cases were comparable", compared, cases);
+    assert!(failures == 0, "{} of {} cases differ from the reference model:\n\n{}", failures, compared, reports.join("\n"));
+}
+
+#[test]
+fn arm_matches_reference() {
+    fuzz(false);
+}
+
+#[test]
+fn thumb_matches_reference() {
+    fuzz(true);
+}
@@ -0,0 +1,160 @@
+use super::Cpu;
+
+/// Backward branches further than this are not taken for idle loops.
+const IDLE_LOOP_BYTES: u32 = 32;
+
+/// Tracks the last short loop the CPU went around. A loop is idle when a whole iteration
+/// wrote nothing, read nothing that changes on being read, and left every register as it
+/// found it: until the next event changes something it reads, it will go around the same
+/// way forever.
+pub struct IdleLoop {
+    target: u32,
+    snapshot: [u32; 16],
+    writes: u32,
+    read_effects: u32,
+    detected: Vec<u32>,
+}
+
+impl IdleLoop {
+    pub fn new() -> IdleLoop {
+        IdleLoop {
+            target: !0,
+            snapshot: [0; 16],
+            writes: 0,
+            read_effects: 0,
+            detected: vec!(),
+        }
+    }
+}
+
+impl Default for IdleLoop {
+    fn default() -> IdleLoop {
+        IdleLoop::new()
+    }
+}
+
+impl Cpu {
+    /// Turns idle loop skipping on or off. Off by default, since a loop that looks idle
+    /// but polls something the scheduler does not know about would be skipped wrongly.
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.idle_skip = enabled;
+        self.idle_loop = IdleLoop::new();
+    }
+
+    /// Called after the instruction at `pc` ran. When it was a short backward branch, the
+    /// second identical trip around the loop fast-forwards the scheduler to the next event.
+    pub(super) fn cpu_idle_branch(&mut self, pc: u32) {
+        if self.arm_next_pc >= pc || pc - self.arm_next_pc > IDLE_LOOP_BYTES {
+            return;
+        }
+
+        let mut snapshot = [0; 16];
+        snapshot[..15].copy_from_slice(&self.regs.r[..15]);
+        snapshot[15] = self.cpsr.bits();
+
+        let idle = self.idle_loop.target == self.arm_next_pc
+            && self.idle_loop.writes == self.bus_writes
+            && self.idle_loop.read_effects == self.bus_read_effects.get()
+            && self.idle_loop.snapshot == snapshot;
+
+        self.idle_loop.target = self.arm_next_pc;
+        self.idle_loop.snapshot = snapshot;
+        self.idle_loop.writes = self.bus_writes;
+        self.idle_loop.read_effects = self.bus_read_effects.get();
+
+        if idle {
+            if !self.idle_loop.detected.contains(&self.arm_next_pc) {
+                debug!("idle loop at {:08x}", self.arm_next_pc);
+                self.idle_loop.detected.push(self.arm_next_pc);
+            }
+
+            self.cpu_total_ticks = self.cpu_total_ticks.max(self.cpu_next_event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::Cpu;
+    use super::super::tests::program_cpu;
+
+    /// Counts frames in r5 by polling VCOUNT for line 160 and then for the next one.
+    const POLL_VCOUNT: [u32; 10] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE3A05000, // mov r5, #0
+        0xE1D400B6, // ldrh r0, [r4, #6]
+        0xE35000A0, // cmp r0, #160
+        0x1AFFFFFC, // bne 0x03000008
+        0xE2855001, // add r5, r5, #1
+        0xE1D400B6, // ldrh r0, [r4, #6]
+        0xE35000A0, // cmp r0, #160
+        0x0AFFFFFC, // beq 0x03000018
+        0xEAFFFFF7, // b 0x03000008
+    ];
+
+    /// Waits for line 160 while counting in r5.
+    const COUNT: [u32; 7] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE3A05000, // mov r5, #0
+        0xE2855001, // add r5, r5, #1
+        0xE1D400B6, // ldrh r0, [r4, #6]
+        0xE35000A0, // cmp r0, #160
+        0x1AFFFFFB, // bne 0x03000008
+        0xEAFFFFFE, // b .
+    ];
+
+    /// Waits for line 160 while storing to EWRAM.
+    const STORE: [u32; 7] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE3A06402, // mov r6, #0x02000000
+        0xE5864000, // str r4, [r6]
+        0xE1D400B6, // ldrh r0, [r4, #6]
+        0xE35000A0, // cmp r0, #160
+        0x1AFFFFFB, // bne 0x03000008
+        0xEAFFFFFE, // b .
+    ];
+
+    /// Polls SIODATA8 in UART mode for a byte other than 0; each read pops the receive FIFO.
+    const UART: [u32; 8] = [
+        0xE3A04301, // mov r4, #0x04000000
+        0xE2844C01, // add r4, r4, #0x100
+        0xE3A00A03, // mov r0, #0x3000
+        0xE1C402B8, // strh r0, [r4, #0x28]
+        0xE5D4002A, // ldrb r0, [r4, #0x2a]
+        0xE3500000, // cmp r0, #0
+        0x0AFFFFFC, // beq 0x03000010
+        0xEAFFFFFE, // b .
+    ];
+
+    fn run(program: &[u32], idle_skip: bool, frames: u32) -> Cpu {
+        let mut cpu = program_cpu(program);
+        cpu.set_idle_skip(idle_skip);
+
+        for _ in 0..frames {
+            cpu.run_frame();
+        }
+        cpu
+    }
+
+    #[test]
+    fn polling_loop_is_skipped() {
+        let skipped = run(&POLL_VCOUNT, true, 5);
+        let stepped = run(&POLL_VCOUNT, false, 5);
+
+        assert_eq!(skipped.idle_loop.detected, [0x03000008, 0x03000018]);
+        assert!(skipped.profile.instructions * 4 < stepped.profile.instructions);
+        assert_eq!(skipped.hash_state(), stepped.hash_state());
+    }
+
+    #[test]
+    fn busy_loops_are_not_skipped() {
+        for program in [&COUNT[..], &STORE[..], &UART[..]].iter() {
+            let skipped = run(program, true, 1);
+            let stepped = run(program, false, 1);
+
+            assert!(skipped.idle_loop.detected.is_empty());
+            assert_eq!(skipped.profile.instructions, stepped.profile.instructions);
+            assert_eq!(skipped.hash_state(), stepped.hash_state());
+        }
+    }
+}
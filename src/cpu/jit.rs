@@ -0,0 +1,911 @@
+//! The x86-64 backend. Blocks from the block cache that keep getting run are compiled to
+//! machine code which does exactly what the cached interpreter would: the same pipeline
+//! updates, the same cycle counts and an exit as soon as the next event is due, so IRQs are
+//! still taken between instructions. Data processing that needs no more than an immediate
+//! shift is translated to x86 instructions; everything else is a call to its handler.
+//!
+//! Instructions that change the mode or need the coprocessor space (MSR, SWI, undefined
+//! opcodes, data processing into r15 with S, LDM with ^) are never compiled: a block's code
+//! stops in front of them and the interpreter takes over for one instruction.
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("the jit feature needs x86-64 Linux");
+
+use std::cell::{Cell, OnceCell};
+use std::mem;
+use std::ptr;
+
+use super::cache::Block;
+use super::Cpu;
+
+/// Runs through the cached interpreter before a block is worth compiling.
+const HOT_RUNS: u32 = 32;
+
+const EAX: u8 = 0;
+const ECX: u8 = 1;
+const EDX: u8 = 2;
+
+/// Condition codes, as they go in the low nibble of jcc and setcc.
+const OVERFLOW: u8 = 0x0;
+const BELOW: u8 = 0x2;
+const ABOVE_EQUAL: u8 = 0x3;
+const EQUAL: u8 = 0x4;
+const NOT_EQUAL: u8 = 0x5;
+const SIGN: u8 = 0x8;
+const GREATER_EQUAL: u8 = 0xD;
+
+/// How far into `Cpu` the fields the generated code touches are, for `[rbx + offset]`.
+fn reg(index: usize) -> i32 {
+    (mem::offset_of!(Cpu, regs.r) + index * 4) as i32
+}
+
+fn field(offset: usize) -> i32 {
+    offset as i32
+}
+
+/// Times a block has been run, and its code once it has been compiled.
+pub struct NativeSlot {
+    runs: Cell<u32>,
+    /// `None` when the block starts with an instruction the JIT leaves to the interpreter.
+    code: OnceCell<Option<NativeCode>>,
+}
+
+impl NativeSlot {
+    pub fn new() -> NativeSlot {
+        NativeSlot {
+            runs: Cell::new(0),
+            code: OnceCell::new(),
+        }
+    }
+}
+
+impl Default for NativeSlot {
+    fn default() -> NativeSlot {
+        NativeSlot::new()
+    }
+}
+
+/// Machine code in its own executable mapping, unmapped on drop.
+struct NativeCode {
+    memory: *mut libc::c_void,
+    len: usize,
+}
+
+impl NativeCode {
+    fn new(code: &[u8]) -> Option<NativeCode> {
+        unsafe {
+            let page = libc::sysconf(libc::_SC_PAGESIZE) as usize;
+            let len = code.len().div_ceil(page) * page;
+
+            let memory = libc::mmap(ptr::null_mut(), len, libc::PROT_READ | libc::PROT_WRITE, libc::MAP_PRIVATE | libc::MAP_ANONYMOUS, -1, 0);
+            if memory == libc::MAP_FAILED {
+                return None;
+            }
+
+            ptr::copy_nonoverlapping(code.as_ptr(), memory as *mut u8, code.len());
+
+            // never writable and executable at once
+            if libc::mprotect(memory, len, libc::PROT_READ | libc::PROT_EXEC) != 0 {
+                libc::munmap(memory, len);
+                return None;
+            }
+
+            Some(NativeCode { memory, len })
+        }
+    }
+
+    fn run(&self, cpu: &mut Cpu) {
+        unsafe {
+            let entry = mem::transmute::<*mut libc::c_void, extern "C" fn(*mut Cpu)>(self.memory);
+            entry(cpu);
+        }
+    }
+}
+
+impl Drop for NativeCode {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.memory, self.len);
+        }
+    }
+}
+
+// The generated code calls back into the emulator through these. Handlers use the Rust ABI,
+// so they go through `call_handler` rather than being called directly. A panic cannot unwind
+// through generated code, so one in a handler aborts the process.
+
+extern "C" fn call_handler(cpu: *mut Cpu, handler: *const (), opcode: u32) -> i32 {
+    unsafe {
+        let handler = mem::transmute::<*const (), super::Handler>(handler);
+        handler(&mut *cpu, opcode)
+    }
+}
+
+extern "C" fn code_ticks_arm(cpu: *mut Cpu) -> i32 {
+    let cpu = unsafe { &mut *cpu };
+    1 + cpu.code_ticks_access_seq_32(cpu.arm_next_pc as usize) as i32
+}
+
+extern "C" fn code_ticks_thumb(cpu: *mut Cpu) -> i32 {
+    let cpu = unsafe { &mut *cpu };
+    1 + cpu.code_ticks_access_seq_16(cpu.arm_next_pc as usize) as i32
+}
+
+/// Fills the second pipeline slot from memory, for the last two instructions of a block.
+extern "C" fn prefetch_next(cpu: *mut Cpu) {
+    let cpu = unsafe { &mut *cpu };
+
+    cpu.cpu_prefetch[1] = if cpu.cpsr.thumb {
+        cpu.mem_map.read_16(cpu.arm_next_pc + 2, cpu.bios_protected, cpu.regs.r[15]) as u32
+    } else {
+        cpu.mem_map.read_32(cpu.arm_next_pc + 4, cpu.bios_protected, cpu.regs.r[15])
+    };
+}
+
+extern "C" fn idle_branch(cpu: *mut Cpu, pc: u32) {
+    unsafe { &mut *cpu }.cpu_idle_branch(pc);
+}
+
+/// The second operand of a compiled data processing instruction.
+enum Operand {
+    /// The value, and the shifter carry out when the rotation sets one.
+    Immediate(u32, Option<bool>),
+    /// A register shifted by a constant: type as in the opcode, amount 1-31, or 0 for none.
+    Register(usize, u32, u32),
+}
+
+/// A data processing instruction, ARM or Thumb, with ARM's numbering of the operations.
+struct Alu {
+    op: u32,
+    rd: usize,
+    rn: usize,
+    operand: Operand,
+    set_flags: bool,
+}
+
+impl Alu {
+    fn logical(&self) -> bool {
+        matches!(self.op, 0x0 | 0x1 | 0x8 | 0x9 | 0xC | 0xD | 0xE | 0xF)
+    }
+}
+
+/// Data processing with an immediate or an immediately shifted register that neither reads
+/// nor writes r15. LSR #32, ASR #32 and RRX are left to the handler.
+fn arm_alu(opcode: u32) -> Option<Alu> {
+    let op = (opcode >> 21) & 0xF;
+    let set_flags = opcode & 0x00100000 != 0;
+    let rn = ((opcode >> 16) & 0xF) as usize;
+    let rd = ((opcode >> 12) & 0xF) as usize;
+
+    // comparisons without S are MRS, MSR, BX and friends
+    if opcode & 0x0C000000 != 0 || (op & 0xC == 0x8 && !set_flags) || rd == 15 || (rn == 15 && op != 0xD && op != 0xF) {
+        return None;
+    }
+
+    let operand = if opcode & 0x02000000 != 0 {
+        let rotate = ((opcode >> 8) & 0xF) * 2;
+        let value = (opcode & 0xFF).rotate_right(rotate);
+
+        Operand::Immediate(value, if rotate == 0 { None } else { Some(value >> 31 != 0) })
+    } else {
+        let rm = (opcode & 0xF) as usize;
+        let shift_type = (opcode >> 5) & 3;
+        let amount = (opcode >> 7) & 0x1F;
+
+        // bit 4 is a register shift, or with bit 7 a multiply or halfword transfer
+        if opcode & 0x10 != 0 || rm == 15 || (amount == 0 && shift_type != 0) {
+            return None;
+        }
+
+        Operand::Register(rm, shift_type, amount)
+    };
+
+    Some(Alu { op, rd, rn, operand, set_flags })
+}
+
+/// Thumb formats 1 to 5 as ARM data processing, leaving out shifts by register, MUL and
+/// anything touching r15.
+fn thumb_alu(opcode: u32) -> Option<Alu> {
+    let low = |shift: u32| ((opcode >> shift) & 7) as usize;
+
+    match opcode >> 10 {
+        0b000110 | 0b000111 => Some(Alu {
+            op: if opcode & 0x0200 != 0 { 0x2 } else { 0x4 },
+            rd: low(0),
+            rn: low(3),
+            operand: if opcode & 0x0400 != 0 { Operand::Immediate((opcode >> 6) & 7, None) } else { Operand::Register(low(6), 0, 0) },
+            set_flags: true,
+        }),
+        0b000000 ..= 0b000101 => {
+            let shift_type = (opcode >> 11) & 3;
+            let amount = (opcode >> 6) & 0x1F;
+
+            if amount == 0 && shift_type != 0 {
+                return None;
+            }
+
+            Some(Alu { op: 0xD, rd: low(0), rn: 0, operand: Operand::Register(low(3), shift_type, amount), set_flags: true })
+        },
+        0b001000 ..= 0b001111 => {
+            let rd = low(8);
+            let op = [0xD, 0xA, 0x4, 0x2][((opcode >> 11) & 3) as usize];
+
+            Some(Alu { op, rd, rn: rd, operand: Operand::Immediate(opcode & 0xFF, None), set_flags: true })
+        },
+        0b010000 => {
+            let rd = low(0);
+            let rs = low(3);
+
+            match (opcode >> 6) & 0xF {
+                // NEG is RSB from zero
+                0x9 => Some(Alu { op: 0x3, rd, rn: rs, operand: Operand::Immediate(0, None), set_flags: true }),
+                op @ (0x0 | 0x1 | 0x5 | 0x6 | 0x8 | 0xA ..= 0xC | 0xE | 0xF) => Some(Alu { op, rd, rn: rd, operand: Operand::Register(rs, 0, 0), set_flags: true }),
+                _ => None,
+            }
+        },
+        0b010001 => {
+            let rd = ((opcode & 7) | ((opcode >> 4) & 8)) as usize;
+            let rs = ((opcode >> 3) & 0xF) as usize;
+
+            if rd == 15 || rs == 15 {
+                return None;
+            }
+
+            match (opcode >> 8) & 3 {
+                0 => Some(Alu { op: 0x4, rd, rn: rd, operand: Operand::Register(rs, 0, 0), set_flags: false }),
+                1 => Some(Alu { op: 0xA, rd, rn: rd, operand: Operand::Register(rs, 0, 0), set_flags: true }),
+                2 => Some(Alu { op: 0xD, rd, rn: 0, operand: Operand::Register(rs, 0, 0), set_flags: false }),
+                _ => None,
+            }
+        },
+        _ => None,
+    }
+}
+
+/// Instructions that end the compiled part of a block, see the module documentation.
+fn interpreter_only(opcode: u32, thumb: bool) -> bool {
+    if thumb {
+        match opcode >> 12 {
+            0xB => opcode & 0x0F00 != 0x0000 && opcode & 0x0600 != 0x0400,
+            0xD => opcode & 0x0E00 == 0x0E00,
+            0xE => opcode & 0x0800 != 0,
+            _ => false,
+        }
+    } else {
+        let msr = opcode & 0x0DB0F000 == 0x0120F000;
+        let restores_cpsr = opcode & 0x0C10F000 == 0x0010F000 && opcode & 0x0E000090 != 0x00000090;
+        let user_bank = opcode & 0x0E400000 == 0x08400000;
+        let undefined = opcode & 0x0E000010 == 0x06000010;
+        let coprocessor = opcode & 0x0C000000 == 0x0C000000;
+
+        msr || restores_cpsr || user_bank || undefined || coprocessor
+    }
+}
+
+/// Compiles the longest prefix of `block` that stays out of the interpreter-only cases.
+fn compile(block: &Block) -> Option<NativeCode> {
+    let thumb = block.thumb;
+    let width = if thumb { 2 } else { 4 };
+    let mut emitter = Emitter::new();
+
+    emitter.prologue();
+
+    for (index, &(opcode, handler)) in block.instructions.iter().enumerate() {
+        if interpreter_only(opcode, thumb) {
+            break;
+        }
+
+        let address = block.start + index as u32 * width;
+        let next = block.instructions.get(index + 2).map(|&(opcode, _)| opcode);
+
+        // anything the handler did may have left the block or unmapped it
+        if index > 0 && emitter.called_handler {
+            emitter.guard(address, thumb, &block.valid);
+        }
+
+        emitter.fetch(address, opcode, next, thumb);
+
+        let alu = if thumb { thumb_alu(opcode) } else { arm_alu(opcode) };
+        let condition = if thumb { 0xE } else { opcode >> 28 };
+
+        let skip = emitter.condition(condition);
+
+        match alu {
+            Some(alu) => {
+                emitter.alu(&alu);
+                emitter.bind(skip);
+                emitter.code_ticks(address + width, thumb);
+                emitter.add_ticks();
+                emitter.count_instruction();
+                emitter.called_handler = false;
+            },
+            None => {
+                emitter.call_handler(handler, opcode);
+
+                if !skip.is_empty() {
+                    let done = emitter.jump();
+                    emitter.bind(skip);
+                    emitter.code_ticks(address + width, thumb);
+                    emitter.bind(vec!(done));
+                }
+
+                emitter.add_ticks();
+                emitter.count_instruction();
+                emitter.idle_branch(address);
+                emitter.called_handler = true;
+            },
+        }
+
+        emitter.check_events();
+    }
+
+    if emitter.code.len() == Emitter::PROLOGUE_BYTES {
+        return None;
+    }
+
+    NativeCode::new(&emitter.finish())
+}
+
+/// Writes x86-64 machine code. `rbx` holds the `Cpu` pointer throughout, `eax`, `ecx` and
+/// `edx` are scratch, and every exit jumps to a shared epilogue at the end.
+struct Emitter {
+    code: Vec<u8>,
+    /// rel32 fields that jump to the epilogue, patched by `finish`.
+    exits: Vec<usize>,
+    /// The last instruction went through its handler, so it may have jumped or rewritten code.
+    called_handler: bool,
+}
+
+impl Emitter {
+    const PROLOGUE_BYTES: usize = 4;
+
+    fn new() -> Emitter {
+        Emitter {
+            code: vec!(),
+            exits: vec!(),
+            called_handler: false,
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    fn imm32(&mut self, value: u32) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    fn imm64(&mut self, value: u64) {
+        self.bytes(&value.to_le_bytes());
+    }
+
+    /// An opcode taking `[rbx + offset]` with `reg` in the ModRM reg field.
+    fn memory(&mut self, opcode: &[u8], reg: u8, offset: i32) {
+        self.bytes(opcode);
+        self.bytes(&[0x80 | reg << 3 | 3]);
+        self.imm32(offset as u32);
+    }
+
+    fn prologue(&mut self) {
+        // push rbx; mov rbx, rdi. One push leaves the stack 16-byte aligned for calls.
+        self.bytes(&[0x53, 0x48, 0x89, 0xFB]);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        let epilogue = self.code.len();
+
+        for exit in self.exits.drain(..) {
+            let relative = epilogue as i32 - (exit as i32 + 4);
+            self.code[exit..exit + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+
+        // pop rbx; ret
+        self.bytes(&[0x5B, 0xC3]);
+        self.code
+    }
+
+    /// jcc rel32 to the epilogue.
+    fn exit_if(&mut self, condition: u8) {
+        self.bytes(&[0x0F, 0x80 | condition]);
+        self.exits.push(self.code.len());
+        self.imm32(0);
+    }
+
+    /// jcc rel32 to a label bound later; returns the field to patch.
+    fn jump_if(&mut self, condition: u8) -> usize {
+        self.bytes(&[0x0F, 0x80 | condition]);
+        let patch = self.code.len();
+        self.imm32(0);
+        patch
+    }
+
+    fn jump(&mut self) -> usize {
+        self.bytes(&[0xE9]);
+        let patch = self.code.len();
+        self.imm32(0);
+        patch
+    }
+
+    /// Points the given jumps at the current position.
+    fn bind(&mut self, patches: Vec<usize>) {
+        let target = self.code.len();
+
+        for patch in patches {
+            let relative = target as i32 - (patch as i32 + 4);
+            self.code[patch..patch + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+    }
+
+    fn load(&mut self, reg: u8, offset: i32) {
+        self.memory(&[0x8B], reg, offset);
+    }
+
+    fn store(&mut self, offset: i32, reg: u8) {
+        self.memory(&[0x89], reg, offset);
+    }
+
+    fn load_byte(&mut self, reg: u8, offset: i32) {
+        // movzx r32, byte
+        self.memory(&[0x0F, 0xB6], reg, offset);
+    }
+
+    fn store_byte(&mut self, offset: i32, reg: u8) {
+        self.memory(&[0x88], reg, offset);
+    }
+
+    fn store_imm32(&mut self, offset: i32, value: u32) {
+        self.memory(&[0xC7], 0, offset);
+        self.imm32(value);
+    }
+
+    fn store_imm8(&mut self, offset: i32, value: u8) {
+        self.memory(&[0xC6], 0, offset);
+        self.bytes(&[value]);
+    }
+
+    fn compare_imm32(&mut self, offset: i32, value: u32) {
+        self.memory(&[0x81], 7, offset);
+        self.imm32(value);
+    }
+
+    fn compare_imm8(&mut self, offset: i32, value: u8) {
+        self.memory(&[0x80], 7, offset);
+        self.bytes(&[value]);
+    }
+
+    fn set(&mut self, condition: u8, offset: i32) {
+        self.memory(&[0x0F, 0x90 | condition], 0, offset);
+    }
+
+    /// `op dst, src` for the register to register form of an ALU opcode.
+    fn register_op(&mut self, opcode: u8, dst: u8, src: u8) {
+        self.bytes(&[opcode, 0xC0 | src << 3 | dst]);
+    }
+
+    fn call(&mut self, function: *const ()) {
+        // mov rax, imm64; call rax
+        self.bytes(&[0x48, 0xB8]);
+        self.imm64(function as u64);
+        self.bytes(&[0xFF, 0xD0]);
+    }
+
+    fn cpu_argument(&mut self) {
+        // mov rdi, rbx
+        self.bytes(&[0x48, 0x89, 0xDF]);
+    }
+
+    /// Leaves the block unless the handler before carried on to `address` in the same
+    /// state and left the block in the cache.
+    fn guard(&mut self, address: u32, thumb: bool, valid: &Cell<bool>) {
+        self.compare_imm32(field(mem::offset_of!(Cpu, arm_next_pc)), address);
+        self.exit_if(NOT_EQUAL);
+
+        self.compare_imm8(field(mem::offset_of!(Cpu, cpsr.thumb)), thumb as u8);
+        self.exit_if(NOT_EQUAL);
+
+        // mov rax, imm64; cmp byte [rax], 0
+        self.bytes(&[0x48, 0xB8]);
+        self.imm64(valid.as_ptr() as u64);
+        self.bytes(&[0x80, 0x38, 0x00]);
+        self.exit_if(EQUAL);
+    }
+
+    /// What `arm_fetch` and `thumb_fetch` do, with the opcode after next taken from the
+    /// block while there is one. Leaves first if the pipeline holds something else than
+    /// the compiled opcode, which happens when code rewrites the instruction right after it.
+    fn fetch(&mut self, address: u32, opcode: u32, next: Option<u32>, thumb: bool) {
+        let prefetch = mem::offset_of!(Cpu, cpu_prefetch);
+
+        self.compare_imm32(field(prefetch), opcode);
+        self.exit_if(NOT_EQUAL);
+
+        self.load(EAX, field(prefetch + 4));
+        self.store(field(prefetch), EAX);
+
+        if address >> 24 >= 0x08 {
+            self.load_byte(EAX, field(mem::offset_of!(Cpu, bus_prefetch_enable)));
+            self.store_byte(field(mem::offset_of!(Cpu, bus_prefetch)), EAX);
+        } else {
+            self.store_imm8(field(mem::offset_of!(Cpu, bus_prefetch)), 0);
+        }
+
+        self.load(EAX, reg(15));
+        self.store(field(mem::offset_of!(Cpu, arm_next_pc)), EAX);
+        // add eax, imm8
+        self.bytes(&[0x83, 0xC0, if thumb { 2 } else { 4 }]);
+        self.store(reg(15), EAX);
+
+        match next {
+            Some(next) => self.store_imm32(field(prefetch + 4), next),
+            None => {
+                self.cpu_argument();
+                self.call(prefetch_next as *const ());
+            },
+        }
+    }
+
+    /// Jumps past the instruction when the ARM condition fails; returns the jumps to bind.
+    fn condition(&mut self, condition: u32) -> Vec<usize> {
+        let n = field(mem::offset_of!(Cpu, cpsr.n));
+        let z = field(mem::offset_of!(Cpu, cpsr.z));
+        let c = field(mem::offset_of!(Cpu, cpsr.c));
+        let v = field(mem::offset_of!(Cpu, cpsr.v));
+
+        let mut skip = vec!();
+
+        // flag set: skip when the byte is zero
+        let when = |emitter: &mut Emitter, flag: i32, set: bool, skip: &mut Vec<usize>| {
+            emitter.compare_imm8(flag, 0);
+            skip.push(emitter.jump_if(if set { EQUAL } else { NOT_EQUAL }));
+        };
+
+        // N == V: skip when they differ
+        let signed = |emitter: &mut Emitter, equal: bool| {
+            emitter.load_byte(EAX, n);
+            emitter.memory(&[0x3A], EAX, v);
+            emitter.jump_if(if equal { NOT_EQUAL } else { EQUAL })
+        };
+
+        match condition {
+            0x0 => when(self, z, true, &mut skip),
+            0x1 => when(self, z, false, &mut skip),
+            0x2 => when(self, c, true, &mut skip),
+            0x3 => when(self, c, false, &mut skip),
+            0x4 => when(self, n, true, &mut skip),
+            0x5 => when(self, n, false, &mut skip),
+            0x6 => when(self, v, true, &mut skip),
+            0x7 => when(self, v, false, &mut skip),
+            0x8 => {
+                when(self, c, true, &mut skip);
+                when(self, z, false, &mut skip);
+            },
+            0x9 => {
+                // C clear or Z set
+                self.compare_imm8(c, 0);
+                let run = self.jump_if(EQUAL);
+                when(self, z, true, &mut skip);
+                self.bind(vec!(run));
+            },
+            0xA => skip.push(signed(self, true)),
+            0xB => skip.push(signed(self, false)),
+            0xC => {
+                when(self, z, false, &mut skip);
+                skip.push(signed(self, true));
+            },
+            0xD => {
+                // Z set or N != V
+                self.compare_imm8(z, 0);
+                let run = self.jump_if(NOT_EQUAL);
+                skip.push(signed(self, false));
+                self.bind(vec!(run));
+            },
+            0xE => (),
+            _ => skip.push(self.jump()),
+        }
+
+        skip
+    }
+
+    fn call_handler(&mut self, handler: super::Handler, opcode: u32) {
+        self.cpu_argument();
+        // mov rsi, imm64; mov edx, imm32
+        self.bytes(&[0x48, 0xBE]);
+        self.imm64(handler as *const () as u64);
+        self.bytes(&[0xB8 + EDX]);
+        self.imm32(opcode);
+        self.call(call_handler as *const ());
+    }
+
+    /// Lets the idle loop detector see a branch the handler may have taken.
+    fn idle_branch(&mut self, pc: u32) {
+        self.compare_imm8(field(mem::offset_of!(Cpu, idle_skip)), 0);
+        let skip = self.jump_if(EQUAL);
+
+        self.cpu_argument();
+        // mov esi, imm32
+        self.bytes(&[0xBE]);
+        self.imm32(pc);
+        self.call(idle_branch as *const ());
+
+        self.bind(vec!(skip));
+    }
+
+    /// `eax` = the cycles of a data processing instruction, or a skipped one. Outside ROM
+    /// there is no prefetch buffer to track, so that is a table lookup.
+    fn code_ticks(&mut self, next_pc: u32, thumb: bool) {
+        let region = ((next_pc >> 24) & 15) as usize;
+
+        if (0x08..=0x0D).contains(&region) {
+            self.cpu_argument();
+            self.call(if thumb { code_ticks_thumb as *const () } else { code_ticks_arm as *const () });
+        } else if thumb {
+            self.store_imm32(field(mem::offset_of!(Cpu, bus_prefetch_count)), 0);
+            self.load_byte(EAX, field(mem::offset_of!(Cpu, memory_wait_seq) + region));
+            self.bytes(&[0x83, 0xC0, 0x01]);
+        } else {
+            self.load_byte(EAX, field(mem::offset_of!(Cpu, memory_wait_seq_32) + region));
+            self.bytes(&[0x83, 0xC0, 0x01]);
+        }
+    }
+
+    fn add_ticks(&mut self) {
+        self.memory(&[0x01], EAX, field(mem::offset_of!(Cpu, cpu_total_ticks)));
+    }
+
+    fn count_instruction(&mut self) {
+        // add qword [rbx + offset], 1
+        self.memory(&[0x48, 0x83], 0, field(mem::offset_of!(Cpu, profile.instructions)));
+        self.bytes(&[0x01]);
+    }
+
+    /// Leaves once the next event is due.
+    fn check_events(&mut self) {
+        self.load(EAX, field(mem::offset_of!(Cpu, cpu_total_ticks)));
+        self.memory(&[0x3B], EAX, field(mem::offset_of!(Cpu, cpu_next_event)));
+        self.exit_if(GREATER_EQUAL);
+    }
+
+    fn alu(&mut self, alu: &Alu) {
+        let c = field(mem::offset_of!(Cpu, cpsr.c));
+        let shifter_carry = alu.set_flags && alu.logical();
+
+        match alu.operand {
+            Operand::Immediate(value, carry) => {
+                self.bytes(&[0xB8 + ECX]);
+                self.imm32(value);
+
+                if let (true, Some(carry)) = (shifter_carry, carry) {
+                    self.store_imm8(c, carry as u8);
+                }
+            },
+            Operand::Register(rm, shift_type, amount) => {
+                self.load(ECX, reg(rm));
+
+                if amount != 0 {
+                    // shl, shr, sar, ror; CF is the last bit out, like the barrel shifter
+                    let extension = [4, 5, 7, 1][shift_type as usize];
+                    self.bytes(&[0xC1, 0xC0 | extension << 3 | ECX, amount as u8]);
+
+                    if shifter_carry {
+                        self.set(BELOW, c);
+                    }
+                }
+            },
+        }
+
+        if alu.op != 0xD && alu.op != 0xF {
+            self.load(EAX, reg(alu.rn));
+        }
+
+        // carry in for ADC, SBC and RSC: cmp sets CF to !C, cmc flips it back for ADC
+        if matches!(alu.op, 0x5 ..= 0x7) {
+            self.compare_imm8(c, 1);
+            if alu.op == 0x5 {
+                self.bytes(&[0xF5]);
+            }
+        }
+
+        match alu.op {
+            0x0 | 0x8 => self.register_op(0x21, EAX, ECX),
+            0x1 | 0x9 => self.register_op(0x31, EAX, ECX),
+            0x2 | 0xA => self.register_op(0x29, EAX, ECX),
+            0x3 => {
+                self.register_op(0x29, ECX, EAX);
+                self.register_op(0x89, EAX, ECX);
+            },
+            0x4 | 0xB => self.register_op(0x01, EAX, ECX),
+            0x5 => self.register_op(0x11, EAX, ECX),
+            0x6 => self.register_op(0x19, EAX, ECX),
+            0x7 => {
+                self.register_op(0x19, ECX, EAX);
+                self.register_op(0x89, EAX, ECX);
+            },
+            0xC => self.register_op(0x09, EAX, ECX),
+            0xD => self.register_op(0x89, EAX, ECX),
+            0xE => {
+                self.bytes(&[0xF7, 0xD0 | ECX]);
+                self.register_op(0x21, EAX, ECX);
+            },
+            _ => {
+                self.bytes(&[0xF7, 0xD0 | ECX]);
+                self.register_op(0x89, EAX, ECX);
+            },
+        }
+
+        if alu.set_flags {
+            if alu.op == 0xD || alu.op == 0xF {
+                // mov and not leave the flags alone
+                self.register_op(0x85, EAX, EAX);
+            }
+
+            if !alu.logical() {
+                // x86 borrows where ARM carries, so subtraction takes the inverse
+                let subtract = matches!(alu.op, 0x2 | 0x3 | 0x6 | 0x7 | 0xA);
+                self.set(OVERFLOW, field(mem::offset_of!(Cpu, cpsr.v)));
+                self.set(if subtract { ABOVE_EQUAL } else { BELOW }, c);
+            }
+
+            self.set(SIGN, field(mem::offset_of!(Cpu, cpsr.n)));
+            self.set(EQUAL, field(mem::offset_of!(Cpu, cpsr.z)));
+        }
+
+        if alu.op & 0xC != 0x8 {
+            self.store(reg(alu.rd), EAX);
+        }
+    }
+}
+
+impl Cpu {
+    /// Runs the block at `arm_next_pc` through its machine code once it is hot, through the
+    /// cached interpreter until then.
+    pub(super) fn cpu_run_jit(&mut self) {
+        let block = match self.cpu_lookup_block() {
+            Some(block) => block,
+            None => return self.cpu_step(),
+        };
+
+        let runs = block.native.runs.get();
+        if runs < HOT_RUNS {
+            block.native.runs.set(runs + 1);
+            return self.cpu_run_decoded(&block);
+        }
+
+        match *block.native.code.get_or_init(|| compile(&block)) {
+            Some(ref code) => {
+                let ticks = self.cpu_total_ticks;
+                code.run(self);
+
+                // every instruction takes a cycle, so nothing ran: the pipeline held
+                // different code than the block
+                if self.cpu_total_ticks == ticks {
+                    self.cpu_step();
+                }
+            },
+            None => self.cpu_step(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::mem_map::WRAM_SIZE;
+    use super::super::{Backend, Cpu};
+
+    const PROGRAMS: u32 = 24;
+    const LENGTH: u32 = 48;
+
+    /// xorshift64, fixed seed so a failure reproduces.
+    struct Rng(u64);
+
+    impl Rng {
+        fn u32(&mut self) -> u32 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 >> 32) as u32
+        }
+
+        fn below(&mut self, limit: u32) -> u32 {
+            self.u32() % limit
+        }
+    }
+
+    /// Data processing of every kind, with multiplies and register shifts mixed in so the
+    /// handler path runs between compiled instructions. No r15 destinations or memory access.
+    fn random_arm(rng: &mut Rng) -> u32 {
+        let cond = rng.below(15) << 28;
+        let register = |rng: &mut Rng| rng.below(15);
+
+        match rng.below(8) {
+            0 => cond | 0x00000090 | (rng.u32() & 0x00300000) | register(rng) << 16 | register(rng) << 12 | register(rng) << 8 | register(rng),
+            _ => {
+                let op = rng.below(16);
+                let set_flags = if op & 0xC == 0x8 { 1 } else { rng.below(2) };
+                let operand = if rng.below(2) == 0 { 0x02000000 | rng.u32() & 0xFFF } else { rng.u32() & 0xFFF };
+                // bit 7 in a register shift would make it a multiply or a transfer
+                let operand = if operand & 0x02000010 == 0x10 { operand & !0x80 } else { operand };
+
+                cond | op << 21 | set_flags << 20 | register(rng) << 16 | register(rng) << 12 | operand
+            },
+        }
+    }
+
+    /// Formats 1 to 5 without branches through r15.
+    fn random_thumb(rng: &mut Rng) -> u32 {
+        match rng.below(5) {
+            0 => rng.below(3) << 11 | rng.u32() & 0x07FF,
+            1 => 0x1800 | rng.u32() & 0x07FF,
+            2 => 0x2000 | rng.u32() & 0x1FFF,
+            3 => 0x4000 | rng.u32() & 0x03FF,
+            _ => {
+                let op = rng.below(3);
+                let rd = rng.below(15);
+                let rs = rng.below(16);
+                0x4400 | op << 8 | (rd & 8) << 4 | rs << 3 | rd & 7
+            },
+        }
+    }
+
+    /// Runs `program` in a loop at the start of IWRAM for a few frames and hashes the state.
+    fn run(backend: Backend, program: &[u32], thumb: bool, seed: u64) -> u64 {
+        let mut cpu = Cpu::new();
+        cpu.reset();
+        cpu.set_backend(backend);
+
+        let mut bytes = vec!();
+        for &opcode in program {
+            if thumb {
+                bytes.extend_from_slice(&(opcode as u16).to_le_bytes());
+            } else {
+                bytes.extend_from_slice(&opcode.to_le_bytes());
+            }
+        }
+        cpu.mem_map.writable_memory_mut()[WRAM_SIZE..WRAM_SIZE + bytes.len()].copy_from_slice(&bytes);
+
+        let mut rng = Rng(seed);
+        for index in 0..15 {
+            cpu.regs.r[index] = rng.u32();
+        }
+        cpu.cpsr.n = rng.below(2) == 0;
+        cpu.cpsr.z = rng.below(2) == 0;
+        cpu.cpsr.c = rng.below(2) == 0;
+        cpu.cpsr.v = rng.below(2) == 0;
+        cpu.cpsr.thumb = thumb;
+        cpu.regs.r[15] = 0x03000000;
+        cpu.cpu_jump();
+
+        for _ in 0..2 {
+            cpu.run_frame();
+        }
+
+        cpu.hash_state()
+    }
+
+    fn matches_interpreter(thumb: bool) {
+        let mut rng = Rng(0x2545F4914F6CDD1D);
+
+        for case in 0..PROGRAMS {
+            let mut program: Vec<u32> = (0..LENGTH).map(|_| if thumb { random_thumb(&mut rng) } else { random_arm(&mut rng) }).collect();
+
+            // and back to the start
+            let offset = (-(LENGTH as i32) - 2) as u32;
+            program.push(if thumb { 0xE000 | offset & 0x7FF } else { 0xEA000000 | offset & 0x00FFFFFF });
+
+            let seed = rng.u32() as u64 | 1;
+            let expected = run(Backend::Interpreter, &program, thumb, seed);
+            let actual = run(Backend::Jit, &program, thumb, seed);
+
+            assert!(expected == actual, "case {} differs from the interpreter, program {:08x?}", case, program);
+        }
+    }
+
+    #[test]
+    fn arm_blocks_match_interpreter() {
+        matches_interpreter(false);
+    }
+
+    #[test]
+    fn thumb_blocks_match_interpreter() {
+        matches_interpreter(true);
+    }
+}
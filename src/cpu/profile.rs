@@ -0,0 +1,189 @@
+use std::array;
+use std::cell::Cell;
+use std::time::{Duration, Instant};
+
+use super::Cpu;
+
+/// Counters `address >> 24` indexes into; everything above the SRAM region shares the last.
+pub const PROFILE_REGIONS: usize = 16;
+
+/// What the emulator spent its time on since profiling was turned on.
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    /// Instructions retired, whatever backend ran them. Counted even with profiling off.
+    pub instructions: u64,
+    /// Data reads and writes by the CPU and DMA, by `address >> 24`.
+    pub reads: [u64; PROFILE_REGIONS],
+    pub writes: [u64; PROFILE_REGIONS],
+    pub lcd_lines: u64,
+    pub lcd_time: Duration,
+    /// Stereo sample pairs mixed.
+    pub apu_samples: u64,
+    pub apu_time: Duration,
+    pub dma_transfers: u64,
+    /// Halfwords and words the transfers moved.
+    pub dma_units: u64,
+    pub dma_time: Duration,
+}
+
+/// The live counters behind `Profile`. Reads go through `&self`, hence the cells.
+pub struct Profiler {
+    pub(super) instructions: u64,
+    reads: [Cell<u64>; PROFILE_REGIONS],
+    writes: [Cell<u64>; PROFILE_REGIONS],
+    lcd_lines: u64,
+    lcd_time: Duration,
+    apu_samples: u64,
+    apu_time: Duration,
+    dma_transfers: u64,
+    dma_units: u64,
+    dma_time: Duration,
+}
+
+fn region(address: u32) -> usize {
+    ((address >> 24) as usize).min(PROFILE_REGIONS - 1)
+}
+
+fn bump(counter: &Cell<u64>) {
+    counter.set(counter.get() + 1);
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler {
+            instructions: 0,
+            reads: Default::default(),
+            writes: Default::default(),
+            lcd_lines: 0,
+            lcd_time: Duration::ZERO,
+            apu_samples: 0,
+            apu_time: Duration::ZERO,
+            dma_transfers: 0,
+            dma_units: 0,
+            dma_time: Duration::ZERO,
+        }
+    }
+
+    pub(super) fn count_read(&self, address: u32) {
+        bump(&self.reads[region(address)]);
+    }
+
+    pub(super) fn count_write(&self, address: u32) {
+        bump(&self.writes[region(address)]);
+    }
+
+    pub(super) fn count_line(&mut self, start: Instant) {
+        self.lcd_lines += 1;
+        self.lcd_time += start.elapsed();
+    }
+
+    pub(super) fn count_samples(&mut self, values: usize, start: Instant) {
+        self.apu_samples += values as u64 / 2;
+        self.apu_time += start.elapsed();
+    }
+
+    pub(super) fn count_dma(&mut self, units: u32, start: Instant) {
+        self.dma_transfers += 1;
+        self.dma_units += units as u64;
+        self.dma_time += start.elapsed();
+    }
+}
+
+impl Default for Profiler {
+    fn default() -> Profiler {
+        Profiler::new()
+    }
+}
+
+impl Cpu {
+    /// Starts counting memory accesses and timing the LCD, APU and DMA from zero, or stops.
+    /// Off by default, since every access and event pays for the bookkeeping.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.profiling = enabled;
+        self.profile = Profiler::new();
+    }
+
+    pub fn profile(&self) -> Profile {
+        let profile = &self.profile;
+
+        Profile {
+            instructions: profile.instructions,
+            reads: array::from_fn(|index| profile.reads[index].get()),
+            writes: array::from_fn(|index| profile.writes[index].get()),
+            lcd_lines: profile.lcd_lines,
+            lcd_time: profile.lcd_time,
+            apu_samples: profile.apu_samples,
+            apu_time: profile.apu_time,
+            dma_transfers: profile.dma_transfers,
+            dma_units: profile.dma_units,
+            dma_time: profile.dma_time,
+        }
+    }
+
+    /// When profiling, the time an event handler starts at.
+    pub(super) fn profile_start(&self) -> Option<Instant> {
+        if self.profiling {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::region;
+
+    /// One data access to each of a few regions, then a loop.
+    const PROGRAM: [u32; 10] = [
+        0xE3A00402, // mov r0, #0x02000000
+        0xE5800000, // str r0, [r0]
+        0xE5901000, // ldr r1, [r0]
+        0xE3A03301, // mov r3, #0x04000000
+        0xE1D320B0, // ldrh r2, [r3]
+        0xE3A0440E, // mov r4, #0x0e000000
+        0xE5C40000, // strb r0, [r4]
+        0xE3A054FF, // mov r5, #0xff000000
+        0xE5D56000, // ldrb r6, [r5]
+        0xEAFFFFFE, // b 0x03000024
+    ];
+
+    #[test]
+    fn regions() {
+        assert_eq!(region(0x00000000), 0);
+        assert_eq!(region(0x02FFFFFF), 2);
+        assert_eq!(region(0x08000000), 8);
+        assert_eq!(region(0x0DFFFFFF), 13);
+        assert_eq!(region(0x0E000000), 14);
+        assert_eq!(region(0x0F000000), 15);
+        assert_eq!(region(0x10000000), 15);
+        assert_eq!(region(0xFFFFFFFF), 15);
+    }
+
+    #[test]
+    fn counts_data_accesses() {
+        let mut cpu = super::super::tests::program_cpu(&PROGRAM);
+        cpu.set_profiling(true);
+        for _ in 0..PROGRAM.len() - 1 {
+            cpu.step_instruction();
+        }
+
+        let profile = cpu.profile();
+        assert_eq!(profile.instructions, PROGRAM.len() as u64 - 1);
+        let mut reads = [0; 16];
+        let mut writes = [0; 16];
+        reads[2] = 1;
+        writes[2] = 1;
+        reads[4] = 1;
+        writes[14] = 1;
+        reads[15] = 1;
+        // instruction fetches are not counted
+        assert_eq!(profile.reads, reads);
+        assert_eq!(profile.writes, writes);
+
+        cpu.set_profiling(false);
+        cpu.step_instruction();
+        assert_eq!(cpu.profile().reads, [0; 16]);
+        assert_eq!(cpu.profile().instructions, 1);
+    }
+}
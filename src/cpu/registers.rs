@@ -0,0 +1,256 @@
+use super::super::error::Error;
+use super::super::frame_hash::FrameHasher;
+use super::super::save_state::{StateError, StateReader, StateWriter};
+
+/// The ARM7TDMI processor modes. Every mode but User and System has its own r13, r14 and
+/// SPSR; FIQ banks r8-r12 as well.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    User,
+    Fiq,
+    Irq,
+    Supervisor,
+    Abort,
+    Undefined,
+    System,
+}
+
+impl Mode {
+    /// Decodes the low five bits of a PSR.
+    pub fn from_bits(bits: u32) -> Result<Mode, Error> {
+        match bits & 0x1F {
+            0x10 => Ok(Mode::User),
+            0x11 => Ok(Mode::Fiq),
+            0x12 => Ok(Mode::Irq),
+            0x13 => Ok(Mode::Supervisor),
+            0x17 => Ok(Mode::Abort),
+            0x1B => Ok(Mode::Undefined),
+            0x1F => Ok(Mode::System),
+            bits => Err(Error::InvalidMode(bits)),
+        }
+    }
+
+    pub fn bits(self) -> u32 {
+        match self {
+            Mode::User => 0x10,
+            Mode::Fiq => 0x11,
+            Mode::Irq => 0x12,
+            Mode::Supervisor => 0x13,
+            Mode::Abort => 0x17,
+            Mode::Undefined => 0x1B,
+            Mode::System => 0x1F,
+        }
+    }
+
+    pub fn privileged(self) -> bool {
+        self != Mode::User
+    }
+
+    /// Index of the r13/r14/SPSR bank; User and System share bank 0, which has no SPSR.
+    fn bank(self) -> usize {
+        match self {
+            Mode::User | Mode::System => 0,
+            Mode::Fiq => 1,
+            Mode::Irq => 2,
+            Mode::Supervisor => 3,
+            Mode::Abort => 4,
+            Mode::Undefined => 5,
+        }
+    }
+}
+
+/// A program status register, unpacked. The reserved bits are not kept; they read as zero.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Psr {
+    pub n: bool,
+    pub z: bool,
+    pub c: bool,
+    pub v: bool,
+    pub irq_disabled: bool,
+    pub fiq_disabled: bool,
+    pub thumb: bool,
+    pub mode: Mode,
+}
+
+impl Psr {
+    pub fn from_bits(bits: u32) -> Result<Psr, Error> {
+        Ok(Psr {
+            n: bits & 0x80000000 != 0,
+            z: bits & 0x40000000 != 0,
+            c: bits & 0x20000000 != 0,
+            v: bits & 0x10000000 != 0,
+            irq_disabled: bits & 0x80 != 0,
+            fiq_disabled: bits & 0x40 != 0,
+            thumb: bits & 0x20 != 0,
+            mode: Mode::from_bits(bits)?,
+        })
+    }
+
+    pub fn bits(self) -> u32 {
+        (self.n as u32) << 31
+            | (self.z as u32) << 30
+            | (self.c as u32) << 29
+            | (self.v as u32) << 28
+            | (self.irq_disabled as u32) << 7
+            | (self.fiq_disabled as u32) << 6
+            | (self.thumb as u32) << 5
+            | self.mode.bits()
+    }
+
+    /// Sets N and Z from a result, as every flag setting instruction does.
+    pub fn set_nz(&mut self, value: u32) {
+        self.n = value & 0x80000000 != 0;
+        self.z = value == 0;
+    }
+
+    /// Evaluates the condition field of an instruction.
+    pub fn condition(self, cond: u32) -> bool {
+        match cond {
+            0x0 => self.z,
+            0x1 => !self.z,
+            0x2 => self.c,
+            0x3 => !self.c,
+            0x4 => self.n,
+            0x5 => !self.n,
+            0x6 => self.v,
+            0x7 => !self.v,
+            0x8 => self.c && !self.z,
+            0x9 => !self.c || self.z,
+            0xA => self.n == self.v,
+            0xB => self.n != self.v,
+            0xC => !self.z && self.n == self.v,
+            0xD => self.z || self.n != self.v,
+            0xE => true,
+            _ => false,
+        }
+    }
+}
+
+impl Default for Psr {
+    fn default() -> Psr {
+        Psr {
+            n: false,
+            z: false,
+            c: false,
+            v: false,
+            irq_disabled: false,
+            fiq_disabled: false,
+            thumb: false,
+            mode: Mode::System,
+        }
+    }
+}
+
+/// The general purpose registers and SPSRs. `r` is r0-r15 as the current mode sees them,
+/// so the interpreter indexes it directly; the other modes' copies are swapped in and out by
+/// `switch_mode`. The CPSR lives with the CPU, which passes its mode in.
+#[derive(Clone)]
+pub struct Registers {
+    pub r: [u32; 16],
+    /// FIQ's r8-r12 while another mode is active, everyone else's while in FIQ.
+    other_r8_r12: [u32; 5],
+    /// r13 and r14 of each bank. The active bank's copy is stale; `r` holds the live one.
+    banked: [[u32; 2]; 6],
+    spsr: [Psr; 6],
+}
+
+impl Registers {
+    pub fn new() -> Registers {
+        Registers {
+            r: [0; 16],
+            other_r8_r12: [0; 5],
+            banked: [[0; 2]; 6],
+            spsr: [Psr::default(); 6],
+        }
+    }
+
+    /// Banks out `from`'s registers and brings in `to`'s.
+    pub fn switch_mode(&mut self, from: Mode, to: Mode) {
+        if from.bank() == to.bank() {
+            return;
+        }
+
+        self.banked[from.bank()] = [self.r[13], self.r[14]];
+
+        if (from == Mode::Fiq) != (to == Mode::Fiq) {
+            for (live, other) in self.r[8..13].iter_mut().zip(self.other_r8_r12.iter_mut()) {
+                ::std::mem::swap(live, other);
+            }
+        }
+
+        let [r13, r14] = self.banked[to.bank()];
+        self.r[13] = r13;
+        self.r[14] = r14;
+    }
+
+    /// A register as `mode` sees it, where `current` is the active mode.
+    pub fn get_in(&self, current: Mode, mode: Mode, index: usize) -> u32 {
+        match index {
+            8..=12 if (current == Mode::Fiq) != (mode == Mode::Fiq) => self.other_r8_r12[index - 8],
+            13 | 14 if current.bank() != mode.bank() => self.banked[mode.bank()][index - 13],
+            _ => self.r[index],
+        }
+    }
+
+    pub fn set_in(&mut self, current: Mode, mode: Mode, index: usize, value: u32) {
+        match index {
+            8..=12 if (current == Mode::Fiq) != (mode == Mode::Fiq) => self.other_r8_r12[index - 8] = value,
+            13 | 14 if current.bank() != mode.bank() => self.banked[mode.bank()][index - 13] = value,
+            _ => self.r[index] = value,
+        }
+    }
+
+    /// The SPSR of `mode`, or `None` for User and System, which have none.
+    pub fn spsr(&self, mode: Mode) -> Option<Psr> {
+        match mode.bank() {
+            0 => None,
+            bank => Some(self.spsr[bank]),
+        }
+    }
+
+    /// Writing the SPSR of User or System does nothing.
+    pub fn set_spsr(&mut self, mode: Mode, psr: Psr) {
+        match mode.bank() {
+            0 => (),
+            bank => self.spsr[bank] = psr,
+        }
+    }
+
+    pub fn hash(&self, hasher: &mut FrameHasher) {
+        for &value in self.r.iter().chain(self.other_r8_r12.iter()).chain(self.banked.iter().flat_map(|bank| bank.iter())) {
+            hasher.write_u32(value);
+        }
+
+        for spsr in &self.spsr[1..] {
+            hasher.write_u32(spsr.bits());
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for &value in self.r.iter().chain(self.other_r8_r12.iter()).chain(self.banked.iter().flat_map(|bank| bank.iter())) {
+            writer.write_u32(value);
+        }
+
+        for spsr in &self.spsr[1..] {
+            writer.write_u32(spsr.bits());
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for value in self.r.iter_mut().chain(self.other_r8_r12.iter_mut()).chain(self.banked.iter_mut().flat_map(|bank| bank.iter_mut())) {
+            *value = reader.read_u32()?;
+        }
+
+        for i in 1..6 {
+            self.spsr[i] = Psr::from_bits(reader.read_u32()?).map_err(|_| reader.corrupt())?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
@@ -0,0 +1,304 @@
+use super::{Cpu, LCD_HDRAW_TICKS};
+use super::cache::BlockCache;
+use super::registers::Psr;
+use super::save_state::{State, StateError, StateReader, StateWriter};
+
+const SECTIONS: [&str; 7] = ["CPU ", "MEM ", "LCD ", "APU ", "TMR ", "KEY ", "SIO "];
+
+impl Cpu {
+    /// Serializes the whole machine. BIOS and ROM are not included; the header carries the
+    /// ROM checksum instead so the state can only be loaded back into the same game.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new(self.rom_checksum);
+
+        writer.begin_section("CPU ");
+        self.cpu_save_state(&mut writer);
+        writer.end_section();
+
+        writer.begin_section("MEM ");
+        writer.write_bytes(self.mem_map.writable_memory());
+        writer.end_section();
+
+        writer.begin_section("LCD ");
+        self.lcd.save_state(&mut writer);
+        writer.end_section();
+
+        writer.begin_section("APU ");
+        self.apu.save_state(&mut writer);
+        writer.end_section();
+
+        writer.begin_section("TMR ");
+        self.timers.save_state(&mut writer);
+        writer.end_section();
+
+        writer.begin_section("KEY ");
+        self.keypad.save_state(&mut writer);
+        writer.end_section();
+
+        writer.begin_section("SIO ");
+        self.serial.save_state(&mut writer);
+        writer.end_section();
+
+        writer.finish()
+    }
+
+    /// Restores a state made by `save_state`. On error the machine is left as it was.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let state = State::parse(data)?;
+
+        if state.rom_checksum != self.rom_checksum {
+            return Err(StateError::RomMismatch { found: state.rom_checksum, expected: self.rom_checksum });
+        }
+
+        state.check_sections(&SECTIONS)?;
+
+        let backup = self.save_state();
+
+        if let Err(e) = self.cpu_load_sections(&state) {
+            let backup = State::parse(&backup).expect("own state must parse");
+            self.cpu_load_sections(&backup).expect("own state must load");
+            return Err(e);
+        }
+
+        Ok(())
+    }
+
+    fn cpu_load_sections(&mut self, state: &State) -> Result<(), StateError> {
+        self.block_cache = BlockCache::new();
+        self.cpu_load_state(&mut state.section("CPU ")?)?;
+
+        let mut memory = state.section("MEM ")?;
+        memory.read_bytes(self.mem_map.writable_memory_mut())?;
+
+        // the wait state tables and the prefetch enable follow from WAITCNT
+        let waitcnt = self.mem_map.read_io_16(0x204);
+        self.cpu_set_wait_states(waitcnt);
+        if !self.bus_prefetch_enable && (self.bus_prefetch || self.bus_prefetch_count != 0) {
+            return Err(StateError::Corrupt("CPU "));
+        }
+
+        self.lcd.load_state(&mut state.section("LCD ")?)?;
+        self.apu.load_state(&mut state.section("APU ")?)?;
+        self.timers.load_state(&mut state.section("TMR ")?)?;
+        self.keypad.load_state(&mut state.section("KEY ")?)?;
+        self.serial.load_state(&mut state.section("SIO ")?)?;
+
+        Ok(())
+    }
+
+    fn cpu_save_state(&self, writer: &mut StateWriter) {
+        self.regs.save_state(writer);
+        writer.write_u32(self.cpsr.bits());
+
+        writer.write_u32(self.cpu_prefetch[0]);
+        writer.write_u32(self.cpu_prefetch[1]);
+        writer.write_u32(self.arm_next_pc);
+
+        writer.write_bool(self.bus_prefetch);
+        writer.write_u32(self.bus_prefetch_count);
+
+        writer.write_u16(self.g_ie);
+        writer.write_u16(self.g_if);
+        writer.write_u16(self.g_ime);
+
+        writer.write_i32(self.cpu_next_event);
+        writer.write_i32(self.cpu_total_ticks);
+        writer.write_bool(self.halt_state);
+        writer.write_bool(self.stop_state);
+
+        writer.write_i32(self.lcd_ticks);
+        writer.write_u32(self.frame_count);
+        writer.write_i32(self.timer_ticks);
+
+        for ch in 0..4 {
+            writer.write_u32(self.dma_source[ch]);
+            writer.write_u32(self.dma_dest[ch]);
+        }
+
+        writer.write_bytes(&self.bios_protected);
+    }
+
+    fn cpu_load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.regs.load_state(reader)?;
+        self.cpsr = Psr::from_bits(reader.read_u32()?).map_err(|_| reader.corrupt())?;
+
+        self.cpu_prefetch[0] = reader.read_u32()?;
+        self.cpu_prefetch[1] = reader.read_u32()?;
+        self.arm_next_pc = reader.read_u32()?;
+
+        self.bus_prefetch = reader.read_bool()?;
+        self.bus_prefetch_count = reader.read_u32()?;
+
+        self.g_ie = reader.read_u16()?;
+        self.g_if = reader.read_u16()?;
+        self.g_ime = reader.read_u16()?;
+
+        self.cpu_next_event = reader.read_i32()?;
+        self.cpu_total_ticks = reader.read_i32()?;
+        self.halt_state = reader.read_bool()?;
+        self.stop_state = reader.read_bool()?;
+
+        self.lcd_ticks = reader.read_i32()?;
+        self.frame_count = reader.read_u32()?;
+        self.timer_ticks = reader.read_i32()?;
+
+        // the scheduler only ever runs up to the next LCD event, and timers catch up within it
+        if !(1..=LCD_HDRAW_TICKS).contains(&self.lcd_ticks)
+            || !(0..=self.lcd_ticks).contains(&self.cpu_next_event)
+            || !(0..=self.cpu_total_ticks).contains(&self.timer_ticks) {
+            return Err(reader.corrupt());
+        }
+
+        for ch in 0..4 {
+            self.dma_source[ch] = reader.read_u32()?;
+            self.dma_dest[ch] = reader.read_u32()?;
+        }
+
+        reader.read_bytes(&mut self.bios_protected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::super::mem_map::{IWRAM_SIZE, WRAM_SIZE};
+    use super::super::super::save_state::StateError;
+    use super::super::Cpu;
+    use super::super::tests::program_cpu;
+
+    /// Counts in r0 from the start of IWRAM with a sweeping square wave playing, a few
+    /// frames in.
+    fn running_cpu() -> Cpu {
+        // add r0, r0, #1; b 0x03000000
+        let mut cpu = program_cpu(&[0xE2800001, 0xEAFFFFFD]);
+
+        cpu.cpu_update_register(0x084, 0x0080);
+        cpu.cpu_update_register(0x080, 0x1177);
+        cpu.cpu_update_register(0x082, 0x0002);
+        cpu.cpu_update_register(0x060, 0x0019);
+        cpu.cpu_update_register(0x062, 0xF080);
+        cpu.cpu_update_register(0x064, 0x8400);
+
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+        cpu
+    }
+
+    /// Where the data of section `tag` starts, past the 14 byte header and the sections
+    /// before it.
+    fn section(state: &[u8], tag: &str) -> (usize, usize) {
+        let mut position = 14;
+
+        loop {
+            let length = u32::from_le_bytes([state[position + 4], state[position + 5], state[position + 6], state[position + 7]]) as usize;
+            if &state[position..position + 4] == tag.as_bytes() {
+                return (position + 8, length);
+            }
+            position += 8 + length;
+        }
+    }
+
+    #[test]
+    fn save_then_load() {
+        let mut cpu = running_cpu();
+        let state = cpu.save_state();
+
+        let run = |cpu: &mut Cpu| {
+            for _ in 0..5 {
+                cpu.run_frame();
+            }
+            (cpu.hash_state(), cpu.audio_samples().to_vec())
+        };
+
+        let expected = run(&mut cpu);
+        assert!(expected.1.iter().any(|&sample| sample != 0), "the square wave should be audible");
+
+        cpu.load_state(&state).unwrap();
+        assert_eq!(run(&mut cpu), expected);
+    }
+
+    #[test]
+    fn corrupt_apu_section() {
+        let mut cpu = running_cpu();
+
+        // out of range square channel fields are masked as the registers would mask them
+        let mut state = cpu.save_state();
+        let (apu, length) = section(&state, "APU ");
+        let square = apu + 0x30;
+        state[square + 3..square + 5].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        state[square + 17] = 0xFF;
+        state[square + 19] = 0xFF;
+        state[square + 22..square + 24].copy_from_slice(&0xFFFFu16.to_le_bytes());
+        cpu.load_state(&state).unwrap();
+        for _ in 0..3 {
+            cpu.run_frame();
+        }
+
+        // counters that would stall the APU are refused, leaving the machine as it was
+        let before = cpu.hash_state();
+        let good = cpu.save_state();
+
+        let mut state = good.clone();
+        state[apu + length - 4..apu + length].copy_from_slice(&i32::MAX.to_le_bytes());
+        match cpu.load_state(&state) {
+            Err(StateError::Corrupt("APU ")) => (),
+            other => panic!("sample counter accepted: {:?}", other),
+        }
+
+        let mut state = good.clone();
+        state[square + 5..square + 9].copy_from_slice(&(-1i32).to_le_bytes());
+        match cpu.load_state(&state) {
+            Err(StateError::Corrupt("APU ")) => (),
+            other => panic!("negative channel timer accepted: {:?}", other),
+        }
+
+        assert_eq!(cpu.hash_state(), before);
+    }
+
+    #[test]
+    fn corrupt_cpu_section() {
+        let mut cpu = running_cpu();
+        let before = cpu.hash_state();
+        let good = cpu.save_state();
+
+        // from the end: BIOS latch, DMA addresses, timer_ticks, frame_count, lcd_ticks, the
+        // halt and stop flags, cpu_total_ticks, cpu_next_event, IE/IF/IME, bus_prefetch_count
+        let (cpu_section, length) = section(&good, "CPU ");
+        let timer_ticks = cpu_section + length - 4 - 32 - 4;
+        let lcd_ticks = timer_ticks - 8;
+        let next_event = lcd_ticks - 2 - 8;
+        let prefetch_count = next_event - 6 - 4;
+
+        let corruptions: [(usize, u32, &str); 5] = [
+            (lcd_ticks, i32::MAX as u32, "LCD countdown"),
+            (lcd_ticks, 0, "LCD countdown"),
+            (next_event, -1i32 as u32, "next event"),
+            (timer_ticks, -5i32 as u32, "timer catch-up"),
+            (prefetch_count, 0xFFFFFFFF, "prefetch count with prefetch off"),
+        ];
+        for &(offset, value, what) in corruptions.iter() {
+            let mut state = good.clone();
+            state[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            match cpu.load_state(&state) {
+                Err(StateError::Corrupt("CPU ")) => (),
+                other => panic!("bad {} accepted: {:?}", what, other),
+            }
+        }
+        assert_eq!(cpu.hash_state(), before);
+
+        // the wait state tables come from WAITCNT in the IO registers
+        let (memory, _) = section(&good, "MEM ");
+        let waitcnt = memory + WRAM_SIZE + IWRAM_SIZE + 0x204;
+        let mut state = good.clone();
+        state[waitcnt..waitcnt + 2].copy_from_slice(&0x4317u16.to_le_bytes());
+        cpu.load_state(&state).unwrap();
+
+        let mut expected = running_cpu();
+        expected.cpu_update_register(0x204, 0x4317);
+        assert_eq!(cpu.memory_wait, expected.memory_wait);
+        assert_eq!(cpu.memory_wait_32, expected.memory_wait_32);
+        assert_eq!(cpu.memory_wait_seq, expected.memory_wait_seq);
+        assert_eq!(cpu.memory_wait_seq_32, expected.memory_wait_seq_32);
+        assert!(cpu.bus_prefetch_enable);
+    }
+}
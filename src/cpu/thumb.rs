@@ -0,0 +1,528 @@
+use super::{Cpu, Handler};
+
+impl Cpu {
+    pub(super) fn thumb_execute(&mut self) -> i32 {
+        let opcode = self.thumb_fetch(None);
+
+        Cpu::thumb_decode(opcode)(self, opcode)
+    }
+
+    /// Moves the pipeline on by one instruction and returns the one to execute. The opcode
+    /// two instructions ahead is read from memory unless the caller already knows it.
+    pub(super) fn thumb_fetch(&mut self, prefetched: Option<u32>) -> u32 {
+        let opcode = self.cpu_prefetch[0];
+        self.cpu_prefetch[0] = self.cpu_prefetch[1];
+
+        self.bus_prefetch = self.bus_prefetch_enable && self.arm_next_pc >> 24 >= 0x08;
+
+        self.arm_next_pc = self.regs.r[15];
+        self.regs.r[15] = self.arm_next_pc + 2;
+        self.cpu_prefetch[1] = match prefetched {
+            Some(opcode) => opcode,
+            None => self.mem_map.read_16(self.arm_next_pc + 2, self.bios_protected, self.regs.r[15]) as u32,
+        };
+
+        opcode
+    }
+
+    pub(super) fn thumb_decode(opcode: u32) -> Handler {
+        match opcode >> 13 {
+            0b000 => {
+                if (opcode >> 11) & 3 == 3 {
+                    Cpu::thumb_add_sub
+                } else {
+                    Cpu::thumb_shift
+                }
+            },
+            0b001 => Cpu::thumb_immediate,
+            0b010 => {
+                if opcode >> 10 == 0b010000 {
+                    Cpu::thumb_alu
+                } else if opcode >> 10 == 0b010001 {
+                    Cpu::thumb_hi_register
+                } else if opcode >> 11 == 0b01001 {
+                    Cpu::thumb_pc_relative_load
+                } else {
+                    Cpu::thumb_register_offset
+                }
+            },
+            0b011 => Cpu::thumb_immediate_offset,
+            0b100 => {
+                if opcode & 0x1000 == 0 {
+                    Cpu::thumb_halfword_offset
+                } else {
+                    Cpu::thumb_sp_relative
+                }
+            },
+            0b101 => {
+                if opcode & 0x1000 == 0 {
+                    Cpu::thumb_load_address
+                } else if opcode & 0x0F00 == 0x0000 {
+                    Cpu::thumb_add_sp
+                } else if opcode & 0x0600 == 0x0400 {
+                    Cpu::thumb_push_pop
+                } else {
+                    Cpu::cpu_undefined_opcode
+                }
+            },
+            0b110 => {
+                if opcode & 0x1000 == 0 {
+                    Cpu::thumb_block_transfer
+                } else if opcode & 0x0F00 == 0x0F00 {
+                    Cpu::cpu_software_interrupt_opcode
+                } else if opcode & 0x0F00 == 0x0E00 {
+                    Cpu::cpu_undefined_opcode
+                } else {
+                    Cpu::thumb_conditional_branch
+                }
+            },
+            _ => {
+                match opcode & 0x1800 {
+                    0x0000 => Cpu::thumb_branch,
+                    0x1000 => Cpu::thumb_long_branch_high,
+                    0x1800 => Cpu::thumb_long_branch_low,
+                    _ => Cpu::cpu_undefined_opcode,
+                }
+            },
+        }
+    }
+
+    fn thumb_ticks(&mut self) -> i32 {
+        1 + self.code_ticks_access_seq_16(self.arm_next_pc as usize) as i32
+    }
+
+    fn thumb_branch_ticks(&mut self) -> i32 {
+        let seq = self.code_ticks_access_seq_16(self.arm_next_pc as usize) as i32;
+        let non_seq = self.code_ticks_access_16(self.arm_next_pc as usize) as i32;
+
+        3 + non_seq + seq * 2
+    }
+
+    fn thumb_shift(&mut self, opcode: u32) -> i32 {
+        let rs = ((opcode >> 3) & 7) as usize;
+        let rd = (opcode & 7) as usize;
+
+        let (value, carry) = self.barrel_shift((opcode >> 11) & 3, self.regs.r[rs], (opcode >> 6) & 0x1F, true);
+        self.regs.r[rd] = value;
+        self.cpsr.set_nz(value);
+        self.cpsr.c = carry;
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_add_sub(&mut self, opcode: u32) -> i32 {
+        let rs = self.regs.r[((opcode >> 3) & 7) as usize];
+        let rd = (opcode & 7) as usize;
+
+        let operand = if opcode & 0x0400 != 0 {
+            (opcode >> 6) & 7
+        } else {
+            self.regs.r[((opcode >> 6) & 7) as usize]
+        };
+
+        let value = if opcode & 0x0200 != 0 {
+            self.alu_sub(rs, operand, true, true)
+        } else {
+            self.alu_add(rs, operand, false, true)
+        };
+        self.regs.r[rd] = value;
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_immediate(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode >> 8) & 7) as usize;
+        let operand = opcode & 0xFF;
+        let value = self.regs.r[rd];
+
+        match (opcode >> 11) & 3 {
+            0 => {
+                self.regs.r[rd] = operand;
+                self.cpsr.set_nz(operand);
+            },
+            1 => {
+                self.alu_sub(value, operand, true, true);
+            },
+            2 => {
+                let result = self.alu_add(value, operand, false, true);
+                self.regs.r[rd] = result;
+            },
+            _ => {
+                let result = self.alu_sub(value, operand, true, true);
+                self.regs.r[rd] = result;
+            },
+        }
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_alu(&mut self, opcode: u32) -> i32 {
+        let rs = self.regs.r[((opcode >> 3) & 7) as usize];
+        let rd = (opcode & 7) as usize;
+        let value = self.regs.r[rd];
+        let carry = self.cpsr.c;
+
+        let mut clock_ticks = self.thumb_ticks();
+
+        let result = match (opcode >> 6) & 0xF {
+            0x0 => Some(value & rs),
+            0x1 => Some(value ^ rs),
+            op @ 0x2 ..= 0x4 | op @ 0x7 => {
+                let shift_type = if op == 0x7 { 3 } else { op - 2 };
+                let (result, carry) = self.barrel_shift(shift_type, value, rs & 0xFF, false);
+                self.cpsr.c = carry;
+                clock_ticks += 1;
+                Some(result)
+            },
+            0x5 => {
+                let result = self.alu_add(value, rs, carry, true);
+                self.regs.r[rd] = result;
+                None
+            },
+            0x6 => {
+                let result = self.alu_sub(value, rs, carry, true);
+                self.regs.r[rd] = result;
+                None
+            },
+            0x8 => {
+                self.cpsr.set_nz(value & rs);
+                None
+            },
+            0x9 => {
+                let result = self.alu_sub(0, rs, true, true);
+                self.regs.r[rd] = result;
+                None
+            },
+            0xA => {
+                self.alu_sub(value, rs, true, true);
+                None
+            },
+            0xB => {
+                self.alu_add(value, rs, false, true);
+                None
+            },
+            0xC => Some(value | rs),
+            0xD => {
+                clock_ticks += self.multiply_ticks(value);
+                Some(value.wrapping_mul(rs))
+            },
+            0xE => Some(value & !rs),
+            _ => Some(!rs),
+        };
+
+        if let Some(result) = result {
+            self.regs.r[rd] = result;
+            self.cpsr.set_nz(result);
+        }
+
+        clock_ticks
+    }
+
+    fn thumb_hi_register(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode & 7) | ((opcode >> 4) & 8)) as usize;
+        let rs = ((opcode >> 3) & 0xF) as usize;
+        let value = self.regs.r[rs];
+
+        match (opcode >> 8) & 3 {
+            0 => {
+                let result = self.regs.r[rd].wrapping_add(value);
+                self.regs.r[rd] = result;
+            },
+            1 => {
+                let rd_value = self.regs.r[rd];
+                self.alu_sub(rd_value, value, true, true);
+                return self.thumb_ticks();
+            },
+            2 => self.regs.r[rd] = value,
+            _ => {
+                self.cpsr.thumb = value & 1 != 0;
+                self.regs.r[15] = value;
+                self.cpu_jump();
+                return self.thumb_branch_ticks();
+            },
+        }
+
+        if rd == 15 {
+            self.cpu_jump();
+            self.thumb_branch_ticks()
+        } else {
+            self.thumb_ticks()
+        }
+    }
+
+    fn thumb_pc_relative_load(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode >> 8) & 7) as usize;
+        let address = (self.regs.r[15] & !2) + ((opcode & 0xFF) << 2);
+
+        let value = self.cpu_read_32(address);
+        self.regs.r[rd] = value;
+
+        3 + self.data_ticks_access_32(address as usize) as i32 + self.code_ticks_access_16(self.arm_next_pc as usize) as i32
+    }
+
+    fn thumb_register_offset(&mut self, opcode: u32) -> i32 {
+        let address = self.regs.r[((opcode >> 3) & 7) as usize].wrapping_add(self.regs.r[((opcode >> 6) & 7) as usize]);
+        let rd = (opcode & 7) as usize;
+
+        let op = if opcode & 0x0200 == 0 {
+            // LDR/STR with byte flag
+            (opcode >> 10) & 3
+        } else {
+            // sign extended and halfword forms
+            4 + ((opcode >> 10) & 3)
+        };
+
+        self.thumb_transfer(op, address, rd)
+    }
+
+    fn thumb_immediate_offset(&mut self, opcode: u32) -> i32 {
+        let byte = opcode & 0x1000 != 0;
+        let offset = ((opcode >> 6) & 0x1F) << if byte { 0 } else { 2 };
+        let address = self.regs.r[((opcode >> 3) & 7) as usize].wrapping_add(offset);
+        let rd = (opcode & 7) as usize;
+
+        let op = ((opcode >> 11) & 1) << 1 | byte as u32;
+
+        self.thumb_transfer(op, address, rd)
+    }
+
+    fn thumb_halfword_offset(&mut self, opcode: u32) -> i32 {
+        let address = self.regs.r[((opcode >> 3) & 7) as usize].wrapping_add(((opcode >> 6) & 0x1F) << 1);
+        let rd = (opcode & 7) as usize;
+
+        let op = if opcode & 0x0800 != 0 { 6 } else { 4 };
+
+        self.thumb_transfer(op, address, rd)
+    }
+
+    fn thumb_sp_relative(&mut self, opcode: u32) -> i32 {
+        let address = self.regs.r[13].wrapping_add((opcode & 0xFF) << 2);
+        let rd = ((opcode >> 8) & 7) as usize;
+
+        let op = ((opcode >> 11) & 1) << 1;
+
+        self.thumb_transfer(op, address, rd)
+    }
+
+    /// op: 0 STR, 1 STRB, 2 LDR, 3 LDRB, 4 STRH, 5 LDSB, 6 LDRH, 7 LDSH
+    fn thumb_transfer(&mut self, op: u32, address: u32, rd: usize) -> i32 {
+        let clock_ticks = match op {
+            0 | 2 => self.data_ticks_access_32(address as usize) as i32,
+            _ => self.data_ticks_access_16(address as usize) as i32,
+        };
+
+        let value = self.regs.r[rd];
+
+        match op {
+            0 => self.cpu_write_32(address & !3, value),
+            1 => self.cpu_write_8(address, value as u8),
+            4 => self.cpu_write_16(address, value as u16),
+            _ => {
+                let value = match op {
+                    2 => self.cpu_read_32(address),
+                    3 => self.cpu_read_8(address),
+                    5 => self.cpu_read_8(address) as u8 as i8 as u32,
+                    6 => self.cpu_read_16(address),
+                    _ => self.cpu_read_16_signed(address),
+                };
+                self.regs.r[rd] = value;
+
+                return clock_ticks + 3 + self.code_ticks_access_16(self.arm_next_pc as usize) as i32;
+            },
+        }
+
+        clock_ticks + 2 + self.code_ticks_access_16(self.arm_next_pc as usize) as i32
+    }
+
+    fn thumb_load_address(&mut self, opcode: u32) -> i32 {
+        let rd = ((opcode >> 8) & 7) as usize;
+        let base = if opcode & 0x0800 != 0 {
+            self.regs.r[13]
+        } else {
+            self.regs.r[15] & !2
+        };
+
+        self.regs.r[rd] = base.wrapping_add((opcode & 0xFF) << 2);
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_add_sp(&mut self, opcode: u32) -> i32 {
+        let offset = (opcode & 0x7F) << 2;
+        let sp = self.regs.r[13];
+
+        if opcode & 0x80 != 0 {
+            self.regs.r[13] = sp.wrapping_sub(offset);
+        } else {
+            self.regs.r[13] = sp.wrapping_add(offset);
+        }
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_push_pop(&mut self, opcode: u32) -> i32 {
+        let load = opcode & 0x0800 != 0;
+        let extra = opcode & 0x0100 != 0;
+        let rlist = opcode & 0xFF;
+        let count = self.cpu_bits_set[rlist as usize] as u32 + extra as u32;
+
+        let sp = self.regs.r[13];
+        let mut address = if load { sp } else { sp.wrapping_sub(count * 4) };
+        let mut clock_ticks = 0;
+        let mut first = true;
+
+        for reg in 0..8 {
+            if rlist & (1 << reg) == 0 {
+                continue;
+            }
+
+            clock_ticks += self.thumb_block_ticks(address, first);
+            if load {
+                let value = self.cpu_read_32(address & !3);
+                self.regs.r[reg] = value;
+            } else {
+                let value = self.regs.r[reg];
+                self.cpu_write_32(address & !3, value);
+            }
+
+            address = address.wrapping_add(4);
+            first = false;
+        }
+
+        if extra {
+            clock_ticks += self.thumb_block_ticks(address, first);
+            if load {
+                let value = self.cpu_read_32(address & !3);
+                self.regs.r[15] = value;
+            } else {
+                let value = self.regs.r[14];
+                self.cpu_write_32(address & !3, value);
+            }
+        }
+
+        if load {
+            self.regs.r[13] = sp.wrapping_add(count * 4);
+        } else {
+            self.regs.r[13] = sp.wrapping_sub(count * 4);
+        }
+
+        clock_ticks += 2 + self.code_ticks_access_16(self.arm_next_pc as usize) as i32;
+
+        if load && extra {
+            self.cpu_jump();
+            clock_ticks += 1 + self.thumb_branch_ticks();
+        }
+
+        clock_ticks
+    }
+
+    fn thumb_block_transfer(&mut self, opcode: u32) -> i32 {
+        let load = opcode & 0x0800 != 0;
+        let rb = ((opcode >> 8) & 7) as usize;
+
+        // an empty list transfers r15 and moves the base by 0x40
+        let (rlist, count) = match opcode & 0xFF {
+            0 => (0x8000, 16),
+            rlist => (rlist, self.cpu_bits_set[rlist as usize] as u32),
+        };
+
+        let base = self.regs.r[rb];
+        let new_base = base.wrapping_add(count * 4);
+        let mut address = base;
+        let mut clock_ticks = 0;
+        let mut first = true;
+
+        if load {
+            self.regs.r[rb] = new_base;
+        }
+
+        for reg in 0..16 {
+            if rlist & (1 << reg) == 0 {
+                continue;
+            }
+
+            clock_ticks += self.thumb_block_ticks(address, first);
+            if load {
+                let value = self.cpu_read_32(address & !3);
+                self.regs.r[reg] = value;
+            } else {
+                let value = if reg == rb && !first {
+                    new_base
+                } else if reg == 15 {
+                    self.regs.r[15] + 2
+                } else {
+                    self.regs.r[reg]
+                };
+                self.cpu_write_32(address & !3, value);
+            }
+
+            address = address.wrapping_add(4);
+            first = false;
+        }
+
+        if !load {
+            self.regs.r[rb] = new_base;
+        }
+
+        clock_ticks += 2 + self.code_ticks_access_16(self.arm_next_pc as usize) as i32;
+
+        if load && rlist & 0x8000 != 0 {
+            self.cpu_jump();
+            clock_ticks += self.thumb_branch_ticks();
+        }
+
+        clock_ticks
+    }
+
+    fn thumb_block_ticks(&mut self, address: u32, first: bool) -> i32 {
+        if first {
+            self.data_ticks_access_32(address as usize) as i32
+        } else {
+            self.data_ticks_access_seq_32(address as usize) as i32
+        }
+    }
+
+    fn thumb_conditional_branch(&mut self, opcode: u32) -> i32 {
+        if !self.cpsr.condition((opcode >> 8) & 0xF) {
+            return self.thumb_ticks();
+        }
+
+        let offset = ((opcode & 0xFF) as u8 as i8 as i32) << 1;
+        let pc = self.regs.r[15];
+
+        self.regs.r[15] = pc.wrapping_add(offset as u32);
+        self.cpu_jump();
+
+        self.thumb_branch_ticks()
+    }
+
+    fn thumb_branch(&mut self, opcode: u32) -> i32 {
+        let offset = (((opcode & 0x7FF) << 21) as i32) >> 20;
+        let pc = self.regs.r[15];
+
+        self.regs.r[15] = pc.wrapping_add(offset as u32);
+        self.cpu_jump();
+
+        self.thumb_branch_ticks()
+    }
+
+    fn thumb_long_branch_high(&mut self, opcode: u32) -> i32 {
+        let offset = (((opcode & 0x7FF) << 21) as i32) >> 9;
+        let pc = self.regs.r[15];
+
+        self.regs.r[14] = pc.wrapping_add(offset as u32);
+
+        self.thumb_ticks()
+    }
+
+    fn thumb_long_branch_low(&mut self, opcode: u32) -> i32 {
+        let next = self.arm_next_pc;
+        let target = self.regs.r[14].wrapping_add((opcode & 0x7FF) << 1);
+
+        self.regs.r[14] = next | 1;
+        self.regs.r[15] = target;
+        self.cpu_jump();
+
+        self.thumb_branch_ticks()
+    }
+}
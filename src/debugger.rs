@@ -0,0 +1,820 @@
+use std::io::{self, BufRead, Write};
+
+use super::cpu::{Cpu, WatchKind, Watchpoint};
+use super::disasm::{disasm_arm, disasm_thumb};
+use super::mem_map::MemMap;
+
+/// Instructions shown before and after the PC by a bare `disasm`.
+const DISASM_BEFORE: u32 = 4;
+const DISASM_AFTER: u32 = 6;
+
+const DUMP_LENGTH: u32 = 64;
+
+const HELP: &str = "\
+commands (numbers are decimal unless prefixed with 0x; registers work as values):
+  s, step [N]                step N instructions
+  n, next                    step over a bl or swi
+  c, continue [N]            run until a breakpoint or watchpoint, or N instructions
+  finish                     run until the return address in lr
+  b, break ADDR [if COND]    break at ADDR, optionally only when COND holds
+  b, break if COND           break when COND becomes true
+  w, watch ADDR [LEN] [r|w|rw]  stop on reads, writes (default) or both of a range
+  d, delete [ID]             delete a breakpoint or watchpoint, or all of them
+  i, info                    list breakpoints and watchpoints
+  r, regs                    show registers and the decoded CPSR
+  set REG VALUE              change a register (r0-r15, sp, lr, pc, cpsr)
+  x ADDR [LEN]               hex dump memory
+  l, disasm [ADDR] [COUNT]   disassemble, by default around the PC
+  q, quit                    leave the debugger
+  an empty line repeats the last command
+COND is `A OP B` with OP one of == != < <= > >=, and A and B a number, a register
+or [ADDR] for the word at ADDR.";
+
+#[derive(Clone, Copy)]
+enum Operand {
+    Register(usize),
+    Cpsr,
+    Memory(u32),
+    Value(u32),
+}
+
+#[derive(Clone, Copy)]
+struct Condition {
+    left: Operand,
+    comparison: &'static str,
+    right: Operand,
+}
+
+enum Point {
+    Break { address: Option<u32>, condition: Option<Condition> },
+    Watch(Watchpoint),
+}
+
+enum Stop {
+    Done,
+    Breakpoint(u32),
+    Watch(u32, u32),
+}
+
+/// An interactive debugger on stdin/stdout. Breakpoints, watchpoints and the stepping
+/// commands are built on the same CPU debug hooks as the GDB stub.
+pub struct Debugger<'a> {
+    cpu: &'a mut Cpu,
+    points: Vec<(u32, Point)>,
+    next_id: u32,
+}
+
+/// Runs the debugger until `quit` or the end of input.
+pub(crate) fn run(cpu: &mut Cpu) -> io::Result<()> {
+    let mut debugger = Debugger {
+        cpu,
+        points: vec!(),
+        next_id: 1,
+    };
+
+    debugger.run()
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn parse_register(text: &str) -> Option<usize> {
+    match text {
+        "sp" => Some(13),
+        "lr" => Some(14),
+        "pc" => Some(15),
+        _ if text.starts_with('r') => text[1..].parse().ok().filter(|&index| index < 16),
+        _ => None,
+    }
+}
+
+fn compare(left: u32, comparison: &str, right: u32) -> bool {
+    match comparison {
+        "==" => left == right,
+        "!=" => left != right,
+        "<" => left < right,
+        "<=" => left <= right,
+        ">" => left > right,
+        _ => left >= right,
+    }
+}
+
+fn mode_name(mode: u32) -> &'static str {
+    match mode {
+        0x10 => "USR",
+        0x11 => "FIQ",
+        0x12 => "IRQ",
+        0x13 => "SVC",
+        0x17 => "ABT",
+        0x1B => "UND",
+        0x1F => "SYS",
+        _ => "???",
+    }
+}
+
+/// Upper case when set, lower case when clear, like `nZCv`.
+fn flag(cpsr: u32, bit: u32, name: char) -> char {
+    if cpsr & (1 << bit) != 0 {
+        name.to_ascii_uppercase()
+    } else {
+        name
+    }
+}
+
+impl<'a> Debugger<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        let stdin = io::stdin();
+        let mut last = String::new();
+
+        println!("gba-rs debugger, type help for a list of commands");
+        self.show_location();
+
+        loop {
+            print!("(gba) ");
+            io::stdout().flush()?;
+
+            let mut line = String::new();
+            if stdin.lock().read_line(&mut line)? == 0 {
+                println!();
+                return Ok(());
+            }
+
+            let line = match line.trim() {
+                "" => last.clone(),
+                line => line.to_string(),
+            };
+
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.is_empty() {
+                continue;
+            }
+
+            match words[0] {
+                "q" | "quit" => return Ok(()),
+                "h" | "help" => println!("{}", HELP),
+                _ => {
+                    if let Err(message) = self.command(&words) {
+                        println!("{}", message);
+                    }
+                },
+            }
+
+            last = line;
+        }
+    }
+
+    fn command(&mut self, words: &[&str]) -> Result<(), String> {
+        let args = &words[1..];
+
+        match words[0] {
+            "s" | "step" => {
+                let count = self.optional_value(args.first(), 1)?;
+                let stop = self.run_until(None, Some(count as u64));
+                self.report(stop);
+            },
+            "n" | "next" => {
+                let stop = match self.call_length() {
+                    Some(length) => self.run_until(Some(self.cpu.pc().wrapping_add(length)), None),
+                    None => self.run_until(None, Some(1)),
+                };
+                self.report(stop);
+            },
+            "c" | "continue" => {
+                let limit = match args.first() {
+                    Some(count) => Some(self.value(count)? as u64),
+                    None => None,
+                };
+                let stop = self.run_until(None, limit);
+                self.report(stop);
+            },
+            "finish" => {
+                let target = self.cpu.reg(14) & !1;
+                let stop = self.run_until(Some(target), None);
+                self.report(stop);
+            },
+            "b" | "break" => self.add_breakpoint(args)?,
+            "w" | "watch" => self.add_watchpoint(args)?,
+            "d" | "delete" => self.delete(args.first())?,
+            "i" | "info" => self.info(),
+            "r" | "regs" => self.show_registers(),
+            "set" => self.set(args)?,
+            "x" => {
+                let address = self.value(args.first().ok_or("x needs an address")?)?;
+                let length = self.optional_value(args.get(1), DUMP_LENGTH)?;
+                self.dump(address, length);
+            },
+            "l" | "disasm" => {
+                match args.first() {
+                    Some(address) => {
+                        let address = self.value(address)?;
+                        let count = self.optional_value(args.get(1), DISASM_BEFORE + DISASM_AFTER)?;
+                        self.disassemble(address, count);
+                    },
+                    None => {
+                        let width = if self.cpu.thumb_state() { 2 } else { 4 };
+                        let start = self.cpu.pc().wrapping_sub(DISASM_BEFORE * width);
+                        self.disassemble(start, DISASM_BEFORE + DISASM_AFTER);
+                    },
+                }
+            },
+            command => return Err(format!("unknown command {}, try help", command)),
+        }
+
+        Ok(())
+    }
+
+    fn optional_value(&self, text: Option<&&str>, default: u32) -> Result<u32, String> {
+        match text {
+            Some(text) => self.value(text),
+            None => Ok(default),
+        }
+    }
+
+    fn value(&self, text: &str) -> Result<u32, String> {
+        self.operand(text).map(|operand| self.evaluate(operand))
+    }
+
+    fn operand(&self, text: &str) -> Result<Operand, String> {
+        if text == "cpsr" {
+            Ok(Operand::Cpsr)
+        } else if let Some(index) = parse_register(text) {
+            Ok(Operand::Register(index))
+        } else if text.starts_with('[') && text.ends_with(']') && text.len() > 2 {
+            Ok(Operand::Memory(self.value(&text[1..text.len() - 1])?))
+        } else {
+            parse_number(text).map(Operand::Value).ok_or_else(|| format!("bad value {}", text))
+        }
+    }
+
+    fn evaluate(&self, operand: Operand) -> u32 {
+        match operand {
+            Operand::Register(index) => self.cpu.reg(index),
+            Operand::Cpsr => self.cpu.cpsr(),
+            Operand::Memory(address) => self.read_32(address),
+            Operand::Value(value) => value,
+        }
+    }
+
+    fn condition(&self, words: &[&str]) -> Result<Condition, String> {
+        if words.len() != 3 {
+            return Err("a condition looks like `r0 == 0x10`".to_string());
+        }
+
+        let comparison = match words[1] {
+            "==" => "==",
+            "!=" => "!=",
+            "<" => "<",
+            "<=" => "<=",
+            ">" => ">",
+            ">=" => ">=",
+            comparison => return Err(format!("unknown comparison {}", comparison)),
+        };
+
+        Ok(Condition {
+            left: self.operand(words[0])?,
+            comparison,
+            right: self.operand(words[2])?,
+        })
+    }
+
+    fn holds(&self, condition: &Condition) -> bool {
+        compare(self.evaluate(condition.left), condition.comparison, self.evaluate(condition.right))
+    }
+
+    fn read_16(&self, address: u32) -> u16 {
+        self.cpu.debug_read_8(address) as u16 | (self.cpu.debug_read_8(address.wrapping_add(1)) as u16) << 8
+    }
+
+    fn read_32(&self, address: u32) -> u32 {
+        self.read_16(address) as u32 | (self.read_16(address.wrapping_add(2)) as u32) << 16
+    }
+
+    /// Length of the instruction at the PC if it is a call `next` should step over.
+    fn call_length(&self) -> Option<u32> {
+        let pc = self.cpu.pc();
+
+        if self.cpu.thumb_state() {
+            let opcode = self.read_16(pc);
+            let next = self.read_16(pc.wrapping_add(2));
+
+            if opcode & 0xF800 == 0xF000 && next & 0xF800 == 0xF800 {
+                Some(4)
+            } else if opcode & 0xFF00 == 0xDF00 {
+                Some(2)
+            } else {
+                None
+            }
+        } else {
+            let opcode = self.read_32(pc);
+
+            if opcode >> 28 != 0xF && (opcode & 0x0F000000 == 0x0B000000 || opcode & 0x0F000000 == 0x0F000000) {
+                Some(4)
+            } else {
+                None
+            }
+        }
+    }
+
+    /// Steps until the PC reaches `target`, a breakpoint or watchpoint fires, or `limit`
+    /// instructions have run.
+    fn run_until(&mut self, target: Option<u32>, limit: Option<u64>) -> Stop {
+        let mut executed = 0;
+
+        let mut held: Vec<bool> = self.points.iter()
+            .map(|(_, point)| match *point {
+                Point::Break { condition: Some(ref condition), .. } => self.holds(condition),
+                _ => false,
+            })
+            .collect();
+
+        loop {
+            if limit == Some(executed) {
+                return Stop::Done;
+            }
+
+            self.cpu.step_instruction();
+            executed += 1;
+
+            if let Some(hit) = self.cpu.take_watch_hit() {
+                let id = self.points.iter()
+                    .find(|&(_, point)| match *point {
+                        Point::Watch(watchpoint) => watchpoint == hit.watchpoint,
+                        _ => false,
+                    })
+                    .map(|&(id, _)| id)
+                    .unwrap_or(0);
+
+                return Stop::Watch(id, hit.address);
+            }
+
+            let pc = self.cpu.pc();
+            if target == Some(pc) {
+                return Stop::Done;
+            }
+
+            let mut hit = None;
+
+            for (i, &(id, ref point)) in self.points.iter().enumerate() {
+                if let Point::Break { address, ref condition } = *point {
+                    let holds = condition.as_ref().is_none_or(|condition| self.holds(condition));
+
+                    let fired = match address {
+                        Some(address) => address == pc && holds,
+                        // stop when the condition becomes true, not on every instruction it stays so
+                        None => holds && !held[i],
+                    };
+                    held[i] = holds;
+
+                    if fired && hit.is_none() {
+                        hit = Some(id);
+                    }
+                }
+            }
+
+            if let Some(id) = hit {
+                return Stop::Breakpoint(id);
+            }
+        }
+    }
+
+    fn report(&self, stop: Stop) {
+        match stop {
+            Stop::Done => (),
+            Stop::Breakpoint(id) => println!("breakpoint {} hit", id),
+            Stop::Watch(id, address) => {
+                println!("watchpoint {} hit by an access to 0x{:08x} ({})", id, address, MemMap::region_name(address));
+            },
+        }
+
+        self.show_location();
+    }
+
+    fn show_location(&self) {
+        let pc = self.cpu.pc();
+        self.print_instruction(pc, true);
+    }
+
+    /// Prints one instruction and returns its length.
+    fn print_instruction(&self, address: u32, current: bool) -> u32 {
+        let marker = if current { "=>" } else { "  " };
+
+        if self.cpu.thumb_state() {
+            let opcode = self.read_16(address);
+            let (text, length) = disasm_thumb(opcode, self.read_16(address.wrapping_add(2)), address);
+            let bytes = if length == 4 {
+                format!("{:04x} {:04x}", opcode, self.read_16(address.wrapping_add(2)))
+            } else {
+                format!("{:04x}     ", opcode)
+            };
+
+            println!("{} 0x{:08x}  {}  {}", marker, address, bytes, text);
+            length
+        } else {
+            let opcode = self.read_32(address);
+            println!("{} 0x{:08x}  {:08x}  {}", marker, address, opcode, disasm_arm(opcode, address));
+            4
+        }
+    }
+
+    fn disassemble(&self, start: u32, count: u32) {
+        let pc = self.cpu.pc();
+        let mut address = start;
+
+        for _ in 0..count {
+            address = address.wrapping_add(self.print_instruction(address, address == pc));
+        }
+    }
+
+    fn show_registers(&self) {
+        const NAMES: [&str; 16] = [
+            "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7",
+            "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc",
+        ];
+
+        for row in NAMES.chunks(4).enumerate() {
+            let line: Vec<String> = row.1.iter().enumerate()
+                .map(|(column, name)| format!("{:>3} {:08x}", name, self.cpu.reg(row.0 * 4 + column)))
+                .collect();
+            println!("{}", line.join("  "));
+        }
+
+        let cpsr = self.cpu.cpsr();
+        println!("cpsr {:08x}  {}{}{}{} {}{}{}  {}",
+            cpsr,
+            flag(cpsr, 31, 'n'), flag(cpsr, 30, 'z'), flag(cpsr, 29, 'c'), flag(cpsr, 28, 'v'),
+            flag(cpsr, 7, 'i'), flag(cpsr, 6, 'f'), flag(cpsr, 5, 't'),
+            mode_name(cpsr & 0x1F));
+    }
+
+    fn set(&mut self, args: &[&str]) -> Result<(), String> {
+        if args.len() != 2 {
+            return Err("set needs a register and a value".to_string());
+        }
+
+        let value = self.value(args[1])?;
+
+        if args[0] == "cpsr" {
+            self.cpu.set_cpsr(value).map_err(|e| e.to_string())?;
+        } else {
+            let index = parse_register(args[0]).ok_or_else(|| format!("unknown register {}", args[0]))?;
+            self.cpu.set_reg(index, value);
+        }
+
+        Ok(())
+    }
+
+    fn dump(&self, address: u32, length: u32) {
+        let mut line_start = address & !0xF;
+
+        while line_start < address.saturating_add(length) {
+            let bytes: Vec<Option<u8>> = (0..16)
+                .map(|offset| line_start.wrapping_add(offset))
+                .map(|byte| {
+                    if byte >= address && byte - address < length {
+                        Some(self.cpu.debug_read_8(byte))
+                    } else {
+                        None
+                    }
+                })
+                .collect();
+
+            let hex: Vec<String> = bytes.iter()
+                .map(|byte| byte.map_or("  ".to_string(), |byte| format!("{:02x}", byte)))
+                .collect();
+            let text: String = bytes.iter()
+                .map(|byte| match *byte {
+                    Some(byte @ 0x20..=0x7E) => byte as char,
+                    Some(_) => '.',
+                    None => ' ',
+                })
+                .collect();
+
+            println!("0x{:08x} {:<7} {}  |{}|", line_start, MemMap::region_name(line_start), hex.join(" "), text);
+
+            line_start = match line_start.checked_add(16) {
+                Some(next) => next,
+                None => return,
+            };
+        }
+    }
+
+    fn add_breakpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let (address, condition) = match args.first() {
+            None => return Err("break needs an address or a condition".to_string()),
+            Some(&"if") => (None, Some(self.condition(&args[1..])?)),
+            Some(address) => {
+                let address = self.value(address)?;
+
+                match args.get(1) {
+                    None => (Some(address), None),
+                    Some(&"if") => (Some(address), Some(self.condition(&args[2..])?)),
+                    Some(word) => return Err(format!("expected if, found {}", word)),
+                }
+            },
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        match address {
+            Some(address) => println!("breakpoint {} at 0x{:08x}", id, address),
+            None => println!("breakpoint {} on a condition", id),
+        }
+
+        self.points.push((id, Point::Break { address, condition }));
+        Ok(())
+    }
+
+    fn add_watchpoint(&mut self, args: &[&str]) -> Result<(), String> {
+        let address = self.value(args.first().ok_or("watch needs an address")?)?;
+        let length = self.optional_value(args.get(1), 4)?;
+
+        let kind = match args.get(2) {
+            None | Some(&"w") => WatchKind::Write,
+            Some(&"r") => WatchKind::Read,
+            Some(&"rw") => WatchKind::Access,
+            Some(kind) => return Err(format!("unknown watch kind {}, expected r, w or rw", kind)),
+        };
+
+        if length == 0 {
+            return Err("a watchpoint needs a length of at least 1".to_string());
+        }
+
+        let watchpoint = Watchpoint { address, length, kind };
+        self.cpu.add_watchpoint(watchpoint);
+
+        let id = self.next_id;
+        self.next_id += 1;
+        println!("watchpoint {} on 0x{:08x}..0x{:08x}", id, address, address.wrapping_add(length));
+
+        self.points.push((id, Point::Watch(watchpoint)));
+        Ok(())
+    }
+
+    fn delete(&mut self, id: Option<&&str>) -> Result<(), String> {
+        let id = match id {
+            Some(id) => Some(self.value(id)?),
+            None => None,
+        };
+
+        let (deleted, kept): (Vec<_>, Vec<_>) = self.points.drain(..).partition(|&(point_id, _)| id.is_none_or(|id| id == point_id));
+        self.points = kept;
+
+        if id.is_some() && deleted.is_empty() {
+            return Err("no such breakpoint or watchpoint".to_string());
+        }
+
+        for (_, point) in deleted {
+            if let Point::Watch(watchpoint) = point {
+                // only drop it from the CPU if no other id watches the same range
+                if !self.points.iter().any(|(_, point)| match *point {
+                    Point::Watch(other) => other == watchpoint,
+                    _ => false,
+                }) {
+                    self.cpu.remove_watchpoint(watchpoint);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn info(&self) {
+        if self.points.is_empty() {
+            println!("no breakpoints or watchpoints");
+        }
+
+        for &(id, ref point) in &self.points {
+            match *point {
+                Point::Break { address, ref condition } => {
+                    let address = address.map_or("anywhere".to_string(), |address| format!("0x{:08x}", address));
+                    let condition = condition.as_ref().map_or(String::new(), |condition| {
+                        format!(" if {} {} {}", self.describe(condition.left), condition.comparison, self.describe(condition.right))
+                    });
+                    println!("{:>3} break {}{}", id, address, condition);
+                },
+                Point::Watch(watchpoint) => {
+                    let kind = match watchpoint.kind {
+                        WatchKind::Read => "r",
+                        WatchKind::Write => "w",
+                        WatchKind::Access => "rw",
+                    };
+                    println!("{:>3} watch 0x{:08x}..0x{:08x} {}", id, watchpoint.address, watchpoint.address.wrapping_add(watchpoint.length), kind);
+                },
+            }
+        }
+    }
+
+    fn describe(&self, operand: Operand) -> String {
+        match operand {
+            Operand::Register(index) => format!("r{}", index),
+            Operand::Cpsr => "cpsr".to_string(),
+            Operand::Memory(address) => format!("[0x{:08x}]", address),
+            Operand::Value(value) => format!("0x{:x}", value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cpu::Cpu;
+    use super::{parse_number, parse_register, Debugger, Stop};
+
+    /// Jumps to the cartridge on reset and returns from a `swi` straight away.
+    const BIOS: [u32; 3] = [
+        0xE3A0F302, // mov pc, #0x08000000
+        0xE1A00000, // nop
+        0xE1B0F00E, // movs pc, lr
+    ];
+
+    /// Calls a subroutine and the BIOS from ARM, then again from Thumb.
+    const PROGRAM: [u32; 10] = [
+        0xE3A00001, // 08000000: mov r0, #1
+        0xEB000005, // 08000004: bl 0x08000020
+        0xEF000005, // 08000008: swi 5
+        0xE28F1001, // 0800000c: add r1, pc, #1
+        0xE12FFF11, // 08000010: bx r1
+        0xF802F000, // 08000014: bl 0x0800001c
+        0xE7FEDF05, // 08000018: swi 5; 0800001a: b 0x0800001a
+        0x47703201, // 0800001c: adds r2, #1; 0800001e: bx lr
+        0xE2833001, // 08000020: add r3, r3, #1
+        0xE12FFF1E, // 08000024: bx lr
+    ];
+
+    fn program_cpu() -> Cpu {
+        let mut bios: Vec<u8> = BIOS.iter().flat_map(|word| word.to_le_bytes()).collect();
+        bios.resize(0x4000, 0);
+        let rom: Vec<u8> = PROGRAM.iter().flat_map(|word| word.to_le_bytes()).collect();
+
+        let mut cpu = Cpu::new();
+        cpu.load_bios(&bios).unwrap();
+        cpu.load_rom(&rom).unwrap();
+        cpu.reset();
+        // through the BIOS into the cartridge
+        cpu.step_instruction();
+        cpu
+    }
+
+    fn debugger(cpu: &mut Cpu) -> Debugger<'_> {
+        Debugger {
+            cpu,
+            points: vec!(),
+            next_id: 1,
+        }
+    }
+
+    fn run(debugger: &mut Debugger, command: &str) -> Result<(), String> {
+        let words: Vec<&str> = command.split_whitespace().collect();
+        debugger.command(&words)
+    }
+
+    #[test]
+    fn numbers_and_registers() {
+        assert_eq!(parse_number("0"), Some(0));
+        assert_eq!(parse_number("1234"), Some(1234));
+        assert_eq!(parse_number("0x08000000"), Some(0x08000000));
+        assert_eq!(parse_number("0XfF"), Some(0xFF));
+        assert_eq!(parse_number("4294967295"), Some(u32::MAX));
+        for bad in &["", "0x", "-1", "4294967296", "0x100000000", "12ab", "x10"] {
+            assert_eq!(parse_number(bad), None, "{}", bad);
+        }
+
+        assert_eq!(parse_register("r0"), Some(0));
+        assert_eq!(parse_register("r15"), Some(15));
+        assert_eq!(parse_register("sp"), Some(13));
+        assert_eq!(parse_register("lr"), Some(14));
+        assert_eq!(parse_register("pc"), Some(15));
+        for bad in &["r16", "r", "r-1", "R0", "cpsr", "0"] {
+            assert_eq!(parse_register(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn values() {
+        let mut cpu = program_cpu();
+        let mut debugger = debugger(&mut cpu);
+        run(&mut debugger, "set r4 0x03000000").unwrap();
+        run(&mut debugger, "set r5 [r4]").unwrap();
+        debugger.cpu.set_reg(6, 0x12345678);
+
+        assert_eq!(debugger.value("r6"), Ok(0x12345678));
+        assert_eq!(debugger.value("pc"), Ok(debugger.cpu.reg(15)));
+        assert_eq!(debugger.value("cpsr"), Ok(debugger.cpu.cpsr()));
+        assert_eq!(debugger.value("[0x08000000]"), Ok(0xE3A00001));
+        assert_eq!(debugger.value("[0x08000004]"), Ok(0xEB000005));
+        assert!(debugger.value("[]").is_err());
+        assert!(debugger.value("[r16]").is_err());
+        assert!(run(&mut debugger, "set r16 1").is_err());
+        assert!(run(&mut debugger, "set r0").is_err());
+    }
+
+    #[test]
+    fn conditions() {
+        let mut cpu = program_cpu();
+        let debugger = debugger(&mut cpu);
+        debugger.cpu.set_reg(0, 5);
+        debugger.cpu.set_reg(1, 7);
+
+        let holds = |debugger: &Debugger, text: &str| {
+            let words: Vec<&str> = text.split_whitespace().collect();
+            debugger.holds(&debugger.condition(&words).unwrap())
+        };
+        assert!(holds(&debugger, "r0 == 5"));
+        assert!(holds(&debugger, "r0 != r1"));
+        assert!(holds(&debugger, "r0 < r1"));
+        assert!(holds(&debugger, "r0 <= 0x5"));
+        assert!(!holds(&debugger, "r0 > r1"));
+        assert!(holds(&debugger, "r1 >= r0"));
+        assert!(holds(&debugger, "[0x08000000] == 0xe3a00001"));
+        // unsigned
+        assert!(holds(&debugger, "0xffffffff > r0"));
+
+        for bad in &["r0 == ", "r0 = 5", "r0 == 5 6", "r0 == five", "=="] {
+            let words: Vec<&str> = bad.split_whitespace().collect();
+            assert!(debugger.condition(&words).is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn breakpoints() {
+        let mut cpu = program_cpu();
+        let mut debugger = debugger(&mut cpu);
+
+        run(&mut debugger, "b 0x08000020").unwrap();
+        assert!(matches!(debugger.run_until(None, Some(100)), Stop::Breakpoint(1)));
+        assert_eq!(debugger.cpu.pc(), 0x08000020);
+        run(&mut debugger, "d 1").unwrap();
+        assert!(run(&mut debugger, "d 1").is_err());
+
+        // an address with a condition only stops while it holds
+        run(&mut debugger, "b 0x0800001c if r2 == 1").unwrap();
+        run(&mut debugger, "b 0x0800001c if r2 == 0").unwrap();
+        assert!(matches!(debugger.run_until(None, Some(100)), Stop::Breakpoint(3)));
+        assert_eq!(debugger.cpu.pc(), 0x0800001C);
+        run(&mut debugger, "d").unwrap();
+
+        // a bare condition stops when it becomes true, not while it stays so
+        run(&mut debugger, "b if r2 == 1").unwrap();
+        assert!(matches!(debugger.run_until(None, Some(100)), Stop::Breakpoint(4)));
+        assert_eq!(debugger.cpu.pc(), 0x0800001E);
+        assert!(matches!(debugger.run_until(None, Some(100)), Stop::Done));
+
+        assert!(run(&mut debugger, "b").is_err());
+        assert!(run(&mut debugger, "b 0x08000000 when r0 == 1").is_err());
+        assert!(run(&mut debugger, "b if r0").is_err());
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let mut cpu = program_cpu();
+        let mut debugger = debugger(&mut cpu);
+        assert_eq!(debugger.cpu.pc(), 0x08000000);
+        assert_eq!(debugger.call_length(), None);
+
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x08000004);
+        // bl
+        assert_eq!(debugger.call_length(), Some(4));
+        run(&mut debugger, "n").unwrap();
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.reg(3)), (0x08000008, 1));
+        // swi, through the BIOS
+        assert_eq!(debugger.call_length(), Some(4));
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0800000C);
+
+        run(&mut debugger, "s 2").unwrap();
+        assert!(debugger.cpu.thumb_state());
+        assert_eq!(debugger.cpu.pc(), 0x08000014);
+        // both halves of a Thumb bl
+        assert_eq!(debugger.call_length(), Some(4));
+        run(&mut debugger, "n").unwrap();
+        assert_eq!((debugger.cpu.pc(), debugger.cpu.reg(2)), (0x08000018, 1));
+        assert_eq!(debugger.call_length(), Some(2));
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0800001A);
+        assert!(debugger.cpu.thumb_state());
+
+        // anything else is a single step
+        assert_eq!(debugger.call_length(), None);
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x0800001A);
+    }
+
+    #[test]
+    fn next_stops_at_breakpoints_inside_the_call() {
+        let mut cpu = program_cpu();
+        let mut debugger = debugger(&mut cpu);
+        run(&mut debugger, "s").unwrap();
+        run(&mut debugger, "b 0x08000024").unwrap();
+
+        run(&mut debugger, "n").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x08000024);
+        run(&mut debugger, "finish").unwrap();
+        assert_eq!(debugger.cpu.pc(), 0x08000008);
+    }
+}
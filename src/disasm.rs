@@ -0,0 +1,642 @@
+const CONDITIONS: [&str; 16] = [
+    "eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc",
+    "hi", "ls", "ge", "lt", "gt", "le", "", "nv",
+];
+
+const SHIFTS: [&str; 4] = ["lsl", "lsr", "asr", "ror"];
+
+const DATA_PROCESSING: [&str; 16] = [
+    "and", "eor", "sub", "rsb", "add", "adc", "sbc", "rsc",
+    "tst", "teq", "cmp", "cmn", "orr", "mov", "bic", "mvn",
+];
+
+const THUMB_ALU: [&str; 16] = [
+    "ands", "eors", "lsls", "lsrs", "asrs", "adcs", "sbcs", "rors",
+    "tst", "negs", "cmp", "cmn", "orrs", "muls", "bics", "mvns",
+];
+
+fn reg(index: u32) -> &'static str {
+    const NAMES: [&str; 16] = [
+        "r0", "r1", "r2", "r3", "r4", "r5", "r6", "r7",
+        "r8", "r9", "r10", "r11", "r12", "sp", "lr", "pc",
+    ];
+
+    NAMES[(index & 0xF) as usize]
+}
+
+fn imm(value: u32) -> String {
+    format!("#{}", value)
+}
+
+fn signed_imm(value: u32, up: bool) -> String {
+    if up {
+        imm(value)
+    } else {
+        format!("#-{}", value)
+    }
+}
+
+/// Rotated immediates get their hex value as a comment, as objdump does.
+fn rotated_imm(opcode: u32) -> String {
+    let rotate = ((opcode >> 8) & 0xF) * 2;
+    let value = (opcode & 0xFF).rotate_right(rotate);
+
+    if rotate == 0 {
+        imm(value)
+    } else {
+        format!("{} @ 0x{:x}", imm(value), value)
+    }
+}
+
+/// Registers are listed one by one, no ranges.
+fn reg_list(list: u32) -> String {
+    let names: Vec<&str> = (0..16).filter(|i| list & (1 << i) != 0).map(reg).collect();
+    format!("{{{}}}", names.join(", "))
+}
+
+/// The shifted register form of an operand: `rm`, `rm, lsl #2`, `rm, lsr rs` or `rm, rrx`.
+fn shifted_register(opcode: u32) -> String {
+    let rm = reg(opcode);
+    let shift = (opcode >> 5) & 3;
+
+    if opcode & 0x10 != 0 {
+        return format!("{}, {} {}", rm, SHIFTS[shift as usize], reg(opcode >> 8));
+    }
+
+    match (shift, (opcode >> 7) & 0x1F) {
+        (0, 0) => rm.to_string(),
+        (3, 0) => format!("{}, rrx", rm),
+        (1, 0) | (2, 0) => format!("{}, {} #32", rm, SHIFTS[shift as usize]),
+        (_, amount) => format!("{}, {} #{}", rm, SHIFTS[shift as usize], amount),
+    }
+}
+
+/// `[rn, offset]`, `[rn, offset]!` or `[rn], offset`, with the target noted for pc relative
+/// immediates.
+fn address_mode(opcode: u32, address: u32, offset: Option<u32>, register_offset: String) -> String {
+    let rn = (opcode >> 16) & 0xF;
+    let pre = opcode & 0x01000000 != 0;
+    let up = opcode & 0x00800000 != 0;
+    let writeback = opcode & 0x00200000 != 0;
+
+    let offset_text = match offset {
+        Some(0) if pre => None,
+        Some(value) => Some(signed_imm(value, up)),
+        None => Some(format!("{}{}", if up { "" } else { "-" }, register_offset)),
+    };
+
+    let mut text = match (pre, offset_text) {
+        (true, None) => format!("[{}]", reg(rn)),
+        (true, Some(offset)) => format!("[{}, {}]{}", reg(rn), offset, if writeback { "!" } else { "" }),
+        (false, Some(offset)) => format!("[{}], {}", reg(rn), offset),
+        (false, None) => format!("[{}]", reg(rn)),
+    };
+
+    if let (15, true, Some(value)) = (rn, pre, offset) {
+        let base = address.wrapping_add(8);
+        let target = if up { base.wrapping_add(value) } else { base.wrapping_sub(value) };
+        text.push_str(&format!(" @ 0x{:08x}", target));
+    }
+
+    text
+}
+
+/// Disassembles one ARM opcode fetched from `address` into GNU objdump style unified syntax.
+/// Branch targets and PC relative addresses are resolved, the latter as `@` comments.
+pub fn disasm_arm(opcode: u32, address: u32) -> String {
+    let cond = CONDITIONS[(opcode >> 28) as usize];
+    let rn = (opcode >> 16) & 0xF;
+    let rd = (opcode >> 12) & 0xF;
+    let set_flags = opcode & 0x00100000 != 0;
+    let s = if set_flags { "s" } else { "" };
+
+    if opcode & 0x0FFFFFF0 == 0x012FFF10 {
+        format!("bx{} {}", cond, reg(opcode))
+    } else if opcode & 0x0FC000F0 == 0x00000090 {
+        if opcode & 0x00200000 != 0 {
+            format!("mla{}{} {}, {}, {}, {}", s, cond, reg(rn), reg(opcode), reg(opcode >> 8), reg(rd))
+        } else {
+            format!("mul{}{} {}, {}, {}", s, cond, reg(rn), reg(opcode), reg(opcode >> 8))
+        }
+    } else if opcode & 0x0F8000F0 == 0x00800090 {
+        let name = match (opcode >> 21) & 3 {
+            0 => "umull",
+            1 => "umlal",
+            2 => "smull",
+            _ => "smlal",
+        };
+        format!("{}{}{} {}, {}, {}, {}", name, s, cond, reg(rd), reg(rn), reg(opcode), reg(opcode >> 8))
+    } else if opcode & 0x0FB00FF0 == 0x01000090 {
+        let b = if opcode & 0x00400000 != 0 { "b" } else { "" };
+        format!("swp{}{} {}, {}, [{}]", b, cond, reg(rd), reg(opcode), reg(rn))
+    } else if opcode & 0x0E000090 == 0x00000090 && opcode & 0x60 != 0 {
+        let name = match (set_flags, (opcode >> 5) & 3) {
+            (false, 1) => "strh",
+            (true, 1) => "ldrh",
+            (true, 2) => "ldrsb",
+            (true, 3) => "ldrsh",
+            _ => return "undefined".to_string(),
+        };
+
+        let offset = if opcode & 0x00400000 != 0 {
+            Some(((opcode >> 4) & 0xF0) | (opcode & 0xF))
+        } else {
+            None
+        };
+
+        format!("{}{} {}, {}", name, cond, reg(rd), address_mode(opcode, address, offset, reg(opcode).to_string()))
+    } else if opcode & 0x0FBF0FFF == 0x010F0000 {
+        let psr = if opcode & 0x00400000 != 0 { "SPSR" } else { "CPSR" };
+        format!("mrs{} {}, {}", cond, reg(rd), psr)
+    } else if opcode & 0x0DB0F000 == 0x0120F000 {
+        let psr = if opcode & 0x00400000 != 0 { "SPSR" } else { "CPSR" };
+        let fields: String = [(19, 'f'), (18, 's'), (17, 'x'), (16, 'c')].iter()
+            .filter(|&&(bit, _)| opcode & (1 << bit) != 0)
+            .map(|&(_, field)| field)
+            .collect();
+
+        let operand = if opcode & 0x02000000 != 0 {
+            rotated_imm(opcode)
+        } else {
+            reg(opcode).to_string()
+        };
+
+        format!("msr{} {}_{}, {}", cond, psr, fields, operand)
+    } else if opcode & 0x0C000000 == 0x00000000 {
+        let op = (opcode >> 21) & 0xF;
+        let name = DATA_PROCESSING[op as usize];
+
+        let immediate = opcode & 0x02000000 != 0;
+        let operand = if immediate {
+            rotated_imm(opcode)
+        } else {
+            shifted_register(opcode)
+        };
+
+        match op {
+            0x8..=0xB => format!("{}{} {}, {}", name, cond, reg(rn), operand),
+            0xD | 0xF => format!("{}{}{} {}, {}", name, s, cond, reg(rd), operand),
+            // adr
+            0x2 | 0x4 if immediate && rn == 15 => {
+                let offset = (opcode & 0xFF).rotate_right(((opcode >> 8) & 0xF) * 2);
+                let base = address.wrapping_add(8);
+                let target = if op == 0x4 { base.wrapping_add(offset) } else { base.wrapping_sub(offset) };
+                format!("{}{}{} {}, pc, {} @ 0x{:08x}", name, s, cond, reg(rd), imm(offset), target)
+            },
+            _ => format!("{}{}{} {}, {}, {}", name, s, cond, reg(rd), reg(rn), operand),
+        }
+    } else if opcode & 0x0E000010 == 0x06000010 {
+        "undefined".to_string()
+    } else if opcode & 0x0C000000 == 0x04000000 {
+        let load = opcode & 0x00100000 != 0;
+        let byte = if opcode & 0x00400000 != 0 { "b" } else { "" };
+        let user = if opcode & 0x01200000 == 0x00200000 { "t" } else { "" };
+
+        let offset = if opcode & 0x02000000 == 0 {
+            Some(opcode & 0xFFF)
+        } else {
+            None
+        };
+
+        format!("{}{}{}{} {}, {}", if load { "ldr" } else { "str" }, byte, user, cond, reg(rd),
+            address_mode(opcode, address, offset, shifted_register(opcode & !0x10)))
+    } else if opcode & 0x0E000000 == 0x08000000 {
+        let load = opcode & 0x00100000 != 0;
+        let writeback = opcode & 0x00200000 != 0;
+        let user = if opcode & 0x00400000 != 0 { "^" } else { "" };
+        let list = reg_list(opcode & 0xFFFF);
+
+        match ((opcode >> 23) & 3, load, rn, writeback) {
+            (1, true, 13, true) => format!("pop{} {}{}", cond, list, user),
+            (2, false, 13, true) => format!("push{} {}{}", cond, list, user),
+            (mode, _, _, _) => {
+                let mode = ["da", "", "db", "ib"][mode as usize];
+                format!("{}{}{} {}{}, {}{}", if load { "ldm" } else { "stm" }, mode, cond, reg(rn),
+                    if writeback { "!" } else { "" }, list, user)
+            },
+        }
+    } else if opcode & 0x0E000000 == 0x0A000000 {
+        let offset = ((opcode << 8) as i32 >> 6) as u32;
+        let link = if opcode & 0x01000000 != 0 { "l" } else { "" };
+        format!("b{}{} 0x{:08x}", link, cond, address.wrapping_add(8).wrapping_add(offset))
+    } else if opcode & 0x0F000000 == 0x0F000000 {
+        format!("svc{} 0x{:08x}", cond, opcode & 0x00FFFFFF)
+    } else {
+        "undefined".to_string()
+    }
+}
+
+/// Disassembles the Thumb opcode at `address`, in the same style as `disasm_arm`. `next` is
+/// the halfword after it, needed to decode the two halves of `bl` as one instruction.
+/// Returns the text and the length in bytes.
+pub fn disasm_thumb(opcode: u16, next: u16, address: u32) -> (String, u32) {
+    let opcode = opcode as u32;
+    let low = |shift: u32| reg((opcode >> shift) & 7);
+
+    let text = match opcode >> 13 {
+        0b000 if (opcode >> 11) & 3 == 3 => {
+            let name = if opcode & 0x0200 != 0 { "subs" } else { "adds" };
+
+            if opcode & 0x0400 != 0 {
+                format!("{} {}, {}, {}", name, low(0), low(3), imm((opcode >> 6) & 7))
+            } else {
+                format!("{} {}, {}, {}", name, low(0), low(3), low(6))
+            }
+        },
+        0b000 => {
+            let shift = (opcode >> 11) & 3;
+            let amount = match (shift, (opcode >> 6) & 0x1F) {
+                (1, 0) | (2, 0) => 32,
+                (_, amount) => amount,
+            };
+
+            if shift == 0 && amount == 0 {
+                format!("movs {}, {}", low(0), low(3))
+            } else {
+                format!("{}s {}, {}, #{}", SHIFTS[shift as usize], low(0), low(3), amount)
+            }
+        },
+        0b001 => {
+            let name = ["movs", "cmp", "adds", "subs"][((opcode >> 11) & 3) as usize];
+            format!("{} {}, {}", name, low(8), imm(opcode & 0xFF))
+        },
+        0b010 if opcode >> 10 == 0b010000 => {
+            let op = (opcode >> 6) & 0xF;
+
+            format!("{} {}, {}", THUMB_ALU[op as usize], low(0), low(3))
+        },
+        0b010 if opcode >> 10 == 0b010001 => {
+            let rd = (opcode & 7) | ((opcode >> 4) & 8);
+            let rs = (opcode >> 3) & 0xF;
+
+            match (opcode >> 8) & 3 {
+                0 => format!("add {}, {}", reg(rd), reg(rs)),
+                1 => format!("cmp {}, {}", reg(rd), reg(rs)),
+                2 if rd == 8 && rs == 8 => "nop".to_string(),
+                2 => format!("mov {}, {}", reg(rd), reg(rs)),
+                _ if opcode & 0x80 == 0 => format!("bx {}", reg(rs)),
+                _ => "undefined".to_string(),
+            }
+        },
+        0b010 if opcode >> 11 == 0b01001 => {
+            let offset = (opcode & 0xFF) << 2;
+            let target = (address.wrapping_add(4) & !3).wrapping_add(offset);
+            format!("ldr {}, [pc, {}] @ 0x{:08x}", low(8), imm(offset), target)
+        },
+        0b010 => {
+            let name = if opcode & 0x0200 == 0 {
+                ["str", "strb", "ldr", "ldrb"][((opcode >> 10) & 3) as usize]
+            } else {
+                ["strh", "ldrsb", "ldrh", "ldrsh"][((opcode >> 10) & 3) as usize]
+            };
+            format!("{} {}, [{}, {}]", name, low(0), low(3), low(6))
+        },
+        0b011 => {
+            let byte = opcode & 0x1000 != 0;
+            let name = match (opcode & 0x0800 != 0, byte) {
+                (false, false) => "str",
+                (false, true) => "strb",
+                (true, false) => "ldr",
+                (true, true) => "ldrb",
+            };
+            let offset = if byte { (opcode >> 6) & 0x1F } else { ((opcode >> 6) & 0x1F) << 2 };
+
+            format!("{} {}, {}", name, low(0), thumb_offset(low(3), offset))
+        },
+        0b100 if opcode & 0x1000 == 0 => {
+            let name = if opcode & 0x0800 != 0 { "ldrh" } else { "strh" };
+            format!("{} {}, {}", name, low(0), thumb_offset(low(3), ((opcode >> 6) & 0x1F) << 1))
+        },
+        0b100 => {
+            let name = if opcode & 0x0800 != 0 { "ldr" } else { "str" };
+            format!("{} {}, {}", name, low(8), thumb_offset("sp", (opcode & 0xFF) << 2))
+        },
+        0b101 if opcode & 0x1000 == 0 => {
+            let offset = (opcode & 0xFF) << 2;
+
+            if opcode & 0x0800 != 0 {
+                format!("add {}, sp, {}", low(8), imm(offset))
+            } else {
+                let target = (address.wrapping_add(4) & !3).wrapping_add(offset);
+                format!("add {}, pc, {} @ 0x{:08x}", low(8), imm(offset), target)
+            }
+        },
+        0b101 if opcode & 0x0F00 == 0x0000 => {
+            let name = if opcode & 0x80 != 0 { "sub" } else { "add" };
+            format!("{} sp, {}", name, imm((opcode & 0x7F) << 2))
+        },
+        0b101 if opcode & 0x0600 == 0x0400 => {
+            let (name, extra) = if opcode & 0x0800 != 0 { ("pop", 15) } else { ("push", 14) };
+            let list = (opcode & 0xFF) | if opcode & 0x0100 != 0 { 1 << extra } else { 0 };
+            format!("{} {}", name, reg_list(list))
+        },
+        0b101 => "undefined".to_string(),
+        0b110 if opcode & 0x1000 == 0 => {
+            let load = opcode & 0x0800 != 0;
+            let rb = (opcode >> 8) & 7;
+            let list = opcode & 0xFF;
+            let writeback = !load || list & (1 << rb) == 0;
+
+            format!("{} {}{}, {}", if load { "ldmia" } else { "stmia" }, reg(rb), if writeback { "!" } else { "" }, reg_list(list))
+        },
+        0b110 if opcode & 0x0F00 == 0x0F00 => format!("svc {}", opcode & 0xFF),
+        0b110 if opcode & 0x0F00 == 0x0E00 => "undefined".to_string(),
+        0b110 => {
+            let offset = ((opcode << 24) as i32 >> 23) as u32;
+            format!("b{} 0x{:08x}", CONDITIONS[((opcode >> 8) & 0xF) as usize], address.wrapping_add(4).wrapping_add(offset))
+        },
+        _ => match opcode & 0x1800 {
+            0x0000 => {
+                let offset = ((opcode << 21) as i32 >> 20) as u32;
+                format!("b 0x{:08x}", address.wrapping_add(4).wrapping_add(offset))
+            },
+            0x1000 if next & 0xF800 == 0xF800 => {
+                let high = ((opcode << 21) as i32 >> 9) as u32;
+                let target = address.wrapping_add(4).wrapping_add(high).wrapping_add((next as u32 & 0x7FF) << 1);
+                return (format!("bl 0x{:08x}", target), 4);
+            },
+            // a lone half of a bl pair
+            0x1000 => format!("bl.hi {}", imm(opcode & 0x7FF)),
+            0x1800 => format!("bl.lo {}", imm(opcode & 0x7FF)),
+            _ => "undefined".to_string(),
+        },
+    };
+
+    (text, 2)
+}
+
+/// Disassembles `count` instructions of a ROM image from `address`, a line each with the
+/// address and opcode. An odd address means Thumb code, as with `bx`; addresses below the
+/// cartridge space are ROM offsets. Stops early at the top of the address space.
+pub fn disasm_rom(rom: &[u8], address: u32, count: u32) -> Vec<String> {
+    let thumb = address & 1 != 0;
+    let mut address = if address < 0x08000000 { address + 0x08000000 } else { address } & !1;
+
+    let read = |address: u32, size: u32| -> u32 {
+        let offset = (address & 0x01FFFFFF) as usize;
+        (0..size as usize).map(|i| *rom.get(offset + i).unwrap_or(&0) as u32).rev().fold(0, |value, byte| value << 8 | byte)
+    };
+
+    let mut lines = vec!();
+
+    for _ in 0..count {
+        if thumb {
+            let opcode = read(address, 2) as u16;
+            let next = read(address.wrapping_add(2), 2) as u16;
+            let (text, length) = disasm_thumb(opcode, next, address);
+
+            if length == 4 {
+                lines.push(format!("{:08x}:  {:04x} {:04x}  {}", address, opcode, next, text));
+            } else {
+                lines.push(format!("{:08x}:  {:04x}       {}", address, opcode, text));
+            }
+            match address.checked_add(length) {
+                Some(next) => address = next,
+                None => break,
+            }
+        } else {
+            let opcode = read(address, 4);
+            lines.push(format!("{:08x}:  {:08x}   {}", address, opcode, disasm_arm(opcode, address)));
+            match address.checked_add(4) {
+                Some(next) => address = next,
+                None => break,
+            }
+        }
+    }
+
+    lines
+}
+
+fn thumb_offset(base: &str, offset: u32) -> String {
+    if offset == 0 {
+        format!("[{}]", base)
+    } else {
+        format!("[{}, {}]", base, imm(offset))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{disasm_arm, disasm_rom, disasm_thumb};
+
+    // Encodings from an assembler, laid out back to back from 0x08000000 so the resolved
+    // branch targets and literal addresses can be checked too.
+    const ARM: &[(u32, &str)] = &[
+        (0x00000000, "andeq r0, r0, r0"),
+        (0xE3A00001, "mov r0, #1"),
+        (0xE3A00301, "mov r0, #67108864 @ 0x4000000"),
+        (0xE3E01000, "mvn r1, #0"),
+        (0xE1B02003, "movs r2, r3"),
+        (0xE28F1008, "add r1, pc, #8 @ 0x08000024"),
+        (0xE24F2004, "sub r2, pc, #4 @ 0x0800001c"),
+        (0xE0954106, "adds r4, r5, r6, lsl #2"),
+        (0xE0410022, "sub r0, r1, r2, lsr #32"),
+        (0xE0610352, "rsb r0, r1, r2, asr r3"),
+        (0xE0A103E2, "adc r0, r1, r2, ror #7"),
+        (0xE0D10062, "sbcs r0, r1, r2, rrx"),
+        (0xE2E100FF, "rsc r0, r1, #255"),
+        (0xE2021CFF, "and r1, r2, #65280 @ 0xff00"),
+        (0xE0221003, "eor r1, r2, r3"),
+        (0xE189800A, "orr r8, r9, r10"),
+        (0xE1CCB00D, "bic r11, r12, sp"),
+        (0xE350000A, "cmp r0, #10"),
+        (0xE1710002, "cmn r1, r2"),
+        (0xE3130001, "tst r3, #1"),
+        (0xE1340615, "teq r4, r5, lsl r6"),
+        (0x11A0F00E, "movne pc, lr"),
+        (0xE0000291, "mul r0, r1, r2"),
+        (0xE0303291, "mlas r0, r1, r2, r3"),
+        (0xE0810392, "umull r0, r1, r2, r3"),
+        (0xE0A10392, "umlal r0, r1, r2, r3"),
+        (0xE0C10392, "smull r0, r1, r2, r3"),
+        (0xE0F10392, "smlals r0, r1, r2, r3"),
+        (0xE1020091, "swp r0, r1, [r2]"),
+        (0xE1420091, "swpb r0, r1, [r2]"),
+        (0xE1D100B0, "ldrh r0, [r1]"),
+        (0xE1D100B2, "ldrh r0, [r1, #2]"),
+        (0xE16100B2, "strh r0, [r1, #-2]!"),
+        (0xE0D100D1, "ldrsb r0, [r1], #1"),
+        (0xE19100F2, "ldrsh r0, [r1, r2]"),
+        (0xE13100B2, "ldrh r0, [r1, -r2]!"),
+        (0xE5910000, "ldr r0, [r1]"),
+        (0xE5910004, "ldr r0, [r1, #4]"),
+        (0xE5110004, "ldr r0, [r1, #-4]"),
+        (0xE5A10004, "str r0, [r1, #4]!"),
+        (0xE4D10001, "ldrb r0, [r1], #1"),
+        (0xE7C10102, "strb r0, [r1, r2, lsl #2]"),
+        (0xE7110002, "ldr r0, [r1, -r2]"),
+        (0xE4B10004, "ldrt r0, [r1], #4"),
+        (0xE59F000C, "ldr r0, [pc, #12] @ 0x080000c4"),
+        (0xE51F0004, "ldr r0, [pc, #-4] @ 0x080000b8"),
+        (0xE890000E, "ldm r0, {r1, r2, r3}"),
+        (0xE8B00006, "ldm r0!, {r1, r2}"),
+        (0xE9204010, "stmdb r0!, {r4, lr}"),
+        (0xE9800002, "stmib r0, {r1}"),
+        (0xE8100002, "ldmda r0, {r1}"),
+        (0xE92D4030, "push {r4, r5, lr}"),
+        (0xE8BD8030, "pop {r4, r5, pc}"),
+        (0xE8DD8001, "ldm sp, {r0, pc}^"),
+        (0xE10F0000, "mrs r0, CPSR"),
+        (0xE14F0000, "mrs r0, SPSR"),
+        (0xE129F000, "msr CPSR_fc, r0"),
+        (0xE368F20F, "msr SPSR_f, #4026531840 @ 0xf0000000"),
+        (0xE321F01F, "msr CPSR_c, #31"),
+        (0xE12FFF1E, "bx lr"),
+        (0x012FFF10, "bxeq r0"),
+        (0xEAFFFFC1, "b 0x08000000"),
+        (0xEBFFFFFE, "bl 0x080000f8"),
+        (0xCA000002, "bgt 0x0800010c"),
+        (0xEF060000, "svc 0x00060000"),
+        (0x1F000006, "svcne 0x00000006"),
+        (0x08900002, "ldmeq r0, {r1}"),
+        (0x05D10000, "ldrbeq r0, [r1]"),
+        (0x01D100F0, "ldrsheq r0, [r1]"),
+    ];
+
+    const THUMB: &[(u16, u16, &str)] = &[
+        (0x0088, 0x0000, "lsls r0, r1, #2"),
+        (0x0808, 0x0000, "lsrs r0, r1, #32"),
+        (0x105A, 0x0000, "asrs r2, r3, #1"),
+        (0x0008, 0x0000, "movs r0, r1"),
+        (0x1888, 0x0000, "adds r0, r1, r2"),
+        (0x1EC8, 0x0000, "subs r0, r1, #3"),
+        (0x20FF, 0x0000, "movs r0, #255"),
+        (0x290A, 0x0000, "cmp r1, #10"),
+        (0x3201, 0x0000, "adds r2, #1"),
+        (0x3BC8, 0x0000, "subs r3, #200"),
+        (0x4008, 0x0000, "ands r0, r1"),
+        (0x4048, 0x0000, "eors r0, r1"),
+        (0x4088, 0x0000, "lsls r0, r1"),
+        (0x40C8, 0x0000, "lsrs r0, r1"),
+        (0x4108, 0x0000, "asrs r0, r1"),
+        (0x4148, 0x0000, "adcs r0, r1"),
+        (0x4188, 0x0000, "sbcs r0, r1"),
+        (0x41C8, 0x0000, "rors r0, r1"),
+        (0x4208, 0x0000, "tst r0, r1"),
+        (0x4248, 0x0000, "negs r0, r1"),
+        (0x4288, 0x0000, "cmp r0, r1"),
+        (0x42C8, 0x0000, "cmn r0, r1"),
+        (0x4308, 0x0000, "orrs r0, r1"),
+        (0x4348, 0x0000, "muls r0, r1"),
+        (0x4388, 0x0000, "bics r0, r1"),
+        (0x43C8, 0x0000, "mvns r0, r1"),
+        (0x4440, 0x0000, "add r0, r8"),
+        (0x448D, 0x0000, "add sp, r1"),
+        (0x4580, 0x0000, "cmp r8, r0"),
+        (0x4660, 0x0000, "mov r0, r12"),
+        (0x46C0, 0x0000, "nop"),
+        (0x4770, 0x0000, "bx lr"),
+        (0x4700, 0x0000, "bx r0"),
+        (0x4802, 0x0000, "ldr r0, [pc, #8] @ 0x0800004c"),
+        (0x5088, 0x0000, "str r0, [r1, r2]"),
+        (0x5488, 0x0000, "strb r0, [r1, r2]"),
+        (0x5888, 0x0000, "ldr r0, [r1, r2]"),
+        (0x5C88, 0x0000, "ldrb r0, [r1, r2]"),
+        (0x5288, 0x0000, "strh r0, [r1, r2]"),
+        (0x5688, 0x0000, "ldrsb r0, [r1, r2]"),
+        (0x5A88, 0x0000, "ldrh r0, [r1, r2]"),
+        (0x5E88, 0x0000, "ldrsh r0, [r1, r2]"),
+        (0x6048, 0x0000, "str r0, [r1, #4]"),
+        (0x6808, 0x0000, "ldr r0, [r1]"),
+        (0x77C8, 0x0000, "strb r0, [r1, #31]"),
+        (0x7848, 0x0000, "ldrb r0, [r1, #1]"),
+        (0x8048, 0x0000, "strh r0, [r1, #2]"),
+        (0x8FC8, 0x0000, "ldrh r0, [r1, #62]"),
+        (0x9001, 0x0000, "str r0, [sp, #4]"),
+        (0x9FFF, 0x0000, "ldr r7, [sp, #1020]"),
+        (0xA008, 0x0000, "add r0, pc, #32 @ 0x08000088"),
+        (0xA903, 0x0000, "add r1, sp, #12"),
+        (0xB002, 0x0000, "add sp, #8"),
+        (0xB0FF, 0x0000, "sub sp, #508"),
+        (0xB530, 0x0000, "push {r4, r5, lr}"),
+        (0xBD01, 0x0000, "pop {r0, pc}"),
+        (0xB4FF, 0x0000, "push {r0, r1, r2, r3, r4, r5, r6, r7}"),
+        (0xC006, 0x0000, "stmia r0!, {r1, r2}"),
+        (0xC806, 0x0000, "ldmia r0!, {r1, r2}"),
+        (0xC803, 0x0000, "ldmia r0, {r0, r1}"),
+        (0xD006, 0x0000, "beq 0x08000088"),
+        (0xD105, 0x0000, "bne 0x08000088"),
+        (0xDF06, 0x0000, "svc 6"),
+        (0xE7FE, 0x0000, "b 0x0800007e"),
+        (0xF000, 0xF802, "bl 0x08000088"),
+        (0xF7FF, 0xFFFE, "bl 0x08000084"),
+    ];
+
+    #[test]
+    fn arm_encodings() {
+        for (i, &(opcode, text)) in ARM.iter().enumerate() {
+            let address = 0x08000000 + i as u32 * 4;
+            assert_eq!(disasm_arm(opcode, address), text, "opcode {:08x} at {:08x}", opcode, address);
+        }
+    }
+
+    #[test]
+    fn thumb_encodings() {
+        let mut address = 0x08000000;
+
+        for &(opcode, next, text) in THUMB {
+            let (disassembly, length) = disasm_thumb(opcode, next, address);
+            assert_eq!(disassembly, text, "opcode {:04x} at {:08x}", opcode, address);
+            address += length;
+        }
+    }
+
+    #[test]
+    fn every_condition() {
+        let names = ["eq", "ne", "cs", "cc", "mi", "pl", "vs", "vc", "hi", "ls", "ge", "lt", "gt", "le", ""];
+
+        for (cond, name) in names.iter().enumerate() {
+            let opcode = (cond as u32) << 28 | 0x01A00001;
+            assert_eq!(disasm_arm(opcode, 0), format!("mov{} r0, r1", name));
+
+            if cond < 14 {
+                let opcode = 0xD000 | (cond as u16) << 8 | 0x02;
+                assert_eq!(disasm_thumb(opcode, 0, 0x08000000), (format!("b{} 0x08000008", name), 2));
+            }
+        }
+    }
+
+    #[test]
+    fn undefined_and_unpaired() {
+        assert_eq!(disasm_arm(0xE7F000F0, 0), "undefined");
+        assert_eq!(disasm_arm(0xEE000000, 0), "undefined");
+        assert_eq!(disasm_arm(0xE1A000F0, 0), "undefined");
+        assert_eq!(disasm_thumb(0xDE00, 0, 0), ("undefined".to_string(), 2));
+        assert_eq!(disasm_thumb(0x4780, 0, 0), ("undefined".to_string(), 2));
+        assert_eq!(disasm_thumb(0xB100, 0, 0), ("undefined".to_string(), 2));
+        assert_eq!(disasm_thumb(0xE800, 0, 0), ("undefined".to_string(), 2));
+
+        // the halves of a bl that are not followed by their partner
+        assert_eq!(disasm_thumb(0xF000, 0x2000, 0), ("bl.hi #0".to_string(), 2));
+        assert_eq!(disasm_thumb(0xF805, 0, 0), ("bl.lo #5".to_string(), 2));
+    }
+
+    #[test]
+    fn branch_targets_wrap_backwards() {
+        assert_eq!(disasm_arm(0xEAFFFFFE, 0x08000100), "b 0x08000100");
+        assert_eq!(disasm_arm(0xEBFFFFFA, 0x08000100), "bl 0x080000f0");
+        assert_eq!(disasm_thumb(0xE7FE, 0, 0x08000100), ("b 0x08000100".to_string(), 2));
+        assert_eq!(disasm_thumb(0xF7FF, 0xFFFE, 0x08000100), ("bl 0x08000100".to_string(), 4));
+    }
+
+    #[test]
+    fn rom_listing() {
+        // mov r0, #1; then movs r0, #1 and a bl in Thumb
+        let rom = [0x01, 0x00, 0xA0, 0xE3, 0x01, 0x20, 0xFF, 0xF7, 0xFD, 0xFF];
+
+        assert_eq!(disasm_rom(&rom, 0, 1), ["08000000:  e3a00001   mov r0, #1"]);
+        assert_eq!(disasm_rom(&rom, 0x08000005, 2), [
+            "08000004:  2001       movs r0, #1",
+            "08000006:  f7ff fffd  bl 0x08000004",
+        ]);
+        // past the end of the image reads as zeroes
+        assert_eq!(disasm_rom(&rom, 0x10, 1), ["08000010:  00000000   andeq r0, r0, r0"]);
+    }
+
+    #[test]
+    fn rom_listing_stops_at_the_top_of_memory() {
+        assert_eq!(disasm_rom(&[], 0xFFFFFFF8, 4).len(), 2);
+        assert_eq!(disasm_rom(&[], 0xFFFFFFFB, 4).len(), 3);
+        assert_eq!(disasm_rom(&[], 0xFFFFFFFF, 4).len(), 1);
+    }
+}
@@ -0,0 +1,52 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+use super::save_state::StateError;
+
+/// Everything the library can fail with. Oddities of the emulated hardware, such as a
+/// program switching to a mode that does not exist, are logged rather than returned.
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    EmptyRom,
+    RomTooLarge { size: usize, max: usize },
+    MultibootTooLarge { size: usize, max: usize },
+    /// A multiboot transfer over the link went wrong.
+    Multiboot(&'static str),
+    BiosSize { found: usize, expected: usize },
+    InvalidMode(u32),
+    /// An access of `size` bytes at `offset` ran past the end of a memory region.
+    BusFault { offset: u32, size: usize },
+    State(StateError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "{}", e),
+            Error::EmptyRom => write!(f, "ROM is empty"),
+            Error::RomTooLarge { size, max } => write!(f, "ROM is {} bytes, the cartridge space holds at most {}", size, max),
+            Error::MultibootTooLarge { size, max } => write!(f, "multiboot image is {} bytes, EWRAM holds at most {}", size, max),
+            Error::Multiboot(reason) => write!(f, "multiboot transfer failed: {}", reason),
+            Error::BiosSize { found, expected } => write!(f, "BIOS is {} bytes, expected {}", found, expected),
+            Error::InvalidMode(mode) => write!(f, "invalid CPU mode 0x{:02X}", mode),
+            Error::BusFault { offset, size } => write!(f, "{} byte access at offset 0x{:X} is outside its memory region", size, offset),
+            Error::State(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::Io(e)
+    }
+}
+
+impl From<StateError> for Error {
+    fn from(e: StateError) -> Error {
+        Error::State(e)
+    }
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+
+use super::cpu::Cpu;
+
+const FNV_OFFSET_BASIS: u64 = 0xCBF29CE484222325;
+const FNV_PRIME: u64 = 0x00000100000001B3;
+
+/// 64 bit FNV-1a. Unlike the std hashers its output is fixed, so golden files stay valid
+/// across hosts and compiler versions.
+pub struct FrameHasher {
+    hash: u64,
+}
+
+impl FrameHasher {
+    pub fn new() -> FrameHasher {
+        FrameHasher {
+            hash: FNV_OFFSET_BASIS,
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.hash ^= *byte as u64;
+            self.hash = self.hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write(&[value as u8, (value >> 8) as u8]);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write(&[value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.hash
+    }
+}
+
+impl Default for FrameHasher {
+    fn default() -> FrameHasher {
+        FrameHasher::new()
+    }
+}
+
+pub fn hash_framebuffer(framebuffer: &[u16]) -> u64 {
+    let mut hasher = FrameHasher::new();
+
+    for pixel in framebuffer {
+        hasher.write_u16(*pixel);
+    }
+
+    hasher.finish()
+}
+
+/// One line of a hash run: `frame <n> fb <hash> [state <hash>]`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct FrameHash {
+    pub frame: u32,
+    pub framebuffer: u64,
+    pub state: Option<u64>,
+}
+
+impl FrameHash {
+    pub fn parse(line: &str) -> Option<FrameHash> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words.as_slice() {
+            ["frame", frame, "fb", framebuffer] => Some(FrameHash {
+                frame: frame.parse().ok()?,
+                framebuffer: u64::from_str_radix(framebuffer, 16).ok()?,
+                state: None,
+            }),
+            ["frame", frame, "fb", framebuffer, "state", state] => Some(FrameHash {
+                frame: frame.parse().ok()?,
+                framebuffer: u64::from_str_radix(framebuffer, 16).ok()?,
+                state: Some(u64::from_str_radix(state, 16).ok()?),
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for FrameHash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "frame {} fb {:016x}", self.frame, self.framebuffer)?;
+
+        if let Some(state) = self.state {
+            write!(f, " state {:016x}", state)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Reads a golden file. Blank lines and lines starting with `#` are skipped.
+pub fn read_golden(path: &str) -> io::Result<Vec<FrameHash>> {
+    let file = File::open(path)?;
+    let mut hashes = vec!();
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match FrameHash::parse(line) {
+            Some(hash) => hashes.push(hash),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: malformed frame hash", path, number + 1))),
+        }
+    }
+
+    Ok(hashes)
+}
+
+/// Finds the first golden entry the run disagrees with. A golden entry without a state
+/// hash only checks the framebuffer; a frame the run never reached counts as a divergence.
+pub fn first_divergence(golden: &[FrameHash], run: &[FrameHash]) -> Option<(FrameHash, Option<FrameHash>)> {
+    for expected in golden {
+        let actual = run.iter().find(|hash| hash.frame == expected.frame);
+
+        let matches = match actual {
+            Some(actual) => actual.framebuffer == expected.framebuffer && (expected.state.is_none() || actual.state == expected.state),
+            None => false,
+        };
+
+        if !matches {
+            return Some((*expected, actual.cloned()));
+        }
+    }
+
+    None
+}
+
+/// Hashes the frame that was just run, plus the machine state when `with_state` is set.
+pub(crate) fn hash_frame(cpu: &Cpu, frame: u32, with_state: bool) -> FrameHash {
+    FrameHash {
+        frame,
+        framebuffer: hash_framebuffer(cpu.framebuffer()),
+        state: if with_state { Some(cpu.hash_state()) } else { None },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{first_divergence, hash_framebuffer, read_golden, FrameHash, FrameHasher};
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("gba-rs-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    fn hash(frame: u32, framebuffer: u64, state: Option<u64>) -> FrameHash {
+        FrameHash { frame, framebuffer, state }
+    }
+
+    #[test]
+    fn fnv_1a() {
+        // the reference values for 64 bit FNV-1a
+        assert_eq!(FrameHasher::new().finish(), 0xCBF29CE484222325);
+        let mut hasher = FrameHasher::new();
+        hasher.write(b"a");
+        assert_eq!(hasher.finish(), 0xAF63DC4C8601EC8C);
+        let mut hasher = FrameHasher::new();
+        hasher.write(b"foobar");
+        assert_eq!(hasher.finish(), 0x85944171F73967E8);
+
+        // pixels are little endian
+        let mut hasher = FrameHasher::new();
+        hasher.write(&[0x34, 0x12, 0xCD, 0xAB]);
+        assert_eq!(hash_framebuffer(&[0x1234, 0xABCD]), hasher.finish());
+    }
+
+    #[test]
+    fn display_and_parse() {
+        let plain = hash(7, 0x0123456789ABCDEF, None);
+        let with_state = hash(120, 0xF, Some(0xFEDCBA9876543210));
+        assert_eq!(plain.to_string(), "frame 7 fb 0123456789abcdef");
+        assert_eq!(with_state.to_string(), "frame 120 fb 000000000000000f state fedcba9876543210");
+
+        for hash in &[plain, with_state] {
+            assert_eq!(FrameHash::parse(&hash.to_string()), Some(*hash));
+        }
+        assert_eq!(FrameHash::parse("  frame 3\tfb  ABC "), Some(hash(3, 0xABC, None)));
+
+        for bad in &["", "frame", "frame 1 fb", "frame x fb 0", "frame 1 fb xyz", "frame 1 fb 0 state",
+                     "frame 1 fb 0 state 0 extra", "frame -1 fb 0", "frame 1 fb 10000000000000000", "fb 0 frame 1"] {
+            assert_eq!(FrameHash::parse(bad), None, "{}", bad);
+        }
+    }
+
+    #[test]
+    fn golden_files() {
+        let path = temp_path("golden.txt");
+        fs::write(&path, "# a comment\n\nframe 1 fb 00000000000000aa\n  frame 2 fb bb state cc  \n").unwrap();
+        let golden = read_golden(&path);
+        fs::write(&path, "frame 1 fb aa\nframe 2 fb\n").unwrap();
+        let malformed = read_golden(&path);
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(golden.unwrap(), [hash(1, 0xAA, None), hash(2, 0xBB, Some(0xCC))]);
+        let error = malformed.unwrap_err();
+        assert!(error.to_string().ends_with("golden.txt:2: malformed frame hash"), "{}", error);
+        assert!(read_golden(&temp_path("missing.txt")).is_err());
+    }
+
+    #[test]
+    fn divergence() {
+        let run = [hash(1, 0xA, Some(0x1)), hash(2, 0xB, Some(0x2)), hash(3, 0xC, None)];
+
+        assert_eq!(first_divergence(&[], &run), None);
+        assert_eq!(first_divergence(&run, &run), None);
+        // no state hash in the golden entry: only the framebuffer counts
+        assert_eq!(first_divergence(&[hash(2, 0xB, None)], &run), None);
+        // golden entries may skip frames and come in any order
+        assert_eq!(first_divergence(&[hash(3, 0xC, None), hash(1, 0xA, None)], &run), None);
+
+        assert_eq!(first_divergence(&[hash(1, 0xA, None), hash(2, 0xD, None)], &run), Some((hash(2, 0xD, None), Some(run[1]))));
+        assert_eq!(first_divergence(&[hash(2, 0xB, Some(0x3))], &run), Some((hash(2, 0xB, Some(0x3)), Some(run[1]))));
+        // a state the run did not hash
+        assert_eq!(first_divergence(&[hash(3, 0xC, Some(0x3))], &run), Some((hash(3, 0xC, Some(0x3)), Some(run[2]))));
+        // a frame the run never reached
+        assert_eq!(first_divergence(&[hash(4, 0xD, None)], &run), Some((hash(4, 0xD, None), None)));
+    }
+}
@@ -0,0 +1,270 @@
+use std::fs;
+use std::io;
+
+use super::cpu::{Backend, Cpu, Profile};
+use super::debugger;
+use super::error::Error;
+use super::frame_hash::{self, FrameHash};
+use super::gdb;
+use super::keypad::Key;
+use super::link::Link;
+use super::trace::Trace;
+
+/// One Game Boy Advance: load a ROM (and optionally a BIOS), reset, then run it a frame at
+/// a time, feeding in the buttons and reading back the picture and sound.
+pub struct Gba {
+    cpu: Cpu,
+}
+
+impl Gba {
+    pub fn new() -> Gba {
+        Gba {
+            cpu: Cpu::new(),
+        }
+    }
+
+    /// Boots through this 16 KiB BIOS image on the next `reset`. Without one the cartridge starts
+    /// straight away with the registers the BIOS would have left behind.
+    pub fn load_bios(&mut self, bios: &[u8]) -> Result<(), Error> {
+        self.cpu.load_bios(bios)
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        self.cpu.load_rom(rom)
+    }
+
+    /// Loads a multiboot image, which runs from EWRAM in place of a cartridge.
+    pub fn load_multiboot(&mut self, image: &[u8]) -> Result<(), Error> {
+        self.cpu.load_multiboot(image)
+    }
+
+    /// Waits for a multiboot image from another instance over the link, then runs it. Call
+    /// after `reset`, with a link connected.
+    pub fn receive_multiboot(&mut self) {
+        self.cpu.receive_multiboot();
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    /// Runs until the LCD enters the next VBlank.
+    pub fn run_frame(&mut self) {
+        self.cpu.run_frame();
+    }
+
+    /// Runs a frame one instruction at a time, logging each one to `trace`.
+    pub fn run_frame_traced(&mut self, trace: &mut Trace) {
+        self.cpu.run_frame_stepped(|cpu| trace.log(cpu));
+    }
+
+    pub fn step_instruction(&mut self) {
+        self.cpu.step_instruction();
+    }
+
+    pub fn backend(&self) -> Backend {
+        self.cpu.backend()
+    }
+
+    /// Picks how instructions are executed; the default is the plain interpreter.
+    pub fn set_backend(&mut self, backend: Backend) {
+        self.cpu.set_backend(backend);
+    }
+
+    /// Fast-forwards through loops that only poll memory until the next event, instead of
+    /// running them. Saves host CPU on games that busy-wait rather than halt.
+    pub fn set_idle_skip(&mut self, enabled: bool) {
+        self.cpu.set_idle_skip(enabled);
+    }
+
+    /// Plugs in a link cable to other instances, for multiplayer and the other serial modes.
+    /// Every instance has to be connected before any of them runs a frame.
+    pub fn connect_link(&mut self, link: Box<dyn Link>) {
+        self.cpu.connect_link(link);
+    }
+
+    /// Counts memory accesses by region and times the LCD, APU and DMA, for `profile`.
+    pub fn set_profiling(&mut self, enabled: bool) {
+        self.cpu.set_profiling(enabled);
+    }
+
+    pub fn profile(&self) -> Profile {
+        self.cpu.profile()
+    }
+
+    /// Replaces the whole button state; bit n of `pressed` is `Key` n.
+    pub fn set_keys(&mut self, pressed: u16) {
+        self.cpu.set_keys(pressed);
+    }
+
+    pub fn press_key(&mut self, key: Key) {
+        self.cpu.press_key(key);
+    }
+
+    pub fn release_key(&mut self, key: Key) {
+        self.cpu.release_key(key);
+    }
+
+    pub fn keys(&self) -> u16 {
+        self.cpu.keys()
+    }
+
+    /// The last frame, `SCREEN_WIDTH` x `SCREEN_HEIGHT` pixels in BGR555, row by row.
+    pub fn framebuffer(&self) -> &[u16] {
+        self.cpu.framebuffer()
+    }
+
+    /// Interleaved stereo samples at `apu::SAMPLE_RATE` produced during the last frame.
+    pub fn audio_samples(&self) -> &[i16] {
+        self.cpu.audio_samples()
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    /// Restores a state from `save_state`. It must come from the same ROM.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), Error> {
+        Ok(self.cpu.load_state(data)?)
+    }
+
+    /// Reads a BIOS image from `path` and boots through it on the next `reset`.
+    pub fn load_bios_file(&mut self, path: &str) -> Result<(), Error> {
+        self.load_bios(&fs::read(path)?)
+    }
+
+    pub fn save_state_file(&self, path: &str) -> Result<(), Error> {
+        Ok(fs::write(path, self.save_state())?)
+    }
+
+    pub fn load_state_file(&mut self, path: &str) -> Result<(), Error> {
+        self.load_state(&fs::read(path)?)
+    }
+
+    /// Hashes the frame that was just run, plus the machine state when `with_state` is set.
+    pub fn hash_frame(&self, frame: u32, with_state: bool) -> FrameHash {
+        frame_hash::hash_frame(&self.cpu, frame, with_state)
+    }
+
+    /// A hash of the whole machine, for checking that two runs are still in step.
+    pub fn hash_state(&self) -> u64 {
+        self.cpu.hash_state()
+    }
+
+    /// r0-r15 as the running code sees them.
+    pub fn reg(&self, index: usize) -> u32 {
+        self.cpu.reg(index)
+    }
+
+    pub fn cpsr(&self) -> u32 {
+        self.cpu.cpsr()
+    }
+
+    /// Reads a byte the way the debuggers do: without side effects on IO registers.
+    pub fn debug_read_8(&self, address: u32) -> u8 {
+        self.cpu.debug_read_8(address)
+    }
+
+    /// Waits for a GDB remote debugger on `127.0.0.1:port` and serves it until it detaches.
+    pub fn serve_gdb(&mut self, port: u16) -> io::Result<()> {
+        gdb::serve(&mut self.cpu, port)
+    }
+
+    /// Runs the interactive debugger on stdin/stdout until `quit` or the end of input.
+    pub fn run_debugger(&mut self) -> io::Result<()> {
+        debugger::run(&mut self.cpu)
+    }
+}
+
+impl Default for Gba {
+    fn default() -> Gba {
+        Gba::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::super::error::Error;
+    use super::Gba;
+
+    /// Counts in r0 and stores the count in EWRAM, forever.
+    const PROGRAM: [u32; 4] = [
+        0xE3A05402, // mov r5, #0x02000000
+        0xE2800001, // add r0, r0, #1
+        0xE5850000, // str r0, [r5]
+        0xEAFFFFFC, // b 0x08000004
+    ];
+
+    fn booted() -> Gba {
+        let rom: Vec<u8> = PROGRAM.iter().flat_map(|opcode| opcode.to_le_bytes()).collect();
+        let mut gba = Gba::new();
+        gba.load_rom(&rom).unwrap();
+        gba.reset();
+        gba
+    }
+
+    #[test]
+    fn runs_frames() {
+        let mut gba = booted();
+        gba.run_frame();
+        gba.run_frame();
+
+        let count = gba.reg(0);
+        assert!(count > 0);
+        assert!((0x08000000..0x08000010).contains(&gba.reg(15)));
+        let stored = (0..4).map(|i| gba.debug_read_8(0x02000000 + i) as u32).rev().fold(0, |value, byte| value << 8 | byte);
+        assert!(stored == count || stored + 1 == count);
+        assert_eq!(gba.framebuffer().len(), 240 * 160);
+    }
+
+    #[test]
+    fn state_round_trip() {
+        let mut gba = booted();
+        gba.run_frame();
+        let state = gba.save_state();
+        let hash = gba.hash_state();
+
+        gba.run_frame();
+        gba.run_frame();
+        let ahead = gba.hash_state();
+        assert_ne!(ahead, hash);
+
+        // into a fresh instance, and back into the one that ran on
+        let mut other = booted();
+        other.load_state(&state).unwrap();
+        assert_eq!(other.hash_state(), hash);
+        gba.load_state(&state).unwrap();
+        assert_eq!(gba.hash_state(), hash);
+
+        for gba in [&mut gba, &mut other] {
+            gba.run_frame();
+            gba.run_frame();
+            assert_eq!(gba.hash_state(), ahead);
+        }
+
+        assert!(gba.load_state(&state[..state.len() / 2]).is_err());
+    }
+
+    #[test]
+    fn state_files() {
+        let path = env::temp_dir().join(format!("gba-rs-{}-state.ss1", process::id())).to_string_lossy().into_owned();
+        let mut gba = booted();
+        gba.run_frame();
+        gba.save_state_file(&path).unwrap();
+        let hash = gba.hash_state();
+
+        gba.run_frame();
+        let loaded = gba.load_state_file(&path);
+        fs::remove_file(&path).unwrap();
+        loaded.unwrap();
+        assert_eq!(gba.hash_state(), hash);
+
+        assert!(matches!(gba.load_state_file(&path), Err(Error::Io(_))));
+        assert!(matches!(gba.load_bios_file(&path), Err(Error::Io(_))));
+        assert!(matches!(gba.save_state_file(&env::temp_dir().join("missing/state").to_string_lossy()), Err(Error::Io(_))));
+    }
+}
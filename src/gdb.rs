@@ -0,0 +1,619 @@
+use std::io::{self, BufRead, BufReader, ErrorKind, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+use super::cpu::{Cpu, WatchKind, Watchpoint};
+
+/// Instructions run between checks for a ^C from the debugger while continuing.
+const INTERRUPT_CHECK_INTERVAL: u32 = 0x4000;
+
+/// SIGTRAP for breakpoints, steps and watchpoints; SIGINT for a ^C.
+const SIGTRAP: u8 = 5;
+const SIGINT: u8 = 2;
+
+/// The largest packet we accept or send, advertised in hex as GDB expects. An `m` reply is two
+/// hex digits a byte, so reads are capped at half of it.
+const PACKET_SIZE: u32 = 0x1000;
+const MAX_MEMORY_READ: u32 = PACKET_SIZE / 2;
+
+/// A GDB remote serial protocol stub. Registers use GDB's legacy ARM layout: r0-r15, eight
+/// 96-bit FPA registers, FPS and the CPSR as register 25. The FPA ones always read as zero.
+pub struct GdbStub<'a> {
+    cpu: &'a mut Cpu,
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    breakpoints: Vec<u32>,
+}
+
+/// Waits for one debugger on `127.0.0.1:port` and serves it until it detaches or kills.
+pub(crate) fn serve(cpu: &mut Cpu, port: u16) -> io::Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port))?;
+    info!("waiting for gdb on port {}", port);
+
+    let (stream, address) = listener.accept()?;
+    info!("gdb connected from {}", address);
+    stream.set_nodelay(true)?;
+
+    let mut stub = GdbStub {
+        cpu,
+        reader: BufReader::new(stream.try_clone()?),
+        writer: stream,
+        breakpoints: vec!(),
+    };
+
+    stub.run()
+}
+
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+fn hex_u32_le(value: u32) -> String {
+    value.to_le_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_u32_le(text: &str) -> Option<u32> {
+    if text.len() != 8 {
+        return None;
+    }
+
+    let mut bytes = [0; 4];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&text[i * 2..i * 2 + 2], 16).ok()?;
+    }
+
+    Some(u32::from_le_bytes(bytes))
+}
+
+impl<'a> GdbStub<'a> {
+    fn run(&mut self) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet()? {
+                Some(packet) => packet,
+                None => return Ok(()),
+            };
+
+            // every command is ASCII; hex slicing below relies on it
+            if !packet.is_ascii() {
+                self.write_packet("E01")?;
+                continue;
+            }
+
+            let reply = match packet.chars().next() {
+                Some('?') => self.stop_reply(SIGTRAP),
+                Some('g') => self.read_registers(),
+                Some('G') => self.write_registers(&packet[1..]),
+                Some('p') => self.read_register(&packet[1..]),
+                Some('P') => self.write_register(&packet[1..]),
+                Some('m') => self.read_memory(&packet[1..]),
+                Some('M') => self.write_memory(&packet[1..]),
+                Some('s') => {
+                    self.resume_at(&packet[1..]);
+                    self.cpu.step_instruction();
+                    match self.cpu.take_watch_hit() {
+                        Some(hit) => self.watch_reply(hit.watchpoint, hit.address),
+                        None => self.stop_reply(SIGTRAP),
+                    }
+                },
+                Some('c') => {
+                    self.resume_at(&packet[1..]);
+                    match self.continue_running()? {
+                        Some(reply) => reply,
+                        None => return Ok(()),
+                    }
+                },
+                Some('Z') => self.breakpoint(&packet[1..], true),
+                Some('z') => self.breakpoint(&packet[1..], false),
+                Some('H') => "OK".to_string(),
+                Some('q') if packet.starts_with("qSupported") => format!("PacketSize={:x}", PACKET_SIZE),
+                Some('q') if packet == "qAttached" => "1".to_string(),
+                Some('D') => {
+                    self.write_packet("OK")?;
+                    return Ok(());
+                },
+                Some('k') => return Ok(()),
+                _ => String::new(),
+            };
+
+            self.write_packet(&reply)?;
+        }
+    }
+
+    /// Reads the next `$packet#xx`, acknowledging it. Returns None once the debugger hangs up.
+    fn read_packet(&mut self) -> io::Result<Option<String>> {
+        loop {
+            let mut byte = [0; 1];
+            if self.reader.read(&mut byte)? == 0 {
+                return Ok(None);
+            }
+
+            if byte[0] != b'$' {
+                // acks and stray ^C between commands
+                continue;
+            }
+
+            let mut data = vec!();
+            if self.reader.read_until(b'#', &mut data)? == 0 {
+                return Ok(None);
+            }
+            data.pop();
+
+            let mut sum = [0; 2];
+            self.reader.read_exact(&mut sum)?;
+
+            let expected = ::std::str::from_utf8(&sum).ok().and_then(|sum| u8::from_str_radix(sum, 16).ok());
+
+            if expected == Some(checksum(&data)) {
+                self.writer.write_all(b"+")?;
+                return Ok(Some(String::from_utf8_lossy(&data).into_owned()));
+            }
+
+            self.writer.write_all(b"-")?;
+        }
+    }
+
+    fn write_packet(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data.as_bytes()));
+
+        loop {
+            self.writer.write_all(packet.as_bytes())?;
+
+            let mut ack = [0; 1];
+            if self.reader.read(&mut ack)? == 0 || ack[0] != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    fn stop_reply(&self, signal: u8) -> String {
+        format!("S{:02x}", signal)
+    }
+
+    fn watch_reply(&self, watchpoint: Watchpoint, address: u32) -> String {
+        let kind = match watchpoint.kind {
+            WatchKind::Write => "watch",
+            WatchKind::Read => "rwatch",
+            WatchKind::Access => "awatch",
+        };
+
+        format!("T{:02x}{}:{:08x};", SIGTRAP, kind, address)
+    }
+
+    fn resume_at(&mut self, address: &str) {
+        if let Some(address) = parse_hex(address) {
+            self.cpu.set_reg(15, address);
+        }
+    }
+
+    /// Runs until a breakpoint, a watchpoint or a ^C. Returns None if the debugger went away.
+    fn continue_running(&mut self) -> io::Result<Option<String>> {
+        // step off a breakpoint we are sitting on
+        let mut first = true;
+
+        loop {
+            for _ in 0..INTERRUPT_CHECK_INTERVAL {
+                if !first && self.breakpoints.contains(&self.cpu.pc()) {
+                    return Ok(Some(self.stop_reply(SIGTRAP)));
+                }
+                first = false;
+
+                self.cpu.step_instruction();
+
+                if let Some(hit) = self.cpu.take_watch_hit() {
+                    return Ok(Some(self.watch_reply(hit.watchpoint, hit.address)));
+                }
+            }
+
+            self.writer.set_nonblocking(true)?;
+            let polled = self.reader.fill_buf().map(|buffer| buffer.first().cloned());
+            // back to blocking before returning, whatever the poll found
+            self.writer.set_nonblocking(false)?;
+
+            let polled = match polled {
+                Ok(None) => return Ok(None),
+                Ok(Some(byte)) => Some(byte),
+                Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+                Err(e) => return Err(e),
+            };
+
+            let interrupted = polled == Some(0x03);
+            if polled.is_some() {
+                self.reader.consume(1);
+            }
+
+            if interrupted {
+                return Ok(Some(self.stop_reply(SIGINT)));
+            }
+        }
+    }
+
+    fn register(&mut self, index: u32) -> Option<String> {
+        match index {
+            0..=15 => Some(hex_u32_le(self.cpu.reg(index as usize))),
+            16..=23 => Some("0".repeat(24)),
+            24 => Some(hex_u32_le(0)),
+            25 => Some(hex_u32_le(self.cpu.cpsr())),
+            _ => None,
+        }
+    }
+
+    fn set_register(&mut self, index: u32, value: u32) -> bool {
+        match index {
+            0..=15 => self.cpu.set_reg(index as usize, value),
+            16..=24 => (),
+            25 => return self.cpu.set_cpsr(value).is_ok(),
+            _ => return false,
+        }
+
+        true
+    }
+
+    fn read_registers(&mut self) -> String {
+        (0..26).filter_map(|index| self.register(index)).collect()
+    }
+
+    fn write_registers(&mut self, data: &str) -> String {
+        // r0-r15, then the FPA block, then CPSR
+        let cpsr_offset = (16 * 4 + 8 * 12 + 4) * 2;
+
+        if data.len() < cpsr_offset + 8 {
+            return "E01".to_string();
+        }
+
+        for index in 0..16 {
+            match parse_u32_le(&data[index * 8..index * 8 + 8]) {
+                Some(value) if self.set_register(index as u32, value) => (),
+                _ => return "E01".to_string(),
+            }
+        }
+
+        match parse_u32_le(&data[cpsr_offset..cpsr_offset + 8]) {
+            Some(value) if self.set_register(25, value) => (),
+            _ => return "E01".to_string(),
+        }
+
+        "OK".to_string()
+    }
+
+    fn read_register(&mut self, args: &str) -> String {
+        match parse_hex(args).and_then(|index| self.register(index)) {
+            Some(value) => value,
+            None => "E01".to_string(),
+        }
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, '=');
+        let index = parts.next().and_then(parse_hex);
+        let value = parts.next().and_then(parse_u32_le);
+
+        match (index, value) {
+            (Some(index), Some(value)) if self.set_register(index, value) => "OK".to_string(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn parse_range(args: &str) -> Option<(u32, u32)> {
+        let mut parts = args.splitn(2, ',');
+        let address = parts.next().and_then(parse_hex)?;
+        let length = parts.next().and_then(parse_hex)?;
+        Some((address, length))
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        match GdbStub::parse_range(args) {
+            Some((address, length)) if length <= MAX_MEMORY_READ => (0..length)
+                .map(|offset| format!("{:02x}", self.cpu.debug_read_8(address.wrapping_add(offset))))
+                .collect(),
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let mut parts = args.splitn(2, ':');
+        let range = parts.next().and_then(GdbStub::parse_range);
+        let data = parts.next().unwrap_or("");
+
+        let (address, length) = match range {
+            Some(range) if data.len() == range.1 as usize * 2 => range,
+            _ => return "E01".to_string(),
+        };
+
+        for offset in 0..length as usize {
+            match u8::from_str_radix(&data[offset * 2..offset * 2 + 2], 16) {
+                Ok(byte) => self.cpu.debug_write_8(address.wrapping_add(offset as u32), byte),
+                Err(_) => return "E01".to_string(),
+            }
+        }
+
+        "OK".to_string()
+    }
+
+    /// Z/z packets. Types 0 and 1 (software and hardware breakpoints) are handled the same
+    /// way; 2, 3 and 4 are write, read and access watchpoints.
+    fn breakpoint(&mut self, args: &str, insert: bool) -> String {
+        let mut parts = args.split(',');
+        let kind = parts.next().and_then(parse_hex);
+        let address = parts.next().and_then(parse_hex);
+        let length = parts.next().and_then(parse_hex);
+
+        let (kind, address, length) = match (kind, address, length) {
+            (Some(kind), Some(address), Some(length)) => (kind, address, length),
+            _ => return "E01".to_string(),
+        };
+
+        let watch_kind = match kind {
+            0 | 1 => {
+                if insert {
+                    if !self.breakpoints.contains(&address) {
+                        self.breakpoints.push(address);
+                    }
+                } else {
+                    self.breakpoints.retain(|&breakpoint| breakpoint != address);
+                }
+                return "OK".to_string();
+            },
+            2 => WatchKind::Write,
+            3 => WatchKind::Read,
+            4 => WatchKind::Access,
+            _ => return String::new(),
+        };
+
+        let watchpoint = Watchpoint { address, length, kind: watch_kind };
+
+        if insert {
+            self.cpu.add_watchpoint(watchpoint);
+        } else {
+            self.cpu.remove_watchpoint(watchpoint);
+        }
+
+        "OK".to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::{checksum, hex_u32_le, parse_u32_le, GdbStub};
+    use super::super::cpu::Cpu;
+
+    fn packet(data: &[u8]) -> Vec<u8> {
+        let mut packet = vec!(b'$');
+        packet.extend_from_slice(data);
+        packet.extend_from_slice(format!("#{:02x}", checksum(data)).as_bytes());
+        packet
+    }
+
+    /// Sends one packet and returns the reply's payload, or "-" if the stub asked for a resend.
+    fn exchange(stream: &mut TcpStream, data: &[u8]) -> String {
+        stream.write_all(&packet(data)).unwrap();
+
+        let mut byte = [0; 1];
+        stream.read_exact(&mut byte).unwrap();
+        if byte[0] == b'-' {
+            return "-".to_string();
+        }
+        assert_eq!(byte[0], b'+');
+
+        let mut reply = vec!();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0; 2];
+        stream.read_exact(&mut sum).unwrap();
+        assert_eq!(&sum[..], format!("{:02x}", checksum(&reply[1..])).as_bytes());
+        stream.write_all(b"+").unwrap();
+
+        String::from_utf8(reply[1..].to_vec()).unwrap()
+    }
+
+    /// Serves `cpu` to a client thread running `script`, returning what the script returned.
+    fn session<F: FnOnce(&mut TcpStream) -> Vec<String> + Send + 'static>(cpu: &mut Cpu, script: F) -> Vec<String> {
+        let listener = TcpListener::bind(("127.0.0.1", 0)).unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(("127.0.0.1", port)).unwrap();
+            script(&mut stream)
+        });
+
+        let (stream, _) = listener.accept().unwrap();
+        let mut stub = GdbStub {
+            cpu,
+            reader: BufReader::new(stream.try_clone().unwrap()),
+            writer: stream,
+            breakpoints: vec!(),
+        };
+        stub.run().unwrap();
+
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn checksum_and_hex() {
+        assert_eq!(checksum(b""), 0);
+        assert_eq!(checksum(b"OK"), 0x9a);
+        assert_eq!(checksum(b"qSupported"), 0x37);
+        assert_eq!(hex_u32_le(0x12345678), "78563412");
+        assert_eq!(parse_u32_le("78563412"), Some(0x12345678));
+        assert_eq!(parse_u32_le("7856341"), None);
+        assert_eq!(parse_u32_le("785634zz"), None);
+    }
+
+    #[test]
+    fn packets() {
+        let mut cpu = Cpu::new();
+        let replies = session(&mut cpu, |stream| {
+            let mut bad = packet(b"g");
+            let length = bad.len();
+            bad[length - 1] ^= 1;
+            stream.write_all(&bad).unwrap();
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).unwrap();
+
+            let mut registers = b"G".to_vec();
+            registers.extend_from_slice("\u{e9}".repeat(100).as_bytes());
+
+            let replies = vec!(
+                (byte[0] as char).to_string(),
+                exchange(stream, b"qAttached"),
+                exchange(stream, &registers),
+                exchange(stream, "M3000000,2:\u{e9}".as_bytes()),
+                exchange(stream, b"P3=78563412"),
+                exchange(stream, b"p3"),
+                exchange(stream, b"M3000000,2:abcd"),
+                exchange(stream, b"m3000000,2"),
+            );
+            stream.write_all(&packet(b"k")).unwrap();
+            replies
+        });
+
+        assert_eq!(replies, ["-", "1", "E01", "E01", "OK", "78563412", "OK", "abcd"]);
+        assert_eq!(cpu.reg(3), 0x12345678);
+    }
+
+    /// mov r0, #0x03000000; mov r1, #5; str r1, [r0]; ldr r2, [r0]; loop: add r3, r3, #1; b loop
+    const PROGRAM: [u32; 6] = [0xE3A00403, 0xE3A01005, 0xE5801000, 0xE5902000, 0xE2833001, 0xEAFFFFFD];
+
+    fn program_cpu() -> Cpu {
+        let rom: Vec<u8> = PROGRAM.iter().flat_map(|word| word.to_le_bytes().to_vec()).collect();
+        let mut cpu = Cpu::new();
+        cpu.load_rom(&rom).unwrap();
+        cpu.reset();
+        cpu
+    }
+
+    #[test]
+    fn breakpoints_and_stepping() {
+        let mut cpu = program_cpu();
+        let replies = session(&mut cpu, |stream| {
+            let replies = vec!(
+                exchange(stream, b"Z0,8000008,4"),
+                exchange(stream, b"c"),
+                exchange(stream, b"pf"),
+                exchange(stream, b"s"),
+                exchange(stream, b"pf"),
+                exchange(stream, b"z0,8000008,4"),
+                exchange(stream, b"Z1,8000010,4"),
+                exchange(stream, b"c"),
+                exchange(stream, b"p3"),
+                // round the loop once and back onto the breakpoint
+                exchange(stream, b"c"),
+                exchange(stream, b"p3"),
+                exchange(stream, b"z1,8000010,4"),
+                // resuming at an address runs from there
+                exchange(stream, b"s8000000"),
+                exchange(stream, b"p0"),
+            );
+            stream.write_all(&packet(b"k")).unwrap();
+            replies
+        });
+
+        assert_eq!(replies, [
+            "OK", "S05", "08000008", "S05", "0c000008", "OK",
+            "OK", "S05", "00000000", "S05", "01000000", "OK",
+            "S05", "00000003",
+        ]);
+    }
+
+    #[test]
+    fn watchpoints() {
+        let mut cpu = program_cpu();
+        let replies = session(&mut cpu, |stream| {
+            let replies = vec!(
+                exchange(stream, b"Z3,3000000,4"),
+                exchange(stream, b"Z2,3000000,4"),
+                exchange(stream, b"c"),
+                exchange(stream, b"z2,3000000,4"),
+                exchange(stream, b"c"),
+                exchange(stream, b"z3,3000000,4"),
+                // an access watchpoint stops on the store and, stepping, on the load
+                exchange(stream, b"Z4,3000000,4"),
+                exchange(stream, b"c8000000"),
+                exchange(stream, b"s"),
+                exchange(stream, b"z4,3000000,4"),
+                exchange(stream, b"Z5,3000000,4"),
+            );
+            stream.write_all(&packet(b"k")).unwrap();
+            replies
+        });
+
+        assert_eq!(replies, [
+            "OK", "OK", "T05watch:03000000;", "OK", "T05rwatch:03000000;", "OK",
+            "OK", "T05awatch:03000000;", "T05awatch:03000000;", "OK", "",
+        ]);
+    }
+
+    #[test]
+    fn memory_and_register_writes() {
+        let mut cpu = program_cpu();
+        let replies = session(&mut cpu, |stream| {
+            // a valid register block whose CPSR has no valid mode
+            let mut registers = b"G".to_vec();
+            registers.extend_from_slice("0".repeat((16 * 4 + 8 * 12 + 4 + 4) * 2).as_bytes());
+
+            let replies = vec!(
+                exchange(stream, b"qSupported:multiprocess+"),
+                exchange(stream, b"M2000010,4:78563412"),
+                exchange(stream, b"m2000010,4"),
+                exchange(stream, b"m2000012,1"),
+                exchange(stream, b"m0,800").len().to_string(),
+                exchange(stream, b"m0,801"),
+                exchange(stream, b"m0,ffffffff"),
+                exchange(stream, &registers),
+            );
+            stream.write_all(&packet(b"k")).unwrap();
+            replies
+        });
+
+        assert_eq!(replies, ["PacketSize=1000", "OK", "78563412", "34", "4096", "E01", "E01", "E01"]);
+        assert_eq!(cpu.debug_read_8(0x02000013), 0x12);
+    }
+
+    #[test]
+    fn interrupt_while_continuing() {
+        let mut cpu = Cpu::new();
+        // b .
+        cpu.load_rom(&[0xFE, 0xFF, 0xFF, 0xEA]).unwrap();
+        cpu.reset();
+
+        let replies = session(&mut cpu, |stream| {
+            stream.write_all(&packet(b"c")).unwrap();
+            let mut byte = [0; 1];
+            stream.read_exact(&mut byte).unwrap();
+            thread::sleep(Duration::from_millis(20));
+            stream.write_all(&[0x03]).unwrap();
+
+            // the reply to the ^C comes without a request of its own
+            let mut reply = vec!();
+            loop {
+                stream.read_exact(&mut byte).unwrap();
+                reply.push(byte[0]);
+                if reply.len() >= 3 && reply[reply.len() - 3] == b'#' {
+                    break;
+                }
+            }
+            stream.write_all(b"+").unwrap();
+
+            let replies = vec!(
+                String::from_utf8(reply).unwrap(),
+                exchange(stream, b"p0"),
+            );
+            stream.write_all(&packet(b"k")).unwrap();
+            replies
+        });
+
+        assert_eq!(replies[0], format!("$S02#{:02x}", checksum(b"S02")));
+        assert_eq!(replies[1], "00000000");
+    }
+}
@@ -0,0 +1,244 @@
+use std::ops::RangeInclusive;
+use std::str::FromStr;
+
+use super::save_state::{StateError, StateReader, StateWriter};
+
+/// The ten GBA buttons, numbered by their KEYINPUT bit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Key {
+    A = 0,
+    B = 1,
+    Select = 2,
+    Start = 3,
+    Right = 4,
+    Left = 5,
+    Up = 6,
+    Down = 7,
+    R = 8,
+    L = 9,
+}
+
+pub const ALL_KEYS: u16 = 0x03FF;
+
+impl Key {
+    pub fn mask(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+impl FromStr for Key {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Key, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "a" => Ok(Key::A),
+            "b" => Ok(Key::B),
+            "select" => Ok(Key::Select),
+            "start" => Ok(Key::Start),
+            "right" => Ok(Key::Right),
+            "left" => Ok(Key::Left),
+            "up" => Ok(Key::Up),
+            "down" => Ok(Key::Down),
+            "r" => Ok(Key::R),
+            "l" => Ok(Key::L),
+            _ => Err(format!("unknown key {}, expected a, b, select, start, right, left, up, down, r or l", name)),
+        }
+    }
+}
+
+/// Buttons held down over a range of frames, parsed from `a+start@60-61`. A single frame
+/// needs no range.
+pub struct Press {
+    pub keys: u16,
+    pub frames: RangeInclusive<u32>,
+}
+
+impl FromStr for Press {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Press, String> {
+        let bad = || format!("bad key press {}: expected KEY+KEY@FIRST-LAST", text);
+        let (keys, frames) = text.split_once('@').ok_or_else(bad)?;
+
+        let mut mask = 0;
+        for key in keys.split('+') {
+            mask |= key.parse::<Key>()?.mask();
+        }
+
+        let (first, last) = frames.split_once('-').unwrap_or((frames, frames));
+        match (first.parse(), last.parse()) {
+            (Ok(first), Ok(last)) if first <= last => Ok(Press { keys: mask, frames: first..=last }),
+            _ => Err(bad()),
+        }
+    }
+}
+
+/// The buttons `presses` hold down during `frame`.
+pub fn keys_held(presses: &[Press], frame: u32) -> u16 {
+    presses.iter().filter(|press| press.frames.contains(&frame)).fold(0, |keys, press| keys | press.keys)
+}
+
+/// KEYINPUT (0x04000130) and KEYCNT (0x04000132). The hardware reports buttons active low;
+/// `pressed` keeps them active high and `keyinput` converts.
+pub struct Keypad {
+    pressed: u16,
+    keycnt: u16,
+    /// Whether the interrupt condition held when last checked; the interrupt is only
+    /// requested when it becomes true.
+    irq_line: bool,
+}
+
+impl Keypad {
+    pub fn new() -> Keypad {
+        Keypad {
+            pressed: 0,
+            keycnt: 0,
+            irq_line: false,
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.pressed);
+        writer.write_u16(self.keycnt);
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.pressed = reader.read_u16()? & ALL_KEYS;
+        self.keycnt = reader.read_u16()? & 0xC3FF;
+        // the condition was checked whenever the keys or KEYCNT last changed
+        self.irq_line = self.irq_condition();
+        Ok(())
+    }
+
+    pub fn keyinput(&self) -> u16 {
+        !self.pressed & ALL_KEYS
+    }
+
+    pub fn keycnt(&self) -> u16 {
+        self.keycnt
+    }
+
+    /// Bitmask of the held buttons, bit n set for `Key` n.
+    pub fn pressed(&self) -> u16 {
+        self.pressed
+    }
+
+    pub fn set_pressed(&mut self, pressed: u16) {
+        self.pressed = pressed & ALL_KEYS;
+    }
+
+    pub fn write_keycnt(&mut self, value: u16) {
+        self.keycnt = value & 0xC3FF;
+    }
+
+    /// Whether KEYCNT asks for an interrupt with the buttons held right now. In OR mode any
+    /// selected button will do, in AND mode all of them have to be down.
+    fn irq_condition(&self) -> bool {
+        if self.keycnt & 0x4000 == 0 {
+            return false;
+        }
+
+        let selected = self.keycnt & ALL_KEYS;
+        let held = self.pressed & selected;
+
+        if self.keycnt & 0x8000 != 0 {
+            selected != 0 && held == selected
+        } else {
+            held != 0
+        }
+    }
+
+    /// Checks the interrupt condition again and returns whether it has just become true.
+    pub fn irq_edge(&mut self) -> bool {
+        let condition = self.irq_condition();
+        let rising = condition && !self.irq_line;
+        self.irq_line = condition;
+        rising
+    }
+}
+
+impl Default for Keypad {
+    fn default() -> Keypad {
+        Keypad::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{keys_held, Key, Keypad, Press};
+
+    fn keypad(keycnt: u16, keys: &[Key]) -> Keypad {
+        let mut keypad = Keypad::new();
+        keypad.write_keycnt(keycnt);
+        keypad.set_pressed(keys.iter().fold(0, |pressed, key| pressed | key.mask()));
+        keypad
+    }
+
+    #[test]
+    fn keyinput_is_active_low() {
+        let keypad = keypad(0, &[Key::A, Key::L]);
+        assert_eq!(keypad.keyinput(), 0x01FE);
+        assert_eq!(keypad.pressed(), 0x0201);
+    }
+
+    #[test]
+    fn or_mode() {
+        // A or Start
+        assert!(!keypad(0x4009, &[]).irq_condition());
+        assert!(!keypad(0x4009, &[Key::B, Key::Select]).irq_condition());
+        assert!(keypad(0x4009, &[Key::Start]).irq_condition());
+        assert!(keypad(0x4009, &[Key::A, Key::Start, Key::R]).irq_condition());
+        // nothing without the IRQ enable
+        assert!(!keypad(0x0009, &[Key::A]).irq_condition());
+    }
+
+    #[test]
+    fn and_mode() {
+        // A and Start
+        assert!(!keypad(0xC009, &[Key::A]).irq_condition());
+        assert!(keypad(0xC009, &[Key::A, Key::Start]).irq_condition());
+        assert!(keypad(0xC009, &[Key::A, Key::Start, Key::B]).irq_condition());
+        // no keys selected never matches
+        assert!(!keypad(0xC000, &[Key::A]).irq_condition());
+    }
+
+    #[test]
+    fn interrupt_on_the_rising_edge() {
+        let mut keypad = keypad(0x4001, &[]);
+        assert!(!keypad.irq_edge());
+
+        keypad.set_pressed(Key::A.mask());
+        assert!(keypad.irq_edge());
+        // still held: no new request
+        assert!(!keypad.irq_edge());
+        keypad.set_pressed(Key::A.mask() | Key::B.mask());
+        assert!(!keypad.irq_edge());
+
+        keypad.set_pressed(0);
+        assert!(!keypad.irq_edge());
+        keypad.set_pressed(Key::A.mask());
+        assert!(keypad.irq_edge());
+
+        // enabling the interrupt while the keys are down counts as becoming true
+        keypad.write_keycnt(0x0001);
+        assert!(!keypad.irq_edge());
+        keypad.write_keycnt(0x4001);
+        assert!(keypad.irq_edge());
+    }
+
+    #[test]
+    fn presses() {
+        let presses: Vec<Press> = ["a+start@60-61", "b@61", "up@100-100"].iter().map(|press| press.parse().unwrap()).collect();
+        assert_eq!(presses[0].keys, Key::A.mask() | Key::Start.mask());
+        assert_eq!(presses[0].frames, 60..=61);
+
+        assert_eq!(keys_held(&presses, 59), 0);
+        assert_eq!(keys_held(&presses, 60), Key::A.mask() | Key::Start.mask());
+        assert_eq!(keys_held(&presses, 61), Key::A.mask() | Key::Start.mask() | Key::B.mask());
+        assert_eq!(keys_held(&presses, 100), Key::Up.mask());
+
+        for bad in &["a", "a@", "a@x", "a@5-4", "a@1-2-3", "turbo@1", "@1"] {
+            assert!(bad.parse::<Press>().is_err(), "{}", bad);
+        }
+    }
+}
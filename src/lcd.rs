@@ -0,0 +1,169 @@
+use super::mem_map::MemMap;
+use super::read_bytes::read_generic;
+use super::save_state::{StateError, StateReader, StateWriter};
+
+pub const SCREEN_WIDTH: usize = 240;
+pub const SCREEN_HEIGHT: usize = 160;
+
+pub struct Lcd {
+    framebuffer: Vec<u16>,
+}
+
+impl Lcd {
+    pub fn new() -> Lcd {
+        Lcd {
+            framebuffer: vec!(0; SCREEN_WIDTH * SCREEN_HEIGHT),
+        }
+    }
+
+    /// The last completed picture, one BGR555 halfword per pixel.
+    pub fn framebuffer(&self) -> &[u16] {
+        &self.framebuffer
+    }
+
+    /// Shows a white screen, as the LCD does while it is switched off.
+    pub fn blank(&mut self) {
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = 0x7FFF;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for pixel in &self.framebuffer {
+            writer.write_u16(*pixel);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for pixel in self.framebuffer.iter_mut() {
+            *pixel = reader.read_u16()?;
+        }
+
+        Ok(())
+    }
+
+    pub fn render_line(&mut self, mem_map: &MemMap, line: usize) {
+        let dispcnt = mem_map.read_io_16(0x000);
+        let palette = mem_map.palette();
+        let vram = mem_map.vram();
+        let row = &mut self.framebuffer[line * SCREEN_WIDTH..(line + 1) * SCREEN_WIDTH];
+
+        if dispcnt & 0x0080 != 0 {
+            for pixel in row.iter_mut() {
+                *pixel = 0x7FFF;
+            }
+            return;
+        }
+
+        let backdrop = read_generic::<u16>(palette, 0, !0) & 0x7FFF;
+        for pixel in row.iter_mut() {
+            *pixel = backdrop;
+        }
+
+        let frame = if dispcnt & 0x0010 != 0 { 0xA000 } else { 0 };
+        let bg2 = dispcnt & 0x0400 != 0;
+
+        match dispcnt & 7 {
+            mode @ 0 | mode @ 1 => {
+                let count = if mode == 0 { 4 } else { 2 };
+
+                for priority in (0..4).rev() {
+                    for bg in (0..count).rev() {
+                        let bgcnt = mem_map.read_io_16(0x008 + bg as u32 * 2);
+
+                        if dispcnt & (0x0100 << bg) != 0 && bgcnt & 3 == priority {
+                            render_text_line(row, mem_map, bg, line);
+                        }
+                    }
+                }
+            },
+            3 if bg2 => {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    *pixel = read_generic::<u16>(vram, ((line * SCREEN_WIDTH + x) * 2) as u32, !0) & 0x7FFF;
+                }
+            },
+            4 if bg2 => {
+                for (x, pixel) in row.iter_mut().enumerate() {
+                    let index = vram[frame + line * SCREEN_WIDTH + x] as u32;
+                    if index != 0 {
+                        *pixel = read_generic::<u16>(palette, index * 2, !0) & 0x7FFF;
+                    }
+                }
+            },
+            5 if bg2 && line < 128 => {
+                for (x, pixel) in row.iter_mut().take(160).enumerate() {
+                    *pixel = read_generic::<u16>(vram, (frame + (line * 160 + x) * 2) as u32, !0) & 0x7FFF;
+                }
+            },
+            _ => (),
+        }
+    }
+}
+
+impl Default for Lcd {
+    fn default() -> Lcd {
+        Lcd::new()
+    }
+}
+
+fn render_text_line(row: &mut [u16], mem_map: &MemMap, bg: usize, line: usize) {
+    let palette = mem_map.palette();
+    let vram = mem_map.vram();
+
+    let bgcnt = mem_map.read_io_16(0x008 + bg as u32 * 2);
+    let hofs = (mem_map.read_io_16(0x010 + bg as u32 * 4) & 0x1FF) as usize;
+    let vofs = (mem_map.read_io_16(0x012 + bg as u32 * 4) & 0x1FF) as usize;
+
+    let char_base = ((bgcnt >> 2) & 3) as usize * 0x4000;
+    let screen_base = ((bgcnt >> 8) & 0x1F) as usize * 0x800;
+    let color_256 = bgcnt & 0x0080 != 0;
+    let (width, height) = match bgcnt >> 14 {
+        0 => (256, 256),
+        1 => (512, 256),
+        2 => (256, 512),
+        _ => (512, 512),
+    };
+
+    let y = (line + vofs) % height;
+
+    for (x, pixel) in row.iter_mut().enumerate() {
+        let x = (x + hofs) % width;
+
+        let mut block = 0;
+        if x >= 256 {
+            block += 1;
+        }
+        if y >= 256 {
+            block += if width == 512 { 2 } else { 1 };
+        }
+
+        let entry_address = screen_base + block * 0x800 + (((y % 256) / 8) * 32 + (x % 256) / 8) * 2;
+        if entry_address + 1 >= vram.len() {
+            continue;
+        }
+        let entry = read_generic::<u16>(vram, entry_address as u32, !0) as usize;
+
+        let tile = entry & 0x3FF;
+        let tile_x = if entry & 0x0400 != 0 { 7 - x % 8 } else { x % 8 };
+        let tile_y = if entry & 0x0800 != 0 { 7 - y % 8 } else { y % 8 };
+
+        let color = if color_256 {
+            match vram.get(char_base + tile * 64 + tile_y * 8 + tile_x) {
+                Some(&index) => index as usize,
+                None => 0,
+            }
+        } else {
+            match vram.get(char_base + tile * 32 + tile_y * 4 + tile_x / 2) {
+                Some(&pair) => {
+                    let index = if tile_x & 1 != 0 { pair >> 4 } else { pair & 0xF } as usize;
+                    if index == 0 { 0 } else { (entry >> 12) * 16 + index }
+                },
+                None => 0,
+            }
+        };
+
+        if color != 0 {
+            *pixel = read_generic::<u16>(palette, color as u32 * 2, !0) & 0x7FFF;
+        }
+    }
+}
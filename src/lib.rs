@@ -0,0 +1,43 @@
+//! A Game Boy Advance emulator. `Gba` is the entry point, and also hosts the debuggers; the
+//! other public modules are the tooling built around it: tracing, frame hashing, rewind,
+//! movies, audio output, the link cable and multiboot.
+
+#[macro_use]
+extern crate log;
+#[cfg(feature = "jit")]
+extern crate libc;
+extern crate num;
+extern crate time;
+
+mod cpu;
+mod debugger;
+mod error;
+mod gba;
+mod gdb;
+mod lcd;
+mod mem_map;
+mod read_bytes;
+mod save_state;
+mod serial;
+mod timer;
+mod write_bytes;
+
+pub mod apu;
+pub mod bench;
+pub mod disasm;
+pub mod frame_hash;
+pub mod keypad;
+pub mod link;
+pub mod movie;
+pub mod multiboot;
+pub mod pacing;
+pub mod rewind;
+pub mod trace;
+pub mod wav;
+
+pub use cpu::{Backend, Profile};
+pub use error::Error;
+pub use gba::Gba;
+pub use keypad::Key;
+pub use lcd::{SCREEN_HEIGHT, SCREEN_WIDTH};
+pub use save_state::StateError;
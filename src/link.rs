@@ -0,0 +1,342 @@
+//! The link cable between emulator instances. Every instance sends its serial port state at
+//! the end of each scanline and waits for everyone else's before running the next one, so
+//! all of them stay within a line (1232 cycles) of each other and see transfers start and
+//! finish on the same line. Player 0 hosts and relays; the others connect to it.
+
+#[cfg(unix)]
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use std::os::unix::fs::FileTypeExt;
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+#[cfg(test)]
+use std::sync::mpsc::{self, Receiver, Sender};
+
+/// Up to four GBAs fit on one multiplayer cable.
+pub const MAX_PLAYERS: usize = 4;
+
+const HANDSHAKE: &[u8; 2] = b"GL";
+const LINK_STATE_BYTES: usize = 9;
+
+/// What one instance's serial port looked like at the end of a line.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkState {
+    pub siocnt: u16,
+    pub rcnt: u16,
+    /// The data the port would send: SIODATA8, SIODATA32, SIOMLT_SEND or a UART byte.
+    pub data: u32,
+    /// The instance started a transfer on this line, as the one driving the clock.
+    pub start: bool,
+}
+
+impl LinkState {
+    fn encode(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.siocnt.to_le_bytes());
+        bytes.extend_from_slice(&self.rcnt.to_le_bytes());
+        bytes.extend_from_slice(&self.data.to_le_bytes());
+        bytes.push(self.start as u8);
+    }
+
+    fn decode(bytes: &[u8]) -> LinkState {
+        LinkState {
+            siocnt: u16::from_le_bytes([bytes[0], bytes[1]]),
+            rcnt: u16::from_le_bytes([bytes[2], bytes[3]]),
+            data: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            start: bytes[8] != 0,
+        }
+    }
+}
+
+/// Connects a serial port to the other players.
+pub trait Link {
+    /// This instance's place on the cable; 0 is the parent.
+    fn player(&self) -> usize;
+
+    fn players(&self) -> usize;
+
+    /// Publishes this instance's state for the line just run and blocks until every player
+    /// has done the same. Returns all of them in player order, this one included.
+    fn exchange(&mut self, state: LinkState) -> io::Result<Vec<LinkState>>;
+}
+
+trait Stream: Read + Write {}
+
+impl<T: Read + Write> Stream for T {}
+
+/// A `Link` over localhost TCP or a Unix socket. Addresses are `unix:PATH`, `HOST:PORT` or
+/// just a port on 127.0.0.1.
+pub struct SocketLink {
+    player: usize,
+    players: usize,
+    /// The parent holds a stream to each child, in player order; a child has one, to the parent.
+    peers: Vec<Box<dyn Stream>>,
+}
+
+fn tcp_address(address: &str) -> String {
+    if address.contains(':') {
+        address.to_string()
+    } else {
+        format!("127.0.0.1:{}", address)
+    }
+}
+
+#[cfg(unix)]
+fn unix_path(address: &str) -> Option<&str> {
+    address.strip_prefix("unix:")
+}
+
+#[cfg(not(unix))]
+fn unix_path(_address: &str) -> Option<&str> {
+    None
+}
+
+impl SocketLink {
+    /// Becomes player 0 and waits on `address` until `players - 1` others have joined.
+    pub fn host(address: &str, players: usize) -> io::Result<SocketLink> {
+        if !(2..=MAX_PLAYERS).contains(&players) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("a link takes 2 to {} players", MAX_PLAYERS)));
+        }
+
+        let mut accept: Box<dyn FnMut() -> io::Result<Box<dyn Stream>>> = match unix_path(address) {
+            #[cfg(unix)]
+            Some(path) => {
+                // a socket left behind by an earlier run would make the bind fail
+                if fs::symlink_metadata(path).map(|meta| meta.file_type().is_socket()).unwrap_or(false) {
+                    fs::remove_file(path)?;
+                }
+
+                let listener = UnixListener::bind(path)?;
+                Box::new(move || Ok(Box::new(listener.accept()?.0) as Box<dyn Stream>))
+            },
+            _ => {
+                let listener = TcpListener::bind(tcp_address(address))?;
+                Box::new(move || {
+                    let stream = listener.accept()?.0;
+                    stream.set_nodelay(true)?;
+                    Ok(Box::new(stream) as Box<dyn Stream>)
+                })
+            },
+        };
+
+        let mut peers = vec!();
+        for player in 1..players {
+            info!("link: waiting for player {} of {} on {}", player + 1, players, address);
+
+            let mut stream = accept()?;
+            stream.write_all(&[HANDSHAKE[0], HANDSHAKE[1], player as u8, players as u8])?;
+            peers.push(stream);
+        }
+
+        Ok(SocketLink {
+            player: 0,
+            players,
+            peers,
+        })
+    }
+
+    /// Connects to the parent at `address` and takes the next free place on the cable.
+    pub fn join(address: &str) -> io::Result<SocketLink> {
+        let mut stream: Box<dyn Stream> = match unix_path(address) {
+            #[cfg(unix)]
+            Some(path) => Box::new(UnixStream::connect(path)?),
+            _ => {
+                let stream = TcpStream::connect(tcp_address(address))?;
+                stream.set_nodelay(true)?;
+                Box::new(stream)
+            },
+        };
+
+        let mut handshake = [0; 4];
+        stream.read_exact(&mut handshake)?;
+
+        let (player, players) = (handshake[2] as usize, handshake[3] as usize);
+        if handshake[..2] != HANDSHAKE[..] || player == 0 || player >= players || players > MAX_PLAYERS {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not a gba-rs link host"));
+        }
+        info!("link: joined as player {} of {}", player + 1, players);
+
+        Ok(SocketLink {
+            player,
+            players,
+            peers: vec!(stream),
+        })
+    }
+}
+
+impl Link for SocketLink {
+    fn player(&self) -> usize {
+        self.player
+    }
+
+    fn players(&self) -> usize {
+        self.players
+    }
+
+    fn exchange(&mut self, state: LinkState) -> io::Result<Vec<LinkState>> {
+        let mut bytes = vec!();
+        state.encode(&mut bytes);
+
+        if self.player == 0 {
+            let mut buffer = [0; LINK_STATE_BYTES];
+            for peer in self.peers.iter_mut() {
+                peer.read_exact(&mut buffer)?;
+                bytes.extend_from_slice(&buffer);
+            }
+
+            for peer in self.peers.iter_mut() {
+                peer.write_all(&bytes)?;
+            }
+        } else {
+            self.peers[0].write_all(&bytes)?;
+
+            bytes.resize(LINK_STATE_BYTES * self.players, 0);
+            self.peers[0].read_exact(&mut bytes)?;
+        }
+
+        Ok(bytes.chunks(LINK_STATE_BYTES).map(LinkState::decode).collect())
+    }
+}
+
+/// A `Link` between threads of one process, for tests. It relays through player 0 like
+/// `SocketLink`, so a player that goes away fails the exchange instead of hanging it.
+#[cfg(test)]
+pub struct ChannelLink {
+    player: usize,
+    players: usize,
+    to: Vec<Sender<Vec<LinkState>>>,
+    from: Vec<Receiver<Vec<LinkState>>>,
+}
+
+#[cfg(test)]
+impl ChannelLink {
+    /// One end for each of `players`, in player order.
+    pub fn cable(players: usize) -> Vec<ChannelLink> {
+        let mut parent = ChannelLink {
+            player: 0,
+            players,
+            to: vec!(),
+            from: vec!(),
+        };
+        let mut children = vec!();
+
+        for player in 1..players {
+            let (to_child, from_parent) = mpsc::channel();
+            let (to_parent, from_child) = mpsc::channel();
+            parent.to.push(to_child);
+            parent.from.push(from_child);
+            children.push(ChannelLink {
+                player,
+                players,
+                to: vec!(to_parent),
+                from: vec!(from_parent),
+            });
+        }
+
+        let mut links = vec!(parent);
+        links.extend(children);
+        links
+    }
+}
+
+#[cfg(test)]
+fn player_left<E>(_: E) -> io::Error {
+    io::Error::new(io::ErrorKind::BrokenPipe, "player left the link")
+}
+
+#[cfg(test)]
+impl Link for ChannelLink {
+    fn player(&self) -> usize {
+        self.player
+    }
+
+    fn players(&self) -> usize {
+        self.players
+    }
+
+    fn exchange(&mut self, state: LinkState) -> io::Result<Vec<LinkState>> {
+        let mut states = vec!(state);
+
+        if self.player == 0 {
+            for from in &self.from {
+                states.extend(from.recv().map_err(player_left)?);
+            }
+            for to in &self.to {
+                to.send(states.clone()).map_err(player_left)?;
+            }
+        } else {
+            self.to[0].send(states).map_err(player_left)?;
+            states = self.from[0].recv().map_err(player_left)?;
+        }
+
+        Ok(states)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+    use std::thread;
+
+    use super::{Link, LinkState, SocketLink};
+
+    fn state(player: usize, line: u32) -> LinkState {
+        LinkState {
+            siocnt: 0x1000 | player as u16,
+            rcnt: 0x4000,
+            data: 0xCAFE0000 | line << 4 | player as u32,
+            start: line & 1 != 0,
+        }
+    }
+
+    /// Runs four lines on `link`, returning what each exchange brought back.
+    fn run_lines(link: &mut SocketLink) -> Vec<Vec<LinkState>> {
+        (0..4).map(|line| link.exchange(state(link.player(), line)).unwrap()).collect()
+    }
+
+    #[test]
+    fn socket_link_exchange() {
+        let path = env::temp_dir().join(format!("gba-rs-{}-link.sock", process::id()));
+        let address = format!("unix:{}", path.to_string_lossy());
+
+        let mut threads = vec!();
+        {
+            let address = address.clone();
+            threads.push(thread::spawn(move || {
+                let mut link = SocketLink::host(&address, 3).unwrap();
+                (link.player(), link.players(), run_lines(&mut link))
+            }));
+        }
+        for _ in 1..3 {
+            let address = address.clone();
+            threads.push(thread::spawn(move || {
+                // the host has to be listening before anyone can join
+                let mut link = loop {
+                    if let Ok(link) = SocketLink::join(&address) {
+                        break link;
+                    }
+                    thread::yield_now();
+                };
+                (link.player(), link.players(), run_lines(&mut link))
+            }));
+        }
+
+        let mut players = vec!();
+        for thread in threads {
+            let (player, count, lines) = thread.join().unwrap();
+            players.push(player);
+            assert_eq!(count, 3);
+
+            for (line, states) in lines.iter().enumerate() {
+                assert_eq!(*states, (0..3).map(|player| state(player, line as u32)).collect::<Vec<_>>());
+            }
+        }
+        let _ = fs::remove_file(&path);
+
+        players.sort();
+        assert_eq!(players, [0, 1, 2]);
+    }
+}
@@ -3,43 +3,593 @@ extern crate getopts;
 #[macro_use]
 extern crate log;
 extern crate env_logger;
-extern crate num;
-
-mod cpu;
-mod mem_map;
-mod read_bytes;
-mod write_bytes;
+extern crate gba_rs;
 
 use std::env;
 use std::fs::File;
 use std::io::Read;
+use std::path::Path;
+use std::process;
+
+use gba_rs::keypad::{self, Press};
+use gba_rs::{apu, bench, disasm, frame_hash, link, movie, multiboot, pacing, rewind, trace, wav, Gba};
+
+/// Snapshots kept by `--rewind`; at one per second that is ten minutes.
+const REWIND_CAPACITY: usize = 600;
+
+#[cfg(not(feature = "jit"))]
+const BACKEND_HELP: &str = "how to run instructions: interpreter (default) or cached";
+#[cfg(feature = "jit")]
+const BACKEND_HELP: &str = "how to run instructions: interpreter (default), cached or jit";
 
 fn usage(opts: &getopts::Options) {
     let prog = env::args().next().unwrap();
-    println!("{}", opts.usage(&format!("usage: {} [options] <rom>", prog)));
+    println!("{}", opts.usage(&format!("usage: {} [options] <rom>\n       {} [options] --multiboot-receive --link-join ADDR\n       {} bench [options] <rom> --frames N\n       {} multiboot-send <image> --link-host ADDR\n       {} disasm <rom> <address> <count>", prog, prog, prog, prog, prog)));
+}
+
+fn parse_address(text: &str) -> Option<u32> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+/// `disasm <rom> <address> <count>`: prints `count` instructions from the ROM.
+fn disasm_rom(args: &[String]) {
+    if args.len() != 3 {
+        println!("usage: disasm <rom> <address> <count>");
+        process::exit(2);
+    }
+
+    let rom = match read_file(&args[0]) {
+        Some(rom) => rom,
+        None => process::exit(2),
+    };
+
+    let (address, count) = match (parse_address(&args[1]), args[2].parse::<u32>()) {
+        (Some(address), Ok(count)) => (address, count),
+        _ => {
+            println!("bad address or count: {} {}", args[1], args[2]);
+            process::exit(2);
+        },
+    };
+
+    for line in disasm::disasm_rom(&rom, address, count) {
+        println!("{}", line);
+    }
+}
+
+fn read_file(path: &str) -> Option<Vec<u8>> {
+    let mut data = vec!();
+
+    let file = File::open(path);
+    match file.and_then(|mut f| f.read_to_end(&mut data)) {
+        Ok(..) => Some(data),
+        Err(e) => {
+            println!("failed to read {}: {}", path, e);
+            None
+        },
+    }
+}
+
+/// Reports a bad command line and exits; a typo should not look like a crash.
+fn usage_error(message: &str) -> ! {
+    println!("{}", message);
+    process::exit(2);
+}
+
+/// Quick-save slot N of `game.gba` lives next to it as `game.ssN`.
+fn slot_path(rom_path: &str, slot: &str) -> String {
+    let slot: u8 = match slot.parse() {
+        Ok(slot) if slot <= 9 => slot,
+        _ => usage_error(&format!("bad save slot {}: expected 0-9", slot)),
+    };
+
+    Path::new(rom_path).with_extension(format!("ss{}", slot)).to_string_lossy().into_owned()
+}
+
+/// Plugs into the link cable `--link-host` or `--link-join` asks for, if either.
+fn open_link(matches: &getopts::Matches) -> Option<link::SocketLink> {
+    let link = match (matches.opt_str("link-host"), matches.opt_str("link-join")) {
+        (Some(_), Some(_)) => usage_error("--link-host and --link-join cannot be used together"),
+        (Some(address), None) => {
+            let players = match matches.opt_str("link-players").map(|players| players.parse::<usize>()) {
+                Some(Ok(players)) => players,
+                Some(Err(f)) => usage_error(&format!("bad player count: {}", f)),
+                None => 2,
+            };
+            (address.clone(), link::SocketLink::host(&address, players))
+        },
+        (None, Some(address)) => (address.clone(), link::SocketLink::join(&address)),
+        (None, None) => return None,
+    };
+
+    match link {
+        (_, Ok(link)) => Some(link),
+        (address, Err(e)) => {
+            println!("failed to link over {}: {}", address, e);
+            process::exit(2);
+        },
+    }
+}
+
+/// `multiboot-send <image>`: plays the host side of a multiboot transfer over the link cable,
+/// for another instance started with `--multiboot-receive`.
+fn send_multiboot(args: &[String], matches: &getopts::Matches) {
+    if args.len() != 1 {
+        usage_error("usage: multiboot-send <image> --link-host ADDR");
+    }
+
+    let image = match read_file(&args[0]) {
+        Some(image) => image,
+        None => process::exit(2),
+    };
+    let mut link = match open_link(matches) {
+        Some(link) => link,
+        None => usage_error("multiboot-send needs a link from --link-host or --link-join"),
+    };
+
+    match multiboot::send(&mut link, &image) {
+        Ok(()) => println!("sent {} ({} bytes)", args[0], image.len()),
+        Err(e) => {
+            println!("failed to send {}: {}", args[0], e);
+            process::exit(1);
+        },
+    }
 }
 
 fn main() {
-    env_logger::init().unwrap();
+    env_logger::init();
     let mut opts = getopts::Options::new();
 
     opts
-        .optflag("h", "help", "show this message");
+        .optflag("h", "help", "show this message")
+        .optopt("", "bios", "BIOS image to boot through", "FILE")
+        .optopt("", "backend", BACKEND_HELP, "NAME")
+        .optflag("", "idle-skip", "fast-forward through busy-wait loops")
+        .optopt("", "speed", "run at X times the GBA's 59.73 fps instead of unlimited", "X")
+        .optflag("", "turbo", "run unlimited, overriding any frame limit; headless runs already default to it")
+        .optopt("", "hash-frames", "run headless for N frames and print a hash of each frame", "N")
+        .optopt("", "hash-state", "also hash the machine state at these frames", "FRAME,FRAME,...")
+        .optopt("", "golden", "compare the frame hashes against a golden file", "FILE")
+        .optopt("", "frames", "run headless for N frames", "N")
+        .optopt("", "wav", "record the audio output of a headless run to a WAV file", "FILE")
+        .optopt("", "sample-rate", "sample rate of the WAV file (default 32768)", "HZ")
+        .optmulti("", "press", "hold buttons during frames FIRST to LAST, e.g. a+start@60-62", "KEYS@FIRST-LAST")
+        .optopt("", "record", "record the keypad state of every frame to a movie", "FILE")
+        .optopt("", "play", "replay the keypad state from a movie", "FILE")
+        .optopt("", "load-slot", "start from the state in quick-save slot N", "N")
+        .optopt("", "save-slot", "save the state to quick-save slot N after the run", "N")
+        .optopt("", "rewind", "keep a rewind buffer with a snapshot every N frames", "N")
+        .optopt("", "step-back", "rewind K frames at the end of the run", "K")
+        .optopt("", "link-host", "be player 1 of a link cable, waiting for the others on ADDR (PORT, HOST:PORT or unix:PATH)", "ADDR")
+        .optopt("", "link-players", "players on the link cable, 2-4 (default 2)", "N")
+        .optopt("", "link-join", "join the link cable hosted at ADDR", "ADDR")
+        .optflag("", "multiboot", "load the ROM as a multiboot image into EWRAM; implied by .mb files and their header")
+        .optflag("", "multiboot-receive", "start without a ROM and wait for a multiboot image over the link cable")
+        .optopt("", "gdb", "wait for a GDB remote debugger on a local TCP port", "PORT")
+        .optflag("", "debug", "start in the interactive debugger")
+        .optopt("", "trace", "log every executed instruction of a headless run to a file", "FILE")
+        .optopt("", "trace-format", "trace line format: plain (default), mgba or regs", "FORMAT")
+        .optopt("", "trace-start", "start tracing when the PC reaches ADDR", "ADDR")
+        .optopt("", "trace-stop", "pause tracing when the PC reaches ADDR", "ADDR")
+        .optopt("", "trace-max", "stop tracing after N lines", "N")
+        .optflag("", "dump-regs", "print the registers at the end of a headless run")
+        .optmulti("", "dump-memory", "print LEN bytes from ADDR at the end of a headless run", "ADDR,LEN");
 
     let matches = match opts.parse(env::args().skip(1)) {
         Ok(m) => m,
-        Err(f) => panic!("{}", f),
+        Err(f) => usage_error(&f.to_string()),
     };
 
-    if matches.opt_present("h") || matches.opt_present("help") || matches.free.len() == 0 {
+    let receive = matches.opt_present("multiboot-receive");
+    if matches.opt_present("h") || matches.opt_present("help") || (matches.free.is_empty() && !receive) {
         return usage(&opts);
     }
 
-    let mut rom = vec!();
+    match matches.free.first().map(|command| command.as_str()) {
+        Some("disasm") => return disasm_rom(&matches.free[1..]),
+        Some("multiboot-send") => return send_multiboot(&matches.free[1..], &matches),
+        _ => (),
+    }
+
+    // `bench <rom>` takes the same options as a plain run
+    let bench = matches.free.first().map(|command| command.as_str()) == Some("bench");
+    let rom_path = match (bench, matches.free.get(1)) {
+        (false, _) if receive => {
+            if !matches.free.is_empty() {
+                usage_error("--multiboot-receive takes its program from the link, not a ROM");
+            }
+            if matches.opt_present("load-slot") || matches.opt_present("save-slot") {
+                usage_error("quick-save slots live next to the ROM, so --multiboot-receive has none");
+            }
+            None
+        },
+        (false, _) => Some(matches.free[0].clone()),
+        (true, Some(path)) => Some(path.clone()),
+        (true, None) => usage_error("usage: bench [options] <rom> --frames N"),
+    };
+
+    let rom = match rom_path {
+        Some(ref path) => match read_file(path) {
+            Some(rom) => rom,
+            None => return,
+        },
+        None => vec!(),
+    };
+
+    let mut gba = Gba::new();
+
+    if let Some(path) = matches.opt_str("bios") {
+        if let Err(e) = gba.load_bios_file(&path) {
+            println!("failed to load {}: {}", path, e);
+            process::exit(2);
+        }
+    }
+
+    if let Some(ref path) = rom_path {
+        // multiboot images run from EWRAM, so they cannot just go in the cartridge space
+        let is_multiboot = matches.opt_present("multiboot")
+            || Path::new(path).extension().is_some_and(|extension| extension.eq_ignore_ascii_case("mb"))
+            || multiboot::is_multiboot(&rom);
+        let loaded = if is_multiboot { gba.load_multiboot(&rom) } else { gba.load_rom(&rom) };
+
+        if let Err(e) = loaded {
+            println!("failed to load {}: {}", path, e);
+            process::exit(2);
+        }
+        info!("loaded {} ({} bytes{})", path, rom.len(), if is_multiboot { ", multiboot" } else { "" });
+    }
+    gba.reset();
+    gba.set_idle_skip(matches.opt_present("idle-skip"));
+    if let Some(backend) = matches.opt_str("backend") {
+        match backend.parse() {
+            Ok(backend) => gba.set_backend(backend),
+            Err(e) => usage_error(&e),
+        }
+    }
+
+    if let Some(slot) = matches.opt_str("load-slot") {
+        if matches.opt_present("record") || matches.opt_present("play") {
+            println!("movies start from power-on and cannot be combined with --load-slot");
+            process::exit(2);
+        }
+
+        let path = slot_path(rom_path.as_ref().unwrap(), &slot);
+        if let Err(e) = gba.load_state_file(&path) {
+            println!("failed to load {}: {}", path, e);
+            process::exit(2);
+        }
+        info!("loaded state from {}", path);
+    }
+
+    match open_link(&matches) {
+        Some(link) => gba.connect_link(Box::new(link)),
+        None if receive => usage_error("--multiboot-receive needs a link from --link-host or --link-join"),
+        None => (),
+    }
+    if receive {
+        gba.receive_multiboot();
+    }
+
+    if bench {
+        let frames = match matches.opt_str("frames").map(|frames| frames.parse::<u32>()) {
+            Some(Ok(frames)) if frames > 0 => frames,
+            Some(_) => usage_error("bad frame count: expected a positive number"),
+            None => usage_error("bench needs a frame count from --frames"),
+        };
+
+        print!("{}", bench::run(&mut gba, frames));
+        return;
+    }
+
+    if let Some(port) = matches.opt_str("gdb") {
+        let port = match port.parse() {
+            Ok(port) => port,
+            Err(f) => usage_error(&format!("bad port {}: {}", port, f)),
+        };
+
+        if let Err(e) = gba.serve_gdb(port) {
+            println!("gdb stub failed: {}", e);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if matches.opt_present("debug") {
+        if let Err(e) = gba.run_debugger() {
+            println!("debugger failed: {}", e);
+            process::exit(2);
+        }
+        return;
+    }
+
+    if matches.opt_present("record") && matches.opt_present("play") {
+        println!("--record and --play cannot be used together");
+        process::exit(2);
+    }
+
+    let playback = match matches.opt_str("play") {
+        Some(path) => {
+            let movie = match movie::Movie::read(&path) {
+                Ok(movie) => movie,
+                Err(e) => {
+                    println!("failed to read {}: {}", path, e);
+                    process::exit(2);
+                },
+            };
+
+            if movie.rom_checksum != movie::rom_checksum(&rom) {
+                println!("{} was recorded with a different ROM (header checksum {:02X}, this ROM has {:02X})",
+                    path, movie.rom_checksum, movie::rom_checksum(&rom));
+                process::exit(2);
+            }
+            if movie.emulator_version != movie::EMULATOR_VERSION {
+                warn!("{} was recorded with version {}, this is {}", path, movie.emulator_version, movie::EMULATOR_VERSION);
+            }
+
+            Some(movie)
+        },
+        None => None,
+    };
+
+    let presses: Vec<Press> = matches.opt_strs("press").iter().map(|press| match press.parse::<Press>() {
+        Ok(press) => press,
+        Err(e) => usage_error(&e),
+    }).collect();
+    if !presses.is_empty() && playback.is_some() {
+        usage_error("--press and --play cannot be used together");
+    }
+
+    let mut recording = matches.opt_str("record").map(|path| (path, movie::Movie::new(&rom)));
+
+    let hash_frames = matches.opt_str("hash-frames");
+    if hash_frames.is_none() && (matches.opt_present("golden") || matches.opt_present("hash-state")) {
+        usage_error("--golden and --hash-state need --hash-frames");
+    }
+    let frames: u32 = match hash_frames.clone().or_else(|| matches.opt_str("frames")) {
+        Some(frames) => match frames.parse() {
+            Ok(frames) => frames,
+            Err(f) => usage_error(&format!("bad frame count {}: {}", frames, f)),
+        },
+        None => match playback {
+            Some(ref movie) => movie.frames.len() as u32,
+            None => {
+                if matches.opt_present("wav") || matches.opt_present("record") || matches.opt_present("trace")
+                    || matches.opt_present("dump-regs") || matches.opt_present("dump-memory") {
+                    println!("--wav, --record, --trace and --dump-* need a frame count from --frames or --hash-frames");
+                    return;
+                }
+                if !matches.opt_present("save-slot") {
+                    return;
+                }
+                0
+            },
+        },
+    };
+
+    let state_frames: Vec<u32> = match matches.opt_str("hash-state") {
+        Some(list) => list.split(',').map(|frame| match frame.trim().parse() {
+            Ok(frame) => frame,
+            Err(f) => usage_error(&format!("bad state frame {}: {}", frame, f)),
+        }).collect(),
+        None => vec!(),
+    };
+
+    let memory_dumps: Vec<(u32, u32)> = matches.opt_strs("dump-memory").iter().map(|range| {
+        let mut parts = range.splitn(2, ',');
+        match (parts.next().and_then(parse_address), parts.next().and_then(parse_address)) {
+            (Some(address), Some(length)) => (address, length),
+            _ => usage_error(&format!("bad memory range {}: expected ADDR,LEN", range)),
+        }
+    }).collect();
+
+    let mut wav: Option<Box<dyn apu::AudioSink>> = match matches.opt_str("wav") {
+        Some(path) => {
+            let rate = match matches.opt_str("sample-rate") {
+                Some(rate) => match rate.parse() {
+                    Ok(0) => usage_error("--sample-rate must be above 0"),
+                    Ok(rate) => rate,
+                    Err(f) => usage_error(&format!("bad sample rate {}: {}", rate, f)),
+                },
+                None => apu::SAMPLE_RATE,
+            };
+
+            match wav::create_sink(&path, rate) {
+                Ok(sink) => Some(sink),
+                Err(e) => {
+                    println!("failed to create {}: {}", path, e);
+                    process::exit(2);
+                },
+            }
+        },
+        None => None,
+    };
+
+    let mut rewind = match matches.opt_str("rewind") {
+        Some(interval) => match interval.parse() {
+            Ok(interval) => Some(rewind::Rewind::new(&gba, interval, REWIND_CAPACITY)),
+            Err(f) => usage_error(&format!("bad rewind interval {}: {}", interval, f)),
+        },
+        None => None,
+    };
+
+    let mut trace = match matches.opt_str("trace") {
+        Some(path) => {
+            let format = match matches.opt_str("trace-format").map(|format| format.parse::<trace::TraceFormat>()) {
+                Some(Ok(format)) => format,
+                Some(Err(e)) => usage_error(&e),
+                None => trace::TraceFormat::Plain,
+            };
+            let address = |name| matches.opt_str(name).map(|address: String| match parse_address(&address) {
+                Some(address) => address,
+                None => usage_error(&format!("bad address {}", address)),
+            });
+            let max_lines = matches.opt_str("trace-max").map(|max| match max.parse() {
+                Ok(max) => max,
+                Err(f) => usage_error(&format!("bad line count {}: {}", max, f)),
+            });
+
+            match trace::Trace::create(&path, format, address("trace-start"), address("trace-stop"), max_lines) {
+                Ok(trace) => Some((path, trace)),
+                Err(e) => {
+                    println!("failed to create {}: {}", path, e);
+                    process::exit(2);
+                },
+            }
+        },
+        None => None,
+    };
+
+    if matches.opt_present("speed") && matches.opt_present("turbo") {
+        usage_error("--speed and --turbo cannot be used together");
+    }
+    let speed = matches.opt_str("speed").map(|speed| match speed.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => speed,
+        _ => usage_error(&format!("bad speed {}: expected a positive multiplier", speed)),
+    });
+    // headless runs are unlimited unless --speed asks otherwise; --turbo says so explicitly
+    let speed = if matches.opt_present("turbo") { None } else { speed };
+    let mut pacer = pacing::Pacer::new(speed);
+
+    let mut hashes = vec!();
+
+    for frame in 1..=frames {
+        if !presses.is_empty() {
+            gba.set_keys(keypad::keys_held(&presses, frame));
+        }
+        if let Some(ref movie) = playback {
+            if let Some(&keys) = movie.frames.get(frame as usize - 1) {
+                gba.set_keys(keys);
+            }
+        }
+        let keys = gba.keys();
+        if let Some((_, ref mut movie)) = recording {
+            movie.frames.push(keys);
+        }
+
+        match trace {
+            Some((_, ref mut trace)) if !trace.full() => gba.run_frame_traced(trace),
+            _ => gba.run_frame(),
+        }
+
+        if let Some(ref mut rewind) = rewind {
+            rewind.record_frame(&gba, keys);
+        }
+
+        if let Some(ref mut sink) = wav {
+            if let Err(e) = sink.write(gba.audio_samples()) {
+                println!("failed to write audio: {}", e);
+                process::exit(2);
+            }
+        }
+
+        if hash_frames.is_some() {
+            let hash = gba.hash_frame(frame, state_frames.contains(&frame));
+            println!("{}", hash);
+            hashes.push(hash);
+        }
+
+        pacer.frame_done();
+    }
+    pacer.finish();
+
+    if let Some(ref mut sink) = wav {
+        if let Err(e) = sink.finish() {
+            println!("failed to write audio: {}", e);
+            process::exit(2);
+        }
+    }
+
+    if let Some((path, trace)) = trace {
+        match trace.finish() {
+            Ok(lines) => info!("wrote {} trace lines to {}", lines, path),
+            Err(e) => {
+                println!("failed to write {}: {}", path, e);
+                process::exit(2);
+            },
+        }
+    }
+
+    if let Some(steps) = matches.opt_str("step-back") {
+        let steps: u32 = match steps.parse() {
+            Ok(steps) => steps,
+            Err(f) => usage_error(&format!("bad step count {}: {}", steps, f)),
+        };
+
+        let rewind = match rewind {
+            Some(ref mut rewind) => rewind,
+            None => {
+                println!("--step-back needs a rewind buffer from --rewind");
+                process::exit(2);
+            },
+        };
+
+        for _ in 0..steps {
+            if !rewind.step_back(&mut gba) {
+                println!("rewind buffer exhausted");
+                break;
+            }
+        }
+
+        info!("rewound to frame {} ({} bytes buffered)", rewind.frame(), rewind.size());
+
+        if hash_frames.is_some() {
+            let frame = rewind.frame() as u32;
+            println!("rewound to {}", gba.hash_frame(frame, state_frames.contains(&frame)));
+        }
+    }
+
+    if matches.opt_present("dump-regs") {
+        let regs: Vec<String> = (0..16).map(|index| format!("r{}={:08x}", index, gba.reg(index))).collect();
+        println!("regs {} cpsr={:08x}", regs.join(" "), gba.cpsr());
+    }
+
+    for &(address, length) in &memory_dumps {
+        let bytes: Vec<String> = (0..length).map(|offset| format!("{:02x}", gba.debug_read_8(address.wrapping_add(offset)))).collect();
+        println!("memory {:08x} {}", address, bytes.join(" "));
+    }
+
+    if let Some(slot) = matches.opt_str("save-slot") {
+        let path = slot_path(rom_path.as_ref().unwrap(), &slot);
+        if let Err(e) = gba.save_state_file(&path) {
+            println!("failed to write {}: {}", path, e);
+            process::exit(2);
+        }
+        info!("saved state to {}", path);
+    }
+
+    if let Some((path, movie)) = recording {
+        if let Err(e) = movie.write(&path) {
+            println!("failed to write {}: {}", path, e);
+            process::exit(2);
+        }
+    }
+
+    if hash_frames.is_some() {
+        if let Some(path) = matches.opt_str("golden") {
+            let golden = match frame_hash::read_golden(&path) {
+                Ok(golden) => golden,
+                Err(e) => {
+                    println!("failed to read {}: {}", path, e);
+                    process::exit(2);
+                },
+            };
 
-    let file = File::open(&matches.free[0]);
-    match file.and_then(|mut f| f.read_to_end(&mut rom)) {
-        Ok(..) => {},
-        Err(e) => println!("failed to read {}: {}", matches.free[0], e),
+            match frame_hash::first_divergence(&golden, &hashes) {
+                Some((expected, Some(actual))) => {
+                    println!("first divergence at frame {}", expected.frame);
+                    println!("  expected {}", expected);
+                    println!("  actual   {}", actual);
+                    process::exit(1);
+                },
+                Some((expected, None)) => {
+                    println!("frame {} is in {} but was never reached", expected.frame, path);
+                    process::exit(1);
+                },
+                None => println!("all {} golden frames match", golden.len()),
+            }
+        }
     }
 }
@@ -1,115 +1,371 @@
 use num::{FromPrimitive, Unsigned, PrimInt};
 use std::mem::size_of;
 
-use super::read_bytes::read_le;
-use super::write_bytes::write_le;
+use super::error::Error;
+use super::read_bytes::{read_generic, read_unreadable};
+use super::write_bytes::write_generic;
 
-fn read_bios_builder<T: Unsigned + FromPrimitive>(mask: u32) -> Box<Fn(u32, [u8; 4], u32, &MemMap) -> T> {
+pub const BIOS_SIZE: usize = 0x4000;
+pub const WRAM_SIZE: usize = 0x40000;
+pub const IWRAM_SIZE: usize = 0x8000;
+pub const IO_SIZE: usize = 0x400;
+pub const PALETTE_SIZE: usize = 0x400;
+pub const VRAM_SIZE: usize = 0x18000;
+pub const OAM_SIZE: usize = 0x400;
+pub const SRAM_SIZE: usize = 0x10000;
+pub const ROM_MAX_SIZE: usize = 0x2000000;
+
+const BIOS_OFFSET: u32 = 0;
+const WRAM_OFFSET: u32 = BIOS_OFFSET + BIOS_SIZE as u32;
+const IWRAM_OFFSET: u32 = WRAM_OFFSET + WRAM_SIZE as u32;
+const IO_OFFSET: u32 = IWRAM_OFFSET + IWRAM_SIZE as u32;
+const PALETTE_OFFSET: u32 = IO_OFFSET + IO_SIZE as u32;
+const VRAM_OFFSET: u32 = PALETTE_OFFSET + PALETTE_SIZE as u32;
+const OAM_OFFSET: u32 = VRAM_OFFSET + VRAM_SIZE as u32;
+const SRAM_OFFSET: u32 = OAM_OFFSET + OAM_SIZE as u32;
+const MEMORY_SIZE: usize = SRAM_OFFSET as usize + SRAM_SIZE;
+
+/// Names for the entries of the `mem_access` table, indexed the same way by `address >> 24`.
+const REGION_NAMES: [&str; 15] = [
+    "BIOS", "UNUSED", "EWRAM", "IWRAM", "IO",
+    "PALETTE", "VRAM", "OAM", "ROM0", "ROM0",
+    "ROM1", "ROM1", "ROM2", "ROM2", "SRAM",
+];
+
+type ReadFn<T> = Box<dyn Fn(u32, [u8; 4], u32, &MemMap) -> T>;
+type WriteFn<T> = Box<dyn Fn(u32, T, &mut [u8])>;
+
+fn read_bios_builder<T: Unsigned + FromPrimitive + 'static>(mask: u32) -> ReadFn<T> {
     Box::new(
-        |address: u32, cpu_protected: [u8; 4], reg_15_i: u32, mem_map: &MemMap| {
+        move |address: u32, cpu_protected: [u8; 4], reg_15_i: u32, mem_map: &MemMap| {
             mem_map.read_bios(address, mask, cpu_protected, reg_15_i)
         }
     )
 }
 
-fn unreadable_builder<T: Unsigned + FromPrimitive>() -> Box<Fn(u32, [u8; 4], u32, &MemMap) -> T> {
+fn read_generic_builder<T: Unsigned + FromPrimitive + 'static>(mask: u32, offset: u32) -> ReadFn<T> {
+    Box::new(
+        move |address: u32, _, _, mem_map: &MemMap| {
+            read_generic(&mem_map.memory[offset as usize..], address, mask)
+        }
+    )
+}
+
+fn read_vram_builder<T: Unsigned + FromPrimitive + 'static>() -> ReadFn<T> {
+    Box::new(
+        |address: u32, _, _, mem_map: &MemMap| {
+            read_generic(&mem_map.memory[VRAM_OFFSET as usize..], vram_mask(address), 0x1FFFF)
+        }
+    )
+}
+
+fn read_rom_builder<T: Unsigned + FromPrimitive + 'static>() -> ReadFn<T> {
+    Box::new(
+        |address: u32, _, _, mem_map: &MemMap| {
+            mem_map.read_rom(address)
+        }
+    )
+}
+
+fn read_sram_builder<T: Unsigned + FromPrimitive + 'static>() -> ReadFn<T> {
+    Box::new(
+        |address: u32, _, _, mem_map: &MemMap| {
+            let value = mem_map.memory[(SRAM_OFFSET + (address & 0xFFFF)) as usize] as u32;
+
+            T::from_u32(value * (0x01010101 >> (32 - size_of::<T>() * 8))).expect("Value can not be converted to type T")
+        }
+    )
+}
+
+fn unreadable_builder<T: Unsigned + FromPrimitive + 'static>() -> ReadFn<T> {
+    Box::new(
+        |_, _, _, _: &MemMap| {
+            read_unreadable()
+        }
+    )
+}
+
+fn write_generic_builder<T: Unsigned + PrimInt + 'static>(mask: u32, offset: u32) -> WriteFn<T> {
+    Box::new(
+        move |address: u32, value: T, memory: &mut [u8]| {
+            write_generic(&mut memory[offset as usize..], address, mask, value)
+        }
+    )
+}
+
+fn write_duplicated_builder(mask: u32, offset: u32) -> WriteFn<u8> {
     Box::new(
-        |_, _, _, mem_map: &MemMap| {
-            mem_map.read_unreadable()
+        move |address: u32, value: u8, memory: &mut [u8]| {
+            write_generic(&mut memory[offset as usize..], address, mask, value as u16 * 0x0101)
         }
     )
 }
 
-fn unwritable_builder<T: Unsigned + PrimInt>() -> Box<Fn(u32, T)> {
+fn write_vram_builder<T: Unsigned + PrimInt + 'static>() -> WriteFn<T> {
     Box::new(
-        |_, _, mem_map: &mut MemMap| {
-            mem_map.write_unwritable()
+        |address: u32, value: T, memory: &mut [u8]| {
+            write_generic(&mut memory[VRAM_OFFSET as usize..], vram_mask(address), 0x1FFFF, value)
         }
     )
 }
 
+fn write_vram_8_builder() -> WriteFn<u8> {
+    Box::new(
+        |address: u32, value: u8, memory: &mut [u8]| {
+            // byte writes only reach the background area and land on both halves
+            if vram_mask(address) < 0x10000 {
+                write_generic(&mut memory[VRAM_OFFSET as usize..], vram_mask(address), 0x1FFFF, value as u16 * 0x0101)
+            }
+        }
+    )
+}
+
+fn write_sram_builder<T: Unsigned + PrimInt + 'static>() -> WriteFn<T> {
+    Box::new(
+        |address: u32, value: T, memory: &mut [u8]| {
+            let shift = (address & (size_of::<T>() as u32 - 1)) * 8;
+            let value = value.to_u32().expect("Unable to convert value to u32") >> shift;
+
+            memory[(SRAM_OFFSET + (address & 0xFFFF)) as usize] = value as u8;
+        }
+    )
+}
+
+fn unwritable_builder<T: Unsigned + PrimInt + 'static>() -> WriteFn<T> {
+    Box::new(
+        |_, _, _: &mut [u8]| {
+
+        }
+    )
+}
+
+fn vram_mask(address: u32) -> u32 {
+    let address = address & 0x1FFFF;
+
+    if address >= VRAM_SIZE as u32 {
+        address - 0x8000
+    } else {
+        address
+    }
+}
+
 pub struct MemMap {
     mem_access: [MemAccess; 15],
     memory: Vec<u8>,
+    rom: Vec<u8>,
+    /// A multiboot image, which takes the cartridge's place and runs from EWRAM.
+    multiboot: Vec<u8>,
 }
 
 impl MemMap {
     pub fn new() -> MemMap {
         MemMap {
             mem_access: [
-                MemAccess::new(0x00003FFF, read_bios_builder::<u8>(0x03), read_bios_builder::<u16>(0x02), read_bios_builder::<u32>(0x0F), unwritable,         unwritable,             unwritable),
-                MemAccess::new(0x00000000, unreadable_builder(),          unreadable_builder(),         unreadable_builder(),         unwritable,         unwritable,             unwritable),
-                MemAccess::new(0x0003FFFF, read_generic_8(2),   read_generic_16(2), read_generic_32(2), write_generic_8(2), write_generic_16(3),    write_generic_32(3)),
-                MemAccess::new(),
-                MemAccess::new(),
-
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
-
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
-                MemAccess::new(),
+                MemAccess::new(0x00003FFF, read_bios_builder(0x03), read_bios_builder(0x02), read_bios_builder(0x00), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x00000000, unreadable_builder(), unreadable_builder(), unreadable_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x0003FFFF, read_generic_builder(0x0003FFFF, WRAM_OFFSET), read_generic_builder(0x0003FFFF, WRAM_OFFSET), read_generic_builder(0x0003FFFF, WRAM_OFFSET), write_generic_builder(0x0003FFFF, WRAM_OFFSET), write_generic_builder(0x0003FFFF, WRAM_OFFSET), write_generic_builder(0x0003FFFF, WRAM_OFFSET)),
+                MemAccess::new(0x00007FFF, read_generic_builder(0x00007FFF, IWRAM_OFFSET), read_generic_builder(0x00007FFF, IWRAM_OFFSET), read_generic_builder(0x00007FFF, IWRAM_OFFSET), write_generic_builder(0x00007FFF, IWRAM_OFFSET), write_generic_builder(0x00007FFF, IWRAM_OFFSET), write_generic_builder(0x00007FFF, IWRAM_OFFSET)),
+                MemAccess::new(0x000003FF, read_generic_builder(0x000003FF, IO_OFFSET), read_generic_builder(0x000003FF, IO_OFFSET), read_generic_builder(0x000003FF, IO_OFFSET), write_generic_builder(0x000003FF, IO_OFFSET), write_generic_builder(0x000003FF, IO_OFFSET), write_generic_builder(0x000003FF, IO_OFFSET)),
+
+                MemAccess::new(0x000003FF, read_generic_builder(0x000003FF, PALETTE_OFFSET), read_generic_builder(0x000003FF, PALETTE_OFFSET), read_generic_builder(0x000003FF, PALETTE_OFFSET), write_duplicated_builder(0x000003FF, PALETTE_OFFSET), write_generic_builder(0x000003FF, PALETTE_OFFSET), write_generic_builder(0x000003FF, PALETTE_OFFSET)),
+                MemAccess::new(0x0001FFFF, read_vram_builder(), read_vram_builder(), read_vram_builder(), write_vram_8_builder(), write_vram_builder(), write_vram_builder()),
+                MemAccess::new(0x000003FF, read_generic_builder(0x000003FF, OAM_OFFSET), read_generic_builder(0x000003FF, OAM_OFFSET), read_generic_builder(0x000003FF, OAM_OFFSET), unwritable_builder(), write_generic_builder(0x000003FF, OAM_OFFSET), write_generic_builder(0x000003FF, OAM_OFFSET)),
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x01FFFFFF, read_rom_builder(), read_rom_builder(), read_rom_builder(), unwritable_builder(), unwritable_builder(), unwritable_builder()),
+                MemAccess::new(0x0000FFFF, read_sram_builder(), read_sram_builder(), read_sram_builder(), write_sram_builder(), write_sram_builder(), write_sram_builder()),
             ],
-            memory: vec!(),
+            memory: vec!(0; MEMORY_SIZE),
+            rom: vec!(),
+            multiboot: vec!(),
+        }
+    }
+
+    pub fn load_bios(&mut self, bios: &[u8]) -> Result<(), Error> {
+        if bios.len() != BIOS_SIZE {
+            return Err(Error::BiosSize { found: bios.len(), expected: BIOS_SIZE });
+        }
+
+        self.memory[BIOS_OFFSET as usize..BIOS_OFFSET as usize + BIOS_SIZE].copy_from_slice(bios);
+        Ok(())
+    }
+
+    pub fn load_rom(&mut self, rom: &[u8]) -> Result<(), Error> {
+        if rom.is_empty() {
+            return Err(Error::EmptyRom);
+        }
+        if rom.len() > ROM_MAX_SIZE {
+            return Err(Error::RomTooLarge { size: rom.len(), max: ROM_MAX_SIZE });
+        }
+
+        self.rom = rom.to_vec();
+        self.multiboot.clear();
+        Ok(())
+    }
+
+    /// Loads a multiboot image in place of a cartridge. It is copied to EWRAM by
+    /// `copy_multiboot`, on every reset, as the BIOS would receive it again.
+    pub fn load_multiboot(&mut self, image: &[u8]) -> Result<(), Error> {
+        if image.is_empty() {
+            return Err(Error::EmptyRom);
+        }
+        if image.len() > WRAM_SIZE {
+            return Err(Error::MultibootTooLarge { size: image.len(), max: WRAM_SIZE });
+        }
+
+        self.rom.clear();
+        self.multiboot = image.to_vec();
+        Ok(())
+    }
+
+    pub fn copy_multiboot(&mut self) {
+        let start = WRAM_OFFSET as usize;
+        self.memory[start..start + self.multiboot.len()].copy_from_slice(&self.multiboot);
+    }
+
+    pub fn read_8(&self, address: u32, cpu_protected: [u8; 4], reg_15_i: u32) -> u8 {
+        match self.mem_access.get((address >> 24) as usize) {
+            Some(access) => (access.read_8)(address, cpu_protected, reg_15_i, self),
+            None => read_unreadable(),
+        }
+    }
+
+    pub fn read_16(&self, address: u32, cpu_protected: [u8; 4], reg_15_i: u32) -> u16 {
+        match self.mem_access.get((address >> 24) as usize) {
+            Some(access) => (access.read_16)(address, cpu_protected, reg_15_i, self),
+            None => read_unreadable(),
+        }
+    }
+
+    pub fn read_32(&self, address: u32, cpu_protected: [u8; 4], reg_15_i: u32) -> u32 {
+        match self.mem_access.get((address >> 24) as usize) {
+            Some(access) => (access.read_32)(address, cpu_protected, reg_15_i, self),
+            None => read_unreadable(),
+        }
+    }
+
+    pub fn write_8(&mut self, address: u32, value: u8) {
+        if let Some(access) = self.mem_access.get((address >> 24) as usize) {
+            (access.write_8)(address, value, &mut self.memory)
         }
     }
 
+    pub fn write_16(&mut self, address: u32, value: u16) {
+        if let Some(access) = self.mem_access.get((address >> 24) as usize) {
+            (access.write_16)(address, value, &mut self.memory)
+        }
+    }
+
+    pub fn write_32(&mut self, address: u32, value: u32) {
+        if let Some(access) = self.mem_access.get((address >> 24) as usize) {
+            (access.write_32)(address, value, &mut self.memory)
+        }
+    }
+
+    pub fn read_io_16(&self, address: u32) -> u16 {
+        read_generic(&self.memory[IO_OFFSET as usize..], address, 0x3FF)
+    }
+
+    pub fn write_io_16(&mut self, address: u32, value: u16) {
+        write_generic(&mut self.memory[IO_OFFSET as usize..], address, 0x3FF, value)
+    }
+
+    pub fn palette(&self) -> &[u8] {
+        &self.memory[PALETTE_OFFSET as usize..PALETTE_OFFSET as usize + PALETTE_SIZE]
+    }
+
+    pub fn vram(&self) -> &[u8] {
+        &self.memory[VRAM_OFFSET as usize..VRAM_OFFSET as usize + VRAM_SIZE]
+    }
+
+    /// Everything the running program can change, i.e. the whole map minus BIOS and ROM.
+    pub fn writable_memory(&self) -> &[u8] {
+        &self.memory[WRAM_OFFSET as usize..]
+    }
+
+    pub fn writable_memory_mut(&mut self) -> &mut [u8] {
+        &mut self.memory[WRAM_OFFSET as usize..]
+    }
+
+    pub fn rom(&self) -> &[u8] {
+        &self.rom
+    }
+
+    pub fn multiboot(&self) -> &[u8] {
+        &self.multiboot
+    }
+
+    pub fn region_name(address: u32) -> &'static str {
+        REGION_NAMES.get((address >> 24) as usize).cloned().unwrap_or("UNUSED")
+    }
+
     fn read_bios<T: Unsigned + FromPrimitive>(&self, address: u32, mask: u32, cpu_protected: [u8; 4], reg_15_i: u32) -> T {
         if reg_15_i >> 24 != 0 {
-            if address > 0x4000 {
-                read_le(address & mask, &cpu_protected)
+            if address < 0x4000 {
+                read_generic(&cpu_protected, address, mask)
             } else {
-                self.read_unreadable()
+                read_unreadable()
             }
+        } else if address < 0x4000 {
+            read_generic(&self.memory[BIOS_OFFSET as usize..], address, self.mem_access[0].mask)
         } else {
-            self.read_generic(address, 0)
+            read_unreadable()
         }
     }
 
-    fn write_generic<T: Unsigned + PrimInt>(&mut self, address: u32, mask: u32, value: T) {
-        let mask = self.mem_access[mask as usize].mask;
+    fn read_rom<T: Unsigned + FromPrimitive>(&self, address: u32) -> T {
+        let address = address & self.mem_access[8].mask & !(size_of::<T>() as u32 - 1);
 
-        write_le(&self.memory, address + mask, value)
-    }
+        if address as usize + size_of::<T>() <= self.rom.len() {
+            read_generic(&self.rom, address, 0xFFFFFFFF)
+        } else {
+            // open bus: the cartridge drives the halfword address back
+            let low = (address >> 1) & 0xFFFF;
+            let high = ((address + 2) >> 1) & 0xFFFF;
 
-    fn write_unwritable(&mut self) {
+            T::from_u32((low | (high << 16)) & (0xFFFFFFFF >> (32 - size_of::<T>() * 8))).expect("Value can not be converted to type T")
+        }
+    }
+}
 
+impl Default for MemMap {
+    fn default() -> MemMap {
+        MemMap::new()
     }
 }
 
 struct MemAccess {
     mask: u32,
-    read_8: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u8>,
-    read_16: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u16>,
-    read_32: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u32>,
-    write_8: u8,
-    write_16: u16,
-    write_32: u32,
+    read_8: ReadFn<u8>,
+    read_16: ReadFn<u16>,
+    read_32: ReadFn<u32>,
+    write_8: WriteFn<u8>,
+    write_16: WriteFn<u16>,
+    write_32: WriteFn<u32>,
 }
 
 impl MemAccess {
     pub fn new(
         mask: u32,
-        read_8: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u8>,
-        read_16: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u16>,
-        read_32: Box<Fn(u32, [u8; 4], u32, &MemMap) -> u32>,
-        write_8: u8,
-        write_16: u16,
-        write_32: u32
+        read_8: ReadFn<u8>,
+        read_16: ReadFn<u16>,
+        read_32: ReadFn<u32>,
+        write_8: WriteFn<u8>,
+        write_16: WriteFn<u16>,
+        write_32: WriteFn<u32>
     ) -> MemAccess {
         MemAccess {
-            mask: mask,
-            read_8: read_8,
-            read_16: read_16,
-            read_32: read_32,
-            write_8: write_8,
-            write_16: write_16,
-            write_32: write_32,
+            mask,
+            read_8,
+            read_16,
+            read_32,
+            write_8,
+            write_16,
+            write_32,
         }
     }
 }
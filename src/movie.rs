@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+
+const MAGIC: &[u8; 4] = b"GBAM";
+const FORMAT_VERSION: u16 = 1;
+
+pub const EMULATOR_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Offset of the complement check byte in the cartridge header.
+const ROM_HEADER_CHECKSUM: usize = 0xBD;
+
+/// Where playback starts from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MovieStart {
+    PowerOn = 0,
+}
+
+/// Keypad state for every frame of a run, as the bitmask `Cpu::set_keys` takes.
+///
+/// Layout, little endian: magic, format version (u16), start marker (u8), ROM header
+/// checksum (u8), emulator version (u8 length + bytes), frame count (u32), one u16 per frame.
+pub struct Movie {
+    pub start: MovieStart,
+    pub rom_checksum: u8,
+    pub emulator_version: String,
+    pub frames: Vec<u16>,
+}
+
+pub fn rom_checksum(rom: &[u8]) -> u8 {
+    rom.get(ROM_HEADER_CHECKSUM).cloned().unwrap_or(0)
+}
+
+fn invalid(path: &str, message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path, message))
+}
+
+fn read_u8<R: Read>(reader: &mut R) -> io::Result<u8> {
+    let mut bytes = [0; 1];
+    reader.read_exact(&mut bytes)?;
+    Ok(bytes[0])
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+    let mut bytes = [0; 2];
+    reader.read_exact(&mut bytes)?;
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(u32::from_le_bytes(bytes))
+}
+
+impl Movie {
+    pub fn new(rom: &[u8]) -> Movie {
+        Movie {
+            start: MovieStart::PowerOn,
+            rom_checksum: rom_checksum(rom),
+            emulator_version: EMULATOR_VERSION.to_string(),
+            frames: vec!(),
+        }
+    }
+
+    pub fn read(path: &str) -> io::Result<Movie> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0; 4];
+        reader.read_exact(&mut magic)?;
+        if &magic != MAGIC {
+            return Err(invalid(path, "not a movie file"));
+        }
+
+        let version = read_u16(&mut reader)?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(path, &format!("movie format version {} is not supported (expected {})", version, FORMAT_VERSION)));
+        }
+
+        let start = match read_u8(&mut reader)? {
+            0 => MovieStart::PowerOn,
+            marker => return Err(invalid(path, &format!("unknown start marker {}", marker))),
+        };
+
+        let rom_checksum = read_u8(&mut reader)?;
+
+        let mut emulator_version = vec!(0; read_u8(&mut reader)? as usize);
+        reader.read_exact(&mut emulator_version)?;
+        let emulator_version = match String::from_utf8(emulator_version) {
+            Ok(version) => version,
+            Err(_) => return Err(invalid(path, "emulator version is not UTF-8")),
+        };
+
+        // the count comes from the file, so let a bad one run out of data rather than memory
+        let count = read_u32(&mut reader)?;
+        let mut frames = vec!();
+        for _ in 0..count {
+            frames.push(read_u16(&mut reader)?);
+        }
+
+        Ok(Movie {
+            start,
+            rom_checksum,
+            emulator_version,
+            frames,
+        })
+    }
+
+    pub fn write(&self, path: &str) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        let version = self.emulator_version.as_bytes();
+        let version = &version[..version.len().min(255)];
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+        writer.write_all(&[self.start as u8, self.rom_checksum, version.len() as u8])?;
+        writer.write_all(version)?;
+        writer.write_all(&(self.frames.len() as u32).to_le_bytes())?;
+
+        for keys in &self.frames {
+            writer.write_all(&keys.to_le_bytes())?;
+        }
+
+        writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use std::process;
+
+    use super::{Movie, MovieStart};
+
+    fn temp_path(name: &str) -> String {
+        env::temp_dir().join(format!("gba-rs-{}-{}", process::id(), name)).to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn write_then_read() {
+        let path = temp_path("round-trip.gbm");
+        let mut rom = vec!(0; 0xC0);
+        rom[0xBD] = 0x5A;
+
+        let mut movie = Movie::new(&rom);
+        movie.frames = (0..300).map(|frame| (frame * 7) as u16 & 0x03FF).collect();
+        movie.write(&path).unwrap();
+
+        let read = Movie::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(read.start, MovieStart::PowerOn);
+        assert_eq!(read.rom_checksum, 0x5A);
+        assert_eq!(read.emulator_version, movie.emulator_version);
+        assert_eq!(read.frames, movie.frames);
+    }
+
+    #[test]
+    fn huge_count_in_a_truncated_file() {
+        let path = temp_path("truncated.gbm");
+        let mut movie = Movie::new(&[]);
+        movie.frames = vec!(1, 2, 3);
+        movie.write(&path).unwrap();
+
+        // claim four billion frames, then end after the three that are there
+        let mut bytes = fs::read(&path).unwrap();
+        let count = bytes.len() - 3 * 2 - 4;
+        bytes[count..count + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &bytes).unwrap();
+
+        let read = Movie::read(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(read.is_err());
+    }
+}
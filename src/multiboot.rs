@@ -0,0 +1,507 @@
+//! Multiboot (single-Pak) images: programs a GBA receives over the link cable into EWRAM and
+//! runs from 0x02000000, instead of from a cartridge.
+//!
+//! The transfer follows the normal-mode (32-bit) handshake GBATEK documents: the sender
+//! detects the receiver, sends the 0xC0 byte header in halfwords, swaps palette, client and
+//! handshake bytes, sends the encrypted body a word at a time and both sides compare CRCs.
+//! Both ends live here, so two instances can pass an image without a BIOS image. Encryption
+//! and CRC use GBATEK's normal-mode parameters: the key stream multiplies by 0x6177614B and
+//! every word is also XORed with 0x20796220; the CRC starts at 0xC387 with polynomial 0xC37B.
+
+use super::error::Error;
+use super::link::{Link, LinkState};
+use super::mem_map::WRAM_SIZE;
+
+pub const HEADER_BYTES: usize = 0xC0;
+/// The BIOS wants at least 0x100 bytes after the header, in whole 16 byte blocks.
+const MIN_IMAGE_BYTES: usize = HEADER_BYTES + 0x100;
+
+/// SIOCNT of the sender: normal 32-bit mode on the internal 2 MHz clock.
+const SENDER_SIOCNT: u16 = 0x1003;
+/// The sender waits 1/16 s after the handshake, as the BIOS does.
+const DELAY_LINES: u32 = 851;
+/// How long the sender looks for a receiver before giving up, about five seconds.
+const DETECT_LINES: u32 = 68100;
+/// Palette byte the sender offers; it only picks the logo colours on hardware.
+const PALETTE_DATA: u8 = 0xC1;
+/// The receiver's two "random" bytes. The BIOS makes them up; fixed ones keep runs repeatable.
+const CLIENT_DATA: u8 = 0x5A;
+const RANDOM_DATA: u8 = 0xA5;
+
+const KEY: u32 = 0x6177614B;
+/// XORed into every word on top of the key stream.
+const KEY_XOR: u32 = 0x20796220;
+const CRC_INIT: u32 = 0xC387;
+const CRC_POLYNOMIAL: u32 = 0xC37B;
+
+/// Header byte 0xC4 and 0xC5, which the BIOS fills in: booted in normal mode, as slave 1.
+const BOOT_MODE_NORMAL: u8 = 0x02;
+const SLAVE_ID: u8 = 0x01;
+
+fn read_32(image: &[u8], offset: usize) -> u32 {
+    (0..4).map(|i| *image.get(offset + i).unwrap_or(&0) as u32).rev().fold(0, |value, byte| value << 8 | byte)
+}
+
+/// A valid cartridge header: the fixed 0x96 and the complement check over 0xA0-0xBC.
+fn valid_header(image: &[u8]) -> bool {
+    let sum = image[0xA0..0xBD].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte));
+    image[0xB2] == 0x96 && sum.wrapping_sub(0x19) == image[0xBD]
+}
+
+/// Guesses whether `image` was linked to run from EWRAM. It has to fit there and have a
+/// proper header, and the code the entry branch leads to has to load EWRAM addresses from
+/// its literal pool before it loads any cartridge ones.
+pub fn is_multiboot(image: &[u8]) -> bool {
+    if image.len() < MIN_IMAGE_BYTES || image.len() > WRAM_SIZE || !valid_header(image) {
+        return false;
+    }
+
+    // the entry point and the multiboot entry at 0xC0 usually branch past the header
+    let mut pc = 0;
+    for _ in 0..4 {
+        let opcode = read_32(image, pc);
+        if opcode & 0xFF000000 != 0xEA000000 {
+            break;
+        }
+        pc = (pc as u32 + 8).wrapping_add(((opcode << 8) as i32 >> 6) as u32) as usize;
+    }
+
+    for address in (pc..image.len().min(pc + 0x100)).step_by(4) {
+        let opcode = read_32(image, address);
+
+        // ldr rd, [pc, #+-imm]
+        if opcode & 0x0F7F0000 == 0x051F0000 {
+            let offset = opcode & 0xFFF;
+            let literal = if opcode & 0x00800000 != 0 { address as u32 + 8 + offset } else { (address as u32 + 8).wrapping_sub(offset) };
+
+            match read_32(image, literal as usize) >> 24 {
+                0x02 => return true,
+                0x08 | 0x09 => return false,
+                _ => (),
+            }
+        }
+    }
+
+    false
+}
+
+/// Advances the key stream and en- or decrypts the word at byte `position` of the image.
+fn crypt(seed: &mut u32, word: u32, position: usize) -> u32 {
+    *seed = seed.wrapping_mul(KEY).wrapping_add(1);
+    word ^ *seed ^ 0xFE000000u32.wrapping_sub(position as u32) ^ KEY_XOR
+}
+
+fn crc(mut crc: u32, mut word: u32) -> u32 {
+    for _ in 0..32 {
+        let bit = (crc ^ word) & 1;
+        crc >>= 1;
+        word >>= 1;
+
+        if bit != 0 {
+            crc ^= CRC_POLYNOMIAL;
+        }
+    }
+
+    crc
+}
+
+fn handshake_data(client: u8) -> u8 {
+    // the BIOS sums all three clients' bytes; missing ones count as 0xFF
+    0x11u8.wrapping_add(client).wrapping_add(0xFF).wrapping_add(0xFF)
+}
+
+fn initial_seed(client: u8, palette: u8) -> u32 {
+    0xFFFF0000 | (client as u32) << 8 | palette as u32
+}
+
+fn final_crc(data_crc: u32, random: u8, handshake: u8) -> u32 {
+    crc(data_crc, 0xFFFF0000 | (random as u32) << 8 | handshake as u32) & 0xFFFF
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SendStep {
+    Detect,
+    Recognize,
+    Header(usize),
+    HeaderDone,
+    Exchange,
+    Palette,
+    Handshake,
+    Delay(u32),
+    Length,
+    Data(usize),
+    Finish,
+    CrcRequest,
+    Crc,
+    Done,
+}
+
+/// The master's half of the handshake. Each transfer delivers the receiver's answer to the
+/// previous word, so every step checks the reply it gets against that.
+struct Sender {
+    image: Vec<u8>,
+    step: SendStep,
+    client: u8,
+    random: u8,
+    seed: u32,
+    crc: u32,
+}
+
+impl Sender {
+    fn new(image: &[u8]) -> Result<Sender, Error> {
+        if image.len() > WRAM_SIZE {
+            return Err(Error::MultibootTooLarge { size: image.len(), max: WRAM_SIZE });
+        }
+
+        let mut image = image.to_vec();
+        let length = image.len().max(MIN_IMAGE_BYTES).div_ceil(0x10) * 0x10;
+        image.resize(length, 0);
+
+        Ok(Sender {
+            image,
+            step: SendStep::Detect,
+            client: 0,
+            random: 0,
+            seed: 0,
+            crc: CRC_INIT,
+        })
+    }
+
+    /// What to send next; `None` while waiting out the delay.
+    fn word(&mut self) -> Option<u32> {
+        let word = match self.step {
+            SendStep::Detect | SendStep::Exchange => 0x6202,
+            SendStep::Recognize => 0x6102,
+            SendStep::Header(index) => self.image[index * 2] as u32 | (self.image[index * 2 + 1] as u32) << 8,
+            SendStep::HeaderDone => 0x6200,
+            SendStep::Palette => 0x6300 | PALETTE_DATA as u32,
+            SendStep::Handshake => 0x6400 | handshake_data(self.client) as u32,
+            SendStep::Delay(lines) => {
+                self.step = if lines > 1 { SendStep::Delay(lines - 1) } else { SendStep::Length };
+                return None;
+            },
+            SendStep::Length => ((self.image.len() - HEADER_BYTES) / 4 - 0x34) as u32,
+            SendStep::Data(position) => {
+                // the key stream and CRC only move on once the word has gone through
+                let mut seed = self.seed;
+                crypt(&mut seed, read_32(&self.image, position), position)
+            },
+            SendStep::Finish => 0x0065,
+            SendStep::CrcRequest => 0x0066,
+            SendStep::Crc => final_crc(self.crc, self.random, handshake_data(self.client)),
+            SendStep::Done => return None,
+        };
+
+        Some(word)
+    }
+
+    /// Moves on once `reply` is what the receiver should have answered.
+    fn advance(&mut self, reply: u32) -> Result<(), Error> {
+        let reply = reply & 0xFFFF;
+        let expected = |value: u32, next: SendStep| if reply == value { Ok(next) } else { Err(Error::Multiboot("unexpected reply from the receiver")) };
+
+        self.step = match self.step {
+            SendStep::Detect if reply == 0x7202 => SendStep::Recognize,
+            SendStep::Detect => SendStep::Detect,
+            SendStep::Recognize if reply == 0x7202 => SendStep::Header(0),
+            SendStep::Recognize => SendStep::Detect,
+            SendStep::Header(index) => {
+                let next = if index + 1 == HEADER_BYTES / 2 { SendStep::HeaderDone } else { SendStep::Header(index + 1) };
+                expected(((HEADER_BYTES / 2 - index) as u32) << 8 | 0x02, next)?
+            },
+            SendStep::HeaderDone => expected(0x0002, SendStep::Exchange)?,
+            SendStep::Exchange => expected(0x7202, SendStep::Palette)?,
+            SendStep::Palette if reply == 0x7202 => SendStep::Palette,
+            SendStep::Palette if reply >> 8 == 0x73 => {
+                self.client = reply as u8;
+                SendStep::Handshake
+            },
+            SendStep::Palette => return Err(Error::Multiboot("receiver sent no client data")),
+            SendStep::Handshake => expected(0x7300 | self.client as u32, SendStep::Delay(DELAY_LINES))?,
+            SendStep::Length if reply >> 8 == 0x73 => {
+                self.random = reply as u8;
+                self.seed = initial_seed(self.client, PALETTE_DATA);
+                SendStep::Data(HEADER_BYTES)
+            },
+            SendStep::Length => return Err(Error::Multiboot("receiver rejected the handshake")),
+            SendStep::Data(position) => {
+                let next = if position + 4 == self.image.len() { SendStep::Finish } else { SendStep::Data(position + 4) };
+                let next = expected(position as u32 & 0xFFFF, next)?;
+
+                let plain = read_32(&self.image, position);
+                self.crc = crc(self.crc, plain);
+                crypt(&mut self.seed, plain, position);
+                next
+            },
+            SendStep::Finish if reply == 0x0075 => SendStep::CrcRequest,
+            SendStep::Finish => SendStep::Finish,
+            SendStep::CrcRequest => expected(0x0075, SendStep::Crc)?,
+            SendStep::Crc => {
+                let crc = final_crc(self.crc, self.random, handshake_data(self.client));
+                if reply != crc {
+                    return Err(Error::Multiboot("CRC mismatch"));
+                }
+                SendStep::Done
+            },
+            SendStep::Delay(_) | SendStep::Done => self.step,
+        };
+
+        Ok(())
+    }
+}
+
+/// Sends `image` to the other end of `link` in normal mode, as the master, the way a game
+/// calling the BIOS's MultiBoot function would. The receiver is the player next to this one: 1 for
+/// player 0, 0 for player 1. Returns once the CRCs have matched, or with an error if nobody
+/// answers within a few seconds.
+pub fn send(link: &mut dyn Link, image: &[u8]) -> Result<(), Error> {
+    let mut sender = Sender::new(image)?;
+    let partner = link.player() ^ 1;
+    // a transfer at 2 MHz takes one line, so every other line starts one
+    let mut busy = false;
+    let mut detect_lines = 0;
+
+    while sender.step != SendStep::Done {
+        if sender.step == SendStep::Detect {
+            detect_lines += 1;
+            if detect_lines > DETECT_LINES {
+                return Err(Error::Multiboot("no receiver answered"));
+            }
+        }
+
+        let word = if busy { None } else { sender.word() };
+
+        let state = LinkState {
+            siocnt: SENDER_SIOCNT | if word.is_some() { 0x0080 } else { 0 },
+            rcnt: 0,
+            data: word.unwrap_or(0),
+            start: word.is_some(),
+        };
+        let states = link.exchange(state)?;
+        busy = word.is_some();
+
+        if word.is_some() {
+            // nobody waiting on the external clock means the word went nowhere: send it again
+            if let Some(state) = states.get(partner).filter(|state| state.rcnt & 0x8000 == 0 && state.siocnt & 0x3081 == 0x1080) {
+                sender.advance(state.data)?;
+            }
+        }
+    }
+
+    // the receiver only gets the last word at the next sync, so stay on the line until then
+    link.exchange(LinkState {
+        siocnt: SENDER_SIOCNT,
+        ..LinkState::default()
+    })?;
+
+    Ok(())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ReceivePhase {
+    Detect,
+    Header(usize),
+    Setup,
+    Length,
+    Data(usize),
+    Finish,
+    Crc,
+}
+
+/// What the receiver makes of a word from the sender.
+pub enum Received {
+    /// Load this into SIODATA32 for the next transfer.
+    Reply(u32),
+    /// The transfer is complete; boot this.
+    Image(Vec<u8>),
+    Failed(&'static str),
+}
+
+/// The slave's half of the handshake, which the BIOS runs when it boots without a cartridge.
+pub struct Receiver {
+    phase: ReceivePhase,
+    image: Vec<u8>,
+    palette: u8,
+    handshake: u8,
+    seed: u32,
+    crc: u32,
+}
+
+impl Receiver {
+    pub fn new() -> Receiver {
+        Receiver {
+            phase: ReceivePhase::Detect,
+            image: vec!(0; HEADER_BYTES),
+            palette: 0,
+            handshake: 0,
+            seed: 0,
+            crc: CRC_INIT,
+        }
+    }
+
+    pub fn receive(&mut self, word: u32) -> Received {
+        let command = word & 0xFFFF;
+
+        match self.phase {
+            ReceivePhase::Detect => match command {
+                0x6202 => Received::Reply(0x7202),
+                0x6102 => {
+                    self.phase = ReceivePhase::Header(0);
+                    Received::Reply(((HEADER_BYTES / 2) as u32) << 8 | 0x02)
+                },
+                _ => Received::Reply(0),
+            },
+            ReceivePhase::Header(index) => {
+                self.image[index * 2] = command as u8;
+                self.image[index * 2 + 1] = (command >> 8) as u8;
+
+                let left = HEADER_BYTES / 2 - index - 1;
+                self.phase = if left == 0 { ReceivePhase::Setup } else { ReceivePhase::Header(index + 1) };
+                Received::Reply((left as u32) << 8 | 0x02)
+            },
+            ReceivePhase::Setup => match command >> 8 {
+                0x62 => Received::Reply(0x7202),
+                0x63 => {
+                    self.palette = command as u8;
+                    Received::Reply(0x7300 | CLIENT_DATA as u32)
+                },
+                0x64 if command as u8 == handshake_data(CLIENT_DATA) => {
+                    self.handshake = command as u8;
+                    self.phase = ReceivePhase::Length;
+                    Received::Reply(0x7300 | RANDOM_DATA as u32)
+                },
+                _ => Received::Failed("bad handshake"),
+            },
+            ReceivePhase::Length => {
+                let length = HEADER_BYTES + (command as usize + 0x34) * 4;
+                if length > WRAM_SIZE {
+                    return Received::Failed("image too large for EWRAM");
+                }
+
+                self.image.resize(length, 0);
+                self.seed = initial_seed(CLIENT_DATA, self.palette);
+                self.phase = ReceivePhase::Data(HEADER_BYTES);
+                Received::Reply(HEADER_BYTES as u32)
+            },
+            ReceivePhase::Data(position) => {
+                let plain = crypt(&mut self.seed, word, position);
+                self.crc = crc(self.crc, plain);
+                self.image[position..position + 4].copy_from_slice(&plain.to_le_bytes());
+
+                let next = position + 4;
+                self.phase = if next == self.image.len() { ReceivePhase::Finish } else { ReceivePhase::Data(next) };
+                Received::Reply(next as u32 & 0xFFFF)
+            },
+            ReceivePhase::Finish => match command {
+                0x0065 => Received::Reply(0x0075),
+                0x0066 => {
+                    self.crc = final_crc(self.crc, RANDOM_DATA, self.handshake);
+                    self.phase = ReceivePhase::Crc;
+                    Received::Reply(self.crc)
+                },
+                _ => Received::Failed("expected the end of the transfer"),
+            },
+            ReceivePhase::Crc => {
+                if command != self.crc {
+                    return Received::Failed("CRC mismatch");
+                }
+
+                let mut image = vec!();
+                std::mem::swap(&mut image, &mut self.image);
+                image[0xC4] = BOOT_MODE_NORMAL;
+                image[0xC5] = SLAVE_ID;
+                Received::Image(image)
+            },
+        }
+    }
+}
+
+impl Default for Receiver {
+    fn default() -> Receiver {
+        Receiver::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::super::link::{ChannelLink, Link, LinkState};
+    use super::{crc, crypt, initial_seed, send, Received, Receiver, CLIENT_DATA, CRC_INIT, DETECT_LINES, PALETTE_DATA};
+
+    #[test]
+    fn known_answers() {
+        let mut seed = initial_seed(CLIENT_DATA, PALETTE_DATA);
+        assert_eq!(seed, 0xFFFF5AC1);
+        assert_eq!(crypt(&mut seed, 0x12345678, 0xC0), 0xDE5B7C94);
+        assert_eq!(crypt(&mut seed, 0x00000000, 0xC4), 0xF19E4F19);
+
+        let mut seed = initial_seed(CLIENT_DATA, PALETTE_DATA);
+        assert_eq!(crypt(&mut seed, 0xDE5B7C94, 0xC0), 0x12345678);
+
+        assert_eq!(crc(CRC_INIT, 0x12345678), 0x4DD0);
+    }
+
+    /// Plays the BIOS on player 1: waits on the external clock with the reply to the last
+    /// word loaded, the way the CPU's receiver does.
+    fn receive(mut link: ChannelLink) -> Vec<u8> {
+        let mut receiver = Receiver::new();
+        let mut reply = 0;
+
+        loop {
+            let states = link.exchange(LinkState {
+                siocnt: 0x1080,
+                data: reply,
+                ..LinkState::default()
+            }).unwrap();
+            if !states[0].start {
+                continue;
+            }
+
+            match receiver.receive(states[0].data) {
+                Received::Reply(next) => reply = next,
+                Received::Image(image) => {
+                    // the sender stays for one more line
+                    link.exchange(LinkState::default()).unwrap();
+                    return image;
+                },
+                Received::Failed(reason) => panic!("receiver failed: {}", reason),
+            }
+        }
+    }
+
+    #[test]
+    fn send_then_receive() {
+        let image: Vec<u8> = (0..0x200).map(|offset| (offset * 7 + offset / 0x100) as u8).collect();
+
+        let mut links = ChannelLink::cable(2);
+        let receiver = links.pop().unwrap();
+        let received = thread::spawn(move || receive(receiver));
+        send(&mut links[0], &image).unwrap();
+        let received = received.join().unwrap();
+
+        assert_eq!(received.len(), image.len());
+        assert_eq!(received[..0xC4], image[..0xC4]);
+        assert_eq!(received[0xC4..0xC6], [0x02, 0x01]);
+        assert_eq!(received[0xC6..], image[0xC6..]);
+    }
+
+    #[test]
+    fn gives_up_without_a_receiver() {
+        let mut links = ChannelLink::cable(2);
+        let mut other = links.pop().unwrap();
+        // a player that never waits on the external clock, until the sender hangs up
+        let lines = thread::spawn(move || {
+            let mut lines = 0;
+            while other.exchange(LinkState::default()).is_ok() {
+                lines += 1;
+            }
+            lines
+        });
+
+        let image = vec!(0; 0x200);
+        assert!(send(&mut links[0], &image).is_err());
+        drop(links);
+        assert_eq!(lines.join().unwrap(), DETECT_LINES);
+    }
+}
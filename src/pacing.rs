@@ -0,0 +1,256 @@
+use std::fmt;
+use std::thread;
+use std::time::Instant;
+
+use time::ext::InstantExt;
+use time::Duration;
+
+/// The GBA's refresh rate: a 16.78 MHz clock over 280896 cycles a frame.
+pub const FRAME_RATE: f64 = 59.7275;
+
+/// Seconds between two frame rate reports in the log.
+const REPORT_INTERVAL: f64 = 1.0;
+
+/// A run that falls further behind than this many frames drops the backlog instead of
+/// running flat out until it has caught up.
+const MAX_LAG_FRAMES: i32 = 4;
+
+/// Where the pacer gets the time from and how it waits, so tests can stand in for both.
+pub trait Clock {
+    fn now(&self) -> Instant;
+
+    fn sleep(&mut self, duration: std::time::Duration);
+}
+
+/// The wall clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&mut self, duration: std::time::Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// Frames run over some real time.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Rate {
+    pub frames: u64,
+    pub seconds: f64,
+}
+
+impl Rate {
+    pub fn fps(&self) -> f64 {
+        self.frames as f64 / self.seconds
+    }
+
+    /// Emulated time over real time: 1 is full speed.
+    pub fn ratio(&self) -> f64 {
+        self.fps() / FRAME_RATE
+    }
+}
+
+impl fmt::Display for Rate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:.1} fps, {:.2}x real time", self.fps(), self.ratio())
+    }
+}
+
+/// Keeps the frame loop at `speed` times the real frame rate, or lets it run as fast as it
+/// can, and logs the frame rate it achieves either way.
+pub struct Pacer<C: Clock = SystemClock> {
+    clock: C,
+    /// Real time per frame; `None` runs unlimited.
+    frame_time: Option<Duration>,
+    /// When the next frame is due.
+    deadline: Instant,
+    start: Instant,
+    frames: u64,
+    report_start: Instant,
+    report_frames: u64,
+}
+
+impl Pacer {
+    /// `speed` is a multiple of `FRAME_RATE`, `None` for unlimited.
+    pub fn new(speed: Option<f64>) -> Pacer {
+        Pacer::with_clock(speed, SystemClock)
+    }
+}
+
+impl<C: Clock> Pacer<C> {
+    pub fn with_clock(speed: Option<f64>, clock: C) -> Pacer<C> {
+        let now = clock.now();
+
+        Pacer {
+            clock,
+            frame_time: speed.map(|speed| Duration::seconds_f64(1.0 / (FRAME_RATE * speed))),
+            deadline: now,
+            start: now,
+            frames: 0,
+            report_start: now,
+            report_frames: 0,
+        }
+    }
+
+    /// Call once a frame has been emulated: waits until the next one is due. Returns the rate
+    /// since the last report whenever one is logged.
+    pub fn frame_done(&mut self) -> Option<Rate> {
+        self.frames += 1;
+        self.report_frames += 1;
+
+        if let Some(frame_time) = self.frame_time {
+            self.deadline = self.deadline.add_signed(frame_time);
+            let remaining = self.deadline.signed_duration_since(self.clock.now());
+
+            if remaining.is_positive() {
+                self.clock.sleep(remaining.unsigned_abs());
+            } else if remaining < -frame_time * MAX_LAG_FRAMES {
+                self.deadline = self.clock.now();
+            }
+        }
+
+        let now = self.clock.now();
+        let seconds = now.signed_duration_since(self.report_start).as_seconds_f64();
+        if seconds < REPORT_INTERVAL {
+            return None;
+        }
+
+        let rate = Rate { frames: self.report_frames, seconds };
+        info!("{}", rate);
+
+        self.report_start = now;
+        self.report_frames = 0;
+        Some(rate)
+    }
+
+    /// Logs the frame rate over the whole run, and returns it.
+    pub fn finish(&self) -> Option<Rate> {
+        let seconds = self.clock.now().signed_duration_since(self.start).as_seconds_f64();
+        if self.frames == 0 || seconds <= 0.0 {
+            return None;
+        }
+
+        let rate = Rate { frames: self.frames, seconds };
+        info!("ran {} frames in {:.2} s: {}", self.frames, seconds, rate);
+        Some(rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{Clock, Pacer, Rate, FRAME_RATE};
+
+    /// Time only moves when a test or the pacer's sleeps move it.
+    struct FakeClock {
+        now: Instant,
+        slept: Vec<Duration>,
+    }
+
+    impl Clock for FakeClock {
+        fn now(&self) -> Instant {
+            self.now
+        }
+
+        fn sleep(&mut self, duration: Duration) {
+            self.now += duration;
+            self.slept.push(duration);
+        }
+    }
+
+    fn pacer(speed: Option<f64>) -> Pacer<FakeClock> {
+        Pacer::with_clock(speed, FakeClock { now: Instant::now(), slept: vec!() })
+    }
+
+    fn frame_nanos(speed: f64) -> u128 {
+        (1e9 / (FRAME_RATE * speed)) as u128
+    }
+
+    #[test]
+    fn sleeps_out_the_rest_of_each_frame() {
+        let mut pacer = pacer(Some(1.0));
+
+        // an instant frame waits the whole frame time, a slow one only what is left
+        pacer.frame_done();
+        pacer.clock.now += Duration::from_millis(10);
+        pacer.frame_done();
+
+        let slept: Vec<u128> = pacer.clock.slept.iter().map(|sleep| sleep.as_nanos()).collect();
+        assert_eq!(slept.len(), 2);
+        assert!(slept[0].abs_diff(frame_nanos(1.0)) <= 1);
+        assert!(slept[1].abs_diff(frame_nanos(1.0) - 10_000_000) <= 1);
+    }
+
+    #[test]
+    fn speed_scales_the_frame_time() {
+        let mut pacer = pacer(Some(2.0));
+        for _ in 0..3 {
+            pacer.frame_done();
+        }
+
+        for sleep in &pacer.clock.slept {
+            assert!(sleep.as_nanos().abs_diff(frame_nanos(2.0)) <= 1);
+        }
+    }
+
+    #[test]
+    fn drops_the_backlog_after_a_long_stall() {
+        let mut pacer = pacer(Some(1.0));
+
+        // a little behind is caught up by not sleeping
+        pacer.clock.now += Duration::from_millis(40);
+        pacer.frame_done();
+        assert!(pacer.clock.slept.is_empty());
+
+        // too far behind starts over from now, with a full frame to the next deadline
+        pacer.clock.now += Duration::from_millis(500);
+        pacer.frame_done();
+        assert!(pacer.clock.slept.is_empty());
+        pacer.frame_done();
+        assert_eq!(pacer.clock.slept.len(), 1);
+        assert!(pacer.clock.slept[0].as_nanos().abs_diff(frame_nanos(1.0)) <= 1);
+    }
+
+    #[test]
+    fn unlimited_never_sleeps() {
+        let mut pacer = pacer(None);
+        for _ in 0..100 {
+            pacer.frame_done();
+        }
+
+        assert!(pacer.clock.slept.is_empty());
+    }
+
+    #[test]
+    fn reports_fps_and_real_time_ratio() {
+        let mut pacer = pacer(None);
+
+        // 8 ms frames: the report comes with the 125th, a second in
+        let mut reports = vec!();
+        for _ in 0..200 {
+            pacer.clock.now += Duration::from_millis(8);
+            if let Some(rate) = pacer.frame_done() {
+                reports.push(rate);
+            }
+        }
+
+        assert_eq!(reports, [Rate { frames: 125, seconds: 1.0 }]);
+        assert_eq!(reports[0].fps(), 125.0);
+        assert!((reports[0].ratio() - 125.0 / FRAME_RATE).abs() < 1e-12);
+        assert_eq!(reports[0].to_string(), "125.0 fps, 2.09x real time");
+
+        let total = pacer.finish().unwrap();
+        assert_eq!(total.frames, 200);
+        assert!((total.seconds - 1.6).abs() < 1e-9);
+        assert!((total.fps() - 125.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn nothing_to_report_without_frames() {
+        assert_eq!(pacer(Some(1.0)).finish(), None);
+    }
+}
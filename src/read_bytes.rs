@@ -1,23 +1,30 @@
 use num::{Unsigned, FromPrimitive};
 use std::mem::size_of;
 
-use super::mem_map::MemMap;
+use super::error::Error;
 
-pub fn read_le<T: Unsigned + FromPrimitive>(memory: &[u8], address: u32) -> T {
+pub fn read_le<T: Unsigned + FromPrimitive>(memory: &[u8], address: u32) -> Result<T, Error> {
     let mut value = 0;
 
     for i in 0..size_of::<T>() {
         match memory.get(address as usize + i) {
             Some(b) => value += (*b as u32) << (i * 8),
-            None => unimplemented!(),
+            None => return Err(Error::BusFault { offset: address, size: size_of::<T>() }),
         }
     }
 
-    T::from_u32(value).expect("Value can not be converted to type T")
+    Ok(T::from_u32(value).expect("Value can not be converted to type T"))
 }
 
-pub fn read_generic<T: Unsigned + FromPrimitive>(memory: &[u8], address: u32, mask: u32, mem_map: &MemMap) -> T {
-    read_le(memory, address & mem_map[mask].mask)
+/// A masked read from a memory region. A fault reads as zero, like an unmapped area.
+pub fn read_generic<T: Unsigned + FromPrimitive>(memory: &[u8], address: u32, mask: u32) -> T {
+    match read_le(memory, address & mask & !(size_of::<T>() as u32 - 1)) {
+        Ok(value) => value,
+        Err(e) => {
+            warn!("{}", e);
+            read_unreadable()
+        },
+    }
 }
 
 pub fn read_unreadable<T: Unsigned + FromPrimitive>() -> T {
@@ -0,0 +1,262 @@
+use std::collections::VecDeque;
+
+use super::gba::Gba;
+
+/// A snapshot plus the keys of every frame played after it, so any frame up to the next
+/// snapshot can be rebuilt by replaying from here.
+struct Snapshot {
+    frame: u64,
+    inputs: Vec<u16>,
+    data: Vec<u8>,
+}
+
+/// Ring buffer of save states taken every `interval` frames. Only the newest snapshot is
+/// kept whole; every older one is stored as a delta against the snapshot after it.
+pub struct Rewind {
+    interval: u32,
+    capacity: usize,
+    frame: u64,
+    snapshots: VecDeque<Snapshot>,
+}
+
+/// XORs `target` against `base` and run-length encodes the result, which is almost all
+/// zeros between two nearby states. Layout: target length, then pairs of (zero run, literal
+/// run) lengths with the literal bytes after each pair. Lengths are LEB128.
+fn encode_delta(base: &[u8], target: &[u8]) -> Vec<u8> {
+    let mut delta = vec!();
+    write_length(&mut delta, target.len());
+
+    let xor = |i: usize| target[i] ^ base.get(i).cloned().unwrap_or(0);
+    let mut i = 0;
+
+    while i < target.len() {
+        let zeros_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+
+        let literal_start = i;
+        // a lone matching byte is cheaper kept inside the literal than as a new pair
+        while i < target.len() && (xor(i) != 0 || (i + 1 < target.len() && xor(i + 1) != 0)) {
+            i += 1;
+        }
+
+        write_length(&mut delta, literal_start - zeros_start);
+        write_length(&mut delta, i - literal_start);
+        delta.extend((literal_start..i).map(xor));
+    }
+
+    delta
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut position = 0;
+    let length = read_length(delta, &mut position);
+    let mut target: Vec<u8> = (0..length).map(|i| base.get(i).cloned().unwrap_or(0)).collect();
+    let mut i = 0;
+
+    while position < delta.len() {
+        i += read_length(delta, &mut position);
+        let literal = read_length(delta, &mut position);
+
+        for byte in &delta[position..position + literal] {
+            target[i] ^= *byte;
+            i += 1;
+        }
+        position += literal;
+    }
+
+    target
+}
+
+fn write_length(out: &mut Vec<u8>, mut value: usize) {
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn read_length(data: &[u8], position: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = data[*position];
+        *position += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        shift += 7;
+
+        if byte & 0x80 == 0 {
+            return value;
+        }
+    }
+}
+
+impl Rewind {
+    /// Starts the buffer at the machine's current state.
+    pub fn new(gba: &Gba, interval: u32, capacity: usize) -> Rewind {
+        let mut rewind = Rewind {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            frame: 0,
+            snapshots: VecDeque::new(),
+        };
+
+        rewind.push_snapshot(gba.save_state());
+        rewind
+    }
+
+    /// Frames run since the buffer was created, less any stepped back.
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Call after every `run_frame` with the keys that were held during it.
+    pub fn record_frame(&mut self, gba: &Gba, keys: u16) {
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.inputs.push(keys);
+        }
+        self.frame += 1;
+
+        if self.frame.is_multiple_of(self.interval as u64) {
+            self.push_snapshot(gba.save_state());
+        }
+    }
+
+    fn push_snapshot(&mut self, state: Vec<u8>) {
+        if let Some(newest) = self.snapshots.back_mut() {
+            newest.data = encode_delta(&state, &newest.data);
+        }
+
+        self.snapshots.push_back(Snapshot {
+            frame: self.frame,
+            inputs: vec!(),
+            data: state,
+        });
+
+        while self.snapshots.len() > self.capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    /// Puts the machine back one frame: the newest snapshot at or before that frame is
+    /// loaded and the recorded keys replayed up to it. Returns false once the oldest
+    /// snapshot has been reached.
+    pub fn step_back(&mut self, gba: &mut Gba) -> bool {
+        if self.frame == 0 {
+            return false;
+        }
+
+        let target = self.frame - 1;
+
+        match self.snapshots.back() {
+            Some(newest) if newest.frame <= target => (),
+            _ => {
+                if self.snapshots.len() < 2 {
+                    return false;
+                }
+
+                let newest = self.snapshots.pop_back().unwrap();
+                let previous = self.snapshots.back_mut().unwrap();
+                previous.data = decode_delta(&newest.data, &previous.data);
+            },
+        }
+
+        let newest = self.snapshots.back_mut().unwrap();
+        newest.inputs.truncate((target - newest.frame) as usize);
+
+        gba.load_state(&newest.data).expect("rewind snapshots come from the running machine");
+        for &keys in &newest.inputs {
+            gba.set_keys(keys);
+            gba.run_frame();
+        }
+
+        self.frame = target;
+        true
+    }
+
+    /// Bytes held by the buffer, for judging the interval and capacity.
+    pub fn size(&self) -> usize {
+        self.snapshots.iter().map(|snapshot| snapshot.data.len() + snapshot.inputs.len() * 2).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_delta, encode_delta, Rewind};
+    use super::super::gba::Gba;
+
+    fn round_trip(base: &[u8], target: &[u8]) -> usize {
+        let delta = encode_delta(base, target);
+        assert_eq!(decode_delta(base, &delta), target);
+        delta.len()
+    }
+
+    #[test]
+    fn delta_round_trip() {
+        let base: Vec<u8> = (0..1000).map(|i| (i * 7) as u8).collect();
+
+        // identical: one pair covering everything, with lengths over 127
+        assert!(round_trip(&base, &base) <= 6);
+
+        let mut sparse = base.clone();
+        for &i in &[0, 1, 300, 301, 302, 999] {
+            sparse[i] ^= 0x5A;
+        }
+        assert!(round_trip(&base, &sparse) < 40);
+
+        let full: Vec<u8> = base.iter().map(|byte| !byte).collect();
+        round_trip(&base, &full);
+
+        // the target may be longer or shorter than the base
+        round_trip(&base, &base[..200]);
+        let mut longer = base.clone();
+        longer.extend((0..300).map(|i| i as u8));
+        round_trip(&base, &longer);
+        round_trip(&[], &base);
+        round_trip(&base, &[]);
+    }
+
+    /// Adds KEYINPUT into r4 and stores it in EWRAM every time around, so the state depends
+    /// on the keys of every frame.
+    const PROGRAM: [u32; 8] = [
+        0xE3A03301, // mov r3, #0x04000000
+        0xE2833E13, // add r3, r3, #0x130
+        0xE3A05402, // mov r5, #0x02000000
+        0xE1D320B0, // ldrh r2, [r3]
+        0xE0844002, // add r4, r4, r2
+        0xE2800001, // add r0, r0, #1
+        0xE5854000, // str r4, [r5]
+        0xEAFFFFFA, // b 0x0800000c
+    ];
+
+    #[test]
+    fn step_back_restores_earlier_frames() {
+        let rom: Vec<u8> = PROGRAM.iter().flat_map(|opcode| opcode.to_le_bytes()).collect();
+        let mut gba = Gba::new();
+        gba.load_rom(&rom).unwrap();
+        gba.reset();
+
+        let mut rewind = Rewind::new(&gba, 4, 10);
+        let mut hashes = vec!(gba.hash_state());
+
+        for frame in 0..22u16 {
+            let keys = frame.wrapping_mul(37) & 0x03FF;
+            gba.set_keys(keys);
+            gba.run_frame();
+            rewind.record_frame(&gba, keys);
+            hashes.push(gba.hash_state());
+        }
+
+        while rewind.step_back(&mut gba) {
+            assert_eq!(gba.hash_state(), hashes[rewind.frame() as usize], "frame {}", rewind.frame());
+        }
+        assert_eq!(rewind.frame(), 0);
+    }
+}
@@ -0,0 +1,224 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+const MAGIC: &[u8; 4] = b"GBAS";
+
+/// Bumped whenever a section changes layout; older states are refused rather than misread.
+pub const STATE_VERSION: u16 = 5;
+
+#[derive(Debug)]
+pub enum StateError {
+    Io(io::Error),
+    NotAState,
+    Version { found: u16, expected: u16 },
+    RomMismatch { found: u64, expected: u64 },
+    MissingSection(&'static str),
+    Truncated(&'static str),
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::Io(ref e) => write!(f, "{}", e),
+            StateError::NotAState => write!(f, "not a save state"),
+            StateError::Version { found, expected } => write!(f, "save state format version {} is not supported (expected {})", found, expected),
+            StateError::RomMismatch { found, expected } => write!(f, "save state belongs to a different ROM (checksum {:016X}, this ROM has {:016X})", found, expected),
+            StateError::MissingSection(tag) => write!(f, "save state has no {} section", tag.trim()),
+            StateError::Truncated(tag) => write!(f, "save state is truncated in the {} section", tag.trim()),
+            StateError::Corrupt(tag) => write!(f, "{} section of the save state is corrupt", tag.trim()),
+        }
+    }
+}
+
+impl error::Error for StateError {}
+
+impl From<io::Error> for StateError {
+    fn from(e: io::Error) -> StateError {
+        StateError::Io(e)
+    }
+}
+
+/// Builds a state: the header, then one tagged, length-prefixed section per component.
+///
+/// Layout, little endian: magic, format version (u16), ROM checksum (u64), then sections of
+/// tag (4 bytes), length (u32) and data.
+pub struct StateWriter {
+    data: Vec<u8>,
+    section_start: usize,
+}
+
+impl StateWriter {
+    pub fn new(rom_checksum: u64) -> StateWriter {
+        let mut writer = StateWriter {
+            data: vec!(),
+            section_start: 0,
+        };
+
+        writer.write_bytes(MAGIC);
+        writer.write_u16(STATE_VERSION);
+        writer.write_u64(rom_checksum);
+
+        writer
+    }
+
+    pub fn begin_section(&mut self, tag: &'static str) {
+        self.write_bytes(tag.as_bytes());
+        self.write_u32(0);
+        self.section_start = self.data.len();
+    }
+
+    pub fn end_section(&mut self) {
+        let length = (self.data.len() - self.section_start) as u32;
+        self.data[self.section_start - 4..self.section_start].copy_from_slice(&length.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.data.extend_from_slice(bytes);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.data.push(value as u8);
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_i32(&mut self, value: i32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// A parsed state with its sections split out. Nothing is loaded until `section` is asked for.
+pub struct State<'a> {
+    pub rom_checksum: u64,
+    sections: Vec<(&'a [u8], &'a [u8])>,
+}
+
+impl<'a> State<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<State<'a>, StateError> {
+        if data.len() < 14 || &data[..4] != MAGIC {
+            return Err(StateError::NotAState);
+        }
+
+        let version = u16::from_le_bytes([data[4], data[5]]);
+        if version != STATE_VERSION {
+            return Err(StateError::Version { found: version, expected: STATE_VERSION });
+        }
+
+        let mut checksum = [0; 8];
+        checksum.copy_from_slice(&data[6..14]);
+
+        let mut sections = vec!();
+        let mut position = 14;
+
+        while position < data.len() {
+            if data.len() - position < 8 {
+                return Err(StateError::Truncated("header"));
+            }
+
+            let tag = &data[position..position + 4];
+            let mut length = [0; 4];
+            length.copy_from_slice(&data[position + 4..position + 8]);
+            let length = u32::from_le_bytes(length) as usize;
+            position += 8;
+
+            if data.len() - position < length {
+                return Err(StateError::Truncated("header"));
+            }
+
+            sections.push((tag, &data[position..position + length]));
+            position += length;
+        }
+
+        Ok(State {
+            rom_checksum: u64::from_le_bytes(checksum),
+            sections,
+        })
+    }
+
+    /// Fails up front if a section is missing, so a load never stops half way for that reason.
+    pub fn check_sections(&self, tags: &[&'static str]) -> Result<(), StateError> {
+        for tag in tags {
+            self.section(tag)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn section(&self, tag: &'static str) -> Result<StateReader<'a>, StateError> {
+        match self.sections.iter().find(|&&(name, _)| name == tag.as_bytes()) {
+            Some(&(_, data)) => Ok(StateReader { data, position: 0, tag }),
+            None => Err(StateError::MissingSection(tag)),
+        }
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    position: usize,
+    tag: &'static str,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn read_bytes(&mut self, bytes: &mut [u8]) -> Result<(), StateError> {
+        if self.data.len() - self.position < bytes.len() {
+            return Err(StateError::Truncated(self.tag));
+        }
+
+        bytes.copy_from_slice(&self.data[self.position..self.position + bytes.len()]);
+        self.position += bytes.len();
+        Ok(())
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        let mut bytes = [0; 1];
+        self.read_bytes(&mut bytes)?;
+        Ok(bytes[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let mut bytes = [0; 2];
+        self.read_bytes(&mut bytes)?;
+        Ok(u16::from_le_bytes(bytes))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    pub fn read_i32(&mut self) -> Result<i32, StateError> {
+        let mut bytes = [0; 4];
+        self.read_bytes(&mut bytes)?;
+        Ok(i32::from_le_bytes(bytes))
+    }
+
+    /// For values that must be in range, like a FIFO length.
+    pub fn corrupt(&self) -> StateError {
+        StateError::Corrupt(self.tag)
+    }
+}
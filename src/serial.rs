@@ -0,0 +1,594 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+
+use super::link::{Link, LinkState, MAX_PLAYERS};
+use super::save_state::{StateError, StateReader, StateWriter};
+
+const CLOCK_HZ: u32 = 16777216;
+/// Transfers start and finish at the end of a scanline, when the link instances sync up.
+const LINE_TICKS: u32 = 1232;
+const BAUD_RATES: [u32; 4] = [9600, 38400, 57600, 115200];
+/// Depth of each UART FIFO; with the FIFOs off there is just the one data register.
+const UART_FIFO_BYTES: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Mode {
+    Normal8,
+    Normal32,
+    Multiplayer,
+    Uart,
+    /// General purpose or JOY bus: RCNT drives the pins and nothing is transferred.
+    General,
+}
+
+fn mode(siocnt: u16, rcnt: u16) -> Mode {
+    if rcnt & 0x8000 != 0 {
+        return Mode::General;
+    }
+
+    match (siocnt >> 12) & 3 {
+        0 => Mode::Normal8,
+        1 => Mode::Normal32,
+        2 => Mode::Multiplayer,
+        _ => Mode::Uart,
+    }
+}
+
+/// Lines a transfer started with `state` takes between `players` GBAs.
+fn transfer_lines(state: &LinkState, players: usize) -> u32 {
+    let bit_ticks = CLOCK_HZ / BAUD_RATES[(state.siocnt & 3) as usize];
+
+    let ticks = match mode(state.siocnt, state.rcnt) {
+        Mode::Normal8 => 8 * if state.siocnt & 0x0002 != 0 { 8 } else { 64 },
+        Mode::Normal32 => 32 * if state.siocnt & 0x0002 != 0 { 8 } else { 64 },
+        // a start bit, 16 data bits and a stop bit from each player in turn
+        Mode::Multiplayer => 18 * bit_ticks * players as u32,
+        Mode::Uart => 10 * bit_ticks,
+        Mode::General => 0,
+    };
+
+    ticks.div_ceil(LINE_TICKS).max(1)
+}
+
+/// A transfer under way, with every player's port as it was when it started.
+struct Transfer {
+    remaining: u32,
+    from: usize,
+    states: Vec<LinkState>,
+}
+
+/// The serial port: SIODATA32/SIOMULTI0-3 (0x04000120), SIOCNT (0x128), SIODATA8/SIOMLT_SEND
+/// (0x12A) and RCNT (0x134). Without a link a transfer still runs its course, with nobody on
+/// the other end: the lines read high, so everything received is all ones.
+pub struct Serial {
+    /// SIOCNT as written, start/busy included; the status bits are added by `siocnt`.
+    control: u16,
+    rcnt: u16,
+    data: [u16; 4],
+    send: u16,
+    uart_send: VecDeque<u8>,
+    /// Reading SIODATA8 takes a byte off, and reads only get `&self`.
+    uart_receive: RefCell<VecDeque<u8>>,
+    uart_error: bool,
+    /// This port started a transfer the other players have not heard of yet.
+    started: bool,
+    transfers: Vec<Transfer>,
+    link: Option<Box<dyn Link>>,
+}
+
+impl Serial {
+    pub fn new() -> Serial {
+        Serial {
+            control: 0,
+            rcnt: 0,
+            data: [0; 4],
+            send: 0,
+            uart_send: VecDeque::new(),
+            uart_receive: RefCell::new(VecDeque::new()),
+            uart_error: false,
+            started: false,
+            transfers: vec!(),
+            link: None,
+        }
+    }
+
+    /// Back to power-on, still plugged into the same link.
+    pub fn reset(&mut self) {
+        let link = self.link.take();
+        *self = Serial::new();
+        self.link = link;
+    }
+
+    pub fn connect(&mut self, link: Box<dyn Link>) {
+        self.link = Some(link);
+    }
+
+    pub fn linked(&self) -> bool {
+        self.link.is_some()
+    }
+
+    fn player(&self) -> usize {
+        self.link.as_ref().map_or(0, |link| link.player())
+    }
+
+    fn players(&self) -> usize {
+        self.link.as_ref().map_or(1, |link| link.players())
+    }
+
+    fn mode(&self) -> Mode {
+        mode(self.control, self.rcnt)
+    }
+
+    fn uart_capacity(&self) -> usize {
+        if self.control & 0x0100 != 0 {
+            UART_FIFO_BYTES
+        } else {
+            1
+        }
+    }
+
+    fn siocnt(&self) -> u16 {
+        match self.mode() {
+            Mode::Multiplayer => {
+                // SI low marks the parent, SD high says every player is there
+                let player = self.player() as u16;
+                let ready = self.players() > 1;
+                self.control & !0x007C | ((player != 0) as u16) << 2 | (ready as u16) << 3 | player << 4
+            },
+            Mode::Uart => {
+                let full = self.uart_send.len() >= self.uart_capacity();
+                let empty = self.uart_receive.borrow().is_empty();
+                self.control & !0x0070 | (full as u16) << 4 | (empty as u16) << 5 | (self.uart_error as u16) << 6
+            },
+            _ => self.control & !0x0004,
+        }
+    }
+
+    /// The data this port would put on the wire.
+    fn outgoing(&self) -> u32 {
+        match self.mode() {
+            Mode::Normal8 => self.send as u32 & 0xFF,
+            Mode::Normal32 => self.data[0] as u32 | (self.data[1] as u32) << 16,
+            Mode::Uart => self.uart_send.front().cloned().unwrap_or(0) as u32,
+            _ => self.send as u32,
+        }
+    }
+
+    /// The value of a serial register, for the IO mirror.
+    pub fn read(&self, address: u32) -> u16 {
+        match address {
+            0x120..=0x126 => self.data[((address - 0x120) >> 1) as usize],
+            0x128 => self.siocnt(),
+            0x12A if self.mode() == Mode::Uart => self.uart_receive.borrow().front().cloned().unwrap_or(0) as u16,
+            0x12A => self.send,
+            0x134 => self.rcnt,
+            _ => 0,
+        }
+    }
+
+    /// In UART mode a CPU read of SIODATA8 takes the byte off the receive FIFO. `None` in the
+    /// other modes, where reads have no side effects.
+    pub fn read_uart_data(&self) -> Option<u8> {
+        if self.mode() != Mode::Uart {
+            return None;
+        }
+
+        Some(self.uart_receive.borrow_mut().pop_front().unwrap_or(0))
+    }
+
+    pub fn write(&mut self, address: u32, value: u16) {
+        match address {
+            0x120..=0x126 => self.data[((address - 0x120) >> 1) as usize] = value,
+            0x128 => self.write_siocnt(value),
+            0x12A if self.mode() == Mode::Uart => self.write_uart_data(value as u8),
+            0x12A => self.send = value,
+            0x134 => self.rcnt = value & 0xC1FF,
+            _ => (),
+        }
+    }
+
+    /// Queues a byte to send; it goes out straight away unless another is still on its way.
+    fn write_uart_data(&mut self, byte: u8) {
+        if self.control & 0x0400 != 0 && self.uart_send.len() < self.uart_capacity() {
+            self.uart_send.push_back(byte);
+            self.started |= self.uart_send.len() == 1;
+        }
+    }
+
+    fn write_siocnt(&mut self, value: u16) {
+        let old = self.control;
+        self.control = value & 0x7FFF;
+        let starting = value & 0x0080 != 0 && old & 0x0080 == 0;
+
+        match self.mode() {
+            // with the external clock a set start bit only means ready for the other side
+            Mode::Normal8 | Mode::Normal32 => self.started |= starting && value & 0x0001 != 0,
+            Mode::Multiplayer => {
+                if self.player() == 0 {
+                    self.started |= starting;
+                } else {
+                    // only the parent starts transfers
+                    self.control = self.control & !0x0080 | old & 0x0080;
+                }
+            },
+            Mode::Uart => self.uart_error = false,
+            Mode::General => (),
+        }
+    }
+
+    /// Called at the end of every scanline: swaps states with the other players, then
+    /// finishes whatever transfers are due. True when that raised the serial interrupt.
+    pub fn end_line(&mut self) -> bool {
+        if self.link.is_none() && !self.started && self.transfers.is_empty() {
+            return false;
+        }
+
+        let mine = LinkState {
+            start: self.started,
+            ..self.state()
+        };
+        self.started = false;
+
+        let states = match self.link.as_mut().map(|link| link.exchange(mine)) {
+            Some(Ok(states)) => states,
+            Some(Err(e)) => {
+                warn!("link lost, carrying on unplugged: {}", e);
+                self.link = None;
+                vec!(mine)
+            },
+            None => vec!(mine),
+        };
+
+        let mut irq = false;
+
+        for transfer in self.transfers.iter_mut() {
+            transfer.remaining -= 1;
+        }
+        while let Some(index) = self.transfers.iter().position(|transfer| transfer.remaining == 0) {
+            let transfer = self.transfers.remove(index);
+            irq |= self.finish(&transfer);
+        }
+
+        for (player, state) in states.iter().enumerate() {
+            if state.start {
+                self.transfers.push(Transfer {
+                    remaining: transfer_lines(state, states.len()),
+                    from: player,
+                    states: states.clone(),
+                });
+            }
+        }
+
+        irq
+    }
+
+    /// Applies the end of `transfer` to this port. True when it raises the interrupt.
+    fn finish(&mut self, transfer: &Transfer) -> bool {
+        let me = self.player();
+        let from = &transfer.states[transfer.from];
+        let own = self.mode();
+
+        match mode(from.siocnt, from.rcnt) {
+            Mode::Multiplayer => {
+                if own != Mode::Multiplayer {
+                    return false;
+                }
+
+                for (index, data) in self.data.iter_mut().enumerate() {
+                    *data = match transfer.states.get(index) {
+                        Some(state) if mode(state.siocnt, state.rcnt) == Mode::Multiplayer => state.data as u16,
+                        _ => 0xFFFF,
+                    };
+                }
+
+                self.control &= !0x0080;
+                self.control & 0x4000 != 0
+            },
+            Mode::Normal8 | Mode::Normal32 => {
+                // the other end takes part if it was waiting on the external clock
+                let waiting = |state: &LinkState| {
+                    matches!(mode(state.siocnt, state.rcnt), Mode::Normal8 | Mode::Normal32) && state.siocnt & 0x0081 == 0x0080
+                };
+                let partner = transfer.from ^ 1;
+
+                if me == transfer.from {
+                    let value = match transfer.states.get(partner) {
+                        Some(state) if waiting(state) => state.data,
+                        _ => 0xFFFFFFFF,
+                    };
+                    self.receive_normal(value)
+                } else if me == partner && waiting(&self.state()) {
+                    self.receive_normal(from.data)
+                } else {
+                    false
+                }
+            },
+            Mode::Uart => {
+                if me == transfer.from {
+                    self.uart_send.pop_front();
+                    self.started |= !self.uart_send.is_empty();
+                    self.control & 0x4000 != 0
+                } else if me == transfer.from ^ 1 && own == Mode::Uart && self.control & 0x0800 != 0 {
+                    if self.uart_receive.borrow().len() < self.uart_capacity() {
+                        self.uart_receive.borrow_mut().push_back(from.data as u8);
+                    } else {
+                        self.uart_error = true;
+                    }
+                    self.control & 0x4000 != 0
+                } else {
+                    false
+                }
+            },
+            Mode::General => false,
+        }
+    }
+
+    /// This port as the other players see it.
+    fn state(&self) -> LinkState {
+        LinkState {
+            siocnt: self.siocnt(),
+            rcnt: self.rcnt,
+            data: self.outgoing(),
+            start: false,
+        }
+    }
+
+    fn receive_normal(&mut self, value: u32) -> bool {
+        if self.mode() == Mode::Normal32 {
+            self.data[0] = value as u16;
+            self.data[1] = (value >> 16) as u16;
+        } else {
+            self.send = value as u16 & 0xFF;
+        }
+
+        self.control &= !0x0080;
+        self.control & 0x4000 != 0
+    }
+
+    /// Saves the registers, FIFOs and transfers under way. The link itself stays out: a state
+    /// loaded while linked picks up with whoever is on the other end now.
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.control);
+        writer.write_u16(self.rcnt);
+        for &data in &self.data {
+            writer.write_u16(data);
+        }
+        writer.write_u16(self.send);
+
+        for fifo in [&self.uart_send, &*self.uart_receive.borrow()].iter() {
+            writer.write_u8(fifo.len() as u8);
+            for &byte in fifo.iter() {
+                writer.write_u8(byte);
+            }
+        }
+        writer.write_bool(self.uart_error);
+        writer.write_bool(self.started);
+
+        writer.write_u8(self.transfers.len() as u8);
+        for transfer in &self.transfers {
+            writer.write_u32(transfer.remaining);
+            writer.write_u8(transfer.from as u8);
+            writer.write_u8(transfer.states.len() as u8);
+
+            for state in &transfer.states {
+                writer.write_u16(state.siocnt);
+                writer.write_u16(state.rcnt);
+                writer.write_u32(state.data);
+                writer.write_bool(state.start);
+            }
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.control = reader.read_u16()? & 0x7FFF;
+        self.rcnt = reader.read_u16()? & 0xC1FF;
+        for data in self.data.iter_mut() {
+            *data = reader.read_u16()?;
+        }
+        self.send = reader.read_u16()?;
+
+        let mut fifos = [VecDeque::new(), VecDeque::new()];
+        for fifo in fifos.iter_mut() {
+            let length = reader.read_u8()? as usize;
+            if length > UART_FIFO_BYTES {
+                return Err(reader.corrupt());
+            }
+
+            for _ in 0..length {
+                fifo.push_back(reader.read_u8()?);
+            }
+        }
+        let [send, receive] = fifos;
+        self.uart_send = send;
+        self.uart_receive = RefCell::new(receive);
+        self.uart_error = reader.read_bool()?;
+        self.started = reader.read_bool()?;
+
+        self.transfers.clear();
+        for _ in 0..reader.read_u8()? {
+            let remaining = reader.read_u32()?;
+            let from = reader.read_u8()? as usize;
+            let players = reader.read_u8()? as usize;
+
+            if remaining == 0 || from >= players || players > MAX_PLAYERS {
+                return Err(reader.corrupt());
+            }
+
+            let mut states = vec!();
+            for _ in 0..players {
+                states.push(LinkState {
+                    siocnt: reader.read_u16()?,
+                    rcnt: reader.read_u16()?,
+                    data: reader.read_u32()?,
+                    start: reader.read_bool()?,
+                });
+            }
+
+            self.transfers.push(Transfer { remaining, from, states });
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for Serial {
+    fn default() -> Serial {
+        Serial::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::super::link::ChannelLink;
+    use super::super::save_state::{State, StateWriter};
+    use super::Serial;
+
+    /// Gives each player a port on one cable and runs `script` for them on threads of their own.
+    fn linked<R: Send + 'static>(players: usize, script: fn(usize, &mut Serial) -> R) -> Vec<R> {
+        let threads: Vec<_> = ChannelLink::cable(players).into_iter().enumerate().map(|(player, link)| thread::spawn(move || {
+            let mut port = Serial::new();
+            port.connect(Box::new(link));
+            script(player, &mut port)
+        })).collect();
+
+        threads.into_iter().map(|thread| thread.join().unwrap()).collect()
+    }
+
+    /// Runs `count` lines, returning the ones that raised the interrupt.
+    fn irq_lines(port: &mut Serial, count: u32) -> Vec<u32> {
+        (0..count).filter(|_| port.end_line()).collect()
+    }
+
+    fn data(port: &Serial) -> [u16; 4] {
+        [port.read(0x120), port.read(0x122), port.read(0x124), port.read(0x126)]
+    }
+
+    fn save(port: &Serial) -> Vec<u8> {
+        let mut writer = StateWriter::new(0);
+        writer.begin_section("SIO ");
+        port.save_state(&mut writer);
+        writer.end_section();
+        writer.finish()
+    }
+
+    fn load(state: &[u8]) -> Serial {
+        let state = State::parse(state).unwrap();
+        let mut port = Serial::new();
+        port.load_state(&mut state.section("SIO ").unwrap()).unwrap();
+        port
+    }
+
+    #[test]
+    fn normal_32() {
+        let ports = linked(2, |player, port| {
+            if player == 0 {
+                port.write(0x120, 0xBABE);
+                port.write(0x122, 0xCAFE);
+                // internal 2 MHz clock, so one line
+                port.write(0x128, 0x5083);
+            } else {
+                port.write(0x120, 0x5678);
+                port.write(0x122, 0x1234);
+                port.write(0x128, 0x5080);
+            }
+
+            (irq_lines(port, 4), data(port), port.read(0x128))
+        });
+
+        assert_eq!(ports[0], (vec!(1), [0x5678, 0x1234, 0, 0], 0x5003));
+        assert_eq!(ports[1], (vec!(1), [0xBABE, 0xCAFE, 0, 0], 0x5000));
+    }
+
+    #[test]
+    fn multiplayer_fills_siomulti() {
+        let ports = linked(3, |player, port| {
+            // the third player is still in normal mode and takes no part
+            if player < 2 {
+                port.write(0x12A, 0x1111 * (player as u16 + 1));
+                port.write(0x128, 0x6003);
+            }
+            if player == 0 {
+                port.write(0x128, 0x6083);
+            }
+
+            // 18 bits from each of 3 players at 115200 baud
+            (irq_lines(port, 10), data(port), port.read(0x128))
+        });
+
+        assert_eq!(ports[0], (vec!(7), [0x1111, 0x2222, 0xFFFF, 0xFFFF], 0x600B));
+        assert_eq!(ports[1], (vec!(7), [0x1111, 0x2222, 0xFFFF, 0xFFFF], 0x601F));
+        assert_eq!(ports[2], (vec!(), [0; 4], 0x0000));
+    }
+
+    #[test]
+    fn uart_overflow_sets_the_error_bit() {
+        let ports = linked(2, |player, port| {
+            let mut received = vec!();
+
+            if player == 0 {
+                port.write(0x128, 0x3503);
+
+                // six bytes as fast as the send FIFO takes them
+                let mut sent = 0;
+                for _ in 0..24 {
+                    if sent < 6 && port.read(0x128) & 0x0010 == 0 {
+                        sent += 1;
+                        port.write(0x12A, sent);
+                    }
+                    port.end_line();
+                }
+            } else {
+                port.write(0x128, 0x3903);
+                irq_lines(port, 24);
+
+                let siocnt = port.read(0x128);
+                while port.read(0x128) & 0x0020 == 0 {
+                    received.push(port.read_uart_data().unwrap());
+                }
+                received.push((siocnt >> 6) as u8 & 1);
+
+                port.write(0x128, 0x3903);
+                received.push((port.read(0x128) >> 6) as u8 & 1);
+            }
+
+            received
+        });
+
+        // four bytes fit, the rest overflow until the error is cleared by a SIOCNT write
+        assert_eq!(ports[1], [1, 2, 3, 4, 1, 0]);
+    }
+
+    #[test]
+    fn unplugged_receives_all_ones() {
+        let mut port = Serial::new();
+        port.write(0x120, 0x5678);
+        port.write(0x122, 0x1234);
+        port.write(0x128, 0x5083);
+
+        assert_eq!(irq_lines(&mut port, 3), [1]);
+        assert_eq!(data(&port), [0xFFFF, 0xFFFF, 0, 0]);
+        assert_eq!(port.read(0x128), 0x5003);
+    }
+
+    #[test]
+    fn save_then_load_mid_transfer() {
+        let mut port = Serial::new();
+        port.write(0x120, 0x5678);
+        port.write(0x122, 0x1234);
+        // 256 KHz, so two lines
+        port.write(0x128, 0x5081);
+        port.end_line();
+
+        let state = save(&port);
+        let mut loaded = load(&state);
+        assert_eq!(save(&loaded), state);
+
+        assert_eq!(irq_lines(&mut loaded, 3), [1]);
+        assert_eq!(irq_lines(&mut port, 3), [1]);
+        assert_eq!(data(&loaded), data(&port));
+        assert_eq!(loaded.read(0x128), port.read(0x128));
+        assert_eq!(save(&loaded), save(&port));
+    }
+}
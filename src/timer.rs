@@ -0,0 +1,229 @@
+use super::save_state::{StateError, StateReader, StateWriter};
+
+const PRESCALERS: [i32; 4] = [1, 64, 256, 1024];
+
+struct Timer {
+    counter: u16,
+    reload: u16,
+    control: u16,
+    prescaler_ticks: i32,
+}
+
+impl Timer {
+    fn new() -> Timer {
+        Timer {
+            counter: 0,
+            reload: 0,
+            control: 0,
+            prescaler_ticks: 0,
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.control & 0x0080 != 0
+    }
+
+    fn prescaler(&self) -> i32 {
+        PRESCALERS[(self.control & 0x0003) as usize]
+    }
+}
+
+/// The four timers at 0x04000100-0x0400010F.
+pub struct Timers {
+    timers: [Timer; 4],
+}
+
+impl Timers {
+    pub fn new() -> Timers {
+        Timers {
+            timers: [Timer::new(), Timer::new(), Timer::new(), Timer::new()],
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut StateWriter) {
+        for timer in &self.timers {
+            writer.write_u16(timer.counter);
+            writer.write_u16(timer.reload);
+            writer.write_u16(timer.control);
+            writer.write_i32(timer.prescaler_ticks);
+        }
+    }
+
+    pub fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for timer in self.timers.iter_mut() {
+            timer.counter = reader.read_u16()?;
+            timer.reload = reader.read_u16()?;
+            timer.control = reader.read_u16()? & 0x00C7;
+            timer.prescaler_ticks = reader.read_i32()?;
+
+            if timer.prescaler_ticks < 0 || timer.prescaler_ticks >= timer.prescaler() {
+                return Err(reader.corrupt());
+            }
+        }
+
+        Ok(())
+    }
+
+    fn cascade(&self, index: usize) -> bool {
+        index > 0 && self.timers[index].control & 0x0004 != 0
+    }
+
+    /// Writes TMxCNT_L (the reload value) or TMxCNT_H.
+    pub fn write(&mut self, address: u32, value: u16) {
+        let timer = &mut self.timers[((address - 0x100) >> 2) as usize & 3];
+
+        if address & 2 == 0 {
+            timer.reload = value;
+        } else {
+            let was_enabled = timer.enabled();
+            timer.control = value & 0x00C7;
+
+            if timer.enabled() && !was_enabled {
+                timer.counter = timer.reload;
+                timer.prescaler_ticks = 0;
+            }
+        }
+    }
+
+    pub fn control(&self, index: usize) -> u16 {
+        self.timers[index].control
+    }
+
+    pub fn irq_enabled(&self, index: usize) -> bool {
+        self.timers[index].control & 0x0040 != 0
+    }
+
+    /// The counter as it would read `elapsed` ticks after the last `tick`. Only valid while
+    /// no overflow is pending, which the event loop guarantees.
+    pub fn counter(&self, index: usize, elapsed: i32) -> u16 {
+        let timer = &self.timers[index];
+
+        if !timer.enabled() || self.cascade(index) {
+            return timer.counter;
+        }
+
+        let increments = (timer.prescaler_ticks + elapsed) / timer.prescaler();
+        (timer.counter as i32 + increments).min(0xFFFF) as u16
+    }
+
+    /// Advances every running timer and returns how many times each one overflowed.
+    pub fn tick(&mut self, ticks: i32) -> [u32; 4] {
+        let mut overflows = [0; 4];
+
+        for index in 0..4 {
+            if !self.timers[index].enabled() {
+                continue;
+            }
+
+            let increments = if self.cascade(index) {
+                overflows[index - 1]
+            } else {
+                let timer = &mut self.timers[index];
+                let prescaler = timer.prescaler();
+                timer.prescaler_ticks += ticks;
+                let increments = timer.prescaler_ticks / prescaler;
+                timer.prescaler_ticks %= prescaler;
+                increments as u32
+            };
+
+            let timer = &mut self.timers[index];
+            let mut counter = timer.counter as u32 + increments;
+            while counter > 0xFFFF {
+                overflows[index] += 1;
+                counter = counter - 0x10000 + timer.reload as u32;
+            }
+            timer.counter = counter as u16;
+        }
+
+        overflows
+    }
+
+    /// Ticks until the next timer overflows, so the CPU can schedule an event for it.
+    pub fn ticks_to_overflow(&self) -> Option<i32> {
+        (0..4)
+            .filter(|&index| self.timers[index].enabled() && !self.cascade(index))
+            .map(|index| {
+                let timer = &self.timers[index];
+                (0x10000 - timer.counter as i32) * timer.prescaler() - timer.prescaler_ticks
+            })
+            .min()
+    }
+}
+
+impl Default for Timers {
+    fn default() -> Timers {
+        Timers::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Timers;
+
+    #[test]
+    fn prescalers() {
+        let mut timers = Timers::new();
+        for (index, prescaler) in [0x0080, 0x0081, 0x0082, 0x0083].iter().enumerate() {
+            timers.write(0x102 + 4 * index as u32, *prescaler);
+        }
+
+        timers.tick(1023);
+        assert_eq!((0..4).map(|index| timers.counter(index, 0)).collect::<Vec<_>>(), [1023, 15, 3, 0]);
+        timers.tick(1);
+        assert_eq!((0..4).map(|index| timers.counter(index, 0)).collect::<Vec<_>>(), [1024, 16, 4, 1]);
+
+        // reads between ticks count the ticks since
+        assert_eq!(timers.counter(1, 63), 16);
+        assert_eq!(timers.counter(1, 64), 17);
+        assert_eq!(timers.counter(3, 1024), 2);
+    }
+
+    #[test]
+    fn overflow_reloads() {
+        let mut timers = Timers::new();
+        timers.write(0x100, 0xFFF0);
+        timers.write(0x102, 0x00C0);
+        assert_eq!(timers.counter(0, 0), 0xFFF0);
+        assert_eq!(timers.ticks_to_overflow(), Some(16));
+
+        assert_eq!(timers.tick(15), [0; 4]);
+        assert_eq!(timers.tick(1), [1, 0, 0, 0]);
+        assert_eq!(timers.counter(0, 0), 0xFFF0);
+        assert_eq!(timers.tick(16 * 3 + 5), [3, 0, 0, 0]);
+        assert_eq!(timers.counter(0, 0), 0xFFF5);
+        assert!(timers.irq_enabled(0));
+
+        // a new reload value waits for the next overflow, and enabling again keeps counting
+        timers.write(0x100, 0xFFFE);
+        assert_eq!(timers.counter(0, 0), 0xFFF5);
+        timers.write(0x102, 0x00C0);
+        assert_eq!(timers.tick(11), [1, 0, 0, 0]);
+        assert_eq!(timers.counter(0, 0), 0xFFFE);
+        assert_eq!(timers.tick(4), [2, 0, 0, 0]);
+
+        // stopped timers hold their count
+        timers.write(0x102, 0x0000);
+        assert_eq!(timers.tick(100), [0; 4]);
+        assert_eq!(timers.counter(0, 50), 0xFFFE);
+        assert_eq!(timers.ticks_to_overflow(), None);
+    }
+
+    #[test]
+    fn cascade() {
+        let mut timers = Timers::new();
+        timers.write(0x100, 0xFFFC);
+        timers.write(0x102, 0x0080);
+        timers.write(0x104, 0xFFFE);
+        timers.write(0x106, 0x0084);
+
+        // timer 1 counts timer 0's overflows and has no overflow of its own to schedule
+        assert_eq!(timers.ticks_to_overflow(), Some(4));
+        assert_eq!(timers.tick(8), [2, 1, 0, 0]);
+        assert_eq!(timers.counter(1, 1000), 0xFFFE);
+        assert_eq!(timers.tick(4 * 4), [4, 2, 0, 0]);
+
+        // timer 0 cannot cascade
+        timers.write(0x102, 0x0084);
+        assert_eq!(timers.tick(4), [1, 0, 0, 0]);
+    }
+}
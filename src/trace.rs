@@ -0,0 +1,253 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+
+use super::cpu::Cpu;
+use super::disasm::{disasm_arm, disasm_thumb};
+
+/// Line layouts for `--trace`. All of them show the registers as the instruction about to
+/// run sees them, so r15 reads two instructions ahead of the PC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// `08000000: e3a00001 mov r0, #1 ... r0=00000000 ... cpsr=0000001f`
+    Plain,
+    /// mGBA's `trace` layout: r0-r15 and the CPSR, then the opcode and its disassembly.
+    Mgba,
+    /// PC, opcode, r0-r15 and CPSR as bare upper case hex, the easiest to diff.
+    Regs,
+}
+
+impl FromStr for TraceFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<TraceFormat, String> {
+        match name {
+            "plain" => Ok(TraceFormat::Plain),
+            "mgba" => Ok(TraceFormat::Mgba),
+            "regs" => Ok(TraceFormat::Regs),
+            _ => Err(format!("unknown trace format {}, expected plain, mgba or regs", name)),
+        }
+    }
+}
+
+/// Writes one line per executed instruction. Logging starts at `start` (or straight away),
+/// pauses at `stop` until `start` is reached again, and ends for good after `max_lines`.
+pub struct Trace<W: Write = BufWriter<File>> {
+    out: W,
+    format: TraceFormat,
+    start: Option<u32>,
+    stop: Option<u32>,
+    max_lines: Option<u64>,
+    lines: u64,
+    active: bool,
+    error: Option<io::Error>,
+}
+
+impl Trace {
+    pub fn create(path: &str, format: TraceFormat, start: Option<u32>, stop: Option<u32>, max_lines: Option<u64>) -> io::Result<Trace> {
+        Ok(Trace::new(BufWriter::new(File::create(path)?), format, start, stop, max_lines))
+    }
+}
+
+impl<W: Write> Trace<W> {
+    pub fn new(out: W, format: TraceFormat, start: Option<u32>, stop: Option<u32>, max_lines: Option<u64>) -> Trace<W> {
+        Trace {
+            out,
+            format,
+            start,
+            stop,
+            max_lines,
+            lines: 0,
+            active: start.is_none(),
+            error: None,
+        }
+    }
+
+    /// True once `max_lines` have been written; nothing more will be logged.
+    pub fn full(&self) -> bool {
+        self.max_lines.is_some_and(|max| self.lines >= max)
+    }
+
+    /// Logs the instruction the CPU is about to execute.
+    pub(crate) fn log(&mut self, cpu: &Cpu) {
+        let pc = cpu.pc();
+
+        if self.start == Some(pc) {
+            self.active = true;
+        }
+        if self.stop == Some(pc) {
+            self.active = false;
+        }
+
+        if !self.active || self.full() || self.error.is_some() {
+            return;
+        }
+
+        let line = self.line(cpu);
+        if let Err(e) = writeln!(self.out, "{}", line) {
+            self.error = Some(e);
+        }
+
+        self.lines += 1;
+    }
+
+    fn line(&self, cpu: &Cpu) -> String {
+        let pc = cpu.pc();
+        let thumb = cpu.thumb_state();
+        let opcode = cpu.opcode();
+
+        let mut regs: Vec<u32> = (0..15).map(|index| cpu.reg(index)).collect();
+        regs.push(pc.wrapping_add(if thumb { 4 } else { 8 }));
+        let cpsr = cpu.cpsr();
+
+        let disassembly = || {
+            if thumb {
+                let next = cpu.debug_read_8(pc.wrapping_add(2)) as u16 | (cpu.debug_read_8(pc.wrapping_add(3)) as u16) << 8;
+                disasm_thumb(opcode as u16, next, pc).0
+            } else {
+                disasm_arm(opcode, pc)
+            }
+        };
+
+        match self.format {
+            TraceFormat::Plain => {
+                let opcode = if thumb { format!("{:04x}    ", opcode) } else { format!("{:08x}", opcode) };
+                let regs: Vec<String> = regs.iter().enumerate().map(|(i, value)| format!("r{}={:08x}", i, value)).collect();
+                format!("{:08x}: {} {:<32} {} cpsr={:08x}", pc, opcode, disassembly(), regs.join(" "), cpsr)
+            },
+            TraceFormat::Mgba => {
+                let opcode = if thumb { format!("    {:04X}", opcode) } else { format!("{:08X}", opcode) };
+                let regs: Vec<String> = regs.iter().map(|value| format!("{:08X}", value)).collect();
+                format!("{} cpsr: {:08X} | {}:  {}", regs.join(" "), cpsr, opcode, disassembly())
+            },
+            TraceFormat::Regs => {
+                let regs: Vec<String> = regs.iter().map(|value| format!("{:08X}", value)).collect();
+                format!("{:08X} {:08X} {} {:08X}", pc, opcode, regs.join(" "), cpsr)
+            },
+        }
+    }
+
+    /// Flushes the file and returns the number of lines written.
+    pub fn finish(mut self) -> io::Result<u64> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+
+        self.out.flush()?;
+        Ok(self.lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::cpu::Cpu;
+    use super::{Trace, TraceFormat};
+
+    /// Switches to Thumb and loops through a `bl`, from EWRAM.
+    const PROGRAM: [u16; 10] = [
+        0x0001, 0xE3A0, // mov r0, #1
+        0x1001, 0xE28F, // add r1, pc, #1
+        0xFF11, 0xE12F, // bx r1
+        0x2205,         // movs r2, #5
+        0x1812,         // adds r2, r2, r0
+        0xF7FF, 0xFFFC, // bl 0x0200000c
+    ];
+
+    fn trace(format: TraceFormat, start: Option<u32>, stop: Option<u32>, max_lines: Option<u64>, steps: usize) -> Vec<String> {
+        let image: Vec<u8> = PROGRAM.iter().flat_map(|half| half.to_le_bytes()).collect();
+        let mut cpu = Cpu::new();
+        cpu.load_multiboot(&image).unwrap();
+        cpu.reset();
+
+        let mut trace = Trace::new(vec!(), format, start, stop, max_lines);
+        for _ in 0..steps {
+            trace.log(&cpu);
+            cpu.step_instruction();
+        }
+
+        String::from_utf8(trace.out).unwrap().lines().map(|line| line.to_string()).collect()
+    }
+
+    /// The PCs a trace went through, from the start of each `Regs` line.
+    fn pcs(lines: &[String]) -> Vec<u32> {
+        lines.iter().map(|line| u32::from_str_radix(&line[..8], 16).unwrap()).collect()
+    }
+
+    #[test]
+    fn formats() {
+        assert_eq!("mgba".parse(), Ok(TraceFormat::Mgba));
+        assert!("MGBA".parse::<TraceFormat>().is_err());
+    }
+
+    #[test]
+    fn plain_lines() {
+        let lines = trace(TraceFormat::Plain, None, None, None, 7);
+        assert_eq!(lines.len(), 7);
+        assert_eq!(lines[1], "02000004: e28f1001 add r1, pc, #1 @ 0x0200000d      r0=00000001 r1=00000000 r2=00000000 \
+                              r3=00000000 r4=00000000 r5=00000000 r6=00000000 r7=00000000 r8=00000000 r9=00000000 \
+                              r10=00000000 r11=00000000 r12=00000000 r13=03007f00 r14=00000000 r15=0200000c cpsr=0000005f");
+        // a bl disassembles whole at its first half
+        assert_eq!(lines[5], "02000010: f7ff     bl 0x0200000c                    r0=00000001 r1=0200000d r2=00000006 \
+                              r3=00000000 r4=00000000 r5=00000000 r6=00000000 r7=00000000 r8=00000000 r9=00000000 \
+                              r10=00000000 r11=00000000 r12=00000000 r13=03007f00 r14=00000000 r15=02000014 cpsr=0000007f");
+    }
+
+    #[test]
+    fn mgba_lines() {
+        let lines = trace(TraceFormat::Mgba, None, None, None, 7);
+        assert_eq!(lines[2], "00000001 0200000D 00000000 00000000 00000000 00000000 00000000 00000000 00000000 00000000 \
+                              00000000 00000000 00000000 03007F00 00000000 02000010 cpsr: 0000005F | E12FFF11:  bx r1");
+        assert_eq!(lines[6], "00000001 0200000D 00000006 00000000 00000000 00000000 00000000 00000000 00000000 00000000 \
+                              00000000 00000000 00000000 03007F00 01FFF014 02000016 cpsr: 0000007F |     FFFC:  bl.lo #2044");
+    }
+
+    #[test]
+    fn regs_lines() {
+        let registers = |r0, r1, r2, r14, r15, cpsr| {
+            format!("{:08X} {:08X} {:08X} {} 03007F00 {:08X} {:08X} {:08X}", r0, r1, r2, ["00000000"; 10].join(" "), r14, r15, cpsr)
+        };
+        let golden = [
+            format!("02000000 E3A00001 {}", registers(0, 0, 0, 0, 0x02000008, 0x5F)),
+            format!("02000004 E28F1001 {}", registers(1, 0, 0, 0, 0x0200000C, 0x5F)),
+            format!("02000008 E12FFF11 {}", registers(1, 0x0200000D, 0, 0, 0x02000010, 0x5F)),
+            format!("0200000C 00002205 {}", registers(1, 0x0200000D, 0, 0, 0x02000010, 0x7F)),
+            format!("0200000E 00001812 {}", registers(1, 0x0200000D, 5, 0, 0x02000012, 0x7F)),
+            format!("02000010 0000F7FF {}", registers(1, 0x0200000D, 6, 0, 0x02000014, 0x7F)),
+            format!("02000012 0000FFFC {}", registers(1, 0x0200000D, 6, 0x01FFF014, 0x02000016, 0x7F)),
+        ];
+        assert_eq!(trace(TraceFormat::Regs, None, None, None, 7), golden);
+    }
+
+    #[test]
+    fn start_stop_and_max_lines() {
+        let all = pcs(&trace(TraceFormat::Regs, None, None, None, 12));
+        assert_eq!(all, [0x02000000, 0x02000004, 0x02000008, 0x0200000C, 0x0200000E, 0x02000010,
+                         0x02000012, 0x0200000C, 0x0200000E, 0x02000010, 0x02000012, 0x0200000C]);
+
+        // nothing before the start address
+        assert_eq!(pcs(&trace(TraceFormat::Regs, Some(0x0200000C), None, None, 8)), all[3..8]);
+        // paused at the stop address, resumed at the start address again
+        assert_eq!(pcs(&trace(TraceFormat::Regs, Some(0x0200000C), Some(0x02000010), None, 12)),
+                   [0x0200000C, 0x0200000E, 0x0200000C, 0x0200000E, 0x0200000C]);
+        // a stop address alone pauses for good
+        assert_eq!(pcs(&trace(TraceFormat::Regs, None, Some(0x0200000E), None, 12)), all[..4]);
+        assert_eq!(pcs(&trace(TraceFormat::Regs, None, None, Some(5), 12)), all[..5]);
+        assert!(trace(TraceFormat::Regs, Some(0x08000000), None, None, 12).is_empty());
+    }
+
+    #[test]
+    fn finish_counts_lines() {
+        let mut cpu = Cpu::new();
+        cpu.load_multiboot(&[0xFE, 0xFF, 0xFF, 0xEA]).unwrap();
+        cpu.reset();
+
+        let mut trace = Trace::new(vec!(), TraceFormat::Plain, None, None, Some(3));
+        for _ in 0..5 {
+            trace.log(&cpu);
+            cpu.step_instruction();
+        }
+
+        assert!(trace.full());
+        assert_eq!(trace.finish().unwrap(), 3);
+    }
+}
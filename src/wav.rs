@@ -0,0 +1,166 @@
+use std::fs::File;
+use std::io::{self, BufWriter, ErrorKind, Seek, SeekFrom, Write};
+
+use super::apu::{self, AudioSink, Resampler};
+
+const HEADER_SIZE: u32 = 44;
+const CHANNELS: u16 = 2;
+const BITS: u16 = 16;
+const BLOCK_ALIGN: u16 = CHANNELS * BITS / 8;
+
+/// The RIFF size field counts everything after itself, so the data has to stop this much
+/// short of 4 GiB, at a whole stereo sample.
+const MAX_DATA_SIZE: u32 = (u32::MAX - (HEADER_SIZE - 8)) / BLOCK_ALIGN as u32 * BLOCK_ALIGN as u32;
+
+/// Writes 16-bit stereo PCM to a WAV file. The RIFF and data sizes are patched in by `finish`.
+/// Samples past the 4 GiB a WAV file can hold are dropped, so the header stays valid.
+pub struct WavWriter<W: Write + Seek = BufWriter<File>> {
+    file: W,
+    data_size: u32,
+    full: bool,
+}
+
+impl WavWriter {
+    pub fn create(path: &str, sample_rate: u32) -> io::Result<WavWriter> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+/// A WAV file at `sample_rate`, resampling the emulator's output to it if need be.
+pub fn create_sink(path: &str, sample_rate: u32) -> io::Result<Box<dyn AudioSink>> {
+    let writer = WavWriter::create(path, sample_rate)?;
+
+    if sample_rate == apu::SAMPLE_RATE {
+        Ok(Box::new(writer))
+    } else {
+        Ok(Box::new(Resampler::new(writer, sample_rate)))
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut file: W, sample_rate: u32) -> io::Result<WavWriter<W>> {
+        let byte_rate = match sample_rate.checked_mul(BLOCK_ALIGN as u32) {
+            Some(byte_rate) if sample_rate > 0 => byte_rate,
+            _ => return Err(io::Error::new(ErrorKind::InvalidInput, format!("unsupported sample rate {}", sample_rate))),
+        };
+
+        file.write_all(b"RIFF")?;
+        file.write_all(&(HEADER_SIZE - 8).to_le_bytes())?;
+        file.write_all(b"WAVE")?;
+        file.write_all(b"fmt ")?;
+        file.write_all(&16u32.to_le_bytes())?;
+        file.write_all(&1u16.to_le_bytes())?;
+        file.write_all(&CHANNELS.to_le_bytes())?;
+        file.write_all(&sample_rate.to_le_bytes())?;
+        file.write_all(&byte_rate.to_le_bytes())?;
+        file.write_all(&BLOCK_ALIGN.to_le_bytes())?;
+        file.write_all(&BITS.to_le_bytes())?;
+        file.write_all(b"data")?;
+        file.write_all(&0u32.to_le_bytes())?;
+
+        Ok(WavWriter {
+            file,
+            data_size: 0,
+            full: false,
+        })
+    }
+}
+
+impl<W: Write + Seek> AudioSink for WavWriter<W> {
+    fn write(&mut self, samples: &[i16]) -> io::Result<()> {
+        let room = ((MAX_DATA_SIZE - self.data_size) / 2) as usize;
+        let samples = if samples.len() > room {
+            if !self.full {
+                warn!("WAV file is full at 4 GiB, dropping the rest of the audio");
+                self.full = true;
+            }
+            &samples[..room]
+        } else {
+            samples
+        };
+
+        for sample in samples {
+            self.file.write_all(&sample.to_le_bytes())?;
+        }
+
+        self.data_size += samples.len() as u32 * 2;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(4))?;
+        self.file.write_all(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes())?;
+        self.file.seek(SeekFrom::Start(HEADER_SIZE as u64 - 4))?;
+        self.file.write_all(&self.data_size.to_le_bytes())?;
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::super::apu::AudioSink;
+    use super::{WavWriter, MAX_DATA_SIZE};
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from_le_bytes([bytes[offset], bytes[offset + 1]])
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes([bytes[offset], bytes[offset + 1], bytes[offset + 2], bytes[offset + 3]])
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let mut wav = WavWriter::new(Cursor::new(vec!()), 44100).unwrap();
+        wav.write(&[1, -1, 0x1234, -0x1234]).unwrap();
+        wav.write(&[i16::MAX, i16::MIN]).unwrap();
+        wav.finish().unwrap();
+        let bytes = wav.file.into_inner();
+
+        assert_eq!(bytes.len(), 44 + 12);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4), 36 + 12);
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 16), 16);
+        // PCM, stereo, the rate, bytes a second, bytes a sample pair, bits a sample
+        assert_eq!(u16_at(&bytes, 20), 1);
+        assert_eq!(u16_at(&bytes, 22), 2);
+        assert_eq!(u32_at(&bytes, 24), 44100);
+        assert_eq!(u32_at(&bytes, 28), 44100 * 4);
+        assert_eq!(u16_at(&bytes, 32), 4);
+        assert_eq!(u16_at(&bytes, 34), 16);
+        assert_eq!(&bytes[36..40], b"data");
+        assert_eq!(u32_at(&bytes, 40), 12);
+
+        let samples: Vec<i16> = bytes[44..].chunks(2).map(|pair| i16::from_le_bytes([pair[0], pair[1]])).collect();
+        assert_eq!(samples, [1, -1, 0x1234, -0x1234, i16::MAX, i16::MIN]);
+    }
+
+    #[test]
+    fn unsupported_sample_rates() {
+        assert!(WavWriter::new(Cursor::new(vec!()), 0).is_err());
+        assert!(WavWriter::new(Cursor::new(vec!()), u32::MAX / 4 + 1).is_err());
+        assert!(WavWriter::new(Cursor::new(vec!()), u32::MAX / 4).is_ok());
+    }
+
+    #[test]
+    fn stops_before_the_sizes_wrap() {
+        let mut wav = WavWriter::new(Cursor::new(vec!()), 32768).unwrap();
+        wav.data_size = MAX_DATA_SIZE - 4;
+
+        // room for one more pair of samples
+        wav.write(&[1, 2, 3, 4]).unwrap();
+        wav.write(&[5, 6]).unwrap();
+        assert_eq!(wav.data_size, MAX_DATA_SIZE);
+        wav.finish().unwrap();
+
+        let bytes = wav.file.into_inner();
+        assert_eq!(&bytes[44..48], [1, 0, 2, 0]);
+        assert_eq!(bytes.len(), 48);
+        assert_eq!(u32_at(&bytes, 4), MAX_DATA_SIZE + 36);
+        assert_eq!(MAX_DATA_SIZE + 36, u32::MAX - 3);
+        assert_eq!(u32_at(&bytes, 40), MAX_DATA_SIZE);
+    }
+}
@@ -1,15 +1,23 @@
 use num::{Unsigned, PrimInt};
 use std::mem::size_of;
 
-pub fn write_le<T: Unsigned + PrimInt>(memory: &[u8], address: u32, value: T) {
+use super::error::Error;
+
+pub fn write_le<T: Unsigned + PrimInt>(memory: &mut [u8], address: u32, value: T) -> Result<(), Error> {
+    if address as usize + size_of::<T>() > memory.len() {
+        return Err(Error::BusFault { offset: address, size: size_of::<T>() });
+    }
+
     for i in 0..size_of::<T>() {
-        match memory.get_mut(address as usize + i) {
-            Some(b) => *b = (value << (i * 8)).to_u8().expect("Unable to convert value to u8"),
-            None => unimplemented!(),
-        }
+        memory[address as usize + i] = (value >> (i * 8)).to_u32().expect("Unable to convert value to u32") as u8;
     }
+
+    Ok(())
 }
 
-pub fn write_generic<T: Unsigned + PrimInt>(memory: &[u8], address: u32, mask: u32, value: T) {
-    
+/// A masked write to a memory region. A faulting write is dropped, like one to ROM.
+pub fn write_generic<T: Unsigned + PrimInt>(memory: &mut [u8], address: u32, mask: u32, value: T) {
+    if let Err(e) = write_le(memory, address & mask & !(size_of::<T>() as u32 - 1), value) {
+        warn!("{}", e);
+    }
 }
@@ -0,0 +1,337 @@
+//! Boots every test ROM in a directory headless and prints a conformance table.
+//!
+//! The ROMs are not part of the repository. Put them in `tests/roms` or point
+//! `GBA_TEST_ROMS` at another directory, and run `cargo test --test conformance`. A ROM is
+//! checked when `name.gba` has a `name.expect` next to it; ROMs without one are listed as
+//! unchecked. Each line of an expect file is one of:
+//!
+//! ```text
+//! # a comment
+//! frames 300                          how long to run, 60 if missing
+//! bios gba_bios.bin                   boot through a BIOS image instead of the HLE reset
+//! reg r0 0x00000000                   a register after the last frame: r0-r15, pc or cpsr
+//! memory 0x03000000 50 41 53 53       bytes after the last frame, the ROM's result signature
+//! golden name.golden                  frame hashes from `--hash-frames`, at most one per ROM
+//! known-failure reason                expected not to pass yet; reported, but not an error
+//! ```
+//!
+//! Paths are relative to the expect file. The run fails if a ROM without `known-failure`
+//! fails a check, or if an expect file cannot be read.
+//!
+//! A ROM that passes its checks is run again on the other backends, `cached` and, with
+//! `--features jit`, `jit`. Their frame and state hashes have to match the interpreter's.
+
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+
+const DEFAULT_FRAMES: u32 = 60;
+
+#[cfg(not(feature = "jit"))]
+const OTHER_BACKENDS: &[&str] = &["cached"];
+#[cfg(feature = "jit")]
+const OTHER_BACKENDS: &[&str] = &["cached", "jit"];
+
+enum Check {
+    Reg(String, u32),
+    Memory(u32, Vec<u8>),
+    Golden(PathBuf),
+}
+
+struct Expect {
+    frames: u32,
+    bios: Option<PathBuf>,
+    checks: Vec<Check>,
+    known_failure: Option<String>,
+}
+
+enum Outcome {
+    Pass,
+    Fail(String),
+    KnownFailure(String),
+    /// Marked as a known failure, but every check passed.
+    Fixed,
+    Unchecked,
+    Error(String),
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    if text.starts_with("0x") || text.starts_with("0X") {
+        u32::from_str_radix(&text[2..], 16).ok()
+    } else {
+        text.parse().ok()
+    }
+}
+
+fn read_expect(path: &Path) -> Result<Expect, String> {
+    let file = File::open(path).map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+    let directory = path.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut expect = Expect {
+        frames: DEFAULT_FRAMES,
+        bios: None,
+        checks: vec!(),
+        known_failure: None,
+    };
+
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("failed to read {}: {}", path.display(), e))?;
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let malformed = || format!("{}:{}: malformed line: {}", path.display(), number + 1, line);
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        match words[0] {
+            "frames" if words.len() == 2 => expect.frames = parse_number(words[1]).ok_or_else(malformed)?,
+            "bios" if words.len() == 2 => expect.bios = Some(directory.join(words[1])),
+            "reg" if words.len() == 3 => {
+                let name = words[1].to_lowercase();
+                let known = name == "pc" || name == "cpsr" || (name.starts_with('r') && name[1..].parse::<u32>().is_ok_and(|index| index < 16));
+                if !known {
+                    return Err(malformed());
+                }
+
+                expect.checks.push(Check::Reg(name, parse_number(words[2]).ok_or_else(malformed)?));
+            },
+            "memory" if words.len() >= 3 => {
+                let address = parse_number(words[1]).ok_or_else(malformed)?;
+                let bytes: Option<Vec<u8>> = words[2..].iter().map(|byte| u8::from_str_radix(byte, 16).ok()).collect();
+                expect.checks.push(Check::Memory(address, bytes.ok_or_else(malformed)?));
+            },
+            "golden" if words.len() == 2 && !expect.checks.iter().any(|check| matches!(*check, Check::Golden(_))) => expect.checks.push(Check::Golden(directory.join(words[1]))),
+            "known-failure" => expect.known_failure = Some(words[1..].join(" ")),
+            _ => return Err(malformed()),
+        }
+    }
+
+    Ok(expect)
+}
+
+/// Runs the ROM once with everything the checks need and returns the first failed one.
+fn run(rom: &Path, expect: &Expect) -> Result<Option<String>, String> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_gba-rs"));
+
+    if let Some(ref bios) = expect.bios {
+        command.arg("--bios").arg(bios);
+    }
+
+    let golden = expect.checks.iter().filter_map(|check| match *check {
+        Check::Golden(ref path) => Some(path),
+        _ => None,
+    }).next();
+
+    match golden {
+        Some(path) => {
+            command.arg("--hash-frames").arg(expect.frames.to_string()).arg("--golden").arg(path);
+        },
+        None => {
+            command.arg("--frames").arg(expect.frames.to_string());
+        },
+    }
+
+    command.arg("--dump-regs");
+    for check in &expect.checks {
+        if let Check::Memory(address, ref bytes) = *check {
+            command.arg("--dump-memory").arg(format!("0x{:08x},{}", address, bytes.len()));
+        }
+    }
+    command.arg(rom);
+
+    let output = command.output().map_err(|e| format!("failed to start the emulator: {}", e))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    let regs: Vec<(String, u32)> = stdout.lines()
+        .filter(|line| line.starts_with("regs "))
+        .flat_map(|line| line.split_whitespace().skip(1))
+        .filter_map(|field| {
+            let mut parts = field.splitn(2, '=');
+            let name = parts.next()?.to_string();
+            let value = u32::from_str_radix(parts.next()?, 16).ok()?;
+            Some((name, value))
+        })
+        .collect();
+
+    if regs.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let last = stderr.lines().chain(stdout.lines()).rfind(|line| !line.trim().is_empty()).unwrap_or("no output");
+        return Err(format!("emulator exited with {}: {}", output.status, last.trim()));
+    }
+
+    for check in &expect.checks {
+        match *check {
+            Check::Reg(ref name, expected) => {
+                let lookup = if name == "pc" { "r15" } else { name.as_str() };
+                let actual = regs.iter().find(|(reg, _)| reg == lookup).map(|&(_, value)| value);
+
+                if actual != Some(expected) {
+                    let actual = actual.map_or("nothing".to_string(), |value| format!("{:08x}", value));
+                    return Ok(Some(format!("{} = {}, expected {:08x}", name, actual, expected)));
+                }
+            },
+            Check::Memory(address, ref expected) => {
+                let prefix = format!("memory {:08x} ", address);
+                let actual: Option<Vec<u8>> = stdout.lines()
+                    .find(|line| line.starts_with(&prefix))
+                    .and_then(|line| line[prefix.len()..].split_whitespace().map(|byte| u8::from_str_radix(byte, 16).ok()).collect());
+
+                if actual.as_ref() != Some(expected) {
+                    let hex = |bytes: &[u8]| bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ");
+                    let actual = actual.map_or("nothing".to_string(), |bytes| hex(&bytes));
+                    return Ok(Some(format!("{:08x} = {}, expected {}", address, actual, hex(expected))));
+                }
+            },
+            Check::Golden(_) => {
+                if !output.status.success() {
+                    let divergence = stdout.lines().find(|line| line.starts_with("first divergence") || line.contains("never reached"));
+                    return Ok(Some(divergence.unwrap_or("frame hashes differ").to_string()));
+                }
+            },
+        }
+    }
+
+    Ok(None)
+}
+
+/// The frame hashes of a run on `backend`, the last with the state hash.
+fn hashes(rom: &Path, expect: &Expect, backend: &str) -> Result<Vec<String>, String> {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_gba-rs"));
+
+    if let Some(ref bios) = expect.bios {
+        command.arg("--bios").arg(bios);
+    }
+
+    command.arg("--backend").arg(backend)
+        .arg("--hash-frames").arg(expect.frames.to_string())
+        .arg("--hash-state").arg(expect.frames.to_string())
+        .arg(rom);
+
+    let output = command.output().map_err(|e| format!("failed to start the emulator: {}", e))?;
+    if !output.status.success() {
+        return Err(format!("emulator exited with {} on the {} backend", output.status, backend));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).lines().map(|line| line.to_string()).collect())
+}
+
+/// Runs the ROM on every other backend and returns where the first one parts from the
+/// interpreter.
+fn compare_backends(rom: &Path, expect: &Expect) -> Result<Option<String>, String> {
+    let reference = hashes(rom, expect, "interpreter")?;
+
+    for backend in OTHER_BACKENDS {
+        let lines = hashes(rom, expect, backend)?;
+
+        if let Some((expected, actual)) = reference.iter().zip(lines.iter()).find(|(expected, actual)| expected != actual) {
+            return Ok(Some(format!("{} backend gave \"{}\", the interpreter \"{}\"", backend, actual, expected)));
+        }
+        if lines.len() != reference.len() {
+            return Ok(Some(format!("{} backend hashed {} frames, the interpreter {}", backend, lines.len(), reference.len())));
+        }
+    }
+
+    Ok(None)
+}
+
+fn check_rom(rom: &Path) -> (Outcome, usize) {
+    let expect_path = rom.with_extension("expect");
+    if !expect_path.exists() {
+        return (Outcome::Unchecked, 0);
+    }
+
+    let expect = match read_expect(&expect_path) {
+        Ok(expect) => expect,
+        Err(e) => return (Outcome::Error(e), 0),
+    };
+    let checks = expect.checks.len();
+
+    if checks == 0 {
+        return (Outcome::Error(format!("{} has no checks", expect_path.display())), 0);
+    }
+
+    let result = run(rom, &expect).and_then(|failure| match failure {
+        None => compare_backends(rom, &expect),
+        failure => Ok(failure),
+    });
+
+    let outcome = match (result, expect.known_failure) {
+        (Err(e), _) => Outcome::Error(e),
+        (Ok(None), None) => Outcome::Pass,
+        (Ok(None), Some(_)) => Outcome::Fixed,
+        (Ok(Some(failure)), None) => Outcome::Fail(failure),
+        (Ok(Some(failure)), Some(reason)) => Outcome::KnownFailure(if reason.is_empty() { failure } else { format!("{} ({})", failure, reason) }),
+    };
+
+    (outcome, checks)
+}
+
+fn main() {
+    let directory = match env::var_os("GBA_TEST_ROMS") {
+        Some(directory) => PathBuf::from(directory),
+        None => Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("roms"),
+    };
+
+    let mut roms: Vec<PathBuf> = match fs::read_dir(&directory) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|extension| extension == "gba"))
+            .collect(),
+        Err(_) => vec!(),
+    };
+    roms.sort();
+
+    if roms.is_empty() {
+        println!("no test ROMs in {}, set GBA_TEST_ROMS to run the conformance suite", directory.display());
+        return;
+    }
+
+    let width = roms.iter().map(|rom| rom.file_stem().unwrap().to_string_lossy().len()).max().unwrap_or(0).max(3);
+
+    println!("{:<width$}  {:>6}  {:<13}  detail", "rom", "checks", "result", width = width);
+    println!("{}", "-".repeat(width + 40));
+
+    let (mut passed, mut checked, mut unexpected) = (0, 0, 0);
+
+    for rom in &roms {
+        let (outcome, checks) = check_rom(rom);
+
+        match outcome {
+            Outcome::Pass | Outcome::Fixed => passed += 1,
+            Outcome::Fail(_) | Outcome::Error(_) => unexpected += 1,
+            _ => (),
+        }
+        if checks > 0 {
+            checked += 1;
+        }
+
+        let (result, detail) = match outcome {
+            Outcome::Pass => ("pass", String::new()),
+            Outcome::Fail(detail) => ("FAIL", detail),
+            Outcome::KnownFailure(detail) => ("known failure", detail),
+            Outcome::Fixed => ("fixed", "passes; remove known-failure".to_string()),
+            Outcome::Unchecked => ("unchecked", "no .expect file".to_string()),
+            Outcome::Error(detail) => ("ERROR", detail),
+        };
+
+        let name = rom.file_stem().unwrap().to_string_lossy();
+        let checks = if checks == 0 { "-".to_string() } else { checks.to_string() };
+        println!("{:<width$}  {:>6}  {:<13}  {}", name, checks, result, detail, width = width);
+    }
+
+    println!("{}", "-".repeat(width + 40));
+    if checked > 0 {
+        println!("{} of {} checked ROMs pass ({:.1}%)", passed, checked, passed as f64 * 100.0 / checked as f64);
+    } else {
+        println!("none of the {} ROMs have an .expect file", roms.len());
+    }
+
+    if unexpected > 0 {
+        println!("{} unexpected failure{}", unexpected, if unexpected == 1 { "" } else { "s" });
+        process::exit(1);
+    }
+}